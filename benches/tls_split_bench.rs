@@ -0,0 +1,152 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use atlas_common::crypto::signature::{KeyPair, PublicKey};
+use atlas_common::node_id::NodeId;
+use atlas_common::socket::identity::NodeKeyDirectory;
+use atlas_common::socket::mtls::MutualTlsConfig;
+use atlas_common::socket::{bind_async_server, connect_async, SecureSocketAsync, SocketConfig};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::io::{BufReader, BufWriter};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Mutual TLS configs for the nodes `0..count`, which all know each other.
+/// The same as the crate's test helper, which benches can't reach since it's only built for tests
+fn tls_configs(count: u32) -> Vec<MutualTlsConfig> {
+    let nodes: Vec<_> = (0..count)
+        .map(|id| (NodeId(id), Arc::new(KeyPair::generate_key_pair().unwrap())))
+        .collect();
+
+    let directory: NodeKeyDirectory = nodes
+        .iter()
+        .map(|(id, key)| (*id, PublicKey::from(key.public_key())))
+        .collect();
+
+    nodes
+        .into_iter()
+        .map(|(id, key)| MutualTlsConfig::new(id, key, directory.clone()).unwrap())
+        .collect()
+}
+
+/// Connect two nodes over loopback, returning the client and server TLS sockets
+async fn tls_pair() -> (SecureSocketAsync, SecureSocketAsync) {
+    let tls = tls_configs(2);
+
+    let config = SocketConfig::default();
+
+    let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (client, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+
+    let (client, server) = tokio::join!(
+        tls[1].connect_async(NodeId(0), client.unwrap()),
+        tls[0].accept_async(server.unwrap())
+    );
+
+    (client.unwrap().1, server.unwrap().1)
+}
+
+type Halves = (
+    Box<dyn AsyncWrite + Send + Unpin>,
+    Box<dyn AsyncRead + Send + Unpin>,
+);
+
+/// The previous split, where both halves share the TLS stream through a bilock
+fn split_bilock(socket: SecureSocketAsync) -> Halves {
+    let SecureSocketAsync::Tls(stream) = socket else {
+        unreachable!()
+    };
+
+    let config = *stream.get_ref().0.config();
+
+    let (read, write) = AsyncReadExt::split(*stream);
+
+    (
+        Box::new(BufWriter::with_capacity(
+            config.write_buffer_capacity,
+            write,
+        )),
+        Box::new(BufReader::with_capacity(config.read_buffer_capacity, read)),
+    )
+}
+
+fn split_duplex(socket: SecureSocketAsync) -> Halves {
+    let (write, read) = socket.split();
+
+    (Box::new(write), Box::new(read))
+}
+
+/// Have both sides send `bytes` to each other at the same time,
+/// returning how long it took for both of them to receive everything
+async fn exchange(split: fn(SecureSocketAsync) -> Halves, bytes: usize) -> Duration {
+    let (client, server) = tls_pair().await;
+
+    let start = Instant::now();
+
+    let sides = [split(client), split(server)].map(|(mut write, mut read)| {
+        tokio::spawn(async move {
+            let send = async {
+                let chunk = vec![7; CHUNK_SIZE];
+
+                for _ in 0..bytes / CHUNK_SIZE {
+                    write.write_all(&chunk).await.unwrap();
+                }
+
+                write.flush().await.unwrap();
+            };
+
+            let receive = async {
+                let mut chunk = vec![0; CHUNK_SIZE];
+
+                for _ in 0..bytes / CHUNK_SIZE {
+                    read.read_exact(&mut chunk).await.unwrap();
+                }
+            };
+
+            futures::join!(send, receive);
+        })
+    });
+
+    for side in sides {
+        side.await.unwrap();
+    }
+
+    start.elapsed()
+}
+
+fn tls_split_benchmark(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("TLS duplex split");
+
+    for bytes in [1 << 20, 16 << 20] {
+        group.throughput(Throughput::Bytes(2 * bytes as u64));
+
+        group.bench_with_input(BenchmarkId::new("bilock", bytes), &bytes, |b, bytes| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| runtime.block_on(exchange(split_bilock, *bytes)))
+                    .sum()
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("duplex", bytes), &bytes, |b, bytes| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| runtime.block_on(exchange(split_duplex, *bytes)))
+                    .sum()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, tls_split_benchmark);
+criterion_main!(benches);
@@ -1,6 +1,7 @@
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use crate::error::*;
 
@@ -8,6 +9,7 @@ pub struct JoinHandle<T> {
     inner: ::async_std::task::JoinHandle<T>,
 }
 
+#[derive(Debug)]
 pub struct Runtime;
 
 pub fn init(num_threads: usize) -> Result<Runtime> {
@@ -25,11 +27,24 @@ impl Runtime {
         JoinHandle { inner }
     }
 
+    pub fn spawn_blocking<F, R>(&self, function: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let inner = ::async_std::task::spawn_blocking(function);
+        JoinHandle { inner }
+    }
+
     pub fn block_on<F: Future>(&self, future: F) -> F::Output {
         ::async_std::task::block_on(future)
     }
 }
 
+pub async fn sleep(duration: Duration) {
+    ::async_std::task::sleep(duration).await
+}
+
 impl<T> Future for JoinHandle<T> {
     type Output = Result<T>;
 
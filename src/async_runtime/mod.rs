@@ -12,6 +12,7 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::OnceLock;
 use std::task::{Context as Cntx, Poll};
+use std::time::Duration;
 
 #[cfg(feature = "async_runtime_tokio")]
 static RUNTIME: OnceLock<tokio::Runtime> = OnceLock::new();
@@ -56,7 +57,7 @@ pub fn init(num_threads: usize) -> Result<()> {
 
     #[cfg(feature = "async_runtime_async_std")]
     {
-        let runtime = async_std::init(num_threads);
+        let runtime = async_std::init(num_threads)?;
 
         RUNTIME
             .set(runtime)
@@ -134,3 +135,12 @@ pub async fn yield_now() {
 
     YieldNow { yielded: false }.await;
 }
+
+/// Waits for the given duration, without blocking the async runtime's threads.
+pub async fn sleep(duration: Duration) {
+    #[cfg(feature = "async_runtime_tokio")]
+    tokio::sleep(duration).await;
+
+    #[cfg(feature = "async_runtime_async_std")]
+    async_std::sleep(duration).await;
+}
@@ -1,6 +1,7 @@
 use crate::error::*;
 use anyhow::Context;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 pub type JoinHandle<T> = ::tokio::task::JoinHandle<T>;
 
@@ -25,3 +26,7 @@ pub fn init(num_threads: usize) -> Result<Runtime> {
 
     Ok(result)
 }
+
+pub async fn sleep(duration: Duration) {
+    ::tokio::time::sleep(duration).await
+}
@@ -0,0 +1,198 @@
+//! Inspect the capture files written by the capturing socket halves.
+//!
+//! ```text
+//! atlas-capture list <capture> [filters] [--payload]
+//! atlas-capture filter <capture> <output> [filters]
+//!
+//! filters:
+//!   --peer <id>             only frames exchanged with this node
+//!   --direction <in|out>    only received (in) or sent (out) frames
+//!   --after <micros>        only frames captured at or after this time
+//!   --before <micros>       only frames captured before this time
+//! ```
+//!
+//! Times are in microseconds since the unix epoch, as shown by `list`.
+
+use std::env;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context};
+
+use atlas_common::node_id::NodeId;
+use atlas_common::socket::capture::{
+    CaptureFile, CaptureFilter, CaptureReader, CaptureRecord, Direction,
+};
+
+/// How many bytes of each payload `list --payload` shows
+const PAYLOAD_PREVIEW: usize = 32;
+
+const USAGE: &str = "usage:
+  atlas-capture list <capture> [filters] [--payload]
+  atlas-capture filter <capture> <output> [filters]
+
+filters:
+  --peer <id>             only frames exchanged with this node
+  --direction <in|out>    only received (in) or sent (out) frames
+  --after <micros>        only frames captured at or after this time
+  --before <micros>       only frames captured before this time";
+
+struct Options {
+    filter: CaptureFilter,
+    show_payload: bool,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        Some("list") if args.len() >= 2 => {
+            parse_options(&args[2..]).and_then(|options| list(&args[1], options))
+        }
+        Some("filter") if args.len() >= 3 => {
+            parse_options(&args[3..]).and_then(|options| filter(&args[1], &args[2], options))
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {:#}", err);
+
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_options(args: &[String]) -> anyhow::Result<Options> {
+    let mut options = Options {
+        filter: CaptureFilter::default(),
+        show_payload: false,
+    };
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--payload" {
+            options.show_payload = true;
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("missing value for {}\n\n{}", arg, USAGE))?;
+
+        match arg.as_str() {
+            "--peer" => {
+                options.filter.peer = Some(NodeId(
+                    value
+                        .parse()
+                        .with_context(|| format!("invalid peer {}", value))?,
+                ));
+            }
+            "--direction" => {
+                options.filter.direction = Some(match value.as_str() {
+                    "in" => Direction::Inbound,
+                    "out" => Direction::Outbound,
+                    _ => bail!("invalid direction {}, expected in or out", value),
+                });
+            }
+            "--after" => options.filter.after = Some(parse_time(value)?),
+            "--before" => options.filter.before = Some(parse_time(value)?),
+            _ => bail!("unknown option {}\n\n{}", arg, USAGE),
+        }
+    }
+
+    Ok(options)
+}
+
+fn parse_time(value: &str) -> anyhow::Result<SystemTime> {
+    let micros: u64 = value
+        .parse()
+        .with_context(|| format!("invalid time {}", value))?;
+
+    Ok(UNIX_EPOCH + Duration::from_micros(micros))
+}
+
+fn list(path: &str, options: Options) -> anyhow::Result<()> {
+    let reader = CaptureReader::open(path)
+        .with_context(|| format!("failed to open {}", path))?
+        .with_filter(options.filter);
+
+    let mut count = 0;
+    let mut bytes = 0;
+
+    for record in reader {
+        let record = record.with_context(|| format!("failed to read {}", path))?;
+
+        println!("{}", describe(&record, options.show_payload));
+
+        count += 1;
+        bytes += record.payload().len();
+    }
+
+    println!("{} frames, {} payload bytes", count, bytes);
+
+    Ok(())
+}
+
+fn filter(path: &str, output: &str, options: Options) -> anyhow::Result<()> {
+    let reader = CaptureReader::open(path)
+        .with_context(|| format!("failed to open {}", path))?
+        .with_filter(options.filter);
+
+    let output_file =
+        CaptureFile::open(output).with_context(|| format!("failed to open {}", output))?;
+
+    let mut count = 0;
+
+    for record in reader {
+        let record = record.with_context(|| format!("failed to read {}", path))?;
+
+        output_file
+            .append(&record)
+            .with_context(|| format!("failed to write {}", output))?;
+
+        count += 1;
+    }
+
+    println!("{} frames written to {}", count, output);
+
+    Ok(())
+}
+
+fn describe(record: &CaptureRecord, show_payload: bool) -> String {
+    let direction = match record.direction() {
+        Direction::Inbound => "<-",
+        Direction::Outbound => "->",
+    };
+
+    let mut line = format!(
+        "{} {} node {:<5} {:>10} bytes",
+        record.timestamp_micros(),
+        direction,
+        record.peer().id(),
+        record.payload().len()
+    );
+
+    if show_payload {
+        let preview = &record.payload()[..record.payload().len().min(PAYLOAD_PREVIEW)];
+
+        line.push_str("  ");
+
+        for byte in preview {
+            line.push_str(&format!("{:02x}", byte));
+        }
+
+        if preview.len() < record.payload().len() {
+            line.push_str("..");
+        }
+    }
+
+    line
+}
@@ -0,0 +1,614 @@
+//! Failure detection based on heartbeats, using the phi accrual detector
+//! (Hayashibara et al., "The φ Accrual Failure Detector").
+//!
+//! Instead of a yes or no answer, the detector keeps a suspicion level (phi) for every peer,
+//! computed from how long it has been since the last heartbeat of that peer compared to
+//! the intervals seen so far. A phi of 1 means there is a 10% chance of the peer being alive
+//! and just late, a phi of 2 a 1% chance, and so on. A peer is suspected once its phi
+//! reaches [FailureDetectorConfig::suspect_threshold], and trusted again on its next heartbeat.
+//! These transitions are published on the channel returned by [FailureDetector::new].
+//!
+//! The heartbeats themselves are exchanged over existing connections (for example, a
+//! [StreamPriority::High](crate::socket::mux::StreamPriority::High) stream of a multiplexed
+//! connection) with [send_heartbeats_sync] on one side and [FailureDetector::receive_heartbeats_sync]
+//! on the other. Suspicion only grows when [FailureDetector::check] is called, which
+//! [FailureDetector::run_checks_sync] does periodically.
+//!
+//! A peer is monitored from the moment [FailureDetector::monitor] is called for it (which
+//! receiving its heartbeats does), as if it had just sent a heartbeat. A peer that never
+//! sends one is therefore suspected too. Heartbeats from peers that are not monitored
+//! are rejected.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use thiserror::Error;
+
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::async_runtime;
+use crate::channel::sync::{new_unbounded_sync, ChannelSyncRx, ChannelSyncTx};
+use crate::error::*;
+use crate::node_id::NodeId;
+use crate::Err;
+
+/// The source of time of a failure detector
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+/// A clock that only moves when told to, for tests.
+/// Clones share the same time
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+/// Configuration of the failure detector
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct FailureDetectorConfig {
+    /// How often heartbeats are sent
+    pub heartbeat_interval: Duration,
+    /// How often [FailureDetector::run_checks_sync] re-evaluates the peers
+    pub check_interval: Duration,
+    /// The phi at which a peer becomes suspected
+    pub suspect_threshold: f64,
+    /// How many heartbeat intervals are kept per peer
+    pub max_samples: usize,
+    /// The lowest standard deviation used, so a peer with very regular heartbeats
+    /// is not suspected as soon as one of them is a little late
+    pub min_std_deviation: Duration,
+    /// Pauses of up to this long are not held against a peer (garbage collection, for example)
+    pub acceptable_heartbeat_pause: Duration,
+}
+
+#[derive(Error, Debug)]
+pub enum FailureDetectorError {
+    #[error("Received a heartbeat from node {0:?}, which is not monitored")]
+    NotMonitored(NodeId),
+}
+
+/// A change in the status of a peer
+#[derive(Clone, Debug, PartialEq)]
+pub enum FailureEvent {
+    /// The phi of the peer reached the suspicion threshold
+    Suspected { node: NodeId, phi: f64 },
+    /// A heartbeat arrived from a peer that was suspected
+    Trusted { node: NodeId },
+}
+
+/// Keeps the suspicion level of every peer we receive heartbeats from
+pub struct FailureDetector<C = SystemClock> {
+    config: FailureDetectorConfig,
+    clock: C,
+    peers: Mutex<HashMap<NodeId, PeerState>>,
+    events: ChannelSyncTx<FailureEvent>,
+}
+
+struct PeerState {
+    last_heartbeat: Instant,
+    history: HeartbeatHistory,
+    suspected: bool,
+}
+
+/// The last intervals between heartbeats, in milliseconds
+struct HeartbeatHistory {
+    intervals: VecDeque<f64>,
+    sum: f64,
+    squared_sum: f64,
+}
+
+impl Default for FailureDetectorConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(1),
+            check_interval: Duration::from_millis(500),
+            suspect_threshold: 8.0,
+            max_samples: 1000,
+            min_std_deviation: Duration::from_millis(100),
+            acceptable_heartbeat_pause: Duration::ZERO,
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+}
+
+impl HeartbeatHistory {
+    /// Start from the expected heartbeat interval, with some deviation,
+    /// since a single heartbeat says nothing about the intervals
+    fn bootstrap(interval: Duration) -> Self {
+        let mean = interval.as_secs_f64() * 1000.0;
+        let deviation = mean / 4.0;
+
+        let mut history = Self {
+            intervals: VecDeque::new(),
+            sum: 0.0,
+            squared_sum: 0.0,
+        };
+
+        history.record(mean - deviation, usize::MAX);
+        history.record(mean + deviation, usize::MAX);
+
+        history
+    }
+
+    fn record(&mut self, interval: f64, max_samples: usize) {
+        if self.intervals.len() >= max_samples {
+            if let Some(oldest) = self.intervals.pop_front() {
+                self.sum -= oldest;
+                self.squared_sum -= oldest * oldest;
+            }
+        }
+
+        self.intervals.push_back(interval);
+        self.sum += interval;
+        self.squared_sum += interval * interval;
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum / self.intervals.len() as f64
+    }
+
+    fn std_deviation(&self) -> f64 {
+        let mean = self.mean();
+
+        (self.squared_sum / self.intervals.len() as f64 - mean * mean)
+            .max(0.0)
+            .sqrt()
+    }
+}
+
+impl PeerState {
+    /// A peer whose first heartbeat is taken to be at `now`
+    fn new(now: Instant, config: &FailureDetectorConfig) -> Self {
+        Self {
+            last_heartbeat: now,
+            history: HeartbeatHistory::bootstrap(config.heartbeat_interval),
+            suspected: false,
+        }
+    }
+
+    fn phi(&self, now: Instant, config: &FailureDetectorConfig) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_heartbeat);
+
+        phi(
+            elapsed.as_secs_f64() * 1000.0,
+            self.history.mean() + config.acceptable_heartbeat_pause.as_secs_f64() * 1000.0,
+            self.history
+                .std_deviation()
+                .max(config.min_std_deviation.as_secs_f64() * 1000.0),
+        )
+    }
+}
+
+/// The phi of a peer whose last heartbeat was `elapsed` ago, with heartbeat intervals
+/// following a normal distribution. Uses the logistic approximation of its cumulative
+/// distribution function, which does not lose all precision far from the mean
+fn phi(elapsed: f64, mean: f64, std_deviation: f64) -> f64 {
+    let y = (elapsed - mean) / std_deviation;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+impl FailureDetector<SystemClock> {
+    pub fn new(config: FailureDetectorConfig) -> (Self, ChannelSyncRx<FailureEvent>) {
+        Self::with_clock(config, SystemClock)
+    }
+}
+
+impl<C> FailureDetector<C>
+where
+    C: Clock,
+{
+    pub fn with_clock(
+        config: FailureDetectorConfig,
+        clock: C,
+    ) -> (Self, ChannelSyncRx<FailureEvent>) {
+        let (events, events_rx) = new_unbounded_sync(Some("FailureDetectorEvents"));
+
+        let detector = Self {
+            config,
+            clock,
+            peers: Mutex::new(HashMap::new()),
+            events,
+        };
+
+        (detector, events_rx)
+    }
+
+    pub fn config(&self) -> &FailureDetectorConfig {
+        &self.config
+    }
+
+    /// Start monitoring a peer, as if it had just sent a heartbeat.
+    /// Does nothing if the peer is already monitored
+    pub fn monitor(&self, node: NodeId) {
+        let now = self.clock.now();
+
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(node)
+            .or_insert_with(|| PeerState::new(now, &self.config));
+    }
+
+    /// Register a heartbeat from the given peer, which must be monitored
+    pub fn heartbeat(&self, node: NodeId) -> Result<()> {
+        let now = self.clock.now();
+        let mut peers = self.peers.lock().unwrap();
+
+        let Some(peer) = peers.get_mut(&node) else {
+            return Err!(FailureDetectorError::NotMonitored(node));
+        };
+
+        let interval = now.saturating_duration_since(peer.last_heartbeat);
+
+        peer.history
+            .record(interval.as_secs_f64() * 1000.0, self.config.max_samples);
+        peer.last_heartbeat = now;
+
+        if peer.suspected {
+            peer.suspected = false;
+
+            self.events.send(FailureEvent::Trusted { node })?;
+        }
+
+        Ok(())
+    }
+
+    /// The current suspicion level of a peer, or `None` if it is not monitored
+    pub fn phi(&self, node: NodeId) -> Option<f64> {
+        let now = self.clock.now();
+
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&node)
+            .map(|peer| peer.phi(now, &self.config))
+    }
+
+    /// Whether the peer is being monitored and its phi is below the suspicion threshold
+    pub fn is_available(&self, node: NodeId) -> bool {
+        self.phi(node)
+            .is_some_and(|phi| phi < self.config.suspect_threshold)
+    }
+
+    /// Stop monitoring a peer
+    pub fn remove(&self, node: NodeId) {
+        self.peers.lock().unwrap().remove(&node);
+    }
+
+    /// Re-evaluate every peer, publishing the ones that became suspected
+    pub fn check(&self) -> Result<()> {
+        let now = self.clock.now();
+        let mut peers = self.peers.lock().unwrap();
+
+        for (node, peer) in peers.iter_mut() {
+            if peer.suspected {
+                continue;
+            }
+
+            let phi = peer.phi(now, &self.config);
+
+            if phi >= self.config.suspect_threshold {
+                peer.suspected = true;
+
+                self.events
+                    .send(FailureEvent::Suspected { node: *node, phi })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Call [Self::check] every [FailureDetectorConfig::check_interval],
+    /// until the receiver of the events is dropped
+    pub fn run_checks_sync(&self) {
+        while self.check().is_ok() {
+            thread::sleep(self.config.check_interval);
+        }
+    }
+
+    /// The async counterpart of [Self::run_checks_sync]
+    pub async fn run_checks_async(&self) {
+        while self.check().is_ok() {
+            async_runtime::sleep(self.config.check_interval).await;
+        }
+    }
+
+    /// Register the heartbeats sent by [send_heartbeats_sync] on the other end of the
+    /// connection, until it is closed. The peer is monitored from the start, so it is
+    /// suspected even if it never sends a heartbeat
+    pub fn receive_heartbeats_sync<R>(&self, node: NodeId, mut read: R) -> Result<()>
+    where
+        R: Read,
+    {
+        let mut heartbeat = [0; HEARTBEAT_LEN];
+
+        self.monitor(node);
+
+        loop {
+            match read.read_exact(&mut heartbeat) {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            }
+
+            self.heartbeat(node)?;
+        }
+    }
+
+    /// The async counterpart of [Self::receive_heartbeats_sync]
+    pub async fn receive_heartbeats_async<R>(&self, node: NodeId, mut read: R) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut heartbeat = [0; HEARTBEAT_LEN];
+
+        self.monitor(node);
+
+        loop {
+            match read.read_exact(&mut heartbeat).await {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            }
+
+            self.heartbeat(node)?;
+        }
+    }
+}
+
+/// Heartbeats carry a sequence number, which is only there to make them easy to follow
+/// in a capture
+const HEARTBEAT_LEN: usize = 8;
+
+/// Send a heartbeat every interval, until the connection fails
+pub fn send_heartbeats_sync<W>(mut write: W, interval: Duration) -> Result<()>
+where
+    W: Write,
+{
+    for sequence in 0u64.. {
+        write.write_all(&sequence.to_be_bytes())?;
+        write.flush()?;
+
+        thread::sleep(interval);
+    }
+
+    Ok(())
+}
+
+/// The async counterpart of [send_heartbeats_sync]
+pub async fn send_heartbeats_async<W>(mut write: W, interval: Duration) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    for sequence in 0u64.. {
+        write.write_all(&sequence.to_be_bytes()).await?;
+        write.flush().await?;
+
+        async_runtime::sleep(interval).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::{bind_sync_server, connect_sync, SecureSocketSync, SocketConfig};
+
+    const PEER: NodeId = NodeId(1);
+
+    fn config() -> FailureDetectorConfig {
+        FailureDetectorConfig {
+            heartbeat_interval: Duration::from_millis(100),
+            min_std_deviation: Duration::from_millis(10),
+            ..Default::default()
+        }
+    }
+
+    fn steady_heartbeats(detector: &FailureDetector<MockClock>, clock: &MockClock) {
+        detector.monitor(PEER);
+
+        for _ in 0..20 {
+            clock.advance(Duration::from_millis(100));
+            detector.heartbeat(PEER).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_phi_grows_with_silence() {
+        let clock = MockClock::new();
+        let (detector, _events) = FailureDetector::with_clock(config(), clock.clone());
+
+        assert_eq!(detector.phi(PEER), None);
+        assert!(!detector.is_available(PEER));
+
+        steady_heartbeats(&detector, &clock);
+
+        let mut last = detector.phi(PEER).unwrap();
+        assert!(last < 1.0);
+
+        for _ in 0..5 {
+            clock.advance(Duration::from_millis(50));
+
+            let phi = detector.phi(PEER).unwrap();
+            assert!(phi > last);
+
+            last = phi;
+        }
+    }
+
+    #[test]
+    fn test_suspect_and_trust_transitions() {
+        let clock = MockClock::new();
+        let (detector, events) = FailureDetector::with_clock(config(), clock.clone());
+
+        steady_heartbeats(&detector, &clock);
+
+        clock.advance(Duration::from_millis(110));
+        detector.check().unwrap();
+        assert!(events.try_recv().is_err());
+        assert!(detector.is_available(PEER));
+
+        clock.advance(Duration::from_secs(1));
+        detector.check().unwrap();
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            FailureEvent::Suspected { node: PEER, .. }
+        ));
+        assert!(!detector.is_available(PEER));
+
+        // Only the transition is published
+        detector.check().unwrap();
+        assert!(events.try_recv().is_err());
+
+        detector.heartbeat(PEER).unwrap();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            FailureEvent::Trusted { node: PEER }
+        );
+    }
+
+    #[test]
+    fn test_silent_peers_are_suspected() {
+        let clock = MockClock::new();
+        let (detector, events) = FailureDetector::with_clock(config(), clock.clone());
+
+        detector.monitor(PEER);
+        assert!(detector.is_available(PEER));
+
+        clock.advance(Duration::from_secs(1));
+        detector.check().unwrap();
+
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            FailureEvent::Suspected { node: PEER, .. }
+        ));
+
+        // Monitoring again does not reset the peer
+        detector.monitor(PEER);
+        assert!(!detector.is_available(PEER));
+    }
+
+    #[test]
+    fn test_heartbeats_of_unmonitored_peers_are_rejected() {
+        let (detector, _events) = FailureDetector::with_clock(config(), MockClock::new());
+
+        let err = detector.heartbeat(PEER).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<FailureDetectorError>(),
+            Some(FailureDetectorError::NotMonitored(PEER))
+        ));
+        assert_eq!(detector.phi(PEER), None);
+    }
+
+    #[test]
+    fn test_acceptable_pause_delays_suspicion() {
+        let clock = MockClock::new();
+        let (detector, _events) = FailureDetector::with_clock(
+            FailureDetectorConfig {
+                acceptable_heartbeat_pause: Duration::from_secs(1),
+                ..config()
+            },
+            clock.clone(),
+        );
+
+        steady_heartbeats(&detector, &clock);
+
+        clock.advance(Duration::from_millis(900));
+        assert!(detector.is_available(PEER));
+    }
+
+    #[test]
+    fn test_heartbeats_over_a_connection() {
+        let socket_config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &socket_config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (write, _read) =
+            SecureSocketSync::new_plain(connect_sync(addr, &socket_config).unwrap()).split();
+        let (_write, read) = SecureSocketSync::new_plain(listener.accept().unwrap()).split();
+
+        thread::spawn(move || send_heartbeats_sync(write, Duration::from_millis(10)));
+
+        let (detector, _events) = FailureDetector::new(FailureDetectorConfig {
+            heartbeat_interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let detector = Arc::new(detector);
+
+        {
+            let detector = detector.clone();
+
+            thread::spawn(move || detector.receive_heartbeats_sync(PEER, read));
+        }
+
+        // The peer is available as soon as it is monitored, but only
+        // the heartbeats it sends add to the bootstrapped history
+        let bootstrapped = HeartbeatHistory::bootstrap(Duration::ZERO).intervals.len();
+        let samples = || {
+            detector
+                .peers
+                .lock()
+                .unwrap()
+                .get(&PEER)
+                .map_or(0, |peer| peer.history.intervals.len())
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while samples() < bootstrapped + 5 {
+            assert!(Instant::now() < deadline, "Not enough heartbeats arrived");
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(detector.is_available(PEER));
+    }
+}
@@ -36,6 +36,7 @@ pub mod collections;
 pub mod config_utils;
 pub mod crypto;
 pub mod error;
+pub mod failure_detector;
 pub mod globals;
 pub mod maybe_vec;
 pub mod node_id;
@@ -1,24 +1,146 @@
-use std::net::SocketAddr;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
 
 #[cfg(feature = "serialize_serde")]
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(feature = "serialize_serde")]
+use std::net::{SocketAddrV4, SocketAddrV6};
+
+/// The purpose of an endpoint of a peer
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub enum EndpointKind {
+    /// Where the other replicas connect to
+    Replica,
+    /// Where the clients connect to
+    Client,
+    /// Where the operators connect to, for administration
+    Admin,
+}
+
+/// The address of an endpoint
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub enum EndpointAddr {
+    /// An IPv4 or IPv6 socket address
+    Socket(SocketAddr),
+    /// A DNS name, which is only resolved when connecting
+    Dns { host: String, port: u16 },
+}
+
+/// One of the addresses a peer can be reached at
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct Endpoint {
+    kind: EndpointKind,
+    addr: EndpointAddr,
+}
 
 ///Represents the server addresses of a peer
-///Clients will only have 1 address while replicas will have 2 addresses (1 for facing clients,
-/// 1 for facing replicas)
+///All nodes have a replica facing socket address, which is always the first endpoint to be tried.
+///Replicas will usually also have a client facing (and possibly an admin) endpoint.
+///Each kind of endpoint may have several alternatives (such as an IPv4 and an IPv6 address),
+/// which are tried in the order they were added
 #[derive(Clone, Debug)]
-#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
 pub struct PeerAddr {
-    // All nodes have a replica facing socket: (SocketAddr, String),
     socket: SocketAddr,
     hostname: String,
+    // Starts with the replica facing socket address
+    endpoints: Vec<Endpoint>,
+}
+
+/// The serialized form of a [PeerAddr] in human readable formats.
+///
+/// Configs written before endpoints were introduced only have the `socket` and
+/// `hostname` fields, so the other endpoints default to none
+#[cfg(feature = "serialize_serde")]
+#[derive(Serialize, Deserialize)]
+struct PeerAddrRepr {
+    socket: SocketAddr,
+    hostname: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    endpoints: Vec<Endpoint>,
+}
+
+/// The serialized form of a [PeerAddr] in binary formats, such as bincode.
+///
+/// Those formats do not write field names, so data written before endpoints were
+/// introduced (the socket address followed by the hostname) cannot be recognized by its
+/// fields. It does start with the variant of the [SocketAddr], which the first two variants
+/// line up with. Addresses with other endpoints use the third one, which older readers reject
+#[cfg(feature = "serialize_serde")]
+#[derive(Serialize, Deserialize)]
+enum PeerAddrBinaryRepr {
+    V4(SocketAddrV4, String),
+    V6(SocketAddrV6, String),
+    Endpoints(SocketAddr, String, Vec<Endpoint>),
+}
+
+impl Endpoint {
+    pub fn new(kind: EndpointKind, addr: EndpointAddr) -> Self {
+        Self { kind, addr }
+    }
+
+    pub fn socket(kind: EndpointKind, addr: SocketAddr) -> Self {
+        Self::new(kind, EndpointAddr::Socket(addr))
+    }
+
+    pub fn dns(kind: EndpointKind, host: impl Into<String>, port: u16) -> Self {
+        Self::new(
+            kind,
+            EndpointAddr::Dns {
+                host: host.into(),
+                port,
+            },
+        )
+    }
+
+    pub fn kind(&self) -> EndpointKind {
+        self.kind
+    }
+
+    pub fn addr(&self) -> &EndpointAddr {
+        &self.addr
+    }
+
+    /// Resolve the socket addresses of this endpoint.
+    /// Blocks while resolving DNS names
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        match &self.addr {
+            EndpointAddr::Socket(addr) => Ok(vec![*addr]),
+            EndpointAddr::Dns { host, port } => {
+                Ok((host.as_str(), *port).to_socket_addrs()?.collect())
+            }
+        }
+    }
 }
 
 impl PeerAddr {
+    /// A peer with a single replica facing address
     pub fn new(socket: SocketAddr, hostname: String) -> Self {
-        Self { socket, hostname }
+        Self::with_endpoints(socket, hostname, Vec::new())
     }
 
+    /// A peer with the given endpoints, which are tried after the replica facing `socket`
+    pub fn with_endpoints(socket: SocketAddr, hostname: String, endpoints: Vec<Endpoint>) -> Self {
+        let mut all_endpoints = Vec::with_capacity(endpoints.len() + 1);
+
+        all_endpoints.push(Endpoint::socket(EndpointKind::Replica, socket));
+        all_endpoints.extend(endpoints);
+
+        Self {
+            socket,
+            hostname,
+            endpoints: all_endpoints,
+        }
+    }
+
+    /// Add an endpoint, which is tried after the ones of the same kind added before it
+    pub fn add_endpoint(&mut self, endpoint: Endpoint) {
+        self.endpoints.push(endpoint);
+    }
+
+    /// The replica facing socket address
     pub fn socket(&self) -> &SocketAddr {
         &self.socket
     }
@@ -30,4 +152,184 @@ impl PeerAddr {
     pub fn into_inner(self) -> (SocketAddr, String) {
         (self.socket, self.hostname)
     }
+
+    /// All endpoints, starting with the replica facing socket address
+    pub fn endpoints(&self) -> &[Endpoint] {
+        &self.endpoints
+    }
+
+    /// The endpoints of the given kind, in the order they should be tried
+    pub fn endpoints_of(&self, kind: EndpointKind) -> impl Iterator<Item = &Endpoint> {
+        self.endpoints
+            .iter()
+            .filter(move |endpoint| endpoint.kind() == kind)
+    }
+
+    /// The first socket address of the given kind that does not need to be resolved, if any
+    pub fn endpoint_socket(&self, kind: EndpointKind) -> Option<&SocketAddr> {
+        self.endpoints_of(kind)
+            .find_map(|endpoint| match endpoint.addr() {
+                EndpointAddr::Socket(addr) => Some(addr),
+                EndpointAddr::Dns { .. } => None,
+            })
+    }
+
+    /// The endpoints added on top of the replica facing socket address
+    fn extra_endpoints(&self) -> &[Endpoint] {
+        &self.endpoints[1..]
+    }
+}
+
+#[cfg(feature = "serialize_serde")]
+impl Serialize for PeerAddr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let hostname = self.hostname.clone();
+        let endpoints = self.extra_endpoints().to_vec();
+
+        if serializer.is_human_readable() {
+            return PeerAddrRepr {
+                socket: self.socket,
+                hostname,
+                endpoints,
+            }
+            .serialize(serializer);
+        }
+
+        // Keep writing the old layout when it is enough, so older readers still understand it
+        let repr = match self.socket {
+            SocketAddr::V4(socket) if endpoints.is_empty() => {
+                PeerAddrBinaryRepr::V4(socket, hostname)
+            }
+            SocketAddr::V6(socket) if endpoints.is_empty() => {
+                PeerAddrBinaryRepr::V6(socket, hostname)
+            }
+            socket => PeerAddrBinaryRepr::Endpoints(socket, hostname, endpoints),
+        };
+
+        repr.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serialize_serde")]
+impl<'de> Deserialize<'de> for PeerAddr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            let repr = PeerAddrRepr::deserialize(deserializer)?;
+
+            return Ok(PeerAddr::with_endpoints(
+                repr.socket,
+                repr.hostname,
+                repr.endpoints,
+            ));
+        }
+
+        Ok(match PeerAddrBinaryRepr::deserialize(deserializer)? {
+            PeerAddrBinaryRepr::V4(socket, hostname) => PeerAddr::new(socket.into(), hostname),
+            PeerAddrBinaryRepr::V6(socket, hostname) => PeerAddr::new(socket.into(), hostname),
+            PeerAddrBinaryRepr::Endpoints(socket, hostname, endpoints) => {
+                PeerAddr::with_endpoints(socket, hostname, endpoints)
+            }
+        })
+    }
+}
+
+#[cfg(all(test, feature = "serialize_serde"))]
+mod tests {
+    use super::*;
+
+    /// The layout of [PeerAddr] before endpoints were introduced
+    #[derive(Serialize, Deserialize)]
+    struct LegacyPeerAddr {
+        socket: SocketAddr,
+        hostname: String,
+    }
+
+    fn peer_with_endpoints() -> PeerAddr {
+        let mut addr = PeerAddr::with_endpoints(
+            "[::1]:10000".parse().unwrap(),
+            "srv-0".to_string(),
+            vec![Endpoint::dns(EndpointKind::Replica, "srv-0.atlas", 10000)],
+        );
+        addr.add_endpoint(Endpoint::socket(
+            EndpointKind::Client,
+            "10.0.0.1:11000".parse().unwrap(),
+        ));
+
+        addr
+    }
+
+    #[test]
+    fn test_legacy_configs_are_migrated() {
+        let legacy = r#"{"socket": "10.0.0.1:10000", "hostname": "srv-0"}"#;
+
+        let addr: PeerAddr = serde_json::from_str(legacy).unwrap();
+
+        assert_eq!(addr.hostname(), "srv-0");
+        assert_eq!(
+            addr.endpoints(),
+            &[Endpoint::socket(
+                EndpointKind::Replica,
+                "10.0.0.1:10000".parse().unwrap()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_legacy_bincode_is_read() {
+        for socket in ["10.0.0.1:10000", "[::1]:10000"] {
+            let legacy = LegacyPeerAddr {
+                socket: socket.parse().unwrap(),
+                hostname: "srv-0".to_string(),
+            };
+
+            let bytes =
+                bincode::serde::encode_to_vec(&legacy, bincode::config::standard()).unwrap();
+
+            let (addr, read): (PeerAddr, usize) =
+                bincode::serde::decode_from_slice(&bytes, bincode::config::standard()).unwrap();
+
+            assert_eq!(read, bytes.len());
+            assert_eq!(addr.into_inner(), (legacy.socket, legacy.hostname));
+
+            // Addresses without other endpoints are still written in the old layout
+            let written = bincode::serde::encode_to_vec(
+                &PeerAddr::new(legacy.socket, "srv-0".to_string()),
+                bincode::config::standard(),
+            )
+            .unwrap();
+
+            assert_eq!(written, bytes);
+        }
+    }
+
+    #[test]
+    fn test_endpoints_round_trip() {
+        let addr = peer_with_endpoints();
+
+        let json = serde_json::to_value(&addr).unwrap();
+
+        // Readers that only know the old format still find the replica address
+        assert_eq!(json["socket"], "[::1]:10000");
+
+        let decoded: PeerAddr = serde_json::from_value(json).unwrap();
+
+        assert_eq!(decoded.endpoints(), addr.endpoints());
+        assert_eq!(decoded.socket(), addr.socket());
+
+        let bytes = bincode::serde::encode_to_vec(&addr, bincode::config::standard()).unwrap();
+        let (decoded, _): (PeerAddr, usize) =
+            bincode::serde::decode_from_slice(&bytes, bincode::config::standard()).unwrap();
+
+        assert_eq!(decoded.endpoints(), addr.endpoints());
+        assert_eq!(
+            decoded.endpoint_socket(EndpointKind::Client),
+            Some(&"10.0.0.1:11000".parse().unwrap())
+        );
+    }
 }
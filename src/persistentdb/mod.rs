@@ -5,6 +5,9 @@ use thiserror::Error;
 
 use crate::error::Result;
 
+#[cfg(feature = "persistent_db_redb")]
+pub mod redb;
+
 #[cfg(feature = "persistent_db_rocksdb")]
 pub mod rocksdb;
 
@@ -23,9 +26,12 @@ pub struct KVDB {
     inner: Arc<rocksdb::RocksKVDB>,
     #[cfg(feature = "persistent_db_sled")]
     inner: Arc<sled::SledKVDB>,
+    #[cfg(feature = "persistent_db_redb")]
+    inner: Arc<redb::RedbKVDB>,
     #[cfg(all(
         not(feature = "persistent_db_rocksdb"),
-        not(feature = "persistent_db_sled")
+        not(feature = "persistent_db_sled"),
+        not(feature = "persistent_db_redb")
     ))]
     inner: disabled::DisabledKV,
 }
@@ -61,9 +67,17 @@ impl KVDB {
                         .context("Failed to create Sled KVDB")?,
                 )
             }
+            #[cfg(feature = "persistent_db_redb")]
+            {
+                Arc::new(
+                    redb::RedbKVDB::new(db_path, prefixes_cpy)
+                        .context("Failed to create redb KVDB")?,
+                )
+            }
             #[cfg(all(
                 not(feature = "persistent_db_rocksdb"),
-                not(feature = "persistent_db_sled")
+                not(feature = "persistent_db_sled"),
+                not(feature = "persistent_db_redb")
             ))]
             {
                 disabled::DisabledKV::new(db_path, prefixes_cpy)?
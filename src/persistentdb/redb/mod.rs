@@ -0,0 +1,385 @@
+#![allow(dead_code)]
+
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use redb::{CompactionError, Database, ReadOnlyTable, ReadableTable, TableDefinition};
+use tracing::debug;
+
+use crate::error::*;
+use crate::persistentdb::{IteratorUtil, PersStorageError};
+use crate::Err;
+
+/// Every prefix is stored in a table of its own
+type PrefixTable = TableDefinition<'static, &'static [u8], &'static [u8]>;
+
+/// Compacting rewrites the whole file and blocks every other operation while it does,
+/// so it is done at most once in this interval
+const MIN_COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) struct RedbKVDB {
+    // Compacting the database requires exclusive access to it
+    db: RwLock<Database>,
+    prefixes: Vec<&'static str>,
+    last_compaction: Mutex<Option<Instant>>,
+}
+
+impl RedbKVDB {
+    pub fn new<T>(location: T, prefixes: Vec<&'static str>) -> Result<Self>
+    where
+        T: AsRef<Path>,
+    {
+        let db = Database::create(location)?;
+
+        let txn = db.begin_write()?;
+
+        for prefix in &prefixes {
+            // Make sure all prefixes exist, so they can be opened by read transactions
+            txn.open_table(PrefixTable::new(prefix))
+                .with_context(|| format!("Failed to create table for prefix {prefix:?}"))?;
+        }
+
+        txn.commit()?;
+
+        Ok(Self {
+            db: RwLock::new(db),
+            prefixes,
+            last_compaction: Mutex::new(None),
+        })
+    }
+
+    fn get_table(&self, prefix: &'static str) -> Result<PrefixTable> {
+        if self.prefixes.contains(&prefix) {
+            Ok(PrefixTable::new(prefix))
+        } else {
+            Err!(PersStorageError::NoPrefix(prefix))
+        }
+    }
+
+    fn db(&self) -> RwLockReadGuard<'_, Database> {
+        self.db.read().unwrap()
+    }
+
+    fn read_table(
+        &self,
+        prefix: &'static str,
+    ) -> Result<ReadOnlyTable<&'static [u8], &'static [u8]>> {
+        let table = self.get_table(prefix)?;
+
+        self.db()
+            .begin_read()?
+            .open_table(table)
+            .with_context(|| format!("Failed to open table for prefix {prefix:?}"))
+    }
+
+    pub fn get<T>(&self, prefix: &'static str, key: T) -> Result<Option<Vec<u8>>>
+    where
+        T: AsRef<[u8]>,
+    {
+        let table = self.read_table(prefix)?;
+
+        let result = table
+            .get(key.as_ref())
+            .with_context(|| format!("Failed to get for prefix {prefix:?}"))?;
+
+        Ok(result.map(|value| value.value().to_vec()))
+    }
+
+    pub fn get_all<T, Y>(
+        &self,
+        prefix: &'static str,
+        keys: T,
+    ) -> Result<Vec<Option<impl AsRef<[u8]>>>>
+    where
+        T: Iterator<Item = Y>,
+        Y: AsRef<[u8]>,
+    {
+        // A single transaction, so all values come from the same snapshot
+        let table = self
+            .read_table(prefix)
+            .context("Failed to get table for prefix to get all items")?;
+
+        keys.map(|key| {
+            table
+                .get(key.as_ref())
+                .map(|value| value.map(|value| value.value().to_vec()))
+                .map_err(From::from)
+        })
+        .collect()
+    }
+
+    pub fn exists<T>(&self, prefix: &'static str, key: T) -> Result<bool>
+    where
+        T: AsRef<[u8]>,
+    {
+        let table = self.read_table(prefix)?;
+
+        Ok(table.get(key.as_ref())?.is_some())
+    }
+
+    pub fn set<T, Y>(&self, prefix: &'static str, key: T, data: Y) -> Result<()>
+    where
+        T: AsRef<[u8]>,
+        Y: AsRef<[u8]>,
+    {
+        self.set_all(prefix, std::iter::once((key, data)))
+            .with_context(|| format!("Failed to set key in prefix {prefix:?}"))
+    }
+
+    pub fn set_all<T, Y, Z>(&self, prefix: &'static str, values: T) -> Result<()>
+    where
+        T: Iterator<Item = (Y, Z)>,
+        Y: AsRef<[u8]>,
+        Z: AsRef<[u8]>,
+    {
+        let table = self.get_table(prefix)?;
+
+        let txn = self.db().begin_write()?;
+
+        {
+            let mut table = txn.open_table(table)?;
+
+            for (key, value) in values {
+                table.insert(key.as_ref(), value.as_ref())?;
+            }
+        }
+
+        txn.commit()
+            .with_context(|| format!("Failed to set keys in prefix {prefix:?}"))
+    }
+
+    pub fn erase<T>(&self, prefix: &'static str, key: T) -> Result<Option<impl AsRef<[u8]>>>
+    where
+        T: AsRef<[u8]>,
+    {
+        let table = self.get_table(prefix)?;
+
+        let txn = self.db().begin_write()?;
+
+        let previous = txn
+            .open_table(table)?
+            .remove(key.as_ref())?
+            .map(|value| value.value().to_vec());
+
+        txn.commit()
+            .with_context(|| format!("Failed to erase key in prefix {prefix:?}"))?;
+
+        Ok(previous)
+    }
+
+    /// Delete a set of keys
+    /// Accepts an [`&[&[u8]]`], in any possible form, as long as it can be dereferenced
+    /// all the way to the intended target.
+    pub fn erase_keys<T, Y>(&self, prefix: &'static str, keys: T) -> Result<()>
+    where
+        T: Iterator<Item = Y>,
+        Y: AsRef<[u8]>,
+    {
+        let table = self
+            .get_table(prefix)
+            .context("Failed to get table to erase keys")?;
+
+        let txn = self.db().begin_write()?;
+
+        {
+            let mut table = txn.open_table(table)?;
+
+            for key in keys {
+                table.remove(key.as_ref())?;
+            }
+        }
+
+        txn.commit()
+            .with_context(|| format!("Failed to erase keys in prefix {prefix:?}"))
+    }
+
+    pub fn erase_range<T>(&self, prefix: &'static str, start: T, end: T) -> Result<()>
+    where
+        T: AsRef<[u8]>,
+    {
+        let table = self
+            .get_table(prefix)
+            .context("Failed to get table to erase range")?;
+
+        let txn = self.db().begin_write()?;
+
+        txn.open_table(table)?
+            .retain_in(start.as_ref()..end.as_ref(), |_, _| false)?;
+
+        txn.commit()
+            .with_context(|| format!("Failed to erase range in prefix {prefix:?}"))
+    }
+
+    /// redb can only compact the whole database file, so the prefix and the range
+    /// are only used to validate the request.
+    ///
+    /// Compacting rewrites the file with exclusive access to the database, blocking every
+    /// read and write until it is done. Requests made within [MIN_COMPACTION_INTERVAL] of
+    /// the last compaction are skipped
+    pub fn compact_range<T, Y>(
+        &self,
+        prefix: &'static str,
+        _start: Option<T>,
+        _end: Option<Y>,
+    ) -> Result<()>
+    where
+        T: AsRef<[u8]>,
+        Y: AsRef<[u8]>,
+    {
+        self.get_table(prefix)?;
+
+        let mut last_compaction = self.last_compaction.lock().unwrap();
+
+        if last_compaction.is_some_and(|last| last.elapsed() < MIN_COMPACTION_INTERVAL) {
+            debug!(
+                "Skipping compaction of {:?}, the database was compacted recently",
+                prefix
+            );
+
+            return Ok(());
+        }
+
+        match self.db.write().unwrap().compact() {
+            Ok(_) => {
+                *last_compaction = Some(Instant::now());
+
+                Ok(())
+            }
+            // An iterator is still alive. Compacting is only an optimization, so try again next time
+            Err(CompactionError::TransactionInProgress) => {
+                debug!(
+                    "Skipping compaction of {:?}, a transaction is in progress",
+                    prefix
+                );
+
+                Ok(())
+            }
+            Err(err) => Err(err).context("Failed to compact redb database"),
+        }
+    }
+
+    pub(super) fn iter(&self, prefix: &'static str) -> Result<impl IteratorUtil + '_> {
+        self.iter_range::<&[u8], &[u8]>(prefix, None, None)
+    }
+
+    pub(super) fn iter_range<T, Y>(
+        &self,
+        prefix: &'static str,
+        start: Option<T>,
+        end: Option<Y>,
+    ) -> Result<impl IteratorUtil + '_>
+    where
+        T: AsRef<[u8]>,
+        Y: AsRef<[u8]>,
+    {
+        let table = self
+            .read_table(prefix)
+            .context("Failed to open table for iterating")?;
+
+        let start = start
+            .as_ref()
+            .map_or(Bound::Unbounded, |start| Bound::Included(start.as_ref()));
+        let end = end
+            .as_ref()
+            .map_or(Bound::Unbounded, |end| Bound::Excluded(end.as_ref()));
+
+        let range = table.range::<&[u8]>((start, end))?;
+
+        Ok(RedbKVDBIterator { range })
+    }
+}
+
+pub struct RedbKVDBIterator {
+    range: redb::Range<'static, &'static [u8], &'static [u8]>,
+}
+
+impl IteratorUtil for RedbKVDBIterator {
+    type ItemType = Vec<u8>;
+}
+
+impl Iterator for RedbKVDBIterator {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next().map(|entry| {
+            entry
+                .map(|(key, value)| (key.value().to_vec(), value.value().to_vec()))
+                .map_err(From::from)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const PREFIX: &str = "test";
+
+    /// A database path unique to this test, removed when dropped
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "atlas-redb-{}-{}.redb",
+                std::process::id(),
+                name
+            ));
+
+            let _ = std::fs::remove_file(&path);
+
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn keys<I: IteratorUtil>(iter: I) -> Vec<Vec<u8>> {
+        iter.map(|entry| entry.unwrap().0.as_ref().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = TempDb::new("round-trip");
+        let db = RedbKVDB::new(&path.0, vec![PREFIX]).unwrap();
+
+        for key in [b"a", b"b", b"c", b"d"] {
+            db.set(PREFIX, key, key).unwrap();
+        }
+
+        assert_eq!(db.get(PREFIX, b"b").unwrap(), Some(b"b".to_vec()));
+        assert_eq!(db.get(PREFIX, b"z").unwrap(), None);
+        assert!(db.get("unknown", b"a").is_err());
+
+        // The start of a range is included, and its end excluded
+        assert_eq!(
+            keys(db.iter_range(PREFIX, Some(b"b"), Some(b"d")).unwrap()),
+            vec![b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(
+            keys(db.iter_range::<&[u8], _>(PREFIX, None, Some(b"c")).unwrap()),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+
+        db.erase_range(PREFIX, b"b", b"d").unwrap();
+
+        assert_eq!(
+            keys(db.iter(PREFIX).unwrap()),
+            vec![b"a".to_vec(), b"d".to_vec()]
+        );
+
+        db.compact_range::<&[u8], &[u8]>(PREFIX, None, None)
+            .unwrap();
+        assert_eq!(db.get(PREFIX, b"d").unwrap(), Some(b"d".to_vec()));
+    }
+}
@@ -0,0 +1,521 @@
+//! Admission control of incoming connections.
+//!
+//! A listener wrapped with an [AdmissionControl] only hands out the connections that
+//! fit within its limits: a cap on the connections open at the same time, a cap per
+//! source IP address and a maximum accept rate. The excess connections are accepted
+//! and closed right away, so misbehaving clients cannot fill up the listen backlog
+//! (or our file descriptors) and starve everyone else.
+//!
+//! Every admitted connection comes with a [ConnectionPermit], which counts towards the
+//! limits until it is dropped. It should be kept for as long as the connection is open.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures::Stream;
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::debug;
+
+use crate::socket::shaping::{RateLimit, TokenBucket};
+use crate::socket::{AsyncListener, AsyncSocket, SyncListener, SyncSocket};
+
+/// The limits enforced on the incoming connections.
+/// A `None` limit is not enforced
+#[derive(Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct AdmissionConfig {
+    /// The maximum amount of admitted connections that may be open at the same time
+    pub max_connections: Option<usize>,
+    /// The maximum amount of admitted connections from a single IP address
+    /// that may be open at the same time
+    pub max_connections_per_ip: Option<usize>,
+    /// The rate at which new connections are admitted
+    pub accept_rate: Option<AcceptRate>,
+}
+
+/// The rate at which new connections may be admitted
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct AcceptRate {
+    /// The sustained rate of new connections
+    pub connections_per_second: u64,
+    /// How many connections may be admitted at once, after a quiet period
+    pub burst: u64,
+}
+
+#[derive(Error, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AdmissionError {
+    #[error("Already at the maximum of {max} open connections")]
+    TooManyConnections { max: usize },
+    #[error("{ip} is already at the maximum of {max} open connections")]
+    TooManyFromAddress { ip: IpAddr, max: usize },
+    #[error("New connections exceed the accept rate")]
+    RateExceeded,
+}
+
+/// Decides which incoming connections are admitted.
+///
+/// Clones share the same limits and counters, so a clone can be kept around to
+/// monitor a listener that is being consumed as a stream.
+#[derive(Clone)]
+pub struct AdmissionControl {
+    inner: Arc<AdmissionState>,
+}
+
+struct AdmissionState {
+    config: AdmissionConfig,
+    open: Mutex<OpenConnections>,
+    rate: Option<Mutex<TokenBucket>>,
+    stats: AdmissionStats,
+}
+
+#[derive(Default)]
+struct OpenConnections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// The admission statistics of a listener
+#[derive(Default)]
+pub struct AdmissionStats {
+    admitted: AtomicU64,
+    rejected_connections: AtomicU64,
+    rejected_per_ip: AtomicU64,
+    rejected_rate: AtomicU64,
+}
+
+/// Keeps an admitted connection counted towards the limits, until it is dropped
+pub struct ConnectionPermit {
+    state: Arc<AdmissionState>,
+    ip: Option<IpAddr>,
+}
+
+/// A connection that was admitted by an [AdmissionControl]
+pub struct Admitted<S> {
+    socket: S,
+    peer: Option<SocketAddr>,
+    permit: ConnectionPermit,
+}
+
+impl AdmissionControl {
+    pub fn new(config: AdmissionConfig) -> Self {
+        let rate = config.accept_rate.map(|rate| {
+            Mutex::new(TokenBucket::new(RateLimit {
+                bytes_per_second: rate.connections_per_second,
+                burst_bytes: rate.burst,
+            }))
+        });
+
+        Self {
+            inner: Arc::new(AdmissionState {
+                config,
+                open: Mutex::new(OpenConnections::default()),
+                rate,
+                stats: AdmissionStats::default(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &AdmissionConfig {
+        &self.inner.config
+    }
+
+    pub fn stats(&self) -> &AdmissionStats {
+        &self.inner.stats
+    }
+
+    /// The amount of admitted connections whose permit is still alive
+    pub fn open_connections(&self) -> usize {
+        self.inner.open.lock().unwrap().total
+    }
+
+    /// Decide whether a new connection from the given address is admitted.
+    /// Connections without an IP address (Unix domain sockets) are not limited per IP
+    pub fn admit(&self, ip: Option<IpAddr>) -> Result<ConnectionPermit, AdmissionError> {
+        let state = &self.inner;
+        let stats = &state.stats;
+
+        let mut open = state.open.lock().unwrap();
+
+        if let Some(max) = state.config.max_connections {
+            if open.total >= max {
+                stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+
+                return Err(AdmissionError::TooManyConnections { max });
+            }
+        }
+
+        if let (Some(max), Some(ip)) = (state.config.max_connections_per_ip, ip) {
+            if open.per_ip.get(&ip).copied().unwrap_or_default() >= max {
+                stats.rejected_per_ip.fetch_add(1, Ordering::Relaxed);
+
+                return Err(AdmissionError::TooManyFromAddress { ip, max });
+            }
+        }
+
+        // Only take a token once we know the connection is otherwise admitted,
+        // so rejected connections do not eat into the rate
+        if let Some(rate) = &state.rate {
+            if rate.lock().unwrap().try_take(1, Instant::now()).is_err() {
+                stats.rejected_rate.fetch_add(1, Ordering::Relaxed);
+
+                return Err(AdmissionError::RateExceeded);
+            }
+        }
+
+        open.total += 1;
+
+        if let Some(ip) = ip {
+            *open.per_ip.entry(ip).or_default() += 1;
+        }
+
+        stats.admitted.fetch_add(1, Ordering::Relaxed);
+
+        Ok(ConnectionPermit {
+            state: self.inner.clone(),
+            ip,
+        })
+    }
+
+    fn admit_socket<S>(&self, socket: S, peer: Option<SocketAddr>) -> Option<Admitted<S>> {
+        match self.admit(peer.map(|addr| addr.ip())) {
+            Ok(permit) => Some(Admitted {
+                socket,
+                peer,
+                permit,
+            }),
+            Err(err) => {
+                debug!("Rejected incoming connection from {:?}: {}", peer, err);
+
+                // Dropping the socket closes the connection
+                None
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut open = self.state.open.lock().unwrap();
+
+        open.total -= 1;
+
+        if let Some(ip) = self.ip {
+            if let Some(count) = open.per_ip.get_mut(&ip) {
+                *count -= 1;
+
+                if *count == 0 {
+                    open.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+impl ConnectionPermit {
+    /// The IP address this permit is counted against, if any
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+}
+
+impl<S> Admitted<S> {
+    pub fn socket(&self) -> &S {
+        &self.socket
+    }
+
+    /// The address of the peer. `None` for Unix domain sockets
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// Split into the connection and its permit, which must be kept for as long as
+    /// the connection is open
+    pub fn into_parts(self) -> (S, ConnectionPermit) {
+        (self.socket, self.permit)
+    }
+}
+
+impl AdmissionStats {
+    /// How many connections were admitted
+    pub fn admitted(&self) -> u64 {
+        self.admitted.load(Ordering::Relaxed)
+    }
+
+    /// How many connections were rejected for exceeding the connection cap
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// How many connections were rejected for exceeding the cap of their IP address
+    pub fn rejected_per_ip(&self) -> u64 {
+        self.rejected_per_ip.load(Ordering::Relaxed)
+    }
+
+    /// How many connections were rejected for exceeding the accept rate
+    pub fn rejected_rate(&self) -> u64 {
+        self.rejected_rate.load(Ordering::Relaxed)
+    }
+
+    /// The total amount of rejected connections
+    pub fn rejected(&self) -> u64 {
+        self.rejected_connections() + self.rejected_per_ip() + self.rejected_rate()
+    }
+}
+
+impl Debug for AdmissionStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdmissionStats")
+            .field("admitted", &self.admitted())
+            .field("rejected_connections", &self.rejected_connections())
+            .field("rejected_per_ip", &self.rejected_per_ip())
+            .field("rejected_rate", &self.rejected_rate())
+            .finish()
+    }
+}
+
+/// The admitted connections of a [SyncListener], see [SyncListener::incoming]
+pub struct IncomingSync {
+    listener: SyncListener,
+    control: AdmissionControl,
+}
+
+impl Iterator for IncomingSync {
+    type Item = io::Result<Admitted<SyncSocket>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept_admitted(&self.control))
+    }
+}
+
+impl SyncListener {
+    /// Accept the next connection admitted by `control`, closing the rejected ones
+    pub fn accept_admitted(&self, control: &AdmissionControl) -> io::Result<Admitted<SyncSocket>> {
+        loop {
+            let socket = self.accept()?;
+            let peer = socket.peer_addr().ok();
+
+            if let Some(admitted) = control.admit_socket(socket, peer) {
+                return Ok(admitted);
+            }
+        }
+    }
+
+    /// The never ending sequence of connections admitted by `control`.
+    /// Errors while accepting are returned, but do not end the sequence
+    pub fn incoming(self, control: AdmissionControl) -> IncomingSync {
+        IncomingSync {
+            listener: self,
+            control,
+        }
+    }
+}
+
+impl AsyncListener {
+    /// Accept the next connection admitted by `control`, closing the rejected ones
+    pub async fn accept_admitted(
+        &self,
+        control: &AdmissionControl,
+    ) -> io::Result<Admitted<AsyncSocket>> {
+        loop {
+            let socket = self.accept().await?;
+            let peer = socket.peer_addr().ok();
+
+            if let Some(admitted) = control.admit_socket(socket, peer) {
+                return Ok(admitted);
+            }
+        }
+    }
+
+    /// The never ending stream of connections admitted by `control`.
+    /// Errors while accepting are returned, but do not end the stream
+    pub fn incoming(
+        self,
+        control: AdmissionControl,
+    ) -> impl Stream<Item = io::Result<Admitted<AsyncSocket>>> {
+        futures::stream::unfold((self, control), |(listener, control)| async move {
+            let admitted = listener.accept_admitted(&control).await;
+
+            Some((admitted, (listener, control)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    use futures::{AsyncReadExt, StreamExt};
+
+    use super::*;
+    use crate::channel::sync::new_unbounded_sync;
+    use crate::socket::{
+        bind_async_server, bind_sync_server, connect_async, connect_sync, SocketConfig,
+    };
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)))
+    }
+
+    #[test]
+    fn test_connection_cap() {
+        let control = AdmissionControl::new(AdmissionConfig {
+            max_connections: Some(2),
+            ..Default::default()
+        });
+
+        let first = control.admit(ip(1)).unwrap();
+        let _second = control.admit(ip(2)).unwrap();
+
+        assert_eq!(
+            control.admit(ip(3)).err(),
+            Some(AdmissionError::TooManyConnections { max: 2 })
+        );
+
+        drop(first);
+
+        assert!(control.admit(ip(3)).is_ok());
+        assert_eq!(control.stats().admitted(), 3);
+        assert_eq!(control.stats().rejected_connections(), 1);
+    }
+
+    #[test]
+    fn test_per_ip_cap() {
+        let control = AdmissionControl::new(AdmissionConfig {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        });
+
+        let permit = control.admit(ip(1)).unwrap();
+
+        assert_eq!(
+            control.admit(ip(1)).err(),
+            Some(AdmissionError::TooManyFromAddress {
+                ip: ip(1).unwrap(),
+                max: 1
+            })
+        );
+
+        // Other addresses, and connections without one, are not affected
+        let _other = control.admit(ip(2)).unwrap();
+        let _unix = control.admit(None).unwrap();
+        let _unix = control.admit(None).unwrap();
+
+        drop(permit);
+
+        assert!(control.admit(ip(1)).is_ok());
+        assert_eq!(control.stats().rejected_per_ip(), 1);
+    }
+
+    #[test]
+    fn test_accept_rate() {
+        let control = AdmissionControl::new(AdmissionConfig {
+            accept_rate: Some(AcceptRate {
+                connections_per_second: 1,
+                burst: 3,
+            }),
+            ..Default::default()
+        });
+
+        let permits: Vec<_> = (0..3).map(|_| control.admit(ip(1)).unwrap()).collect();
+
+        assert_eq!(
+            control.admit(ip(1)).err(),
+            Some(AdmissionError::RateExceeded)
+        );
+
+        // The rate is about new connections, closing old ones does not help
+        drop(permits);
+
+        assert_eq!(
+            control.admit(ip(1)).err(),
+            Some(AdmissionError::RateExceeded)
+        );
+        assert_eq!(control.stats().rejected_rate(), 2);
+        assert_eq!(control.open_connections(), 0);
+    }
+
+    #[test]
+    fn test_excess_connections_are_closed() {
+        let socket_config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &socket_config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let control = AdmissionControl::new(AdmissionConfig {
+            max_connections: Some(2),
+            ..Default::default()
+        });
+
+        let (tx, rx) = new_unbounded_sync(Some("AdmittedConnections"));
+        let incoming = listener.incoming(control.clone());
+
+        thread::spawn(move || {
+            for admitted in incoming {
+                if tx.send(admitted.unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let _first_client = connect_sync(addr, &socket_config).unwrap();
+        let _second_client = connect_sync(addr, &socket_config).unwrap();
+
+        let first = rx.recv().unwrap();
+        let _second = rx.recv().unwrap();
+
+        assert_eq!(first.peer_addr().unwrap().ip(), addr.ip());
+
+        // The third connection goes over the cap, so it is closed straight away
+        let mut rejected = connect_sync(addr, &socket_config).unwrap();
+        assert_eq!(rejected.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(control.stats().rejected_connections(), 1);
+
+        drop(first);
+
+        let _third_client = connect_sync(addr, &socket_config).unwrap();
+        let _third = rx.recv().unwrap();
+
+        assert_eq!(control.open_connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_incoming_stream() {
+        let socket_config = SocketConfig::default();
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &socket_config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let control = AdmissionControl::new(AdmissionConfig {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        });
+
+        let incoming = listener.incoming(control.clone());
+        futures::pin_mut!(incoming);
+
+        let (client, admitted) = tokio::join!(connect_async(addr, &socket_config), incoming.next());
+
+        let _client = client.unwrap();
+        let (_socket, _permit) = admitted.unwrap().unwrap().into_parts();
+
+        // A second connection from the same address is closed
+        let mut rejected = connect_async(addr, &socket_config).await.unwrap();
+
+        tokio::select! {
+            read = rejected.read(&mut [0; 1]) => assert_eq!(read.unwrap(), 0),
+            _ = incoming.next() => panic!("The second connection should have been rejected"),
+        }
+
+        assert_eq!(control.stats().rejected_per_ip(), 1);
+    }
+}
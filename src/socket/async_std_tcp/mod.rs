@@ -1,31 +1,75 @@
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
 use std::io;
+use std::io::IoSlice;
 use std::net::SocketAddr;
-use std::ops::{Deref, DerefMut};
+use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use crate::error::*;
+use crate::socket::SocketConfig;
 use async_std::net::{TcpListener, TcpStream};
+use async_std::os::unix::net::{UnixListener, UnixStream};
 
 pub struct Listener {
-    inner: TcpListener,
+    inner: AsyncStdListener,
 }
 
 pub struct Socket {
-    inner: TcpStream,
+    inner: Stream,
 }
 
-pub async fn bind<A: Into<SocketAddr>>(addr: A) -> Result<Listener> {
-    let inner = TcpListener::bind(addr.into()).await?;
-    Ok(Listener { inner })
+enum AsyncStdListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
 }
 
-pub async fn connect<A: Into<SocketAddr>>(addr: A) -> Result<Socket> {
-    TcpStream::connect(addr.into())
-        .await
-        .map(|inner| Socket { inner })
-        .into()
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+pub fn from_std_listener(listener: std::net::TcpListener) -> io::Result<Listener> {
+    Ok(Listener {
+        inner: AsyncStdListener::Tcp(TcpListener::from(listener)),
+    })
+}
+
+pub fn from_std_unix_listener(listener: std::os::unix::net::UnixListener) -> io::Result<Listener> {
+    Ok(Listener {
+        inner: AsyncStdListener::Unix(UnixListener::from(listener)),
+    })
+}
+
+pub async fn connect<A: Into<SocketAddr>>(addr: A, config: &SocketConfig) -> io::Result<Socket> {
+    let connect = TcpStream::connect(addr.into());
+
+    let inner = match config.connect_timeout {
+        Some(timeout) => async_std::io::timeout(timeout, connect).await?,
+        None => connect.await?,
+    };
+
+    Ok(Socket {
+        inner: Stream::Tcp(inner),
+    })
+}
+
+pub async fn connect_unix(path: &Path, config: &SocketConfig) -> io::Result<Socket> {
+    let connect = UnixStream::connect(path);
+
+    let inner = match config.connect_timeout {
+        Some(timeout) => async_std::io::timeout(timeout, connect).await?,
+        None => connect.await?,
+    };
+
+    Ok(Socket {
+        inner: Stream::Unix(inner),
+    })
+}
+
+impl Socket {
+    pub fn is_unix(&self) -> bool {
+        matches!(self.inner, Stream::Unix(_))
+    }
 }
 
 impl AsyncRead for Socket {
@@ -34,7 +78,10 @@ impl AsyncRead for Socket {
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_read(cx, buf)
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
     }
 }
 
@@ -44,124 +91,184 @@ impl AsyncWrite for Socket {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_write(cx, buf)
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.inner).poll_flush(cx)
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.inner).poll_close(cx)
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_close(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_close(cx),
+        }
     }
 }
 
 impl Listener {
-    pub async fn accept(&self) -> Result<Socket> {
-        self.inner
-            .accept()
-            .await
-            .map(|(inner, _)| Socket { inner })
-            .into()
+    pub async fn accept(&self) -> io::Result<Socket> {
+        let inner = match &self.inner {
+            AsyncStdListener::Tcp(listener) => {
+                listener.accept().await.map(|(s, _)| Stream::Tcp(s))?
+            }
+            AsyncStdListener::Unix(listener) => {
+                listener.accept().await.map(|(s, _)| Stream::Unix(s))?
+            }
+        };
+
+        Ok(Socket { inner })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.inner {
+            AsyncStdListener::Tcp(listener) => listener.local_addr(),
+            AsyncStdListener::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unix domain listeners have no socket address",
+            )),
+        }
     }
 }
 
 /// The write half of a socket
-pub struct WriteHalf {
-    inner: futures::io::WriteHalf<TcpStream>,
+pub enum WriteHalf {
+    Tcp(futures::io::WriteHalf<TcpStream>),
+    Unix(futures::io::WriteHalf<UnixStream>),
 }
 
 /// The read half of a socket
-pub struct ReadHalf {
-    inner: futures::io::ReadHalf<TcpStream>,
+pub enum ReadHalf {
+    Tcp(futures::io::ReadHalf<TcpStream>),
+    Unix(futures::io::ReadHalf<UnixStream>),
 }
 
-impl Deref for WriteHalf {
-    type Target = futures::io::WriteHalf<TcpStream>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+impl WriteHalf {
+    /// The write half of the underlying TCP stream, if this is not a Unix domain socket
+    pub fn as_tcp(&self) -> Option<&futures::io::WriteHalf<TcpStream>> {
+        match self {
+            WriteHalf::Tcp(write) => Some(write),
+            WriteHalf::Unix(_) => None,
+        }
     }
-}
 
-impl DerefMut for WriteHalf {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+    /// The mutable counterpart of [Self::as_tcp]
+    pub fn as_tcp_mut(&mut self) -> Option<&mut futures::io::WriteHalf<TcpStream>> {
+        match self {
+            WriteHalf::Tcp(write) => Some(write),
+            WriteHalf::Unix(_) => None,
+        }
     }
 }
 
-impl Deref for ReadHalf {
-    type Target = futures::io::ReadHalf<TcpStream>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+impl ReadHalf {
+    /// The read half of the underlying TCP stream, if this is not a Unix domain socket
+    pub fn as_tcp(&self) -> Option<&futures::io::ReadHalf<TcpStream>> {
+        match self {
+            ReadHalf::Tcp(read) => Some(read),
+            ReadHalf::Unix(_) => None,
+        }
     }
 }
 
-impl AsyncRead for Socket {
+impl AsyncRead for ReadHalf {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_read(cx, buf)
+        match &mut *self {
+            ReadHalf::Tcp(read) => Pin::new(read).poll_read(cx, buf),
+            ReadHalf::Unix(read) => Pin::new(read).poll_read(cx, buf),
+        }
     }
 }
 
-impl AsyncWrite for Socket {
+impl AsyncWrite for WriteHalf {
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_write(cx, buf)
+        match &mut *self {
+            WriteHalf::Tcp(write) => Pin::new(write).poll_write(cx, buf),
+            WriteHalf::Unix(write) => Pin::new(write).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            WriteHalf::Tcp(write) => Pin::new(write).poll_write_vectored(cx, bufs),
+            WriteHalf::Unix(write) => Pin::new(write).poll_write_vectored(cx, bufs),
+        }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.inner).poll_flush(cx)
+        match &mut *self {
+            WriteHalf::Tcp(write) => Pin::new(write).poll_flush(cx),
+            WriteHalf::Unix(write) => Pin::new(write).poll_flush(cx),
+        }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.inner).poll_close(cx)
+        match &mut *self {
+            WriteHalf::Tcp(write) => Pin::new(write).poll_close(cx),
+            WriteHalf::Unix(write) => Pin::new(write).poll_close(cx),
+        }
     }
 }
 
 pub(super) fn split_socket(sock: Socket) -> (WriteHalf, ReadHalf) {
-    let (read, write) = sock.inner.split();
-
-    (WriteHalf { inner: write }, ReadHalf { inner: read })
-}
-
-#[cfg(windows)]
-mod sys {
-    use std::os::windows::io::{AsRawSocket, RawSocket};
+    match sock.inner {
+        Stream::Tcp(stream) => {
+            let (read, write) = stream.split();
 
-    impl AsRawSocket for super::Socket {
-        fn as_raw_socket(&self) -> RawSocket {
-            self.inner.as_raw_socket()
+            (WriteHalf::Tcp(write), ReadHalf::Tcp(read))
         }
-    }
+        Stream::Unix(stream) => {
+            let (read, write) = stream.split();
 
-    impl AsRawSocket for super::Listener {
-        fn as_raw_socket(&self) -> RawSocket {
-            self.inner.as_raw_socket()
+            (WriteHalf::Unix(write), ReadHalf::Unix(read))
         }
     }
 }
 
+#[cfg(windows)]
+mod sys {
+    compile_error!("Sorry Windows users! Unix domain sockets are required by the socket backends.");
+}
+
 #[cfg(unix)]
 mod sys {
     use std::os::unix::io::{AsRawFd, RawFd};
 
+    use super::{AsyncStdListener, Stream};
+
     impl AsRawFd for super::Socket {
         fn as_raw_fd(&self) -> RawFd {
-            self.inner.as_raw_fd()
+            match &self.inner {
+                Stream::Tcp(stream) => stream.as_raw_fd(),
+                Stream::Unix(stream) => stream.as_raw_fd(),
+            }
         }
     }
 
     impl AsRawFd for super::Listener {
         fn as_raw_fd(&self) -> RawFd {
-            self.inner.as_raw_fd()
+            match &self.inner {
+                AsyncStdListener::Tcp(listener) => listener.as_raw_fd(),
+                AsyncStdListener::Unix(listener) => listener.as_raw_fd(),
+            }
         }
     }
 }
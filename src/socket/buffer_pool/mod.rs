@@ -0,0 +1,854 @@
+//! Adaptive, pooled user space buffers for the socket halves.
+//!
+//! Allocating the full read and write buffers for every connection up front does not
+//! scale to thousands of (mostly idle) client connections. Instead, the halves borrow
+//! their buffers from a [BufferPool] only while there is buffered data, and give them
+//! back as soon as they are drained, so an idle connection holds no buffer at all.
+//!
+//! The size of the buffer a half asks for adapts to the traffic it sees: it starts at
+//! the configured minimum, doubles whenever a buffer turns out to be too small, and is
+//! halved again after a run of buffers that were mostly left unused. It never goes
+//! above the configured maximum (the old fixed capacity).
+//!
+//! Note that a blocking read holds on to its buffer while it waits, so only the async
+//! halves are fully released while idle.
+
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+
+use futures::{AsyncRead, AsyncWrite};
+
+/// The smallest buffer handed out by the pool is `2^MIN_CLASS_SHIFT` (1 KiB)
+const MIN_CLASS_SHIFT: u32 = 10;
+
+/// The largest buffer kept by the pool is `2^MAX_CLASS_SHIFT` (64 MiB).
+/// Larger buffers are still handed out, but freed when given back
+const MAX_CLASS_SHIFT: u32 = 26;
+
+const CLASS_COUNT: usize = (MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1) as usize;
+
+/// The default amount of memory kept around for each buffer size (32 MiB)
+const DEFAULT_RETAINED_BYTES_PER_CLASS: usize = 32 * 1024 * 1024;
+
+/// How many buffers in a row have to be mostly unused before the capacity is halved
+const SHRINK_AFTER: u32 = 16;
+
+static GLOBAL_POOL: OnceLock<Arc<BufferPool>> = OnceLock::new();
+
+/// A pool of buffers, grouped by size (powers of two), shared by many connections.
+pub struct BufferPool {
+    classes: Vec<Mutex<Vec<Box<[u8]>>>>,
+    retained_bytes_per_class: usize,
+    stats: BufferPoolStats,
+}
+
+/// Counters describing the memory held by a [BufferPool]
+#[derive(Default)]
+pub struct BufferPoolStats {
+    in_use_bytes: AtomicUsize,
+    retained_bytes: AtomicUsize,
+    allocations: AtomicUsize,
+    reuses: AtomicUsize,
+}
+
+impl BufferPool {
+    /// Create a new pool, which keeps at most `retained_bytes_per_class` bytes worth of
+    /// free buffers of each size
+    pub fn new(retained_bytes_per_class: usize) -> Self {
+        Self {
+            classes: (0..CLASS_COUNT).map(|_| Mutex::new(Vec::new())).collect(),
+            retained_bytes_per_class,
+            stats: BufferPoolStats::default(),
+        }
+    }
+
+    /// The pool used by the socket halves
+    pub fn global() -> Arc<BufferPool> {
+        GLOBAL_POOL
+            .get_or_init(|| Arc::new(BufferPool::new(DEFAULT_RETAINED_BYTES_PER_CLASS)))
+            .clone()
+    }
+
+    /// Take a buffer with at least `capacity` bytes out of the pool
+    pub fn acquire(&self, capacity: usize) -> Box<[u8]> {
+        let size = Self::size_for(capacity);
+
+        let recycled =
+            Self::class_of(size).and_then(|class| self.classes[class].lock().unwrap().pop());
+
+        let buf = match recycled {
+            Some(buf) => {
+                self.stats.retained_bytes.fetch_sub(size, Ordering::Relaxed);
+                self.stats.reuses.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.stats.allocations.fetch_add(1, Ordering::Relaxed);
+                vec![0; size].into_boxed_slice()
+            }
+        };
+
+        self.stats.in_use_bytes.fetch_add(size, Ordering::Relaxed);
+
+        buf
+    }
+
+    /// Give a buffer back to the pool, so it can be reused by another connection
+    pub fn release(&self, buf: Box<[u8]>) {
+        let size = buf.len();
+
+        self.stats.in_use_bytes.fetch_sub(size, Ordering::Relaxed);
+
+        let Some(class) = Self::class_of(size) else {
+            return;
+        };
+
+        let mut free = self.classes[class].lock().unwrap();
+
+        if (free.len() + 1) * size <= self.retained_bytes_per_class {
+            free.push(buf);
+
+            self.stats.retained_bytes.fetch_add(size, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> &BufferPoolStats {
+        &self.stats
+    }
+
+    /// The size of the buffers that are handed out for the given capacity
+    fn size_for(capacity: usize) -> usize {
+        capacity
+            .max(1 << MIN_CLASS_SHIFT)
+            .checked_next_power_of_two()
+            .unwrap_or(capacity)
+    }
+
+    fn class_of(size: usize) -> Option<usize> {
+        let shift = size.trailing_zeros();
+
+        (size.is_power_of_two() && (MIN_CLASS_SHIFT..=MAX_CLASS_SHIFT).contains(&shift))
+            .then(|| (shift - MIN_CLASS_SHIFT) as usize)
+    }
+}
+
+impl BufferPoolStats {
+    /// The bytes currently held by connections
+    pub fn in_use_bytes(&self) -> usize {
+        self.in_use_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The bytes of free buffers kept by the pool
+    pub fn retained_bytes(&self) -> usize {
+        self.retained_bytes.load(Ordering::Relaxed)
+    }
+
+    /// How many buffers had to be allocated
+    pub fn allocations(&self) -> usize {
+        self.allocations.load(Ordering::Relaxed)
+    }
+
+    /// How many buffers were served from the pool, instead of allocated
+    pub fn reuses(&self) -> usize {
+        self.reuses.load(Ordering::Relaxed)
+    }
+}
+
+impl Debug for BufferPoolStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BufferPoolStats")
+            .field("in_use_bytes", &self.in_use_bytes())
+            .field("retained_bytes", &self.retained_bytes())
+            .field("allocations", &self.allocations())
+            .field("reuses", &self.reuses())
+            .finish()
+    }
+}
+
+/// The capacity a half asks for, adapted to the amount of data it has seen
+#[derive(Copy, Clone, Debug)]
+struct AdaptiveCapacity {
+    min: usize,
+    max: usize,
+    current: usize,
+    underused: u32,
+}
+
+impl AdaptiveCapacity {
+    fn new(min: usize, max: usize) -> Self {
+        let min = min.min(max);
+
+        Self {
+            min,
+            max,
+            current: min,
+            underused: 0,
+        }
+    }
+
+    fn current(&self) -> usize {
+        self.current
+    }
+
+    /// A maximum of 0 leaves the half unbuffered
+    fn is_unbuffered(&self) -> bool {
+        self.max == 0
+    }
+
+    /// Record how many bytes were needed during the last use of a buffer
+    fn record(&mut self, used: usize) {
+        if used >= self.current {
+            let grown = (self.current * 2).max(used.checked_next_power_of_two().unwrap_or(used));
+
+            self.current = grown.min(self.max);
+            self.underused = 0;
+        } else if used <= self.current / 4 {
+            self.underused += 1;
+
+            if self.underused >= SHRINK_AFTER {
+                self.current = (self.current / 2).max(self.min);
+                self.underused = 0;
+            }
+        } else {
+            self.underused = 0;
+        }
+    }
+}
+
+/// A buffered writer that only holds a buffer while there is unflushed data.
+///
+/// Unlike [io::BufWriter], it does not flush when dropped.
+pub struct PooledBufWriter<W> {
+    inner: W,
+    pool: Arc<BufferPool>,
+    buf: Option<Box<[u8]>>,
+    // The buffered data is `buf[start..end]`. `start` advances as it is flushed
+    start: usize,
+    end: usize,
+    // The most bytes that we wanted to buffer since the buffer was last released
+    demand: usize,
+    capacity: AdaptiveCapacity,
+}
+
+/// A buffered reader that only holds a buffer while there is unread data.
+pub struct PooledBufReader<R> {
+    inner: R,
+    pool: Arc<BufferPool>,
+    buf: Option<Box<[u8]>>,
+    // The unread data is `buf[pos..filled]`
+    pos: usize,
+    filled: usize,
+    capacity: AdaptiveCapacity,
+}
+
+impl<W> PooledBufWriter<W> {
+    /// Wrap the given writer, with buffers between `min_capacity` and `max_capacity`
+    /// taken from the global pool. A `max_capacity` of 0 leaves it unbuffered
+    pub fn new(inner: W, min_capacity: usize, max_capacity: usize) -> Self {
+        Self::with_pool(inner, min_capacity, max_capacity, BufferPool::global())
+    }
+
+    pub fn with_pool(
+        inner: W,
+        min_capacity: usize,
+        max_capacity: usize,
+        pool: Arc<BufferPool>,
+    ) -> Self {
+        Self {
+            inner,
+            pool,
+            buf: None,
+            start: 0,
+            end: 0,
+            demand: 0,
+            capacity: AdaptiveCapacity::new(min_capacity, max_capacity),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// The size of the buffer currently held (0 when there is nothing buffered)
+    pub fn buffer_size(&self) -> usize {
+        self.buf.as_ref().map_or(0, |buf| buf.len())
+    }
+
+    /// The capacity of the next buffer this writer will ask for
+    pub fn target_capacity(&self) -> usize {
+        self.capacity.current()
+    }
+
+    /// How much data fits in the buffer, counting what is already there
+    fn available_capacity(&self) -> usize {
+        self.buf
+            .as_ref()
+            .map_or(self.capacity.current(), |buf| buf.len())
+    }
+
+    /// Whether the given data should skip the buffer. Only possible when there is
+    /// nothing buffered, or the data would be sent out of order
+    fn bypass_buffer(&self, data: &[u8]) -> bool {
+        self.end == 0 && data.len() >= self.capacity.current()
+    }
+
+    fn buffer(&mut self, data: &[u8]) -> usize {
+        let pool = &self.pool;
+        let buf = self
+            .buf
+            .get_or_insert_with(|| pool.acquire(self.capacity.current()));
+
+        let len = data.len().min(buf.len() - self.end);
+
+        buf[self.end..self.end + len].copy_from_slice(&data[..len]);
+        self.end += len;
+
+        len
+    }
+
+    /// Called once the buffer has been drained: give it back and adapt the capacity
+    fn release_buffer(&mut self) {
+        self.start = 0;
+        self.end = 0;
+
+        if let Some(buf) = self.buf.take() {
+            self.pool.release(buf);
+        }
+
+        self.capacity.record(std::mem::take(&mut self.demand));
+    }
+}
+
+impl<W> PooledBufWriter<W>
+where
+    W: Write,
+{
+    fn flush_buf(&mut self) -> io::Result<()> {
+        while self.start < self.end {
+            let buf = self.buf.as_deref().unwrap_or_default();
+
+            match self.inner.write(&buf[self.start..self.end]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    ))
+                }
+                Ok(written) => self.start += written,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.release_buffer();
+
+        Ok(())
+    }
+}
+
+impl<W> Write for PooledBufWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.capacity.is_unbuffered() {
+            return self.inner.write(data);
+        }
+
+        self.demand = self.demand.max(self.end + data.len());
+
+        if self.end + data.len() > self.available_capacity() {
+            self.flush_buf()?;
+        }
+
+        if self.bypass_buffer(data) {
+            let written = self.inner.write(data);
+
+            self.release_buffer();
+
+            return written;
+        }
+
+        Ok(self.buffer(data))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+
+        self.inner.flush()
+    }
+}
+
+impl<W> PooledBufWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_flush_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.start < self.end {
+            let buf = self.buf.as_deref().unwrap_or_default();
+
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[self.start..self.end])) {
+                Ok(0) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    )))
+                }
+                Ok(written) => self.start += written,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+
+        self.release_buffer();
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> AsyncWrite for PooledBufWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.capacity.is_unbuffered() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+
+        this.demand = this.demand.max(this.end + data.len());
+
+        if this.end + data.len() > this.available_capacity() {
+            ready!(this.poll_flush_buf(cx))?;
+        }
+
+        if this.bypass_buffer(data) {
+            let written = ready!(Pin::new(&mut this.inner).poll_write(cx, data));
+
+            this.release_buffer();
+
+            return Poll::Ready(written);
+        }
+
+        Poll::Ready(Ok(this.buffer(data)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_flush_buf(cx))?;
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_flush_buf(cx))?;
+
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+impl<W> Drop for PooledBufWriter<W> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.release(buf);
+        }
+    }
+}
+
+impl<R> PooledBufReader<R> {
+    /// Wrap the given reader, with buffers between `min_capacity` and `max_capacity`
+    /// taken from the global pool. A `max_capacity` of 0 leaves it unbuffered
+    pub fn new(inner: R, min_capacity: usize, max_capacity: usize) -> Self {
+        Self::with_pool(inner, min_capacity, max_capacity, BufferPool::global())
+    }
+
+    pub fn with_pool(
+        inner: R,
+        min_capacity: usize,
+        max_capacity: usize,
+        pool: Arc<BufferPool>,
+    ) -> Self {
+        Self {
+            inner,
+            pool,
+            buf: None,
+            pos: 0,
+            filled: 0,
+            capacity: AdaptiveCapacity::new(min_capacity, max_capacity),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// The size of the buffer currently held (0 when there is nothing buffered)
+    pub fn buffer_size(&self) -> usize {
+        self.buf.as_ref().map_or(0, |buf| buf.len())
+    }
+
+    /// The capacity of the next buffer this reader will ask for
+    pub fn target_capacity(&self) -> usize {
+        self.capacity.current()
+    }
+
+    /// Whether the read should go directly into the caller's buffer
+    fn bypass_buffer(&self, out: &[u8]) -> bool {
+        self.pos == self.filled && out.len() >= self.capacity.current()
+    }
+
+    /// Copy out the buffered data, giving the buffer back once it has all been read
+    fn consume(&mut self, out: &mut [u8]) -> usize {
+        let buf = self.buf.as_deref().unwrap_or_default();
+
+        let len = out.len().min(self.filled - self.pos);
+
+        out[..len].copy_from_slice(&buf[self.pos..self.pos + len]);
+        self.pos += len;
+
+        if self.pos == self.filled {
+            self.release_buffer();
+        }
+
+        len
+    }
+
+    fn release_buffer(&mut self) {
+        self.capacity.record(self.filled);
+
+        self.pos = 0;
+        self.filled = 0;
+
+        self.discard_buffer();
+    }
+
+    /// Give the buffer back without recording a use, as nothing was read into it
+    fn discard_buffer(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.release(buf);
+        }
+    }
+}
+
+impl<R> Read for PooledBufReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.capacity.is_unbuffered() || out.is_empty() {
+            return self.inner.read(out);
+        }
+
+        if self.bypass_buffer(out) {
+            let read = self.inner.read(out)?;
+
+            self.capacity.record(read);
+
+            return Ok(read);
+        }
+
+        if self.pos == self.filled {
+            let pool = &self.pool;
+            let buf = self
+                .buf
+                .get_or_insert_with(|| pool.acquire(self.capacity.current()));
+
+            let read = match read_into(&mut self.inner, buf) {
+                Ok(read) => read,
+                Err(err) => {
+                    self.discard_buffer();
+                    return Err(err);
+                }
+            };
+
+            self.pos = 0;
+            self.filled = read;
+        }
+
+        Ok(self.consume(out))
+    }
+}
+
+impl<R> AsyncRead for PooledBufReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.capacity.is_unbuffered() || out.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, out);
+        }
+
+        if this.bypass_buffer(out) {
+            let read = ready!(Pin::new(&mut this.inner).poll_read(cx, out))?;
+
+            this.capacity.record(read);
+
+            return Poll::Ready(Ok(read));
+        }
+
+        if this.pos == this.filled {
+            let pool = &this.pool;
+            let buf = this
+                .buf
+                .get_or_insert_with(|| pool.acquire(this.capacity.current()));
+
+            match Pin::new(&mut this.inner).poll_read(cx, buf) {
+                Poll::Ready(Ok(read)) => {
+                    this.pos = 0;
+                    this.filled = read;
+                }
+                Poll::Ready(Err(err)) => {
+                    this.discard_buffer();
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Pending => {
+                    // Nothing to hold on to while we wait for the peer
+                    this.discard_buffer();
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        Poll::Ready(Ok(this.consume(out)))
+    }
+}
+
+impl<R> Drop for PooledBufReader<R> {
+    fn drop(&mut self) {
+        self.discard_buffer();
+    }
+}
+
+fn read_into<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match reader.read(buf) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const KIB: usize = 1024;
+    const MIB: usize = 1024 * KIB;
+
+    fn pool() -> Arc<BufferPool> {
+        Arc::new(BufferPool::new(DEFAULT_RETAINED_BYTES_PER_CLASS))
+    }
+
+    #[test]
+    fn test_buffers_are_recycled() {
+        let pool = pool();
+
+        let buf = pool.acquire(3000);
+        assert_eq!(buf.len(), 4 * KIB);
+        assert_eq!(pool.stats().in_use_bytes(), 4 * KIB);
+
+        pool.release(buf);
+        assert_eq!(pool.stats().in_use_bytes(), 0);
+        assert_eq!(pool.stats().retained_bytes(), 4 * KIB);
+
+        let _buf = pool.acquire(4 * KIB);
+        assert_eq!(pool.stats().allocations(), 1);
+        assert_eq!(pool.stats().reuses(), 1);
+    }
+
+    #[test]
+    fn test_pool_retention_is_bounded() {
+        let pool = Arc::new(BufferPool::new(8 * KIB));
+
+        let bufs: Vec<_> = (0..4).map(|_| pool.acquire(4 * KIB)).collect();
+        bufs.into_iter().for_each(|buf| pool.release(buf));
+
+        assert_eq!(pool.stats().retained_bytes(), 8 * KIB);
+    }
+
+    #[test]
+    fn test_capacity_grows_and_shrinks() {
+        let mut capacity = AdaptiveCapacity::new(4 * KIB, MIB);
+
+        capacity.record(4 * KIB);
+        assert_eq!(capacity.current(), 8 * KIB);
+
+        capacity.record(100 * KIB);
+        assert_eq!(capacity.current(), 128 * KIB);
+
+        capacity.record(10 * MIB);
+        assert_eq!(capacity.current(), MIB);
+
+        for _ in 0..SHRINK_AFTER {
+            capacity.record(100);
+        }
+        assert_eq!(capacity.current(), MIB / 2);
+
+        for _ in 0..SHRINK_AFTER * 20 {
+            capacity.record(0);
+        }
+        assert_eq!(capacity.current(), 4 * KIB);
+    }
+
+    #[test]
+    fn test_writer_round_trip() {
+        let pool = pool();
+        let mut writer = PooledBufWriter::with_pool(Vec::new(), KIB, 64 * KIB, pool.clone());
+
+        writer.write_all(b"small").unwrap();
+        assert_eq!(writer.buffer_size(), KIB);
+        assert!(writer.get_ref().is_empty());
+
+        writer.write_all(&[1; 10 * KIB]).unwrap();
+        writer.flush().unwrap();
+
+        // Drained writers give their buffer back, and ask for a larger one next time
+        assert_eq!(writer.buffer_size(), 0);
+        assert_eq!(pool.stats().in_use_bytes(), 0);
+        assert!(writer.target_capacity() >= 16 * KIB);
+
+        let written = writer.get_ref();
+        assert_eq!(&written[..5], b"small");
+        assert_eq!(&written[5..], &[1; 10 * KIB][..]);
+    }
+
+    #[test]
+    fn test_reader_round_trip() {
+        let pool = pool();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+
+        let mut reader =
+            PooledBufReader::with_pool(Cursor::new(data.clone()), KIB, 64 * KIB, pool.clone());
+
+        let mut received = Vec::new();
+        let mut chunk = [0; 100];
+
+        loop {
+            let read = reader.read(&mut chunk).unwrap();
+
+            if read == 0 {
+                break;
+            }
+
+            received.extend_from_slice(&chunk[..read]);
+        }
+
+        assert_eq!(received, data);
+        assert_eq!(reader.target_capacity(), 64 * KIB);
+        assert_eq!(pool.stats().in_use_bytes(), 0);
+    }
+
+    #[test]
+    fn test_unbuffered_halves_pass_through() {
+        let pool = pool();
+        let mut writer = PooledBufWriter::with_pool(Vec::new(), KIB, 0, pool.clone());
+
+        writer.write_all(b"direct").unwrap();
+
+        assert_eq!(writer.get_ref(), b"direct");
+        assert_eq!(pool.stats().allocations(), 0);
+    }
+
+    /// Many idle connections must not hold on to any buffer, where the fixed
+    /// buffers used to take 16 MiB per connection
+    #[test]
+    fn test_idle_connections_hold_no_buffers() {
+        const CONNECTIONS: usize = 200;
+
+        let pool = pool();
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut connections = Vec::new();
+
+        for _ in 0..CONNECTIONS {
+            let client = TcpStream::connect(addr).unwrap();
+            let (server, _) = listener.accept().unwrap();
+
+            let mut writer = PooledBufWriter::with_pool(client, 4 * KIB, 8 * MIB, pool.clone());
+            let mut reader = PooledBufReader::with_pool(server, 4 * KIB, 8 * MIB, pool.clone());
+
+            writer.write_all(b"hello replica").unwrap();
+            writer.flush().unwrap();
+
+            let mut received = [0; 13];
+            reader.read_exact(&mut received).unwrap();
+            assert_eq!(&received, b"hello replica");
+
+            connections.push((writer, reader));
+        }
+
+        let stats = pool.stats();
+
+        assert_eq!(stats.in_use_bytes(), 0);
+        // One buffer is enough to serve every connection in turn
+        assert_eq!(stats.allocations(), 1);
+        assert_eq!(stats.retained_bytes(), 4 * KIB);
+    }
+
+    #[tokio::test]
+    async fn test_async_round_trip() {
+        let pool = pool();
+
+        let mut writer = PooledBufWriter::with_pool(
+            futures::io::Cursor::new(Vec::new()),
+            KIB,
+            64 * KIB,
+            pool.clone(),
+        );
+
+        writer.write_all(b"small").await.unwrap();
+        writer.write_all(&[2; 50_000]).await.unwrap();
+        writer.close().await.unwrap();
+
+        assert_eq!(pool.stats().in_use_bytes(), 0);
+
+        let written = writer.get_ref().get_ref().clone();
+
+        let mut reader = PooledBufReader::with_pool(
+            futures::io::Cursor::new(written),
+            KIB,
+            64 * KIB,
+            pool.clone(),
+        );
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(&received[..5], b"small");
+        assert_eq!(&received[5..], &[2; 50_000][..]);
+        assert_eq!(pool.stats().in_use_bytes(), 0);
+    }
+}
@@ -0,0 +1,1048 @@
+//! Capturing the frames exchanged with peers, and replaying them later.
+//!
+//! The capturing halves wrap the [framing](crate::socket::framing) halves and append
+//! every frame that goes through them to a [CaptureFile], along with the time it was
+//! seen, the peer it was exchanged with and its direction. Several connections can
+//! share the same capture file, which is how a node records everything it received.
+//!
+//! A capture file is append-only, and made of a file header followed by records.
+//!
+//! File header layout (big endian): `| magic: [u8; 4] | version: u16 |`
+//!
+//! Record layout (big endian):
+//! `| timestamp (micros since the epoch): u64 | peer: u32 | direction: u8 | payload length: u32 | payload crc32: u32 | payload |`
+//!
+//! Every record is written with a single write, so a crash leaves (at most) a partially
+//! written record at the end of the file, which [CaptureReader] reports as truncated.
+//!
+//! The sync halves write their records directly. The async halves must not block the
+//! runtime on the file, so they hand their records to a writer thread instead, which is
+//! started the first time one of them captures a frame. Records that do not fit in its
+//! queue are dropped, see [CaptureFile::flush_queued] to wait for the queued ones.
+//!
+//! Captures are replayed with [replay_sync] or [replay_async], which hand the records
+//! to the given inbound path, either paced like they were recorded (optionally faster)
+//! or as fast as possible.
+
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{AsyncRead, AsyncWrite};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::async_runtime;
+use crate::channel::sync::{new_bounded_sync, ChannelSyncRx, ChannelSyncTx};
+use crate::channel::TrySendError;
+use crate::node_id::NodeId;
+use crate::socket::framing::{
+    FrameError, FramedReadHalfAsync, FramedReadHalfSync, FramedWriteHalfAsync, FramedWriteHalfSync,
+};
+use crate::socket::{
+    SecureReadHalfAsync, SecureReadHalfSync, SecureWriteHalfAsync, SecureWriteHalfSync,
+};
+
+/// The magic number that starts every capture file
+const CAPTURE_MAGIC: [u8; 4] = *b"ACAP";
+
+/// The current version of the capture format
+const CAPTURE_VERSION: u16 = 1;
+
+/// Length in bytes of the file header
+const FILE_HEADER_LENGTH: usize = 6;
+
+/// Length in bytes of the header of each record
+const RECORD_HEADER_LENGTH: usize = 21;
+
+/// How many records of the async halves can be waiting for the writer thread
+const WRITER_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum CaptureError {
+    #[error("IO error while handling capture {0:?}")]
+    Io(#[from] io::Error),
+    #[error("Invalid capture magic {0:?}")]
+    InvalidMagic([u8; 4]),
+    #[error("Unsupported capture version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Invalid direction {0} in capture record")]
+    InvalidDirection(u8),
+    #[error("Record with {0} bytes does not fit in a capture")]
+    RecordTooLarge(usize),
+    #[error("The capture ends in the middle of a record")]
+    TruncatedRecord,
+    #[error("Record checksum mismatch, header says {expected:#x}, payload has {found:#x}")]
+    ChecksumMismatch { expected: u32, found: u32 },
+    #[error("The capture writer is behind, the record was dropped")]
+    WriterQueueFull,
+    #[error("The capture writer has stopped")]
+    WriterStopped,
+}
+
+/// Whether a frame was received from, or sent to, the peer
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A captured frame
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CaptureRecord {
+    timestamp_micros: u64,
+    peer: NodeId,
+    direction: Direction,
+    payload: Vec<u8>,
+}
+
+/// Selects the records of a capture. Every criteria that is set must match
+#[derive(Clone, Debug, Default)]
+pub struct CaptureFilter {
+    pub peer: Option<NodeId>,
+    pub direction: Option<Direction>,
+    /// Only records captured at or after this time
+    pub after: Option<SystemTime>,
+    /// Only records captured before this time
+    pub before: Option<SystemTime>,
+}
+
+/// A capture file that frames are appended to.
+/// Cloning it gives another handle to the same file
+#[derive(Clone)]
+pub struct CaptureFile {
+    file: Arc<Mutex<File>>,
+    // Only started once an async half captures a frame, and stops with the last handle
+    writer: Arc<OnceLock<ChannelSyncTx<WriterMessage>>>,
+}
+
+/// What the async halves hand to the writer thread of a [CaptureFile]
+enum WriterMessage {
+    /// A serialized record
+    Record(Vec<u8>),
+    /// Answered once every record queued before it was written
+    Flush(ChannelSyncTx<()>),
+}
+
+/// Reads the records of a capture, in the order they were captured
+pub struct CaptureReader<R = BufReader<File>> {
+    inner: R,
+    filter: CaptureFilter,
+    failed: bool,
+}
+
+/// How fast a capture is replayed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReplaySpeed {
+    /// With the same spacing between records as when they were captured
+    Original,
+    /// The given amount of times faster than when they were captured
+    Accelerated(f64),
+    /// Without waiting between records
+    Unpaced,
+}
+
+/// A [FramedReadHalfSync] that captures every frame it reads
+pub struct CapturingReadHalfSync<R = SecureReadHalfSync> {
+    inner: FramedReadHalfSync<R>,
+    peer: NodeId,
+    capture: CaptureFile,
+}
+
+/// A [FramedWriteHalfSync] that captures every frame it writes
+pub struct CapturingWriteHalfSync<W = SecureWriteHalfSync> {
+    inner: FramedWriteHalfSync<W>,
+    peer: NodeId,
+    capture: CaptureFile,
+}
+
+/// The async counterpart of [CapturingReadHalfSync]
+pub struct CapturingReadHalfAsync<R = SecureReadHalfAsync> {
+    inner: FramedReadHalfAsync<R>,
+    peer: NodeId,
+    capture: CaptureFile,
+}
+
+/// The async counterpart of [CapturingWriteHalfSync]
+pub struct CapturingWriteHalfAsync<W = SecureWriteHalfAsync> {
+    inner: FramedWriteHalfAsync<W>,
+    peer: NodeId,
+    capture: CaptureFile,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, CaptureError> {
+        match byte {
+            0 => Ok(Direction::Inbound),
+            1 => Ok(Direction::Outbound),
+            other => Err(CaptureError::InvalidDirection(other)),
+        }
+    }
+}
+
+impl CaptureRecord {
+    /// A record of a frame seen now
+    pub fn new(peer: NodeId, direction: Direction, payload: Vec<u8>) -> Self {
+        Self::at(SystemTime::now(), peer, direction, payload)
+    }
+
+    pub fn at(timestamp: SystemTime, peer: NodeId, direction: Direction, payload: Vec<u8>) -> Self {
+        let timestamp_micros = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        Self {
+            timestamp_micros,
+            peer,
+            direction,
+            payload,
+        }
+    }
+
+    pub fn timestamp(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.timestamp_micros)
+    }
+
+    /// The capture time, in microseconds since the unix epoch
+    pub fn timestamp_micros(&self) -> u64 {
+        self.timestamp_micros
+    }
+
+    pub fn peer(&self) -> NodeId {
+        self.peer
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>, CaptureError> {
+        let payload_len = u32::try_from(self.payload.len())
+            .map_err(|_| CaptureError::RecordTooLarge(self.payload.len()))?;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH + self.payload.len());
+
+        record.extend_from_slice(&self.timestamp_micros.to_be_bytes());
+        record.extend_from_slice(&self.peer.id().to_be_bytes());
+        record.push(self.direction.to_byte());
+        record.extend_from_slice(&payload_len.to_be_bytes());
+        record.extend_from_slice(&crc32fast::hash(&self.payload).to_be_bytes());
+        record.extend_from_slice(&self.payload);
+
+        Ok(record)
+    }
+}
+
+impl CaptureFilter {
+    /// Only the frames received from peers, which is what a node's inbound path sees
+    pub fn inbound() -> Self {
+        Self {
+            direction: Some(Direction::Inbound),
+            ..Default::default()
+        }
+    }
+
+    pub fn matches(&self, record: &CaptureRecord) -> bool {
+        self.peer.is_none_or(|peer| record.peer() == peer)
+            && self
+                .direction
+                .is_none_or(|direction| record.direction() == direction)
+            && self.after.is_none_or(|after| record.timestamp() >= after)
+            && self.before.is_none_or(|before| record.timestamp() < before)
+    }
+}
+
+impl CaptureFile {
+    /// Open the capture file at the given path, creating it if it does not exist.
+    /// Records are appended to the ones already in the file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CaptureError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        if file.metadata()?.len() == 0 {
+            let mut header = [0; FILE_HEADER_LENGTH];
+
+            header[..4].copy_from_slice(&CAPTURE_MAGIC);
+            header[4..].copy_from_slice(&CAPTURE_VERSION.to_be_bytes());
+
+            file.write_all(&header)?;
+        } else {
+            read_file_header(&mut file)?;
+        }
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            writer: Arc::new(OnceLock::new()),
+        })
+    }
+
+    /// Capture a frame seen now
+    pub fn record(
+        &self,
+        peer: NodeId,
+        direction: Direction,
+        payload: &[u8],
+    ) -> Result<(), CaptureError> {
+        self.append(&CaptureRecord::new(peer, direction, payload.to_vec()))
+    }
+
+    /// Append an existing record, keeping its timestamp
+    pub fn append(&self, record: &CaptureRecord) -> Result<(), CaptureError> {
+        let record = record.serialize()?;
+
+        lock_file(&self.file).write_all(&record)?;
+
+        Ok(())
+    }
+
+    /// Hand a frame seen now to the writer thread, without blocking.
+    /// The record is dropped if the writer is too far behind
+    pub fn queue(
+        &self,
+        peer: NodeId,
+        direction: Direction,
+        payload: &[u8],
+    ) -> Result<(), CaptureError> {
+        let record = CaptureRecord::new(peer, direction, payload.to_vec()).serialize()?;
+
+        self.writer()
+            .try_send(WriterMessage::Record(record))
+            .map_err(|err| match err {
+                TrySendError::Full | TrySendError::Timeout => CaptureError::WriterQueueFull,
+                TrySendError::Disconnected => CaptureError::WriterStopped,
+            })
+    }
+
+    /// Block until the records queued so far have been written to the file
+    pub fn flush_queued(&self) -> Result<(), CaptureError> {
+        let Some(writer) = self.writer.get() else {
+            // Nothing was ever queued
+            return Ok(());
+        };
+
+        let (done_tx, done_rx) = new_bounded_sync(1, None::<&str>);
+
+        writer
+            .send(WriterMessage::Flush(done_tx))
+            .map_err(|_| CaptureError::WriterStopped)?;
+
+        done_rx.recv().map_err(|_| CaptureError::WriterStopped)
+    }
+
+    fn writer(&self) -> &ChannelSyncTx<WriterMessage> {
+        self.writer.get_or_init(|| {
+            let (tx, rx) = new_bounded_sync(WRITER_QUEUE_CAPACITY, Some("CaptureWriter"));
+            let file = self.file.clone();
+
+            thread::Builder::new()
+                .name("Atlas-Capture-Writer".to_string())
+                .spawn(move || writer_loop(file, rx))
+                .expect("Failed to spawn capture writer thread");
+
+            tx
+        })
+    }
+
+    /// Capture a frame, only logging failures.
+    /// Failing to capture must not take the connection down with it
+    fn record_or_warn(&self, peer: NodeId, direction: Direction, payload: &[u8]) {
+        if let Err(err) = self.record(peer, direction, payload) {
+            warn!(
+                "Failed to capture {:?} frame of {:?}: {:?}",
+                direction, peer, err
+            );
+        }
+    }
+
+    /// The counterpart of [Self::record_or_warn] for the async halves
+    fn queue_or_warn(&self, peer: NodeId, direction: Direction, payload: &[u8]) {
+        if let Err(err) = self.queue(peer, direction, payload) {
+            warn!(
+                "Failed to capture {:?} frame of {:?}: {:?}",
+                direction, peer, err
+            );
+        }
+    }
+}
+
+/// Writes the records queued by the async halves, until every handle to the capture is dropped
+fn writer_loop(file: Arc<Mutex<File>>, queue: ChannelSyncRx<WriterMessage>) {
+    while let Ok(message) = queue.recv() {
+        match message {
+            WriterMessage::Record(record) => {
+                if let Err(err) = lock_file(&file).write_all(&record) {
+                    warn!("Failed to write captured record: {:?}", err);
+                }
+            }
+            WriterMessage::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+fn lock_file(file: &Mutex<File>) -> MutexGuard<'_, File> {
+    // A poisoned lock only means another writer panicked, the file is still usable
+    file.lock().unwrap_or_else(|poison| poison.into_inner())
+}
+
+impl CaptureReader {
+    /// Open the capture file at the given path
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CaptureError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R> CaptureReader<R>
+where
+    R: Read,
+{
+    /// Read a capture from the given reader, validating its header
+    pub fn new(mut inner: R) -> Result<Self, CaptureError> {
+        read_file_header(&mut inner)?;
+
+        Ok(Self {
+            inner,
+            filter: CaptureFilter::default(),
+            failed: false,
+        })
+    }
+
+    /// Skip the records that do not match the given filter
+    pub fn with_filter(mut self, filter: CaptureFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Read the next record that matches the filter.
+    /// Returns `None` once the end of the capture is reached
+    pub fn read_record(&mut self) -> Result<Option<CaptureRecord>, CaptureError> {
+        loop {
+            let Some(record) = self.read_any_record()? else {
+                return Ok(None);
+            };
+
+            if self.filter.matches(&record) {
+                return Ok(Some(record));
+            }
+        }
+    }
+
+    fn read_any_record(&mut self) -> Result<Option<CaptureRecord>, CaptureError> {
+        let mut header = [0; RECORD_HEADER_LENGTH];
+
+        match read_until_full(&mut self.inner, &mut header)? {
+            0 => return Ok(None),
+            RECORD_HEADER_LENGTH => {}
+            _ => return Err(CaptureError::TruncatedRecord),
+        }
+
+        let timestamp_micros = u64::from_be_bytes(header[0..8].try_into().unwrap());
+        let peer = NodeId(u32::from_be_bytes(header[8..12].try_into().unwrap()));
+        let direction = Direction::from_byte(header[12])?;
+        let payload_len = u32::from_be_bytes(header[13..17].try_into().unwrap());
+        let expected = u32::from_be_bytes(header[17..21].try_into().unwrap());
+
+        // Grown as it is read, so a corrupted length cannot make us allocate up front
+        let mut payload = Vec::new();
+
+        (&mut self.inner)
+            .take(payload_len as u64)
+            .read_to_end(&mut payload)?;
+
+        if payload.len() != payload_len as usize {
+            return Err(CaptureError::TruncatedRecord);
+        }
+
+        let found = crc32fast::hash(&payload);
+
+        if found != expected {
+            return Err(CaptureError::ChecksumMismatch { expected, found });
+        }
+
+        Ok(Some(CaptureRecord {
+            timestamp_micros,
+            peer,
+            direction,
+            payload,
+        }))
+    }
+}
+
+impl<R> Iterator for CaptureReader<R>
+where
+    R: Read,
+{
+    type Item = Result<CaptureRecord, CaptureError>;
+
+    /// Yields the records, stopping after the first error
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let result = self.read_record();
+
+        self.failed = result.is_err();
+
+        result.transpose()
+    }
+}
+
+impl ReplaySpeed {
+    /// How long after the start of the replay a record should be delivered, given
+    /// how long after the first record it was captured.
+    /// `None` if it should be delivered right away
+    fn delay(&self, since_first: Duration) -> Option<Duration> {
+        match *self {
+            ReplaySpeed::Original => Some(since_first),
+            ReplaySpeed::Accelerated(factor) if factor.is_finite() && factor > 0.0 => {
+                Some(since_first.div_f64(factor))
+            }
+            ReplaySpeed::Accelerated(_) | ReplaySpeed::Unpaced => None,
+        }
+    }
+}
+
+/// Keeps track of when each record of a replay is due
+struct ReplaySchedule {
+    speed: ReplaySpeed,
+    start: Option<(Instant, u64)>,
+}
+
+impl ReplaySchedule {
+    fn new(speed: ReplaySpeed) -> Self {
+        Self { speed, start: None }
+    }
+
+    /// How long to wait before delivering the given record.
+    /// Waits are relative to the start of the replay, so they do not accumulate drift
+    fn wait_for(&mut self, record: &CaptureRecord) -> Option<Duration> {
+        let (started, first_micros) = *self
+            .start
+            .get_or_insert_with(|| (Instant::now(), record.timestamp_micros()));
+
+        let since_first =
+            Duration::from_micros(record.timestamp_micros().saturating_sub(first_micros));
+
+        let due = started + self.speed.delay(since_first)?;
+
+        due.checked_duration_since(Instant::now())
+    }
+}
+
+/// Replay the given records into an inbound path, blocking until all were delivered.
+/// Returns how many records were delivered
+pub fn replay_sync<I, F>(
+    records: I,
+    speed: ReplaySpeed,
+    mut deliver: F,
+) -> Result<usize, CaptureError>
+where
+    I: IntoIterator<Item = Result<CaptureRecord, CaptureError>>,
+    F: FnMut(CaptureRecord),
+{
+    let mut schedule = ReplaySchedule::new(speed);
+    let mut delivered = 0;
+
+    for record in records {
+        let record = record?;
+
+        if let Some(wait) = schedule.wait_for(&record) {
+            std::thread::sleep(wait);
+        }
+
+        deliver(record);
+        delivered += 1;
+    }
+
+    Ok(delivered)
+}
+
+/// The async counterpart of [replay_sync]
+pub async fn replay_async<I, F, Fut>(
+    records: I,
+    speed: ReplaySpeed,
+    mut deliver: F,
+) -> Result<usize, CaptureError>
+where
+    I: IntoIterator<Item = Result<CaptureRecord, CaptureError>>,
+    F: FnMut(CaptureRecord) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut schedule = ReplaySchedule::new(speed);
+    let mut delivered = 0;
+
+    for record in records {
+        let record = record?;
+
+        if let Some(wait) = schedule.wait_for(&record) {
+            async_runtime::sleep(wait).await;
+        }
+
+        deliver(record).await;
+        delivered += 1;
+    }
+
+    Ok(delivered)
+}
+
+impl<R> CapturingReadHalfSync<R>
+where
+    R: Read,
+{
+    pub fn new(inner: FramedReadHalfSync<R>, peer: NodeId, capture: CaptureFile) -> Self {
+        Self {
+            inner,
+            peer,
+            capture,
+        }
+    }
+
+    /// See [FramedReadHalfSync::read_frame]
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, FrameError> {
+        let payload = self.inner.read_frame()?;
+
+        self.capture
+            .record_or_warn(self.peer, Direction::Inbound, &payload);
+
+        Ok(payload)
+    }
+
+    /// See [FramedReadHalfSync::read_frame_into]
+    pub fn read_frame_into(&mut self, payload: &mut Vec<u8>) -> Result<usize, FrameError> {
+        let len = self.inner.read_frame_into(payload)?;
+
+        self.capture
+            .record_or_warn(self.peer, Direction::Inbound, payload);
+
+        Ok(len)
+    }
+
+    pub fn peer(&self) -> NodeId {
+        self.peer
+    }
+
+    pub fn into_inner(self) -> FramedReadHalfSync<R> {
+        self.inner
+    }
+}
+
+impl<W> CapturingWriteHalfSync<W>
+where
+    W: Write,
+{
+    pub fn new(inner: FramedWriteHalfSync<W>, peer: NodeId, capture: CaptureFile) -> Self {
+        Self {
+            inner,
+            peer,
+            capture,
+        }
+    }
+
+    /// See [FramedWriteHalfSync::write_frame]
+    pub fn write_frame(&mut self, payload: &[u8]) -> Result<(), FrameError> {
+        self.inner.write_frame(payload)?;
+
+        self.capture
+            .record_or_warn(self.peer, Direction::Outbound, payload);
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), FrameError> {
+        self.inner.flush()
+    }
+
+    pub fn peer(&self) -> NodeId {
+        self.peer
+    }
+
+    pub fn into_inner(self) -> FramedWriteHalfSync<W> {
+        self.inner
+    }
+}
+
+impl<R> CapturingReadHalfAsync<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(inner: FramedReadHalfAsync<R>, peer: NodeId, capture: CaptureFile) -> Self {
+        Self {
+            inner,
+            peer,
+            capture,
+        }
+    }
+
+    /// See [FramedReadHalfAsync::read_frame]
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>, FrameError> {
+        let payload = self.inner.read_frame().await?;
+
+        self.capture
+            .queue_or_warn(self.peer, Direction::Inbound, &payload);
+
+        Ok(payload)
+    }
+
+    /// See [FramedReadHalfAsync::read_frame_into]
+    pub async fn read_frame_into(&mut self, payload: &mut Vec<u8>) -> Result<usize, FrameError> {
+        let len = self.inner.read_frame_into(payload).await?;
+
+        self.capture
+            .queue_or_warn(self.peer, Direction::Inbound, payload);
+
+        Ok(len)
+    }
+
+    pub fn peer(&self) -> NodeId {
+        self.peer
+    }
+
+    pub fn into_inner(self) -> FramedReadHalfAsync<R> {
+        self.inner
+    }
+}
+
+impl<W> CapturingWriteHalfAsync<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(inner: FramedWriteHalfAsync<W>, peer: NodeId, capture: CaptureFile) -> Self {
+        Self {
+            inner,
+            peer,
+            capture,
+        }
+    }
+
+    /// See [FramedWriteHalfAsync::write_frame]
+    pub async fn write_frame(&mut self, payload: &[u8]) -> Result<(), FrameError> {
+        self.inner.write_frame(payload).await?;
+
+        self.capture
+            .queue_or_warn(self.peer, Direction::Outbound, payload);
+
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<(), FrameError> {
+        self.inner.flush().await
+    }
+
+    pub fn peer(&self) -> NodeId {
+        self.peer
+    }
+
+    pub fn into_inner(self) -> FramedWriteHalfAsync<W> {
+        self.inner
+    }
+}
+
+fn read_file_header<R>(reader: &mut R) -> Result<(), CaptureError>
+where
+    R: Read,
+{
+    let mut header = [0; FILE_HEADER_LENGTH];
+
+    reader.read_exact(&mut header)?;
+
+    let magic: [u8; 4] = header[..4].try_into().unwrap();
+
+    if magic != CAPTURE_MAGIC {
+        return Err(CaptureError::InvalidMagic(magic));
+    }
+
+    let version = u16::from_be_bytes(header[4..].try_into().unwrap());
+
+    if version != CAPTURE_VERSION {
+        return Err(CaptureError::UnsupportedVersion(version));
+    }
+
+    Ok(())
+}
+
+/// Like [Read::read_exact], but returns how much was read when the reader ends early
+fn read_until_full<R>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize>
+where
+    R: Read,
+{
+    let mut read = 0;
+
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use super::*;
+    use crate::socket::framing::FramingConfig;
+    use crate::socket::{
+        bind_async_server, bind_sync_server, connect_async, connect_sync, SecureSocketAsync,
+        SecureSocketSync, SocketConfig,
+    };
+
+    /// A capture file path unique to this test, removed when dropped
+    struct TempCapture(PathBuf);
+
+    impl TempCapture {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "atlas-capture-{}-{}.acap",
+                std::process::id(),
+                name
+            ));
+
+            let _ = std::fs::remove_file(&path);
+
+            Self(path)
+        }
+    }
+
+    impl Drop for TempCapture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn record_at(micros: u64, peer: u32, direction: Direction, payload: &[u8]) -> CaptureRecord {
+        CaptureRecord::at(
+            UNIX_EPOCH + Duration::from_micros(micros),
+            NodeId(peer),
+            direction,
+            payload.to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_records_are_appended_across_opens() {
+        let path = TempCapture::new("append");
+
+        let first = record_at(1_000, 1, Direction::Inbound, b"first");
+        let second = record_at(2_000, 2, Direction::Outbound, b"");
+
+        CaptureFile::open(&path.0).unwrap().append(&first).unwrap();
+        CaptureFile::open(&path.0).unwrap().append(&second).unwrap();
+
+        let records: Vec<_> = CaptureReader::open(&path.0)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(records, vec![first, second]);
+    }
+
+    #[test]
+    fn test_truncated_and_corrupted_records_are_reported() {
+        let path = TempCapture::new("truncated");
+
+        CaptureFile::open(&path.0)
+            .unwrap()
+            .append(&record_at(1_000, 1, Direction::Inbound, b"payload"))
+            .unwrap();
+
+        let capture = std::fs::read(&path.0).unwrap();
+
+        let mut reader = CaptureReader::new(Cursor::new(&capture[..capture.len() - 1])).unwrap();
+        assert!(matches!(
+            reader.next(),
+            Some(Err(CaptureError::TruncatedRecord))
+        ));
+        assert!(reader.next().is_none());
+
+        let mut corrupted = capture.clone();
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+
+        let mut reader = CaptureReader::new(Cursor::new(corrupted)).unwrap();
+        assert!(matches!(
+            reader.next(),
+            Some(Err(CaptureError::ChecksumMismatch { .. }))
+        ));
+
+        assert!(matches!(
+            CaptureReader::new(Cursor::new(b"NOPE\x00\x01")).err(),
+            Some(CaptureError::InvalidMagic(_))
+        ));
+    }
+
+    #[test]
+    fn test_filter() {
+        let records = [
+            record_at(1_000, 1, Direction::Inbound, b"a"),
+            record_at(2_000, 2, Direction::Inbound, b"b"),
+            record_at(3_000, 1, Direction::Outbound, b"c"),
+            record_at(4_000, 1, Direction::Inbound, b"d"),
+        ];
+
+        let filter = CaptureFilter {
+            peer: Some(NodeId(1)),
+            before: Some(UNIX_EPOCH + Duration::from_micros(4_000)),
+            ..CaptureFilter::inbound()
+        };
+
+        let matching: Vec<_> = records
+            .iter()
+            .filter(|record| filter.matches(record))
+            .map(CaptureRecord::payload)
+            .collect();
+
+        assert_eq!(matching, vec![&b"a"[..]]);
+    }
+
+    #[test]
+    fn test_replay_pacing() {
+        let records = || {
+            vec![
+                Ok(record_at(0, 1, Direction::Inbound, b"a")),
+                Ok(record_at(200_000, 1, Direction::Inbound, b"b")),
+            ]
+        };
+
+        let started = Instant::now();
+        let mut delivered = Vec::new();
+
+        let count = replay_sync(records(), ReplaySpeed::Original, |record| {
+            delivered.push((record.into_payload(), started.elapsed()))
+        })
+        .unwrap();
+
+        assert_eq!(count, 2);
+        assert!(delivered[1].1 >= Duration::from_millis(200));
+
+        let started = Instant::now();
+
+        replay_sync(records(), ReplaySpeed::Accelerated(10.0), |_| {}).unwrap();
+
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert!(started.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_capture_and_replay_connection() {
+        let path = TempCapture::new("connection");
+        let capture = CaptureFile::open(&path.0).unwrap();
+
+        let socket_config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &socket_config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let socket = SecureSocketSync::new_plain(listener.accept().unwrap());
+            let (mut write, mut read) = socket.split_framed(FramingConfig::default());
+
+            let request = read.read_frame().unwrap();
+            write.write_frame(&request).unwrap();
+            write.flush().unwrap();
+        });
+
+        let socket = SecureSocketSync::new_plain(connect_sync(addr, &socket_config).unwrap());
+        let (write, read) = socket.split_framed(FramingConfig::default());
+
+        let mut write = CapturingWriteHalfSync::new(write, NodeId(3), capture.clone());
+        let mut read = CapturingReadHalfSync::new(read, NodeId(3), capture);
+
+        write.write_frame(b"ping").unwrap();
+        write.flush().unwrap();
+        assert_eq!(read.read_frame().unwrap(), b"ping");
+
+        server.join().unwrap();
+
+        let reader = CaptureReader::open(&path.0)
+            .unwrap()
+            .with_filter(CaptureFilter::inbound());
+
+        let mut replayed = Vec::new();
+
+        replay_sync(reader, ReplaySpeed::Unpaced, |record| {
+            replayed.push((record.peer(), record.into_payload()))
+        })
+        .unwrap();
+
+        assert_eq!(replayed, vec![(NodeId(3), b"ping".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn test_async_capture_and_replay() {
+        let path = TempCapture::new("async");
+        let capture = CaptureFile::open(&path.0).unwrap();
+
+        let config = SocketConfig::default();
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+
+        let (write, _read) =
+            SecureSocketAsync::new_plain(client.unwrap()).split_framed(FramingConfig::default());
+        let (_write, read) =
+            SecureSocketAsync::new_plain(server.unwrap()).split_framed(FramingConfig::default());
+
+        let mut write = CapturingWriteHalfAsync::new(write, NodeId(1), capture.clone());
+        let mut read = CapturingReadHalfAsync::new(read, NodeId(0), capture.clone());
+
+        write.write_frame(b"request").await.unwrap();
+        write.flush().await.unwrap();
+        assert_eq!(read.read_frame().await.unwrap(), b"request");
+
+        capture.flush_queued().unwrap();
+
+        let reader = CaptureReader::open(&path.0).unwrap();
+
+        let mut replayed = Vec::new();
+
+        let delivered = replay_async(reader, ReplaySpeed::Accelerated(100.0), |record| {
+            replayed.push((record.peer(), record.direction()));
+
+            async {}
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(delivered, 2);
+        assert_eq!(
+            replayed,
+            vec![
+                (NodeId(1), Direction::Outbound),
+                (NodeId(0), Direction::Inbound)
+            ]
+        );
+    }
+}
@@ -0,0 +1,982 @@
+//! Transparent compression of the data sent over the socket halves.
+//!
+//! The compressed halves sit between the [framing](crate::socket::framing) layer and
+//! the secure halves. The data is sent in blocks, each preceded by a small header:
+//! writes of at least `threshold` bytes (such as the payload of a large frame) are
+//! compressed as their own blocks, while smaller writes are gathered and sent
+//! uncompressed when the half is flushed. A block is only sent compressed if that
+//! actually makes it smaller.
+//!
+//! The algorithm is negotiated when the connection is set up: each side sends a
+//! [CompressionHello] with the algorithms it supports, and both use the best one they
+//! have in common. When there is none, the halves just pass the data through.
+
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::socket::{
+    SecureReadHalfAsync, SecureReadHalfSync, SecureSocketAsync, SecureSocketSync,
+    SecureWriteHalfAsync, SecureWriteHalfSync,
+};
+
+/// The magic number that starts the compression hello
+const HELLO_MAGIC: u16 = 0xA7C0;
+
+/// The current version of the compression hello
+const HELLO_VERSION: u8 = 1;
+
+const HELLO_LEN: usize = 4;
+
+const LZ4_FLAG: u8 = 1 << 0;
+
+const BLOCK_RAW: u8 = 0;
+
+const BLOCK_LZ4: u8 = 1;
+
+/// The default size below which writes are not compressed (1 KiB)
+const DEFAULT_THRESHOLD: usize = 1024;
+
+/// The default maximum amount of data in a single block (1 MiB)
+const DEFAULT_MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("Invalid compression hello magic {0:#x}")]
+    InvalidMagic(u16),
+    #[error("Unsupported compression hello version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Invalid block kind {0}")]
+    InvalidBlockKind(u8),
+    #[error("Block with {size} bytes exceeds the maximum block size of {max} bytes")]
+    BlockTooLarge { size: usize, max: usize },
+    #[error("Compressed block of {compressed} bytes is not smaller than its {raw} bytes of data")]
+    InvalidCompressedLength { compressed: usize, raw: usize },
+    #[error("Failed to decompress block {0}")]
+    Decompress(String),
+}
+
+impl From<CompressionError> for io::Error {
+    fn from(value: CompressionError) -> Self {
+        io::Error::new(ErrorKind::InvalidData, value)
+    }
+}
+
+/// The compression algorithms that can be used over a connection
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub enum CompressionAlgorithm {
+    /// The data is passed through as is
+    #[default]
+    None,
+    Lz4,
+}
+
+/// Configuration of the compression layer
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct CompressionConfig {
+    /// The algorithm we offer to the peer. `None` disables compression
+    pub algorithm: CompressionAlgorithm,
+    /// Writes smaller than this (in bytes) are sent uncompressed
+    pub threshold: usize,
+    /// The maximum amount of data in a single block. Larger writes are split
+    /// into several blocks, and larger blocks from the peer are rejected
+    pub max_block_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Lz4,
+            threshold: DEFAULT_THRESHOLD,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+        }
+    }
+}
+
+/// The message exchanged to negotiate the algorithm.
+///
+/// Wire layout: `| magic: u16 (big endian) | version: u8 | supported algorithms: u8 |`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CompressionHello {
+    algorithms: u8,
+}
+
+impl CompressionHello {
+    pub fn new(config: &CompressionConfig) -> Self {
+        let algorithms = match config.algorithm {
+            CompressionAlgorithm::None => 0,
+            CompressionAlgorithm::Lz4 => LZ4_FLAG,
+        };
+
+        Self { algorithms }
+    }
+
+    pub fn serialize(&self) -> [u8; HELLO_LEN] {
+        let magic = HELLO_MAGIC.to_be_bytes();
+
+        [magic[0], magic[1], HELLO_VERSION, self.algorithms]
+    }
+
+    pub fn deserialize(bytes: &[u8; HELLO_LEN]) -> Result<Self, CompressionError> {
+        let magic = u16::from_be_bytes([bytes[0], bytes[1]]);
+
+        if magic != HELLO_MAGIC {
+            return Err(CompressionError::InvalidMagic(magic));
+        }
+
+        if bytes[2] != HELLO_VERSION {
+            return Err(CompressionError::UnsupportedVersion(bytes[2]));
+        }
+
+        Ok(Self {
+            algorithms: bytes[3],
+        })
+    }
+
+    /// The best algorithm supported by both sides.
+    /// Both sides reach the same result, regardless of which side is which
+    pub fn agree(&self, other: &CompressionHello) -> CompressionAlgorithm {
+        let common = self.algorithms & other.algorithms;
+
+        if common & LZ4_FLAG != 0 {
+            CompressionAlgorithm::Lz4
+        } else {
+            CompressionAlgorithm::None
+        }
+    }
+}
+
+/// The header that precedes every block.
+///
+/// Wire layout (big endian):
+/// `| kind: u8 | data length: u32 | length on the wire: u32 |`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct BlockHeader {
+    kind: u8,
+    raw_len: u32,
+    wire_len: u32,
+}
+
+impl BlockHeader {
+    const LENGTH: usize = 9;
+
+    fn serialize(&self) -> [u8; Self::LENGTH] {
+        let mut bytes = [0; Self::LENGTH];
+
+        bytes[0] = self.kind;
+        bytes[1..5].copy_from_slice(&self.raw_len.to_be_bytes());
+        bytes[5..9].copy_from_slice(&self.wire_len.to_be_bytes());
+
+        bytes
+    }
+
+    /// Parses and validates a header read from the wire, before allocating anything for it
+    fn deserialize(
+        bytes: &[u8; Self::LENGTH],
+        max_block_size: usize,
+    ) -> Result<Self, CompressionError> {
+        let kind = bytes[0];
+        let raw_len = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        let wire_len = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
+
+        if raw_len as usize > max_block_size {
+            return Err(CompressionError::BlockTooLarge {
+                size: raw_len as usize,
+                max: max_block_size,
+            });
+        }
+
+        let valid_len = match kind {
+            BLOCK_RAW => wire_len == raw_len,
+            BLOCK_LZ4 => wire_len < raw_len,
+            _ => return Err(CompressionError::InvalidBlockKind(kind)),
+        };
+
+        if !valid_len {
+            return Err(CompressionError::InvalidCompressedLength {
+                compressed: wire_len as usize,
+                raw: raw_len as usize,
+            });
+        }
+
+        Ok(Self {
+            kind,
+            raw_len,
+            wire_len,
+        })
+    }
+}
+
+/// Turns the written data into blocks
+struct BlockEncoder {
+    config: CompressionConfig,
+    // Small writes, gathered until the next flush
+    pending: Vec<u8>,
+    // Encoded blocks which are yet to be written to the inner half
+    out: Vec<u8>,
+    sent: usize,
+}
+
+impl BlockEncoder {
+    fn new(config: CompressionConfig) -> Self {
+        Self {
+            config,
+            pending: Vec::new(),
+            out: Vec::new(),
+            sent: 0,
+        }
+    }
+
+    /// Take (part of) the given data, returning how much of it was taken
+    fn push(&mut self, buf: &[u8]) -> usize {
+        let max_block_size = self.config.max_block_size.max(1);
+
+        if buf.len() >= self.config.threshold {
+            // Keep the order of the data
+            self.seal_pending();
+
+            let block = &buf[..buf.len().min(max_block_size)];
+
+            encode_block(block, true, &mut self.out);
+
+            block.len()
+        } else {
+            let taken = buf.len().min(max_block_size - self.pending.len());
+
+            self.pending.extend_from_slice(&buf[..taken]);
+
+            if self.pending.len() == max_block_size {
+                self.seal_pending();
+            }
+
+            taken
+        }
+    }
+
+    /// Encode the gathered small writes into a block
+    fn seal_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        // Many small writes might add up to something worth compressing
+        let compress = self.pending.len() >= self.config.threshold;
+
+        encode_block(&self.pending, compress, &mut self.out);
+
+        self.pending.clear();
+    }
+
+    fn unsent(&self) -> &[u8] {
+        &self.out[self.sent..]
+    }
+
+    fn advance(&mut self, sent: usize) {
+        self.sent += sent;
+
+        if self.sent == self.out.len() {
+            self.out.clear();
+            self.sent = 0;
+        }
+    }
+}
+
+/// Append the block holding `data` to `out`
+fn encode_block(data: &[u8], compress: bool, out: &mut Vec<u8>) {
+    let start = out.len();
+
+    if compress {
+        let max_len = lz4_flex::block::get_maximum_output_size(data.len());
+
+        out.resize(start + BlockHeader::LENGTH + max_len, 0);
+
+        let compressed =
+            lz4_flex::block::compress_into(data, &mut out[start + BlockHeader::LENGTH..]);
+
+        if let Some(compressed) = compressed.ok().filter(|len| *len < data.len()) {
+            let header = BlockHeader {
+                kind: BLOCK_LZ4,
+                raw_len: data.len() as u32,
+                wire_len: compressed as u32,
+            };
+
+            out[start..start + BlockHeader::LENGTH].copy_from_slice(&header.serialize());
+            out.truncate(start + BlockHeader::LENGTH + compressed);
+
+            return;
+        }
+
+        // Not worth it, send the data as is
+        out.truncate(start);
+    }
+
+    let header = BlockHeader {
+        kind: BLOCK_RAW,
+        raw_len: data.len() as u32,
+        wire_len: data.len() as u32,
+    };
+
+    out.extend_from_slice(&header.serialize());
+    out.extend_from_slice(data);
+}
+
+/// Turns the received blocks back into data
+struct BlockDecoder {
+    max_block_size: usize,
+    header: [u8; BlockHeader::LENGTH],
+    // The header of the block being received, once it has been fully read
+    current: Option<BlockHeader>,
+    // The bytes of the header or of the body of the current block that were read
+    filled: usize,
+    body: Vec<u8>,
+    decoded: Vec<u8>,
+    consumed: usize,
+}
+
+impl BlockDecoder {
+    fn new(max_block_size: usize) -> Self {
+        Self {
+            max_block_size,
+            header: [0; BlockHeader::LENGTH],
+            current: None,
+            filled: 0,
+            body: Vec::new(),
+            decoded: Vec::new(),
+            consumed: 0,
+        }
+    }
+
+    /// Copy decoded data into `buf`, returning `None` if there is none available
+    fn read_decoded(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let available = &self.decoded[self.consumed..];
+
+        if available.is_empty() {
+            return None;
+        }
+
+        let read = available.len().min(buf.len());
+
+        buf[..read].copy_from_slice(&available[..read]);
+
+        self.consumed += read;
+
+        Some(read)
+    }
+
+    /// The buffer the next bytes from the inner half should be read into
+    fn unfilled(&mut self) -> &mut [u8] {
+        match self.current {
+            None => &mut self.header[self.filled..],
+            Some(_) => &mut self.body[self.filled..],
+        }
+    }
+
+    /// Register that `read` bytes were read into [Self::unfilled].
+    /// Returns false if the stream cleanly ended
+    fn advance(&mut self, read: usize) -> io::Result<bool> {
+        if read == 0 {
+            if self.current.is_none() && self.filled == 0 {
+                return Ok(false);
+            }
+
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        self.filled += read;
+
+        match self.current {
+            None if self.filled == BlockHeader::LENGTH => {
+                let header = BlockHeader::deserialize(&self.header, self.max_block_size)?;
+
+                self.body.clear();
+                self.body.resize(header.wire_len as usize, 0);
+                self.current = Some(header);
+                self.filled = 0;
+
+                if header.wire_len == 0 {
+                    self.decode()?;
+                }
+            }
+            Some(header) if self.filled == header.wire_len as usize => self.decode()?,
+            _ => {}
+        }
+
+        Ok(true)
+    }
+
+    /// Decode the block which was just fully received
+    fn decode(&mut self) -> Result<(), CompressionError> {
+        let Some(header) = self.current.take() else {
+            return Ok(());
+        };
+
+        self.filled = 0;
+        self.consumed = 0;
+
+        match header.kind {
+            BLOCK_LZ4 => {
+                self.decoded.clear();
+                self.decoded.resize(header.raw_len as usize, 0);
+
+                let len = lz4_flex::block::decompress_into(&self.body, &mut self.decoded)
+                    .map_err(|err| CompressionError::Decompress(format!("{err:?}")))?;
+
+                if len != header.raw_len as usize {
+                    return Err(CompressionError::Decompress(format!(
+                        "Expected {} bytes, got {}",
+                        header.raw_len, len
+                    )));
+                }
+            }
+            _ => std::mem::swap(&mut self.decoded, &mut self.body),
+        }
+
+        Ok(())
+    }
+}
+
+/// A read half that decompresses the data sent by a [CompressedWriteHalfSync]
+pub struct CompressedReadHalfSync<R = SecureReadHalfSync> {
+    inner: R,
+    algorithm: CompressionAlgorithm,
+    decoder: BlockDecoder,
+}
+
+/// A write half that compresses large writes
+pub struct CompressedWriteHalfSync<W = SecureWriteHalfSync> {
+    inner: W,
+    algorithm: CompressionAlgorithm,
+    encoder: BlockEncoder,
+}
+
+/// The async counterpart of [CompressedReadHalfSync]
+pub struct CompressedReadHalfAsync<R = SecureReadHalfAsync> {
+    inner: R,
+    algorithm: CompressionAlgorithm,
+    decoder: BlockDecoder,
+}
+
+/// The async counterpart of [CompressedWriteHalfSync]
+pub struct CompressedWriteHalfAsync<W = SecureWriteHalfAsync> {
+    inner: W,
+    algorithm: CompressionAlgorithm,
+    encoder: BlockEncoder,
+}
+
+/// Negotiate the algorithm to use with the peer, over the given (not yet compressed) halves
+pub fn negotiate_sync<W, R>(
+    write: &mut W,
+    read: &mut R,
+    config: &CompressionConfig,
+) -> io::Result<CompressionAlgorithm>
+where
+    W: Write,
+    R: Read,
+{
+    let ours = CompressionHello::new(config);
+
+    write.write_all(&ours.serialize())?;
+    write.flush()?;
+
+    let mut theirs = [0; HELLO_LEN];
+    read.read_exact(&mut theirs)?;
+
+    Ok(ours.agree(&CompressionHello::deserialize(&theirs)?))
+}
+
+/// The async version of [negotiate_sync]
+pub async fn negotiate_async<W, R>(
+    write: &mut W,
+    read: &mut R,
+    config: &CompressionConfig,
+) -> io::Result<CompressionAlgorithm>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let ours = CompressionHello::new(config);
+
+    write.write_all(&ours.serialize()).await?;
+    write.flush().await?;
+
+    let mut theirs = [0; HELLO_LEN];
+    read.read_exact(&mut theirs).await?;
+
+    Ok(ours.agree(&CompressionHello::deserialize(&theirs)?))
+}
+
+impl SecureSocketSync {
+    /// Split this socket, negotiating compression with the peer
+    pub fn split_compressed(
+        self,
+        config: &CompressionConfig,
+    ) -> io::Result<(CompressedWriteHalfSync, CompressedReadHalfSync)> {
+        let (mut write, mut read) = self.split();
+
+        let algorithm = negotiate_sync(&mut write, &mut read, config)?;
+
+        Ok((
+            CompressedWriteHalfSync::new(write, algorithm, *config),
+            CompressedReadHalfSync::new(read, algorithm, *config),
+        ))
+    }
+}
+
+impl SecureSocketAsync {
+    /// Split this socket, negotiating compression with the peer
+    pub async fn split_compressed(
+        self,
+        config: &CompressionConfig,
+    ) -> io::Result<(CompressedWriteHalfAsync, CompressedReadHalfAsync)> {
+        let (mut write, mut read) = self.split();
+
+        let algorithm = negotiate_async(&mut write, &mut read, config).await?;
+
+        Ok((
+            CompressedWriteHalfAsync::new(write, algorithm, *config),
+            CompressedReadHalfAsync::new(read, algorithm, *config),
+        ))
+    }
+}
+
+impl<R> CompressedReadHalfSync<R>
+where
+    R: Read,
+{
+    /// Wrap the given half, which receives data compressed with the (already negotiated) algorithm
+    pub fn new(inner: R, algorithm: CompressionAlgorithm, config: CompressionConfig) -> Self {
+        Self {
+            inner,
+            algorithm,
+            decoder: BlockDecoder::new(config.max_block_size),
+        }
+    }
+
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> Read for CompressedReadHalfSync<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.algorithm == CompressionAlgorithm::None {
+            return self.inner.read(buf);
+        }
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if let Some(read) = self.decoder.read_decoded(buf) {
+                return Ok(read);
+            }
+
+            let read = match self.inner.read(self.decoder.unfilled()) {
+                Ok(read) => read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            if !self.decoder.advance(read)? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl<W> CompressedWriteHalfSync<W>
+where
+    W: Write,
+{
+    /// Wrap the given half, compressing with the (already negotiated) algorithm
+    pub fn new(inner: W, algorithm: CompressionAlgorithm, config: CompressionConfig) -> Self {
+        Self {
+            inner,
+            algorithm,
+            encoder: BlockEncoder::new(config),
+        }
+    }
+
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    /// Unwrap the inner half. Data which was not flushed is lost
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn write_encoded(&mut self) -> io::Result<()> {
+        self.inner.write_all(self.encoder.unsent())?;
+
+        let sent = self.encoder.unsent().len();
+        self.encoder.advance(sent);
+
+        Ok(())
+    }
+}
+
+impl<W> Write for CompressedWriteHalfSync<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.algorithm == CompressionAlgorithm::None {
+            return self.inner.write(buf);
+        }
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let taken = self.encoder.push(buf);
+
+        self.write_encoded()?;
+
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.seal_pending();
+
+        self.write_encoded()?;
+
+        self.inner.flush()
+    }
+}
+
+impl<R> CompressedReadHalfAsync<R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Wrap the given half, which receives data compressed with the (already negotiated) algorithm
+    pub fn new(inner: R, algorithm: CompressionAlgorithm, config: CompressionConfig) -> Self {
+        Self {
+            inner,
+            algorithm,
+            decoder: BlockDecoder::new(config.max_block_size),
+        }
+    }
+
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> AsyncRead for CompressedReadHalfAsync<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.algorithm == CompressionAlgorithm::None {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            if let Some(read) = this.decoder.read_decoded(buf) {
+                return Poll::Ready(Ok(read));
+            }
+
+            let read = ready!(Pin::new(&mut this.inner).poll_read(cx, this.decoder.unfilled()))?;
+
+            if !this.decoder.advance(read)? {
+                return Poll::Ready(Ok(0));
+            }
+        }
+    }
+}
+
+impl<W> CompressedWriteHalfAsync<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Wrap the given half, compressing with the (already negotiated) algorithm
+    pub fn new(inner: W, algorithm: CompressionAlgorithm, config: CompressionConfig) -> Self {
+        Self {
+            inner,
+            algorithm,
+            encoder: BlockEncoder::new(config),
+        }
+    }
+
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    /// Unwrap the inner half. Data which was not flushed is lost
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Write the encoded blocks to the inner half
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.encoder.unsent().is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, self.encoder.unsent()))?;
+
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+
+            self.encoder.advance(written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> AsyncWrite for CompressedWriteHalfAsync<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.algorithm == CompressionAlgorithm::None {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Bound the encoded data we hold to the blocks of a single write
+        ready!(this.poll_drain(cx))?;
+
+        let taken = this.encoder.push(buf);
+
+        // Whatever the inner half does not take now is sent on the next write or flush
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+
+        Poll::Ready(Ok(taken))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        this.encoder.seal_pending();
+
+        ready!(this.poll_drain(cx))?;
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        this.encoder.seal_pending();
+
+        ready!(this.poll_drain(cx))?;
+
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::thread;
+
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::socket::framing::{FramedReadHalfSync, FramedWriteHalfSync, FramingConfig};
+    use crate::socket::{bind_sync_server, connect_sync, SecureSocketSync, SocketConfig};
+
+    fn small_config() -> CompressionConfig {
+        CompressionConfig {
+            algorithm: CompressionAlgorithm::Lz4,
+            threshold: 64,
+            max_block_size: 4096,
+        }
+    }
+
+    /// Compressible, but not trivially so
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i / 7) % 13) as u8).collect()
+    }
+
+    #[test]
+    fn test_framed_round_trip() {
+        let config = small_config();
+
+        let compressed =
+            CompressedWriteHalfSync::new(Vec::new(), CompressionAlgorithm::Lz4, config);
+        let mut writer = FramedWriteHalfSync::new(compressed, FramingConfig::default());
+
+        let frames = [payload(10), payload(100_000), Vec::new(), payload(63)];
+
+        for frame in &frames {
+            writer.write_frame(frame).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let wire = writer.into_inner().into_inner();
+
+        // The large frame is compressed, the small ones are sent as is
+        assert!(wire.len() < 50_000);
+
+        let compressed =
+            CompressedReadHalfSync::new(Cursor::new(wire), CompressionAlgorithm::Lz4, config);
+        let mut reader = FramedReadHalfSync::new(compressed, FramingConfig::default());
+
+        for frame in &frames {
+            assert_eq!(&reader.read_frame().unwrap(), frame);
+        }
+
+        // The stream ends cleanly at a block boundary
+        let mut rest = Vec::new();
+        reader.into_inner().read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_incompressible_data_is_sent_raw() {
+        let config = small_config();
+
+        // xorshift, which lz4 finds no matches in
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let data: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect();
+
+        let mut writer =
+            CompressedWriteHalfSync::new(Vec::new(), CompressionAlgorithm::Lz4, config);
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+
+        let wire = writer.into_inner();
+
+        assert_eq!(wire[0], BLOCK_RAW);
+        assert_eq!(wire.len(), BlockHeader::LENGTH + data.len());
+    }
+
+    #[test]
+    fn test_oversized_blocks_are_rejected() {
+        let header = BlockHeader {
+            kind: BLOCK_RAW,
+            raw_len: u32::MAX,
+            wire_len: u32::MAX,
+        };
+
+        let mut reader = CompressedReadHalfSync::new(
+            Cursor::new(header.serialize().to_vec()),
+            CompressionAlgorithm::Lz4,
+            small_config(),
+        );
+
+        let err = reader.read(&mut [0; 16]).unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let lz4 = CompressionHello::new(&small_config());
+        let none = CompressionHello::new(&CompressionConfig {
+            algorithm: CompressionAlgorithm::None,
+            ..small_config()
+        });
+
+        assert_eq!(lz4.agree(&lz4), CompressionAlgorithm::Lz4);
+        assert_eq!(lz4.agree(&none), CompressionAlgorithm::None);
+        assert_eq!(none.agree(&lz4), CompressionAlgorithm::None);
+    }
+
+    #[test]
+    fn test_split_compressed_over_socket() {
+        let socket_config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &socket_config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let socket = SecureSocketSync::new_plain(listener.accept().unwrap());
+
+            let (mut write, mut read) = socket.split_compressed(&small_config()).unwrap();
+
+            let mut received = vec![0; 100_000];
+            read.read_exact(&mut received).unwrap();
+
+            write.write_all(&received).unwrap();
+            write.flush().unwrap();
+
+            read.algorithm()
+        });
+
+        let socket = SecureSocketSync::new_plain(connect_sync(addr, &socket_config).unwrap());
+        let (mut write, mut read) = socket.split_compressed(&small_config()).unwrap();
+
+        write.write_all(&payload(100_000)).unwrap();
+        write.flush().unwrap();
+
+        let mut echo = vec![0; 100_000];
+        read.read_exact(&mut echo).unwrap();
+
+        assert_eq!(echo, payload(100_000));
+        assert_eq!(server.join().unwrap(), CompressionAlgorithm::Lz4);
+    }
+
+    #[tokio::test]
+    async fn test_async_round_trip() {
+        let config = small_config();
+
+        let mut writer =
+            CompressedWriteHalfAsync::new(Vec::new(), CompressionAlgorithm::Lz4, config);
+
+        writer.write_all(b"small").await.unwrap();
+        writer.write_all(&payload(50_000)).await.unwrap();
+        writer.close().await.unwrap();
+
+        let mut reader = CompressedReadHalfAsync::new(
+            futures::io::Cursor::new(writer.into_inner()),
+            CompressionAlgorithm::Lz4,
+            config,
+        );
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(&received[..5], b"small");
+        assert_eq!(&received[5..], &payload(50_000)[..]);
+    }
+}
@@ -0,0 +1,385 @@
+//! Configuration of the socket options applied to listeners and connections.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockAddr, Socket, TcpKeepalive, Type};
+
+/// The kernel and user space buffer sizes we have historically used (8 MiB each)
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// The size the user space buffers start at (4 KiB)
+const DEFAULT_MIN_BUFFER_SIZE: usize = 4 * 1024;
+
+/// The default backlog of pending connections of a listener
+const DEFAULT_LISTEN_BACKLOG: i32 = 1024;
+
+/// The delay between connection attempts to the alternative addresses of a peer,
+/// as recommended by RFC 8305
+const DEFAULT_CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The socket options used when binding listeners and establishing connections.
+///
+/// The defaults match the options that were previously hard coded
+/// (translated from BFT-SMaRt), so `SocketConfig::default()` keeps the
+/// old behaviour.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct SocketConfig {
+    /// The kernel send buffer size (`SO_SNDBUF`). `None` keeps the OS default
+    pub send_buffer_size: Option<usize>,
+    /// The kernel receive buffer size (`SO_RCVBUF`). `None` keeps the OS default
+    pub recv_buffer_size: Option<usize>,
+    /// The maximum capacity of the user space buffer of each write half
+    pub write_buffer_capacity: usize,
+    /// The maximum capacity of the user space buffer of each read half
+    pub read_buffer_capacity: usize,
+    /// The capacity the user space buffers start at. They grow up to the maximum
+    /// capacities as larger messages are seen, see [crate::socket::buffer_pool]
+    pub min_buffer_capacity: usize,
+    /// Whether to disable Nagle's algorithm (`TCP_NODELAY`)
+    pub nodelay: bool,
+    /// TCP keepalive configuration. `None` disables keepalive
+    pub keepalive: Option<KeepaliveConfig>,
+    /// How long to wait for an outgoing connection to be established.
+    /// `None` waits for as long as the OS allows
+    pub connect_timeout: Option<Duration>,
+    /// When a peer has several addresses, how long to wait for an attempt to connect
+    /// before also trying the next address (see [crate::socket::happy_eyeballs])
+    pub connect_attempt_delay: Duration,
+    /// The maximum amount of pending connections of a listener (`SO_BACKLOG`)
+    pub listen_backlog: i32,
+    /// How long transmitted data may remain unacknowledged before the
+    /// connection is forcibly closed (`TCP_USER_TIMEOUT`, Linux only)
+    pub tcp_user_timeout: Option<Duration>,
+    /// The `SO_LINGER` timeout. `None` disables lingering
+    pub linger: Option<Duration>,
+    /// Whether listeners should set `SO_REUSEADDR`
+    pub reuse_address: bool,
+}
+
+/// The TCP keepalive options.
+/// Fields left as `None` keep the OS defaults
+#[derive(Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct KeepaliveConfig {
+    /// Idle time before the first keepalive probe is sent (`TCP_KEEPIDLE`)
+    pub time: Option<Duration>,
+    /// Time between keepalive probes (`TCP_KEEPINTVL`)
+    pub interval: Option<Duration>,
+    /// Amount of unanswered probes before dropping the connection (`TCP_KEEPCNT`)
+    pub retries: Option<u32>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            send_buffer_size: Some(DEFAULT_BUFFER_SIZE),
+            recv_buffer_size: Some(DEFAULT_BUFFER_SIZE),
+            write_buffer_capacity: DEFAULT_BUFFER_SIZE,
+            read_buffer_capacity: DEFAULT_BUFFER_SIZE,
+            min_buffer_capacity: DEFAULT_MIN_BUFFER_SIZE,
+            nodelay: true,
+            keepalive: Some(KeepaliveConfig::default()),
+            connect_timeout: None,
+            connect_attempt_delay: DEFAULT_CONNECT_ATTEMPT_DELAY,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            tcp_user_timeout: None,
+            linger: None,
+            reuse_address: true,
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    fn to_tcp_keepalive(self) -> TcpKeepalive {
+        let mut keepalive = TcpKeepalive::new();
+
+        if let Some(time) = self.time {
+            keepalive = keepalive.with_time(time);
+        }
+
+        if let Some(interval) = self.interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+
+        if let Some(retries) = self.retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+
+        keepalive
+    }
+}
+
+impl SocketConfig {
+    /// Apply the kernel buffer sizes to the given socket.
+    /// These should be set before connecting or listening, so the
+    /// TCP window scale is negotiated accordingly.
+    pub(super) fn apply_buffer_sizes(&self, sock: &Socket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer_size {
+            sock.set_send_buffer_size(size)?;
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            sock.set_recv_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply the options that concern a listening socket
+    pub(super) fn apply_listener_options(&self, sock: &Socket) -> io::Result<()> {
+        self.apply_buffer_sizes(sock)?;
+
+        sock.set_reuse_address(self.reuse_address)?;
+
+        self.apply_connection_options(sock)
+    }
+
+    /// Apply the options that concern an established (or connecting) TCP stream
+    pub(super) fn apply_stream_options(&self, sock: &Socket) -> io::Result<()> {
+        self.apply_buffer_sizes(sock)?;
+
+        self.apply_connection_options(sock)
+    }
+
+    fn apply_connection_options(&self, sock: &Socket) -> io::Result<()> {
+        sock.set_nodelay(self.nodelay)?;
+
+        match self.keepalive {
+            Some(keepalive) => {
+                sock.set_keepalive(true)?;
+                sock.set_tcp_keepalive(&keepalive.to_tcp_keepalive())?;
+            }
+            None => sock.set_keepalive(false)?,
+        }
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        sock.set_tcp_user_timeout(self.tcp_user_timeout)?;
+
+        sock.set_linger(self.linger)?;
+
+        Ok(())
+    }
+}
+
+/// Create a new TCP socket, bound to `addr` and listening with the configured backlog
+pub(super) fn bind_std_listener(
+    addr: SocketAddr,
+    config: &SocketConfig,
+) -> io::Result<std::net::TcpListener> {
+    let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    config.apply_listener_options(&sock)?;
+
+    sock.bind(&addr.into())?;
+    sock.listen(config.listen_backlog)?;
+
+    Ok(sock.into())
+}
+
+/// Connect a new blocking TCP stream to `addr`, respecting the configured connect timeout
+pub(super) fn connect_std_stream(
+    addr: SocketAddr,
+    config: &SocketConfig,
+) -> io::Result<std::net::TcpStream> {
+    let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    config.apply_buffer_sizes(&sock)?;
+
+    match config.connect_timeout {
+        Some(timeout) => sock.connect_timeout(&addr.into(), timeout)?,
+        None => sock.connect(&addr.into())?,
+    }
+
+    Ok(sock.into())
+}
+
+/// Start connecting a new nonblocking TCP socket to `addr`.
+/// The connection is established (or failed) once the socket is writable
+pub(super) fn start_connect_std_stream(
+    addr: SocketAddr,
+    config: &SocketConfig,
+) -> io::Result<Socket> {
+    let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    config.apply_buffer_sizes(&sock)?;
+
+    sock.set_nonblocking(true)?;
+
+    match sock.connect(&addr.into()) {
+        Ok(()) => Ok(sock),
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => Ok(sock),
+        Err(err) => Err(err),
+    }
+}
+
+/// Create a new Unix domain socket, bound to `path` and listening with the configured backlog
+pub(super) fn bind_std_unix_listener(
+    path: &Path,
+    config: &SocketConfig,
+) -> io::Result<UnixListener> {
+    // Held until the new socket is listening, so another process binding the same path
+    // cannot take the stale socket for its own (or ours for a stale one)
+    let _lock = lock_socket_path(path)?;
+
+    remove_stale_socket(path)?;
+
+    let sock = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+
+    config.apply_buffer_sizes(&sock)?;
+
+    sock.bind(&SockAddr::unix(path)?)?;
+    sock.listen(config.listen_backlog)?;
+
+    Ok(sock.into())
+}
+
+/// Connect a new blocking Unix domain stream to `path`, respecting the configured connect timeout
+pub(super) fn connect_std_unix_stream(
+    path: &Path,
+    config: &SocketConfig,
+) -> io::Result<UnixStream> {
+    let sock = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+
+    config.apply_buffer_sizes(&sock)?;
+
+    let addr = SockAddr::unix(path)?;
+
+    match config.connect_timeout {
+        Some(timeout) => sock.connect_timeout(&addr, timeout)?,
+        None => sock.connect(&addr)?,
+    }
+
+    Ok(sock.into())
+}
+
+/// An exclusive advisory lock on `<path>.lock`, whose file is removed when the lock is dropped
+struct SocketPathLock {
+    path: PathBuf,
+    // Closing the file releases the lock
+    _file: File,
+}
+
+impl Drop for SocketPathLock {
+    fn drop(&mut self) {
+        // Removed while we still hold the lock, so whoever is waiting on it
+        // notices the file is gone and locks a new one
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Take an exclusive advisory lock on `<path>.lock`, waiting for whoever holds it.
+/// The lock is released (and its file removed) when the returned guard is dropped.
+///
+/// Since the previous holder removes the file, the lock we get may be on a file that
+/// is no longer at `<path>.lock`, in which case we start over with the new one
+fn lock_socket_path(path: &Path) -> io::Result<SocketPathLock> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+
+    let lock_path = PathBuf::from(lock_path);
+
+    loop {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+
+        // SAFETY: the descriptor is valid for as long as `file` is alive
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let locked = file.metadata()?;
+
+        match std::fs::metadata(&lock_path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok(SocketPathLock {
+                    path: lock_path,
+                    _file: file,
+                });
+            }
+            Ok(_) => continue,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Remove the socket file left at `path` by a process that is no longer listening on it
+/// (for example, a replica that crashed). Sockets that are still in use are left alone.
+///
+/// The file is only removed once connecting to it was refused, which means nobody is
+/// accepting on it. Between the probe and the removal, another process could still bind
+/// the path, which is why callers must hold the lock of [lock_socket_path]. Processes that
+/// bind the path without taking that lock are not covered
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already in use", path.display()),
+            )),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path)
+            }
+            Err(_) => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use socket2::SockRef;
+
+    use super::*;
+
+    #[test]
+    fn test_options_are_applied() {
+        let config = SocketConfig {
+            nodelay: false,
+            keepalive: None,
+            linger: Some(Duration::from_secs(1)),
+            connect_timeout: Some(Duration::from_secs(5)),
+            listen_backlog: 16,
+            ..Default::default()
+        };
+
+        let listener = bind_std_listener(([127, 0, 0, 1], 0).into(), &config).unwrap();
+        let stream = connect_std_stream(listener.local_addr().unwrap(), &config).unwrap();
+
+        config
+            .apply_stream_options(&SockRef::from(&stream))
+            .unwrap();
+
+        let sock = SockRef::from(&stream);
+
+        assert!(!sock.nodelay().unwrap());
+        assert!(!sock.keepalive().unwrap());
+        assert_eq!(sock.linger().unwrap(), Some(Duration::from_secs(1)));
+
+        let (accepted, _) = listener.accept().unwrap();
+
+        SocketConfig::default()
+            .apply_stream_options(&SockRef::from(&accepted))
+            .unwrap();
+
+        let sock = SockRef::from(&accepted);
+
+        assert!(sock.nodelay().unwrap());
+        assert!(sock.keepalive().unwrap());
+    }
+}
@@ -0,0 +1,136 @@
+//! Deadlines for the handshakes that run when a connection is established.
+//!
+//! A peer that connects and then stays silent must not hold the connection (or
+//! a thread) forever, so every handshake gets a bounded amount of time to complete.
+//! Synchronous handshakes run over a [DeadlineSocket], which bounds each blocking
+//! operation by the time left, while async ones race against a timer.
+
+use std::future::Future;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use socket2::SockRef;
+
+use crate::async_runtime;
+use crate::error::*;
+
+/// The default time a handshake has to complete
+pub(super) const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bounds every blocking operation of a synchronous handshake by its deadline
+pub(super) struct DeadlineSocket<'a, S> {
+    socket: &'a mut S,
+    deadline: Option<Instant>,
+    // The name of the handshake, for the timeout error
+    handshake: &'static str,
+}
+
+impl<'a, S: AsRawFd> DeadlineSocket<'a, S> {
+    /// Bound the handshake about to run over `socket` by `timeout`, if any
+    pub(super) fn new(
+        socket: &'a mut S,
+        timeout: Option<Duration>,
+        handshake: &'static str,
+    ) -> Self {
+        Self {
+            socket,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            handshake,
+        }
+    }
+
+    /// Clear the socket timeouts once the handshake is over, whatever its outcome
+    pub(super) fn finish(self) -> io::Result<()> {
+        if self.deadline.is_some() {
+            set_socket_timeouts(&*self.socket, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Limit the next operation on the socket to the time left until the deadline
+    fn arm(&self) -> io::Result<()> {
+        let Some(deadline) = self.deadline else {
+            return Ok(());
+        };
+
+        let left = deadline.saturating_duration_since(Instant::now());
+
+        if left.is_zero() {
+            return Err(handshake_timed_out(self.handshake));
+        }
+
+        set_socket_timeouts(&*self.socket, Some(left))
+    }
+
+    /// Socket timeouts are reported as [ErrorKind::WouldBlock]
+    fn timed_out(&self, err: io::Error) -> io::Error {
+        if err.kind() == ErrorKind::WouldBlock {
+            handshake_timed_out(self.handshake)
+        } else {
+            err
+        }
+    }
+}
+
+impl<S: Read + AsRawFd> Read for DeadlineSocket<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.arm()?;
+
+        self.socket.read(buf).map_err(|err| self.timed_out(err))
+    }
+}
+
+impl<S: Write + AsRawFd> Write for DeadlineSocket<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.arm()?;
+
+        self.socket.write(buf).map_err(|err| self.timed_out(err))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.arm()?;
+
+        self.socket.flush().map_err(|err| self.timed_out(err))
+    }
+}
+
+/// Run the async `handshake`, failing it if it doesn't complete within `timeout`
+pub(super) async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    name: &'static str,
+    handshake: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return handshake.await;
+    };
+
+    let timeout = async_runtime::sleep(timeout);
+
+    futures::pin_mut!(handshake, timeout);
+
+    match future::select(handshake, timeout).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(handshake_timed_out(name).into()),
+    }
+}
+
+fn set_socket_timeouts<S: AsRawFd>(socket: &S, timeout: Option<Duration>) -> io::Result<()> {
+    // Safety: the descriptor is owned by `socket`, which outlives this borrow
+    let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+
+    let socket = SockRef::from(&fd);
+
+    socket.set_read_timeout(timeout)?;
+    socket.set_write_timeout(timeout)
+}
+
+fn handshake_timed_out(handshake: &'static str) -> io::Error {
+    io::Error::new(
+        ErrorKind::TimedOut,
+        format!("The {handshake} handshake timed out"),
+    )
+}
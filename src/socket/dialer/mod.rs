@@ -0,0 +1,983 @@
+//! Keeps connections to other nodes alive, redialing them when they fail.
+//!
+//! Unlike [crate::circuit_breaker::CircuitBreaker], the dialer never gives
+//! up on a peer: it keeps retrying with a jittered exponential backoff until
+//! the peer is removed.
+//!
+//! [PeerDialer] dials each peer from its own thread and hands out synchronous halves,
+//! while [PeerDialerAsync] dials them from tasks of the [async runtime](crate::async_runtime)
+//! and hands out async halves.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use rand::Rng;
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::async_runtime;
+use crate::channel::mixed::{new_bounded_mixed, ChannelMixedRx, ChannelMixedTx};
+use crate::channel::sync::{new_unbounded_sync, ChannelSyncRx, ChannelSyncTx};
+use crate::node_id::NodeId;
+use crate::peer_addr::{EndpointKind, PeerAddr};
+use crate::socket::happy_eyeballs::{connect_async_peer, connect_sync_peer};
+use crate::socket::{
+    SecureChannel, SecureReadHalfAsync, SecureReadHalfSync, SecureWriteHalfAsync,
+    SecureWriteHalfSync, SocketConfig,
+};
+
+/// How many events of a [PeerDialerAsync] can wait for its owner,
+/// before the dialing tasks wait for them to be received
+const ASYNC_EVENTS_CAPACITY: usize = 1024;
+
+/// How long to wait between consecutive connection attempts to a peer
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct BackoffConfig {
+    /// The delay after the first failed attempt
+    pub initial_delay: Duration,
+    /// The delay never grows past this value
+    pub max_delay: Duration,
+    /// How much the delay grows after each failed attempt
+    pub multiplier: f64,
+    /// The fraction of each delay which is randomized, between 0 and 1,
+    /// so that nodes do not redial in lock step
+    pub jitter: f64,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+/// The state of the backoff of consecutive failed attempts
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    config: BackoffConfig,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Self {
+            config,
+            failures: 0,
+        }
+    }
+
+    /// The amount of failures since the last reset
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Register a failed attempt, returning how long to wait before the next one
+    pub fn next_delay<R: Rng>(&mut self, rng: &mut R) -> Duration {
+        let max = self.config.max_delay.as_secs_f64();
+
+        let delay = (self.config.initial_delay.as_secs_f64()
+            * self.config.multiplier.powi(self.failures as i32))
+        .min(max);
+
+        self.failures = self.failures.saturating_add(1);
+
+        let jitter = self.config.jitter.clamp(0.0, 1.0);
+
+        let delay = if jitter > 0.0 {
+            delay * (1.0 - jitter * rng.gen_range(0.0, 1.0))
+        } else {
+            delay
+        };
+
+        Duration::from_secs_f64(delay)
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// The changes in the state of the connections kept by a [PeerDialer].
+///
+/// A [PeerDialerAsync] reports the same changes, but its connections have async halves
+pub enum DialerEvent<W = SecureWriteHalfSync, R = SecureReadHalfSync> {
+    /// We are attempting to connect to the peer
+    Connecting { node: NodeId, attempt: u32 },
+    /// An attempt failed. The next one is made after `retry_in`
+    ConnectFailed {
+        node: NodeId,
+        attempt: u32,
+        error: String,
+        retry_in: Duration,
+    },
+    /// A new connection to the peer has been established
+    Connected(Box<PeerConnection<W, R>>),
+    /// The connection of the given generation was reported as broken,
+    /// and will be redialed
+    Disconnected { node: NodeId, generation: u64 },
+    /// The peer has been removed from the dialer, which no longer connects to it
+    Removed { node: NodeId },
+}
+
+/// The events of a [PeerDialerAsync]
+pub type DialerEventAsync = DialerEvent<SecureWriteHalfAsync, SecureReadHalfAsync>;
+
+/// A connection established by the [PeerDialer].
+///
+/// Each new connection to a peer has a higher generation than the previous ones.
+pub struct PeerConnection<W = SecureWriteHalfSync, R = SecureReadHalfSync> {
+    node: NodeId,
+    addr: PeerAddr,
+    generation: u64,
+    write: W,
+    read: R,
+}
+
+/// A connection established by the [PeerDialerAsync]
+pub type PeerConnectionAsync = PeerConnection<SecureWriteHalfAsync, SecureReadHalfAsync>;
+
+impl<W, R> PeerConnection<W, R> {
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    pub fn addr(&self) -> &PeerAddr {
+        &self.addr
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn into_halves(self) -> (W, R) {
+        (self.write, self.read)
+    }
+}
+
+impl<W, R> Debug for PeerConnection<W, R> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerConnection")
+            .field("node", &self.node)
+            .field("addr", &self.addr)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl<W, R> Debug for DialerEvent<W, R> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DialerEvent::Connecting { node, attempt } => f
+                .debug_struct("Connecting")
+                .field("node", node)
+                .field("attempt", attempt)
+                .finish(),
+            DialerEvent::ConnectFailed {
+                node,
+                attempt,
+                error,
+                retry_in,
+            } => f
+                .debug_struct("ConnectFailed")
+                .field("node", node)
+                .field("attempt", attempt)
+                .field("error", error)
+                .field("retry_in", retry_in)
+                .finish(),
+            DialerEvent::Connected(connection) => {
+                f.debug_tuple("Connected").field(connection).finish()
+            }
+            DialerEvent::Disconnected { node, generation } => f
+                .debug_struct("Disconnected")
+                .field("node", node)
+                .field("generation", generation)
+                .finish(),
+            DialerEvent::Removed { node } => f.debug_struct("Removed").field("node", node).finish(),
+        }
+    }
+}
+
+/// Keeps a connection to each of the registered peers, redialing them
+/// whenever their connection is reported as broken.
+///
+/// Every peer is dialed by its own thread. The established connections
+/// (and every other state change) are delivered through the event channel
+/// returned by [PeerDialer::new]. Dropping the dialer removes every peer.
+pub struct PeerDialer {
+    shared: Arc<DialerShared<ChannelSyncTx<DialerEvent>>>,
+    peers: PeerRegistry,
+}
+
+/// The async counterpart of [PeerDialer].
+///
+/// Every peer is dialed by a task of the [async runtime](crate::async_runtime),
+/// which must have been initialized. The event channel is bounded, so the tasks
+/// wait for the owner to catch up when it falls behind.
+pub struct PeerDialerAsync {
+    shared: Arc<DialerShared<ChannelMixedTx<DialerEventAsync>>>,
+    peers: PeerRegistry,
+}
+
+struct DialerShared<E> {
+    channel: SecureChannel,
+    socket_config: SocketConfig,
+    backoff: BackoffConfig,
+    events: E,
+}
+
+/// The registered peers, with the controls of the threads (or tasks) dialing them.
+/// Dropping the registry removes every peer
+struct PeerRegistry {
+    peers: Mutex<HashMap<NodeId, Arc<PeerControl>>>,
+}
+
+/// The requests made to the thread (or task) dialing a peer
+struct PeerControl {
+    state: Mutex<PeerControlState>,
+    cond: Condvar,
+}
+
+struct PeerControlState {
+    removed: bool,
+    /// Whether the peer was registered again, so its dialing continues on a new thread (or task)
+    replaced: bool,
+    /// The generation of the latest connection
+    generation: u64,
+    /// The latest connection generation reported as broken
+    broken_generation: u64,
+    /// The tasks waiting for a change, as the condvar only wakes threads
+    wakers: Vec<Waker>,
+}
+
+impl PeerDialer {
+    pub fn new(
+        channel: SecureChannel,
+        socket_config: SocketConfig,
+        backoff: BackoffConfig,
+    ) -> (Self, ChannelSyncRx<DialerEvent>) {
+        let (events, rx) = new_unbounded_sync(Some("PeerDialerEvents"));
+
+        let dialer = Self {
+            shared: Arc::new(DialerShared {
+                channel,
+                socket_config,
+                backoff,
+                events,
+            }),
+            peers: PeerRegistry::new(),
+        };
+
+        (dialer, rx)
+    }
+
+    /// Start keeping a connection to `node`, at the given address.
+    ///
+    /// If the peer was already registered, its previous connection is
+    /// abandoned and it's dialed at the new address. The generations of the
+    /// new connections continue after the ones of the previous address, so
+    /// late reports about the abandoned connections are still ignored
+    pub fn add_peer(&self, node: NodeId, addr: PeerAddr) {
+        let control = self.peers.register(node);
+
+        let shared = self.shared.clone();
+
+        thread::Builder::new()
+            .name(format!("peer-dialer-{}", node.id()))
+            .spawn(move || dial_loop(shared, control, node, addr))
+            .expect("Failed to spawn peer dialer thread");
+    }
+
+    /// Stop keeping a connection to `node`. Returns whether the peer was registered.
+    ///
+    /// A connection attempt that is in progress is not interrupted, so the peer's
+    /// [DialerEvent::Removed] can take up to [SocketConfig::connect_timeout] to arrive.
+    /// A connection established by that attempt is dropped instead of being delivered
+    pub fn remove_peer(&self, node: NodeId) -> bool {
+        self.peers.remove(node)
+    }
+
+    /// Report that the connection of the given generation to `node` is broken,
+    /// so a new one is established.
+    ///
+    /// Reports of older generations are ignored, so every holder of the halves
+    /// of a connection can report it without causing more than one redial.
+    pub fn report_disconnected(&self, node: NodeId, generation: u64) {
+        self.peers.report_disconnected(node, generation)
+    }
+
+    pub fn peers(&self) -> Vec<NodeId> {
+        self.peers.nodes()
+    }
+}
+
+impl PeerDialerAsync {
+    pub fn new(
+        channel: SecureChannel,
+        socket_config: SocketConfig,
+        backoff: BackoffConfig,
+    ) -> (Self, ChannelMixedRx<DialerEventAsync>) {
+        let (events, rx) = new_bounded_mixed(ASYNC_EVENTS_CAPACITY, Some("PeerDialerAsyncEvents"));
+
+        let dialer = Self {
+            shared: Arc::new(DialerShared {
+                channel,
+                socket_config,
+                backoff,
+                events,
+            }),
+            peers: PeerRegistry::new(),
+        };
+
+        (dialer, rx)
+    }
+
+    /// Start keeping a connection to `node`, at the given address.
+    /// See [PeerDialer::add_peer]
+    pub fn add_peer(&self, node: NodeId, addr: PeerAddr) {
+        let control = self.peers.register(node);
+
+        // Detached, as the task ends by itself once the peer is removed
+        drop(async_runtime::spawn(dial_loop_async(
+            self.shared.clone(),
+            control,
+            node,
+            addr,
+        )));
+    }
+
+    /// Stop keeping a connection to `node`. Returns whether the peer was registered.
+    ///
+    /// Unlike with [PeerDialer::remove_peer], a connection attempt that is in
+    /// progress is abandoned, so the peer's [DialerEvent::Removed] follows shortly
+    pub fn remove_peer(&self, node: NodeId) -> bool {
+        self.peers.remove(node)
+    }
+
+    /// Report that the connection of the given generation to `node` is broken.
+    /// See [PeerDialer::report_disconnected]
+    pub fn report_disconnected(&self, node: NodeId, generation: u64) {
+        self.peers.report_disconnected(node, generation)
+    }
+
+    pub fn peers(&self) -> Vec<NodeId> {
+        self.peers.nodes()
+    }
+}
+
+impl PeerRegistry {
+    fn new() -> Self {
+        Self {
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register `node`, replacing its previous registration (if any),
+    /// and return the control of its dialing
+    fn register(&self, node: NodeId) -> Arc<PeerControl> {
+        let mut peers = self.peers.lock().unwrap();
+
+        let generation = peers.get(&node).map_or(0, |previous| previous.replace());
+
+        let control = Arc::new(PeerControl::new(generation));
+
+        peers.insert(node, control.clone());
+
+        control
+    }
+
+    fn remove(&self, node: NodeId) -> bool {
+        match self.peers.lock().unwrap().remove(&node) {
+            Some(control) => {
+                control.remove();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn report_disconnected(&self, node: NodeId, generation: u64) {
+        if let Some(control) = self.peers.lock().unwrap().get(&node) {
+            control.report_broken(generation);
+        }
+    }
+
+    fn nodes(&self) -> Vec<NodeId> {
+        self.peers.lock().unwrap().keys().copied().collect()
+    }
+}
+
+impl Drop for PeerRegistry {
+    fn drop(&mut self) {
+        for (_, control) in self.peers.lock().unwrap().drain() {
+            control.remove();
+        }
+    }
+}
+
+impl PeerControl {
+    /// A control whose connections start after the given generation
+    fn new(generation: u64) -> Self {
+        Self {
+            state: Mutex::new(PeerControlState {
+                removed: false,
+                replaced: false,
+                generation,
+                broken_generation: generation,
+                wakers: Vec::new(),
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Wake up whoever is waiting for a change of the state
+    fn notify(&self, state: &mut PeerControlState) {
+        self.cond.notify_all();
+
+        state.wakers.drain(..).for_each(Waker::wake);
+    }
+
+    fn remove(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.removed = true;
+
+        self.notify(&mut state);
+    }
+
+    /// Stop this control's dialing because the peer was registered again.
+    /// Returns the generation the new control must continue from
+    fn replace(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+
+        state.removed = true;
+        state.replaced = true;
+
+        self.notify(&mut state);
+
+        state.generation
+    }
+
+    fn report_broken(&self, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        if generation > state.broken_generation {
+            state.broken_generation = generation;
+
+            self.notify(&mut state);
+        }
+    }
+
+    fn is_removed(&self) -> bool {
+        self.state.lock().unwrap().removed
+    }
+
+    fn is_replaced(&self) -> bool {
+        self.state.lock().unwrap().replaced
+    }
+
+    /// The generation of a newly established connection, or `None` if the peer
+    /// was removed (or replaced) while connecting
+    fn next_generation(&self) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.removed {
+            return None;
+        }
+
+        state.generation += 1;
+
+        Some(state.generation)
+    }
+
+    /// Wait for the given duration, or until the peer is removed.
+    /// Returns whether the peer was removed
+    fn wait_removed(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        let mut state = self.state.lock().unwrap();
+
+        while !state.removed {
+            let now = Instant::now();
+
+            if now >= deadline {
+                break;
+            }
+
+            state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        state.removed
+    }
+
+    /// Wait until the connection of the given generation is reported broken,
+    /// or the peer is removed. Returns whether the peer was removed
+    fn wait_broken(&self, generation: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        while !state.removed && state.broken_generation < generation {
+            state = self.cond.wait(state).unwrap();
+        }
+
+        state.removed
+    }
+
+    /// Resolves once `condition` holds for the state
+    fn changed(
+        &self,
+        condition: impl Fn(&PeerControlState) -> bool,
+    ) -> impl Future<Output = ()> + '_ {
+        future::poll_fn(move |cx| {
+            let mut state = self.state.lock().unwrap();
+
+            if condition(&state) {
+                return Poll::Ready(());
+            }
+
+            if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                state.wakers.push(cx.waker().clone());
+            }
+
+            Poll::Pending
+        })
+    }
+
+    /// The async counterpart of [Self::wait_removed]
+    async fn wait_removed_async(&self, timeout: Duration) -> bool {
+        let removed = self.changed(|state| state.removed);
+        let timeout = async_runtime::sleep(timeout);
+
+        futures::pin_mut!(removed, timeout);
+
+        match future::select(removed, timeout).await {
+            Either::Left(_) => true,
+            Either::Right(_) => self.is_removed(),
+        }
+    }
+
+    /// The async counterpart of [Self::wait_broken]
+    async fn wait_broken_async(&self, generation: u64) -> bool {
+        self.changed(|state| state.removed || state.broken_generation >= generation)
+            .await;
+
+        self.is_removed()
+    }
+}
+
+/// Register a failed attempt, returning how long to wait before the next one
+/// along with the event reporting it
+fn connect_failed<W, R>(
+    backoff: &mut Backoff,
+    node: NodeId,
+    attempt: u32,
+    err: &anyhow::Error,
+) -> (Duration, DialerEvent<W, R>) {
+    let retry_in = backoff.next_delay(&mut rand::thread_rng());
+
+    warn!(
+        "Failed to connect to node {:?} (attempt {}), retrying in {:?}: {:?}",
+        node, attempt, retry_in, err
+    );
+
+    let event = DialerEvent::ConnectFailed {
+        node,
+        attempt,
+        error: format!("{err:?}"),
+        retry_in,
+    };
+
+    (retry_in, event)
+}
+
+fn dial_loop(
+    shared: Arc<DialerShared<ChannelSyncTx<DialerEvent>>>,
+    control: Arc<PeerControl>,
+    node: NodeId,
+    addr: PeerAddr,
+) {
+    let mut backoff = Backoff::new(shared.backoff);
+
+    loop {
+        if control.is_removed() {
+            break;
+        }
+
+        let attempt = backoff.failures() + 1;
+
+        shared.emit(DialerEvent::Connecting { node, attempt });
+
+        let result = connect_sync_peer(&addr, EndpointKind::Replica, &shared.socket_config)
+            .map_err(anyhow::Error::from)
+            .and_then(|socket| shared.channel.connect_sync(node, socket));
+
+        match result {
+            Ok((_, socket)) => {
+                let Some(generation) = control.next_generation() else {
+                    break;
+                };
+
+                backoff.reset();
+
+                debug!("Connected to node {:?} (generation {})", node, generation);
+
+                let (write, read) = socket.split();
+
+                shared.emit(DialerEvent::Connected(Box::new(PeerConnection {
+                    node,
+                    addr: addr.clone(),
+                    generation,
+                    write,
+                    read,
+                })));
+
+                if control.wait_broken(generation) {
+                    break;
+                }
+
+                shared.emit(DialerEvent::Disconnected { node, generation });
+            }
+            Err(err) => {
+                let (retry_in, event) = connect_failed(&mut backoff, node, attempt, &err);
+
+                shared.emit(event);
+
+                if control.wait_removed(retry_in) {
+                    break;
+                }
+            }
+        }
+    }
+
+    // A replaced peer is still registered, its dialing continues on another thread
+    if !control.is_replaced() {
+        shared.emit(DialerEvent::Removed { node });
+    }
+}
+
+async fn dial_loop_async(
+    shared: Arc<DialerShared<ChannelMixedTx<DialerEventAsync>>>,
+    control: Arc<PeerControl>,
+    node: NodeId,
+    addr: PeerAddr,
+) {
+    let mut backoff = Backoff::new(shared.backoff);
+
+    loop {
+        if control.is_removed() {
+            break;
+        }
+
+        let attempt = backoff.failures() + 1;
+
+        shared.emit(DialerEvent::Connecting { node, attempt }).await;
+
+        let connect = async {
+            let socket =
+                connect_async_peer(&addr, EndpointKind::Replica, &shared.socket_config).await?;
+
+            shared.channel.connect_async(node, socket).await
+        };
+
+        let removed = control.changed(|state| state.removed);
+
+        futures::pin_mut!(connect, removed);
+
+        // Unlike a thread, the attempt can be abandoned as soon as the peer is removed
+        let result = match future::select(connect, removed).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => break,
+        };
+
+        match result {
+            Ok((_, socket)) => {
+                let Some(generation) = control.next_generation() else {
+                    break;
+                };
+
+                backoff.reset();
+
+                debug!("Connected to node {:?} (generation {})", node, generation);
+
+                let (write, read) = socket.split();
+
+                shared
+                    .emit(DialerEvent::Connected(Box::new(PeerConnection {
+                        node,
+                        addr: addr.clone(),
+                        generation,
+                        write,
+                        read,
+                    })))
+                    .await;
+
+                if control.wait_broken_async(generation).await {
+                    break;
+                }
+
+                shared
+                    .emit(DialerEvent::Disconnected { node, generation })
+                    .await;
+            }
+            Err(err) => {
+                let (retry_in, event) = connect_failed(&mut backoff, node, attempt, &err);
+
+                shared.emit(event).await;
+
+                if control.wait_removed_async(retry_in).await {
+                    break;
+                }
+            }
+        }
+    }
+
+    // A replaced peer is still registered, its dialing continues on another task
+    if !control.is_replaced() {
+        shared.emit(DialerEvent::Removed { node }).await;
+    }
+}
+
+impl DialerShared<ChannelSyncTx<DialerEvent>> {
+    fn emit(&self, event: DialerEvent) {
+        // The owner may have stopped listening, which is not our concern
+        let _ = self.events.send(event);
+    }
+}
+
+impl DialerShared<ChannelMixedTx<DialerEventAsync>> {
+    async fn emit(&self, event: DialerEventAsync) {
+        // The owner may have stopped listening, which is not our concern
+        let _ = self.events.send_async(event).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::socket::bind_refusing;
+
+    fn next_event(rx: &ChannelSyncRx<DialerEvent>) -> DialerEvent {
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("Dialer did not produce an event")
+    }
+
+    async fn next_event_async(rx: &mut ChannelMixedRx<DialerEventAsync>) -> DialerEventAsync {
+        let event = rx.recv_async();
+        let timeout = async_runtime::sleep(Duration::from_secs(10));
+
+        futures::pin_mut!(event, timeout);
+
+        match future::select(event, timeout).await {
+            Either::Left((event, _)) => event.expect("The dialer stopped"),
+            Either::Right(_) => panic!("Dialer did not produce an event"),
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let config = BackoffConfig {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.5,
+        };
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut backoff = Backoff::new(config);
+
+        for failures in 0..10 {
+            let ceiling = (100.0 * 2f64.powi(failures)).min(1000.0);
+            let delay = backoff.next_delay(&mut rng).as_secs_f64() * 1000.0;
+
+            assert!(delay <= ceiling + 1e-6, "{delay} > {ceiling}");
+            assert!(delay >= ceiling / 2.0 - 1e-6, "{delay} < {ceiling} / 2");
+        }
+
+        backoff.reset();
+
+        assert!(backoff.next_delay(&mut rng) <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_redials_until_reachable_and_after_disconnect() {
+        let config = SocketConfig::default();
+
+        // Keep the port bound but not listening, so the first attempts are refused
+        let reserved = bind_refusing();
+        let addr: SocketAddr = reserved.local_addr().unwrap().as_socket().unwrap();
+
+        let backoff = BackoffConfig {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let (dialer, events) = PeerDialer::new(SecureChannel::Plain, config, backoff);
+
+        dialer.add_peer(NodeId(1), PeerAddr::new(addr, "node-1".to_string()));
+
+        assert!(matches!(
+            next_event(&events),
+            DialerEvent::Connecting { attempt: 1, .. }
+        ));
+        assert!(matches!(
+            next_event(&events),
+            DialerEvent::ConnectFailed { attempt: 1, .. }
+        ));
+
+        reserved.listen(128).unwrap();
+        let listener = TcpListener::from(reserved);
+
+        let first = loop {
+            if let DialerEvent::Connected(connection) = next_event(&events) {
+                break connection;
+            }
+        };
+
+        assert_eq!(first.generation(), 1);
+
+        let (mut server, _) = listener.accept().unwrap();
+        let (mut write, _read) = first.into_halves();
+        write.write_all(b"ping").unwrap();
+        write.flush().unwrap();
+
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Stale reports are ignored, fresh ones trigger a new connection
+        dialer.report_disconnected(NodeId(1), 0);
+        dialer.report_disconnected(NodeId(1), 1);
+
+        assert!(matches!(
+            next_event(&events),
+            DialerEvent::Disconnected { generation: 1, .. }
+        ));
+        assert!(matches!(
+            next_event(&events),
+            DialerEvent::Connecting { attempt: 1, .. }
+        ));
+
+        match next_event(&events) {
+            DialerEvent::Connected(connection) => assert_eq!(connection.generation(), 2),
+            event => panic!("Unexpected event {event:?}"),
+        }
+
+        // Registering the peer again continues the generations, without removing it
+        dialer.add_peer(NodeId(1), PeerAddr::new(addr, "node-1".to_string()));
+
+        loop {
+            match next_event(&events) {
+                DialerEvent::Connected(connection) => {
+                    assert_eq!(connection.generation(), 3);
+                    break;
+                }
+                DialerEvent::Removed { .. } => panic!("A replaced peer was reported as removed"),
+                _ => {}
+            }
+        }
+
+        // Reports about the abandoned connection are stale for the new one
+        dialer.report_disconnected(NodeId(1), 2);
+
+        assert!(dialer.remove_peer(NodeId(1)));
+        assert!(!dialer.remove_peer(NodeId(1)));
+
+        assert!(matches!(
+            next_event(&events),
+            DialerEvent::Removed { node } if node == NodeId(1)
+        ));
+    }
+
+    #[test]
+    fn test_async_dialer_redials_until_reachable_and_after_disconnect() {
+        // The dialing tasks run on the async runtime, which another test may have started
+        let _ = async_runtime::init(2);
+
+        let reserved = bind_refusing();
+        let addr: SocketAddr = reserved.local_addr().unwrap().as_socket().unwrap();
+
+        let backoff = BackoffConfig {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let (dialer, mut events) =
+            PeerDialerAsync::new(SecureChannel::Plain, SocketConfig::default(), backoff);
+
+        async_runtime::block_on(async move {
+            dialer.add_peer(NodeId(1), PeerAddr::new(addr, "node-1".to_string()));
+
+            assert!(matches!(
+                next_event_async(&mut events).await,
+                DialerEvent::Connecting { attempt: 1, .. }
+            ));
+            assert!(matches!(
+                next_event_async(&mut events).await,
+                DialerEvent::ConnectFailed { attempt: 1, .. }
+            ));
+
+            reserved.listen(128).unwrap();
+            let listener = TcpListener::from(reserved);
+
+            let first = loop {
+                if let DialerEvent::Connected(connection) = next_event_async(&mut events).await {
+                    break connection;
+                }
+            };
+
+            assert_eq!(first.generation(), 1);
+
+            // The connection is already established, so accepting it doesn't block for long
+            let (mut server, _) = listener.accept().unwrap();
+            let (mut write, _read) = first.into_halves();
+            write.write_all(b"ping").await.unwrap();
+            write.flush().await.unwrap();
+
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"ping");
+
+            dialer.report_disconnected(NodeId(1), 1);
+
+            assert!(matches!(
+                next_event_async(&mut events).await,
+                DialerEvent::Disconnected { generation: 1, .. }
+            ));
+            assert!(matches!(
+                next_event_async(&mut events).await,
+                DialerEvent::Connecting { attempt: 1, .. }
+            ));
+
+            match next_event_async(&mut events).await {
+                DialerEvent::Connected(connection) => assert_eq!(connection.generation(), 2),
+                event => panic!("Unexpected event {event:?}"),
+            }
+
+            assert!(dialer.remove_peer(NodeId(1)));
+
+            assert!(matches!(
+                next_event_async(&mut events).await,
+                DialerEvent::Removed { node } if node == NodeId(1)
+            ));
+        });
+    }
+}
@@ -0,0 +1,561 @@
+//! Length-prefixed message framing on top of the socket halves.
+//!
+//! Every frame is preceded by a fixed size [FrameHeader], carrying the length
+//! of the payload and a CRC32 checksum of its contents.
+//! The header is validated (and the announced length checked against the configured
+//! maximum frame size) before any memory is allocated for the payload, so a
+//! malicious peer cannot make us allocate arbitrary amounts of memory.
+
+use std::io;
+use std::io::{Read, Write};
+
+use bytes::Bytes;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use thiserror::Error;
+
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::socket::{
+    SecureReadHalfAsync, SecureReadHalfSync, SecureSocketAsync, SecureSocketSync,
+    SecureWriteHalfAsync, SecureWriteHalfSync,
+};
+
+/// The magic number that starts every frame
+const FRAME_MAGIC: u16 = 0xA71A;
+
+/// The current version of the frame format
+const FRAME_VERSION: u16 = 1;
+
+/// The default maximum size of a frame's payload (64 MiB)
+const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("IO error while handling frame {0:?}")]
+    Io(#[from] io::Error),
+    #[error("Frame with {size} bytes exceeds the maximum frame size of {max} bytes")]
+    FrameTooLarge { size: usize, max: usize },
+    #[error("Invalid frame magic {0:#x}")]
+    InvalidMagic(u16),
+    #[error("Unsupported frame version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Frame checksum mismatch, header says {expected:#x}, payload has {found:#x}")]
+    ChecksumMismatch { expected: u32, found: u32 },
+}
+
+/// Configuration of the framing layer
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct FramingConfig {
+    /// The maximum size (in bytes) of the payload of a single frame.
+    /// Frames that exceed this size are rejected, both when reading and writing
+    pub max_frame_size: usize,
+}
+
+impl Default for FramingConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+/// The header that precedes every frame.
+///
+/// Wire layout (big endian):
+/// `| magic: u16 | version: u16 | payload length: u32 | payload crc32: u32 |`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FrameHeader {
+    payload_len: u32,
+    checksum: u32,
+}
+
+impl FrameHeader {
+    /// Length in bytes of a serialized `FrameHeader`.
+    pub const LENGTH: usize = 12;
+
+    /// Builds the header for the given payload, validating its size against the config
+    pub fn for_payload(payload: &[u8], config: &FramingConfig) -> Result<Self, FrameError> {
+        if payload.len() > config.max_frame_size || payload.len() > u32::MAX as usize {
+            return Err(FrameError::FrameTooLarge {
+                size: payload.len(),
+                max: config.max_frame_size,
+            });
+        }
+
+        Ok(Self {
+            payload_len: payload.len() as u32,
+            checksum: crc32fast::hash(payload),
+        })
+    }
+
+    /// Parses and validates a header read from the wire.
+    ///
+    /// This does not allocate, so it is safe to call before trusting the peer
+    pub fn deserialize(
+        bytes: &[u8; Self::LENGTH],
+        config: &FramingConfig,
+    ) -> Result<Self, FrameError> {
+        let magic = u16::from_be_bytes([bytes[0], bytes[1]]);
+
+        if magic != FRAME_MAGIC {
+            return Err(FrameError::InvalidMagic(magic));
+        }
+
+        let version = u16::from_be_bytes([bytes[2], bytes[3]]);
+
+        if version != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+
+        let payload_len = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+
+        if payload_len as usize > config.max_frame_size {
+            return Err(FrameError::FrameTooLarge {
+                size: payload_len as usize,
+                max: config.max_frame_size,
+            });
+        }
+
+        let checksum = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+
+        Ok(Self {
+            payload_len,
+            checksum,
+        })
+    }
+
+    pub fn serialize(&self) -> [u8; Self::LENGTH] {
+        let mut bytes = [0; Self::LENGTH];
+
+        bytes[0..2].copy_from_slice(&FRAME_MAGIC.to_be_bytes());
+        bytes[2..4].copy_from_slice(&FRAME_VERSION.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.payload_len.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.checksum.to_be_bytes());
+
+        bytes
+    }
+
+    /// The length of the payload that follows this header
+    pub fn payload_len(&self) -> usize {
+        self.payload_len as usize
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// Verifies that the given payload matches this header
+    pub fn verify_payload(&self, payload: &[u8]) -> Result<(), FrameError> {
+        let found = crc32fast::hash(payload);
+
+        if found != self.checksum {
+            return Err(FrameError::ChecksumMismatch {
+                expected: self.checksum,
+                found,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A frame that has already been serialized, so it can be sent to many peers
+/// without serializing (or copying) it again. Cloning it is cheap, the header and
+/// payload buffers are shared.
+///
+/// See [FramedWriteHalfSync::write_shared_frame].
+#[derive(Clone, Debug)]
+pub struct SharedFrame {
+    header: Bytes,
+    payload: Bytes,
+}
+
+impl SharedFrame {
+    pub fn new(payload: Bytes, config: &FramingConfig) -> Result<Self, FrameError> {
+        let header = FrameHeader::for_payload(&payload, config)?;
+
+        Ok(Self {
+            header: Bytes::copy_from_slice(&header.serialize()),
+            payload,
+        })
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    fn check_size(&self, config: &FramingConfig) -> Result<(), FrameError> {
+        if self.payload.len() > config.max_frame_size {
+            return Err(FrameError::FrameTooLarge {
+                size: self.payload.len(),
+                max: config.max_frame_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn buffers(&self) -> [Bytes; 2] {
+        [self.header.clone(), self.payload.clone()]
+    }
+}
+
+/// A read half that yields whole frames, instead of a raw byte stream.
+pub struct FramedReadHalfSync<R = SecureReadHalfSync> {
+    inner: R,
+    config: FramingConfig,
+}
+
+/// A write half that writes whole frames, instead of a raw byte stream.
+pub struct FramedWriteHalfSync<W = SecureWriteHalfSync> {
+    inner: W,
+    config: FramingConfig,
+}
+
+/// The async counterpart of [FramedReadHalfSync].
+pub struct FramedReadHalfAsync<R = SecureReadHalfAsync> {
+    inner: R,
+    config: FramingConfig,
+}
+
+/// The async counterpart of [FramedWriteHalfSync].
+pub struct FramedWriteHalfAsync<W = SecureWriteHalfAsync> {
+    inner: W,
+    config: FramingConfig,
+}
+
+impl SecureSocketSync {
+    /// Split this socket into halves that read and write whole frames
+    pub fn split_framed(self, config: FramingConfig) -> (FramedWriteHalfSync, FramedReadHalfSync) {
+        let (write, read) = self.split();
+
+        (
+            FramedWriteHalfSync::new(write, config),
+            FramedReadHalfSync::new(read, config),
+        )
+    }
+}
+
+impl SecureSocketAsync {
+    /// Split this socket into halves that read and write whole frames
+    pub fn split_framed(
+        self,
+        config: FramingConfig,
+    ) -> (FramedWriteHalfAsync, FramedReadHalfAsync) {
+        let (write, read) = self.split();
+
+        (
+            FramedWriteHalfAsync::new(write, config),
+            FramedReadHalfAsync::new(read, config),
+        )
+    }
+}
+
+impl<R> FramedReadHalfSync<R>
+where
+    R: Read,
+{
+    pub fn new(inner: R, config: FramingConfig) -> Self {
+        Self { inner, config }
+    }
+
+    /// Read the next frame, blocking until it has been fully received
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, FrameError> {
+        let mut payload = Vec::new();
+
+        self.read_frame_into(&mut payload)?;
+
+        Ok(payload)
+    }
+
+    /// Read the next frame into the given buffer, replacing its contents.
+    /// Returns the size of the frame's payload.
+    pub fn read_frame_into(&mut self, payload: &mut Vec<u8>) -> Result<usize, FrameError> {
+        let mut header = [0; FrameHeader::LENGTH];
+
+        self.inner.read_exact(&mut header)?;
+
+        let header = FrameHeader::deserialize(&header, &self.config)?;
+
+        payload.clear();
+        payload.resize(header.payload_len(), 0);
+
+        self.inner.read_exact(payload)?;
+
+        header.verify_payload(payload)?;
+
+        Ok(header.payload_len())
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<W> FramedWriteHalfSync<W>
+where
+    W: Write,
+{
+    pub fn new(inner: W, config: FramingConfig) -> Self {
+        Self { inner, config }
+    }
+
+    /// Write a frame with the given payload.
+    /// The frame might be buffered, call [Self::flush] to make sure it is sent
+    pub fn write_frame(&mut self, payload: &[u8]) -> Result<(), FrameError> {
+        let header = FrameHeader::for_payload(payload, &self.config)?;
+
+        self.inner.write_all(&header.serialize())?;
+        self.inner.write_all(payload)?;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), FrameError> {
+        self.inner.flush().map_err(From::from)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl FramedWriteHalfSync<SecureWriteHalfSync> {
+    /// Write an already serialized frame, without copying it into the write buffer
+    /// (for plain sockets). Unlike [Self::write_frame], the frame (and anything
+    /// buffered before it) is sent by the time this returns
+    pub fn write_shared_frame(&mut self, frame: &SharedFrame) -> Result<(), FrameError> {
+        frame.check_size(&self.config)?;
+
+        self.inner.write_shared(&frame.buffers())?;
+
+        Ok(())
+    }
+}
+
+impl<R> FramedReadHalfAsync<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(inner: R, config: FramingConfig) -> Self {
+        Self { inner, config }
+    }
+
+    /// Read the next frame, waiting until it has been fully received
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>, FrameError> {
+        let mut payload = Vec::new();
+
+        self.read_frame_into(&mut payload).await?;
+
+        Ok(payload)
+    }
+
+    /// Read the next frame into the given buffer, replacing its contents.
+    /// Returns the size of the frame's payload.
+    pub async fn read_frame_into(&mut self, payload: &mut Vec<u8>) -> Result<usize, FrameError> {
+        let mut header = [0; FrameHeader::LENGTH];
+
+        self.inner.read_exact(&mut header).await?;
+
+        let header = FrameHeader::deserialize(&header, &self.config)?;
+
+        payload.clear();
+        payload.resize(header.payload_len(), 0);
+
+        self.inner.read_exact(payload).await?;
+
+        header.verify_payload(payload)?;
+
+        Ok(header.payload_len())
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<W> FramedWriteHalfAsync<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(inner: W, config: FramingConfig) -> Self {
+        Self { inner, config }
+    }
+
+    /// Write a frame with the given payload.
+    /// The frame might be buffered, call [Self::flush] to make sure it is sent
+    pub async fn write_frame(&mut self, payload: &[u8]) -> Result<(), FrameError> {
+        let header = FrameHeader::for_payload(payload, &self.config)?;
+
+        self.inner.write_all(&header.serialize()).await?;
+        self.inner.write_all(payload).await?;
+
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<(), FrameError> {
+        self.inner.flush().await.map_err(From::from)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl FramedWriteHalfAsync<SecureWriteHalfAsync> {
+    /// The async counterpart of [FramedWriteHalfSync::write_shared_frame].
+    pub async fn write_shared_frame(&mut self, frame: &SharedFrame) -> Result<(), FrameError> {
+        frame.check_size(&self.config)?;
+
+        self.inner.write_shared(&frame.buffers()).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::socket::SocketConfig;
+
+    fn small_config() -> FramingConfig {
+        FramingConfig {
+            max_frame_size: 1024,
+        }
+    }
+
+    #[test]
+    fn test_sync_round_trip() {
+        let mut writer = FramedWriteHalfSync::new(Vec::new(), small_config());
+
+        writer.write_frame(b"first frame").unwrap();
+        writer.write_frame(b"").unwrap();
+        writer.write_frame(&[7; 1024]).unwrap();
+        writer.flush().unwrap();
+
+        let mut reader = FramedReadHalfSync::new(Cursor::new(writer.into_inner()), small_config());
+
+        assert_eq!(reader.read_frame().unwrap(), b"first frame");
+        assert!(reader.read_frame().unwrap().is_empty());
+        assert_eq!(reader.read_frame().unwrap(), vec![7; 1024]);
+        assert!(matches!(reader.read_frame(), Err(FrameError::Io(_))));
+    }
+
+    #[test]
+    fn test_oversized_frames_are_rejected() {
+        let mut writer = FramedWriteHalfSync::new(Vec::new(), small_config());
+
+        assert!(matches!(
+            writer.write_frame(&[0; 1025]),
+            Err(FrameError::FrameTooLarge {
+                size: 1025,
+                max: 1024
+            })
+        ));
+        assert!(writer.into_inner().is_empty());
+
+        // A peer announcing a huge frame must be refused before we allocate it
+        let mut header = FrameHeader::for_payload(b"", &small_config())
+            .unwrap()
+            .serialize();
+        header[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        let mut reader = FramedReadHalfSync::new(Cursor::new(header.to_vec()), small_config());
+
+        assert!(matches!(
+            reader.read_frame(),
+            Err(FrameError::FrameTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_malformed_frames_are_rejected() {
+        let mut writer = FramedWriteHalfSync::new(Vec::new(), small_config());
+        writer.write_frame(b"payload").unwrap();
+
+        let frame = writer.into_inner();
+
+        let mut bad_magic = frame.clone();
+        bad_magic[0] ^= 0xFF;
+
+        let mut reader = FramedReadHalfSync::new(Cursor::new(bad_magic), small_config());
+        assert!(matches!(
+            reader.read_frame(),
+            Err(FrameError::InvalidMagic(_))
+        ));
+
+        let mut bad_version = frame.clone();
+        bad_version[3] = 42;
+
+        let mut reader = FramedReadHalfSync::new(Cursor::new(bad_version), small_config());
+        assert!(matches!(
+            reader.read_frame(),
+            Err(FrameError::UnsupportedVersion(42))
+        ));
+
+        let mut corrupted = frame;
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+
+        let mut reader = FramedReadHalfSync::new(Cursor::new(corrupted), small_config());
+        assert!(matches!(
+            reader.read_frame(),
+            Err(FrameError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_async_round_trip() {
+        futures::executor::block_on(async {
+            let mut writer = FramedWriteHalfAsync::new(Vec::new(), small_config());
+
+            writer.write_frame(b"hello").await.unwrap();
+            writer.write_frame(b"world").await.unwrap();
+            writer.flush().await.unwrap();
+
+            let mut reader = FramedReadHalfAsync::new(
+                futures::io::Cursor::new(writer.into_inner()),
+                small_config(),
+            );
+
+            assert_eq!(reader.read_frame().await.unwrap(), b"hello");
+
+            let mut buf = Vec::new();
+            assert_eq!(reader.read_frame_into(&mut buf).await.unwrap(), 5);
+            assert_eq!(buf, b"world");
+        });
+    }
+
+    #[test]
+    fn test_plain_socket_round_trip() {
+        let listener =
+            crate::socket::bind_sync_server(([127, 0, 0, 1], 0), &SocketConfig::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let socket = crate::socket::connect_sync(addr, &SocketConfig::default()).unwrap();
+
+            let (mut write, mut read) =
+                SecureSocketSync::new_plain(socket).split_framed(small_config());
+
+            write.write_frame(b"ping").unwrap();
+            write.flush().unwrap();
+
+            read.read_frame().unwrap()
+        });
+
+        let socket = listener.accept().unwrap();
+        let (mut write, mut read) =
+            SecureSocketSync::new_plain(socket).split_framed(small_config());
+
+        assert_eq!(read.read_frame().unwrap(), b"ping");
+
+        write.write_frame(b"pong").unwrap();
+        write.flush().unwrap();
+
+        assert_eq!(client.join().unwrap(), b"pong");
+    }
+}
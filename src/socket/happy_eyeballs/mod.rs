@@ -0,0 +1,426 @@
+//! Connecting to peers that can be reached at several addresses.
+//!
+//! The endpoints of the wanted kind are resolved when connecting (so DNS changes are
+//! picked up), and the resulting addresses are tried in order, alternating between
+//! IPv6 and IPv4 as in RFC 8305 ("happy eyeballs"). An attempt that fails starts the
+//! next one right away, while an attempt that is slow to complete only gets a head
+//! start of [SocketConfig::connect_attempt_delay] before the next one is started
+//! alongside it. The first connection to be established is used, and the attempts
+//! still in progress are abandoned.
+
+use std::collections::HashSet;
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use socket2::Socket;
+use tracing::debug;
+
+use crate::async_runtime;
+use crate::peer_addr::{EndpointAddr, EndpointKind, PeerAddr};
+use crate::socket::config::start_connect_std_stream;
+use crate::socket::{
+    connect_async, sync_socket_from_connected, AsyncSocket, SocketConfig, SyncSocket,
+};
+
+/// Connect to the endpoints of the given kind of a peer
+pub fn connect_sync_peer(
+    peer: &PeerAddr,
+    kind: EndpointKind,
+    config: &SocketConfig,
+) -> io::Result<SyncSocket> {
+    let mut resolved = Vec::new();
+    let mut last_error = None;
+
+    for endpoint in peer.endpoints_of(kind) {
+        match endpoint.resolve() {
+            Ok(addrs) => resolved.extend(addrs),
+            Err(err) => {
+                debug!("Failed to resolve {:?}: {:?}", endpoint.addr(), err);
+
+                last_error = Some(err);
+            }
+        }
+    }
+
+    let candidates = candidates(peer, kind, resolved, last_error)?;
+
+    connect_sync_candidates(candidates, config)
+}
+
+/// The async counterpart of [connect_sync_peer]
+pub async fn connect_async_peer(
+    peer: &PeerAddr,
+    kind: EndpointKind,
+    config: &SocketConfig,
+) -> io::Result<AsyncSocket> {
+    let mut resolved = Vec::new();
+    let mut last_error = None;
+
+    for endpoint in peer.endpoints_of(kind) {
+        let addrs = match endpoint.addr() {
+            EndpointAddr::Socket(addr) => Ok(vec![*addr]),
+            EndpointAddr::Dns { host, port } => lookup_host(host, *port).await,
+        };
+
+        match addrs {
+            Ok(addrs) => resolved.extend(addrs),
+            Err(err) => {
+                debug!("Failed to resolve {:?}: {:?}", endpoint.addr(), err);
+
+                last_error = Some(err);
+            }
+        }
+    }
+
+    let candidates = candidates(peer, kind, resolved, last_error)?;
+
+    connect_async_candidates(candidates, config).await
+}
+
+/// A connection attempt in progress, made with a nonblocking socket
+struct SyncAttempt {
+    socket: Socket,
+    deadline: Option<Instant>,
+}
+
+/// Try to connect to each of the given addresses, in order, with the attempts
+/// staggered by the configured delay.
+///
+/// The attempts are nonblocking connects, waited on together from the calling thread.
+/// Once one of them succeeds, the ones still in progress are abandoned (and their
+/// sockets closed)
+pub fn connect_sync_candidates(
+    candidates: Vec<SocketAddr>,
+    config: &SocketConfig,
+) -> io::Result<SyncSocket> {
+    let mut candidates = candidates.into_iter();
+    let mut attempts: Vec<SyncAttempt> = Vec::new();
+    let mut last_error = None;
+
+    // When the next address gets its turn, even if the previous attempts are still going
+    let mut next_start = Instant::now();
+
+    loop {
+        let now = Instant::now();
+
+        if now >= next_start || attempts.is_empty() {
+            if let Some(addr) = candidates.next() {
+                match start_connect_std_stream(addr, config) {
+                    Ok(socket) => {
+                        attempts.push(SyncAttempt {
+                            socket,
+                            deadline: config.connect_timeout.map(|timeout| now + timeout),
+                        });
+
+                        next_start = now + config.connect_attempt_delay;
+                    }
+                    // A failed attempt starts the next one right away
+                    Err(err) => {
+                        last_error = Some(err);
+                        next_start = now;
+                    }
+                }
+
+                continue;
+            }
+
+            if attempts.is_empty() {
+                break;
+            }
+        }
+
+        // Wait for an attempt to complete, for the next address' turn or for the first deadline
+        let mut wake_at = attempts.iter().filter_map(|attempt| attempt.deadline).min();
+
+        if !candidates.as_slice().is_empty() {
+            wake_at = Some(wake_at.map_or(next_start, |at| at.min(next_start)));
+        }
+
+        let completed = wait_for_attempts(
+            &attempts,
+            wake_at.map(|at| at.saturating_duration_since(now)),
+        )?;
+
+        let now = Instant::now();
+
+        // In reverse, so removing an attempt only moves the ones we already went through
+        for (index, completed) in completed.into_iter().enumerate().rev() {
+            if completed {
+                let attempt = attempts.swap_remove(index);
+
+                match attempt.socket.take_error() {
+                    Ok(None) => return sync_socket_from_connected(attempt.socket, config),
+                    Ok(Some(err)) | Err(err) => last_error = Some(err),
+                }
+
+                next_start = now;
+            } else if attempts[index]
+                .deadline
+                .is_some_and(|deadline| now >= deadline)
+            {
+                attempts.swap_remove(index);
+
+                last_error = Some(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "The connection attempt timed out",
+                ));
+                next_start = now;
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(no_candidates))
+}
+
+/// Wait until one of the attempts completes (or fails), for at most `timeout`.
+/// Returns which of them did
+fn wait_for_attempts(attempts: &[SyncAttempt], timeout: Option<Duration>) -> io::Result<Vec<bool>> {
+    let mut fds: Vec<libc::pollfd> = attempts
+        .iter()
+        .map(|attempt| libc::pollfd {
+            fd: attempt.socket.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        })
+        .collect();
+
+    // Rounded up, so we don't wake up just before the deadline
+    let timeout = timeout.map_or(-1, |timeout| {
+        timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32
+    });
+
+    // SAFETY: `fds` holds `fds.len()` valid entries, and outlives the call
+    let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+
+    if result < 0 {
+        let err = io::Error::last_os_error();
+
+        // Nothing completed, the caller waits again
+        if err.kind() == ErrorKind::Interrupted {
+            return Ok(vec![false; fds.len()]);
+        }
+
+        return Err(err);
+    }
+
+    Ok(fds.iter().map(|fd| fd.revents != 0).collect())
+}
+
+/// The async counterpart of [connect_sync_candidates]
+pub async fn connect_async_candidates(
+    candidates: Vec<SocketAddr>,
+    config: &SocketConfig,
+) -> io::Result<AsyncSocket> {
+    let mut candidates = candidates.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = candidates.next() {
+            attempts.push(connect_async(addr, config));
+        } else if attempts.is_empty() {
+            break;
+        }
+
+        let delay = async_runtime::sleep(config.connect_attempt_delay);
+        futures::pin_mut!(delay);
+
+        // Either an attempt finished, or it's time to start the next one
+        match future::select(attempts.next(), delay).await {
+            Either::Left((Some(Ok(socket)), _)) => return Ok(socket),
+            Either::Left((Some(Err(err)), _)) => last_error = Some(err),
+            Either::Left((None, _)) | Either::Right(_) => {}
+        }
+    }
+
+    Err(last_error.unwrap_or_else(no_candidates))
+}
+
+/// The addresses to try, in order. Fails if there are none
+fn candidates(
+    peer: &PeerAddr,
+    kind: EndpointKind,
+    resolved: Vec<SocketAddr>,
+    last_error: Option<io::Error>,
+) -> io::Result<Vec<SocketAddr>> {
+    if resolved.is_empty() {
+        return Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} has no {:?} endpoint", peer.hostname(), kind),
+            )
+        }));
+    }
+
+    let mut seen = HashSet::new();
+
+    let unique = resolved
+        .into_iter()
+        .filter(|addr| seen.insert(*addr))
+        .collect();
+
+    Ok(interleave_families(unique))
+}
+
+/// Alternate between the address families, starting with the family of the first
+/// address, so a broken IPv6 (or IPv4) path does not hold up the other one
+pub fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (first, second) => {
+                interleaved.extend(first);
+                interleaved.extend(second);
+            }
+        }
+    }
+
+    interleaved
+}
+
+fn no_candidates() -> io::Error {
+    io::Error::new(ErrorKind::NotFound, "No addresses to connect to")
+}
+
+async fn lookup_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    #[cfg(feature = "socket_tokio_tcp")]
+    {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+
+    #[cfg(feature = "socket_async_std_tcp")]
+    {
+        use async_std::net::ToSocketAddrs;
+
+        Ok((host, port).to_socket_addrs().await?.collect())
+    }
+
+    #[cfg(feature = "socket_io_uring_tcp")]
+    {
+        // The io_uring backend has no resolver of its own
+        Ok(std::net::ToSocketAddrs::to_socket_addrs(&(host, port))?.collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_addr::Endpoint;
+    use crate::socket::{bind_async_server, bind_refusing, bind_sync_server};
+
+    /// An address that refuses connections for as long as the returned socket is kept
+    fn closed_addr() -> (Socket, SocketAddr) {
+        let socket = bind_refusing();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+
+        (socket, addr)
+    }
+
+    #[test]
+    fn test_families_are_interleaved() {
+        let addrs: Vec<SocketAddr> = [
+            "[::1]:1",
+            "[::1]:2",
+            "[::1]:3",
+            "127.0.0.1:4",
+            "127.0.0.1:5",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+
+        let ports: Vec<_> = interleave_families(addrs)
+            .iter()
+            .map(SocketAddr::port)
+            .collect();
+
+        assert_eq!(ports, vec![1, 4, 2, 5, 3]);
+    }
+
+    #[test]
+    fn test_falls_back_to_the_next_endpoint() {
+        let config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &config).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (_replica, replica) = closed_addr();
+        let (_client, client) = closed_addr();
+
+        let peer = PeerAddr::with_endpoints(
+            replica,
+            "srv-0".to_string(),
+            vec![
+                Endpoint::socket(EndpointKind::Client, client),
+                Endpoint::dns(EndpointKind::Replica, "localhost", port),
+            ],
+        );
+
+        let socket = connect_sync_peer(&peer, EndpointKind::Replica, &config).unwrap();
+        listener.accept().unwrap();
+
+        assert_eq!(socket.peer_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn test_missing_endpoints_are_reported() {
+        let (_closed, closed) = closed_addr();
+
+        let peer = PeerAddr::new(closed, "srv-0".to_string());
+
+        let err = connect_sync_peer(&peer, EndpointKind::Admin, &SocketConfig::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let err = connect_sync_peer(&peer, EndpointKind::Replica, &SocketConfig::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn test_async_falls_back_to_the_next_endpoint() {
+        let config = SocketConfig::default();
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (_replica, replica) = closed_addr();
+        let (_client, client) = closed_addr();
+
+        let peer = PeerAddr::with_endpoints(
+            replica,
+            "srv-0".to_string(),
+            vec![
+                Endpoint::socket(EndpointKind::Client, client),
+                Endpoint::socket(EndpointKind::Client, addr),
+            ],
+        );
+
+        let (socket, accepted) = tokio::join!(
+            connect_async_peer(&peer, EndpointKind::Client, &config),
+            listener.accept()
+        );
+
+        assert_eq!(socket.unwrap().peer_addr().unwrap(), addr);
+        assert!(accepted.is_ok());
+    }
+}
@@ -0,0 +1,135 @@
+//! The known identities of the nodes in the system.
+
+use std::sync::Arc;
+
+use crate::collections::{concurrent_hash_map, ConcurrentHashMap};
+use crate::crypto::signature::PublicKey;
+use crate::node_id::{NodeId, NodeType};
+
+/// A directory of the public keys of the nodes we know of.
+///
+/// Used by the secure channels to pin the identity of the peer on the
+/// other end of a connection, instead of relying on a web PKI.
+/// Cloning the directory yields a handle to the same underlying map, so
+/// reconfigurations are seen by every connection that uses it.
+///
+/// The type of each node can also be registered, for the handshakes in which
+/// the peer claims it (see [node_auth](crate::socket::node_auth)).
+#[derive(Clone)]
+pub struct NodeKeyDirectory {
+    keys: Arc<ConcurrentHashMap<NodeId, PublicKey>>,
+    types: Arc<ConcurrentHashMap<NodeId, NodeType>>,
+}
+
+impl Default for NodeKeyDirectory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NodeKeyDirectory {
+    pub fn new() -> Self {
+        Self {
+            keys: Arc::new(concurrent_hash_map()),
+            types: Arc::new(concurrent_hash_map()),
+        }
+    }
+
+    /// Register (or replace) the public key of a node
+    pub fn insert(&self, node: NodeId, key: PublicKey) -> Option<PublicKey> {
+        self.keys.insert(node, key)
+    }
+
+    /// Register (or replace) the public key of a node, along with its type
+    pub fn insert_with_type(
+        &self,
+        node: NodeId,
+        node_type: NodeType,
+        key: PublicKey,
+    ) -> Option<PublicKey> {
+        self.types.insert(node, node_type);
+
+        self.keys.insert(node, key)
+    }
+
+    pub fn remove(&self, node: &NodeId) -> Option<PublicKey> {
+        self.types.remove(node);
+
+        self.keys.remove(node).map(|(_, key)| key)
+    }
+
+    pub fn get(&self, node: &NodeId) -> Option<PublicKey> {
+        self.keys.get(node).map(|key| key.value().clone())
+    }
+
+    /// The type registered for the given node, if any
+    pub fn node_type(&self, node: &NodeId) -> Option<NodeType> {
+        self.types.get(node).map(|node_type| *node_type.value())
+    }
+
+    pub fn contains(&self, node: &NodeId) -> bool {
+        self.keys.contains_key(node)
+    }
+
+    /// Whether the given node is known, and its key matches the given key bytes
+    pub fn matches(&self, node: &NodeId, key_bytes: &[u8]) -> bool {
+        self.keys
+            .get(node)
+            .map(|key| key.value().pk_bytes() == key_bytes)
+            .unwrap_or(false)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Fresh keys for the nodes `0..count`, along with a directory that knows all of them
+#[cfg(test)]
+pub(crate) fn test_node_keys(
+    count: u32,
+) -> (
+    Vec<(NodeId, Arc<crate::crypto::signature::KeyPair>)>,
+    NodeKeyDirectory,
+) {
+    use crate::crypto::signature::KeyPair;
+
+    let nodes: Vec<_> = (0..count)
+        .map(|id| (NodeId(id), Arc::new(KeyPair::generate_key_pair().unwrap())))
+        .collect();
+
+    let directory = nodes
+        .iter()
+        .map(|(id, key)| (*id, PublicKey::from(key.public_key())))
+        .collect();
+
+    (nodes, directory)
+}
+
+impl FromIterator<(NodeId, PublicKey)> for NodeKeyDirectory {
+    fn from_iter<T: IntoIterator<Item = (NodeId, PublicKey)>>(iter: T) -> Self {
+        let directory = Self::new();
+
+        for (node, key) in iter {
+            directory.insert(node, key);
+        }
+
+        directory
+    }
+}
+
+impl FromIterator<(NodeId, NodeType, PublicKey)> for NodeKeyDirectory {
+    fn from_iter<T: IntoIterator<Item = (NodeId, NodeType, PublicKey)>>(iter: T) -> Self {
+        let directory = Self::new();
+
+        for (node, node_type, key) in iter {
+            directory.insert_with_type(node, node_type, key);
+        }
+
+        directory
+    }
+}
@@ -0,0 +1,863 @@
+//! A socket backend built directly on Linux io_uring.
+//!
+//! Every operation is submitted to a single global ring, whose completions
+//! are reaped by a dedicated driver thread that wakes up the tasks waiting
+//! on them. The memory the kernel accesses (buffers, addresses) is owned by
+//! the operation itself, so dropping a pending future never leaves the kernel
+//! writing to freed memory: the operation is cancelled and its memory is only
+//! released once the kernel is done with it.
+//!
+//! If the driver thread stops, the pending operations fail instead of waiting
+//! for completions that will never be reaped.
+
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncWrite};
+use io_uring::types::{Fd, Timespec};
+use io_uring::{opcode, squeue, IoUring};
+use socket2::{Domain, SockAddr, SockRef, Socket as SSocket, Type};
+use tracing::error;
+
+use crate::socket::SocketConfig;
+
+/// The amount of submission queue entries of the global ring
+const RING_ENTRIES: u32 = 1024;
+
+/// The maximum amount of bytes moved by a single send or receive
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// The user data of the entries whose completions nobody waits for
+/// (cancellations and link timeouts)
+const IGNORED_USER_DATA: u64 = u64::MAX;
+
+static DRIVER: OnceLock<Driver> = OnceLock::new();
+
+static DRIVER_INIT: Mutex<()> = Mutex::new(());
+
+/// The global ring, along with the state of the operations submitted to it
+struct Driver {
+    ring: IoUring,
+    /// Serializes the access to the submission queue
+    submission: Mutex<()>,
+    ops: Mutex<HashMap<u64, OpState>>,
+    next_id: AtomicU64,
+    /// The driver thread stopped, so no more completions will be reaped.
+    /// Only changed while holding the lock of `ops`
+    stopped: AtomicBool,
+}
+
+struct OpState {
+    result: Option<i32>,
+    waker: Option<Waker>,
+    /// Memory accessed by the kernel, kept alive until the operation completes
+    resources: OpResources,
+    /// The timeout linked to the operation, also accessed by the kernel
+    _timeout: Option<Box<Timespec>>,
+    /// The owner no longer waits for the result, which is discarded
+    abandoned: bool,
+}
+
+enum OpResources {
+    None,
+    Buffer(Vec<u8>),
+    Connect(Box<SockAddr>),
+    /// The result of the operation is an accepted descriptor,
+    /// which must be closed if nobody takes it
+    Accept,
+}
+
+impl OpState {
+    /// Release an operation whose result nobody will read
+    fn discard(self) {
+        if let (OpResources::Accept, Some(fd)) = (&self.resources, self.result) {
+            if fd >= 0 {
+                // Safety: the descriptor was accepted for this operation, and is owned by nobody else
+                unsafe { libc::close(fd) };
+            }
+        }
+    }
+}
+
+/// Set up the global ring and start its driver thread.
+/// Sockets also do this on first use, so calling it is optional
+pub fn init() -> io::Result<()> {
+    driver().map(|_| ())
+}
+
+/// The ring lives for as long as the process does, as operations
+/// may still be in flight when this is called
+pub fn drop() -> io::Result<()> {
+    Ok(())
+}
+
+fn driver() -> io::Result<&'static Driver> {
+    if let Some(driver) = DRIVER.get() {
+        return Ok(driver);
+    }
+
+    let _guard = DRIVER_INIT.lock().unwrap();
+
+    if let Some(driver) = DRIVER.get() {
+        return Ok(driver);
+    }
+
+    let ring = IoUring::new(RING_ENTRIES)?;
+
+    let driver = DRIVER.get_or_init(|| Driver {
+        ring,
+        submission: Mutex::new(()),
+        ops: Mutex::new(HashMap::new()),
+        next_id: AtomicU64::new(0),
+        stopped: AtomicBool::new(false),
+    });
+
+    thread::Builder::new()
+        .name("io-uring-driver".to_string())
+        .spawn(move || driver.run())?;
+
+    Ok(driver)
+}
+
+impl Driver {
+    /// Reap the completions of the ring, waking up the tasks waiting on them
+    fn run(&self) {
+        // Also taken care of if this thread panics
+        let _stop = StopOnExit(self);
+
+        loop {
+            match self.ring.submitter().submit_and_wait(1) {
+                Ok(_) => {}
+                Err(err) if err.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {}
+                Err(err) => {
+                    error!("io_uring driver failed, stopping: {:?}", err);
+                    break;
+                }
+            }
+
+            // Safety: this thread is the only consumer of the completion queue
+            let completions: Vec<(u64, i32)> = unsafe { self.ring.completion_shared() }
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+
+            let mut ops = self.ops.lock().unwrap();
+
+            for (id, result) in completions {
+                if id == IGNORED_USER_DATA {
+                    continue;
+                }
+
+                let Some(state) = ops.get_mut(&id) else {
+                    continue;
+                };
+
+                state.result = Some(result);
+
+                if state.abandoned {
+                    ops.remove(&id).unwrap().discard();
+                } else if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    /// Mark the driver as stopped, waking up every task waiting on an operation
+    /// so it fails instead of hanging
+    fn stop(&self) {
+        let mut ops = self.ops.lock().unwrap_or_else(PoisonError::into_inner);
+
+        self.stopped.store(true, Ordering::Release);
+
+        for state in ops.values_mut() {
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Push the given entries to the submission queue, back to back, and submit them
+    fn push(&self, entries: &[squeue::Entry]) -> io::Result<()> {
+        let _guard = self.submission.lock().unwrap();
+
+        loop {
+            {
+                // Safety: the access to the submission queue is serialized by the guard
+                let mut queue = unsafe { self.ring.submission_shared() };
+
+                if queue.capacity() - queue.len() >= entries.len() {
+                    for entry in entries {
+                        // Safety: the memory referenced by the entries is owned by their operations
+                        unsafe { queue.push(entry) }.expect("Checked the available capacity");
+                    }
+
+                    break;
+                }
+            }
+
+            // Make room by handing the queued entries over to the kernel
+            self.ring.submit()?;
+        }
+
+        self.ring.submit()?;
+
+        Ok(())
+    }
+}
+
+struct StopOnExit<'a>(&'a Driver);
+
+impl Drop for StopOnExit<'_> {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}
+
+fn driver_stopped() -> io::Error {
+    io::Error::other("The io_uring driver thread stopped")
+}
+
+/// An operation submitted to the ring
+struct Op {
+    driver: &'static Driver,
+    id: Option<u64>,
+}
+
+impl Op {
+    /// Submit an operation, with the given entry built by `build` from its resources
+    fn submit<F>(resources: OpResources, timeout: Option<Duration>, build: F) -> io::Result<Op>
+    where
+        F: FnOnce(&mut OpResources) -> squeue::Entry,
+    {
+        let driver = driver()?;
+
+        let id = driver.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut resources = resources;
+
+        let entry = build(&mut resources).user_data(id);
+
+        let timeout = timeout.map(|timeout| Box::new(Timespec::from(timeout)));
+
+        let entries = match &timeout {
+            Some(timespec) => vec![
+                entry.flags(squeue::Flags::IO_LINK),
+                opcode::LinkTimeout::new(&**timespec as *const Timespec)
+                    .build()
+                    .user_data(IGNORED_USER_DATA),
+            ],
+            None => vec![entry],
+        };
+
+        {
+            let mut ops = driver.ops.lock().unwrap();
+
+            if driver.stopped.load(Ordering::Acquire) {
+                return Err(driver_stopped());
+            }
+
+            ops.insert(
+                id,
+                OpState {
+                    result: None,
+                    waker: None,
+                    resources,
+                    _timeout: timeout,
+                    abandoned: false,
+                },
+            );
+        }
+
+        if let Err(err) = driver.push(&entries) {
+            driver.ops.lock().unwrap().remove(&id);
+
+            return Err(err);
+        }
+
+        Ok(Op {
+            driver,
+            id: Some(id),
+        })
+    }
+
+    /// Poll for the completion of this operation, returning its result and resources.
+    /// Fails if the driver stopped before the operation completed
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(i32, OpResources)>> {
+        let id = self.id.expect("Polled an operation after it completed");
+
+        let mut ops = self.driver.ops.lock().unwrap();
+
+        let state = ops.get_mut(&id).expect("Pending operations are registered");
+
+        match state.result {
+            Some(result) => {
+                let state = ops.remove(&id).unwrap();
+
+                self.id = None;
+
+                Poll::Ready(Ok((result, state.resources)))
+            }
+            None if self.driver.stopped.load(Ordering::Acquire) => {
+                // The kernel may still access the resources, so they're never released
+                state.abandoned = true;
+
+                self.id = None;
+
+                Poll::Ready(Err(driver_stopped()))
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Op {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+
+        {
+            let mut ops = self.driver.ops.lock().unwrap();
+
+            match ops.get_mut(&id) {
+                Some(state) if state.result.is_some() => {
+                    ops.remove(&id).unwrap().discard();
+                    return;
+                }
+                Some(state) => state.abandoned = true,
+                None => return,
+            }
+        }
+
+        let cancel = opcode::AsyncCancel::new(id)
+            .build()
+            .user_data(IGNORED_USER_DATA);
+
+        if let Err(err) = self.driver.push(&[cancel]) {
+            error!("Failed to cancel io_uring operation: {:?}", err);
+        }
+    }
+}
+
+fn completion_result(result: i32) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::from_raw_os_error(-result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+pub struct Socket {
+    write: Box<WriteHalf>,
+    read: Box<ReadHalf>,
+    unix: bool,
+}
+
+pub struct Listener {
+    fd: OwnedFd,
+    unix: bool,
+}
+
+/// The write half of a socket. Writes are copied to a buffer owned by the
+/// send operation, so at most [MAX_TRANSFER] bytes are accepted at a time.
+///
+/// A write completes as soon as its bytes are submitted, so a failure to send
+/// them is reported by the following write, flush or close
+pub struct WriteHalf {
+    fd: Arc<OwnedFd>,
+    op: Option<Op>,
+    buffer: Vec<u8>,
+    /// The amount of bytes of `buffer` the kernel already sent
+    sent: usize,
+}
+
+/// The read half of a socket
+pub struct ReadHalf {
+    fd: Arc<OwnedFd>,
+    op: Option<Op>,
+    buffer: Vec<u8>,
+    /// The range of `buffer` which was received but not yet read
+    start: usize,
+    end: usize,
+}
+
+pub fn from_std_listener(listener: std::net::TcpListener) -> io::Result<Listener> {
+    // The ring takes care of waiting, so the descriptors stay in blocking mode
+    listener.set_nonblocking(false)?;
+
+    Ok(Listener {
+        fd: listener.into(),
+        unix: false,
+    })
+}
+
+pub fn from_std_unix_listener(listener: std::os::unix::net::UnixListener) -> io::Result<Listener> {
+    listener.set_nonblocking(false)?;
+
+    Ok(Listener {
+        fd: listener.into(),
+        unix: true,
+    })
+}
+
+pub async fn connect<A: Into<SocketAddr>>(addr: A, config: &SocketConfig) -> io::Result<Socket> {
+    let addr = addr.into();
+
+    let socket = SSocket::new(Domain::for_address(addr), Type::STREAM.cloexec(), None)?;
+
+    // The buffer sizes have to be set before connecting, so the window scale is correct
+    config.apply_buffer_sizes(&socket)?;
+
+    connect_socket(socket, SockAddr::from(addr), config.connect_timeout, false).await
+}
+
+pub async fn connect_unix(path: &Path, config: &SocketConfig) -> io::Result<Socket> {
+    let socket = SSocket::new(Domain::UNIX, Type::STREAM.cloexec(), None)?;
+
+    connect_socket(socket, SockAddr::unix(path)?, config.connect_timeout, true).await
+}
+
+async fn connect_socket(
+    socket: SSocket,
+    addr: SockAddr,
+    timeout: Option<Duration>,
+    unix: bool,
+) -> io::Result<Socket> {
+    let fd = socket.as_raw_fd();
+
+    let mut op =
+        Op::submit(
+            OpResources::Connect(Box::new(addr)),
+            timeout,
+            |resources| match resources {
+                OpResources::Connect(addr) => {
+                    opcode::Connect::new(Fd(fd), addr.as_ptr(), addr.len()).build()
+                }
+                _ => unreachable!(),
+            },
+        )?;
+
+    let (result, _) = poll_fn(|cx| op.poll(cx)).await?;
+
+    match completion_result(result) {
+        Ok(_) => Ok(Socket::new(socket.into(), unix)),
+        Err(err) if err.raw_os_error() == Some(libc::ECANCELED) && timeout.is_some() => Err(
+            io::Error::new(io::ErrorKind::TimedOut, "Connection timed out"),
+        ),
+        Err(err) => Err(err),
+    }
+}
+
+impl Listener {
+    pub async fn accept(&self) -> io::Result<Socket> {
+        let fd = self.fd.as_raw_fd();
+
+        // If this future is dropped, a connection accepted meanwhile is closed by the driver
+        let mut op = Op::submit(OpResources::Accept, None, |_| {
+            opcode::Accept::new(Fd(fd), std::ptr::null_mut(), std::ptr::null_mut())
+                .flags(libc::SOCK_CLOEXEC)
+                .build()
+        })?;
+
+        let (result, _) = poll_fn(|cx| op.poll(cx)).await?;
+
+        let accepted = completion_result(result)? as RawFd;
+
+        // Safety: the descriptor was just created by the kernel, and is owned by nobody else
+        let fd = unsafe { OwnedFd::from_raw_fd(accepted) };
+
+        Ok(Socket::new(fd, self.unix))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        SockRef::from(&self.fd)
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Unix domain listeners have no socket address",
+                )
+            })
+    }
+}
+
+impl Socket {
+    fn new(fd: OwnedFd, unix: bool) -> Self {
+        let fd = Arc::new(fd);
+
+        Self {
+            write: Box::new(WriteHalf {
+                fd: fd.clone(),
+                op: None,
+                buffer: Vec::new(),
+                sent: 0,
+            }),
+            read: Box::new(ReadHalf {
+                fd,
+                op: None,
+                buffer: Vec::new(),
+                start: 0,
+                end: 0,
+            }),
+            unix,
+        }
+    }
+
+    pub fn is_unix(&self) -> bool {
+        self.unix
+    }
+}
+
+pub(super) fn split_socket(sock: Socket) -> (WriteHalf, ReadHalf) {
+    (*sock.write, *sock.read)
+}
+
+impl AsyncRead for ReadHalf {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        if this.start < this.end {
+            let n = (this.end - this.start).min(buf.len());
+
+            buf[..n].copy_from_slice(&this.buffer[this.start..this.start + n]);
+            this.start += n;
+
+            return Poll::Ready(Ok(n));
+        }
+
+        if this.op.is_none() {
+            let mut buffer = std::mem::take(&mut this.buffer);
+            buffer.resize(buf.len().min(MAX_TRANSFER), 0);
+
+            let fd = this.fd.as_raw_fd();
+
+            this.op = Some(Op::submit(
+                OpResources::Buffer(buffer),
+                None,
+                |resources| match resources {
+                    OpResources::Buffer(buffer) => {
+                        opcode::Recv::new(Fd(fd), buffer.as_mut_ptr(), buffer.len() as u32).build()
+                    }
+                    _ => unreachable!(),
+                },
+            )?);
+        }
+
+        let completed = match this.op.as_mut().unwrap().poll(cx) {
+            Poll::Ready(completed) => completed,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        this.op = None;
+
+        let (result, resources) = completed?;
+
+        if let OpResources::Buffer(buffer) = resources {
+            this.buffer = buffer;
+        }
+
+        let received = completion_result(result)?;
+
+        let n = received.min(buf.len());
+
+        buf[..n].copy_from_slice(&this.buffer[..n]);
+
+        this.start = n;
+        this.end = received;
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl WriteHalf {
+    /// Submit a send of the bytes of `buffer` which were not sent yet
+    fn submit_send(&mut self) -> io::Result<()> {
+        let fd = self.fd.as_raw_fd();
+        let sent = self.sent;
+
+        self.op = Some(Op::submit(
+            OpResources::Buffer(std::mem::take(&mut self.buffer)),
+            None,
+            |resources| match resources {
+                OpResources::Buffer(buffer) => {
+                    let remaining = &buffer[sent..];
+
+                    opcode::Send::new(Fd(fd), remaining.as_ptr(), remaining.len() as u32)
+                        .flags(libc::MSG_NOSIGNAL)
+                        .build()
+                }
+                _ => unreachable!(),
+            },
+        )?);
+
+        Ok(())
+    }
+
+    /// Wait until the bytes of the previous write were all sent
+    fn poll_sent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let Some(op) = self.op.as_mut() else {
+                return Poll::Ready(Ok(()));
+            };
+
+            let completed = match op.poll(cx) {
+                Poll::Ready(completed) => completed,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            self.op = None;
+
+            let (result, resources) = completed?;
+
+            if let OpResources::Buffer(buffer) = resources {
+                self.buffer = buffer;
+            }
+
+            let sent = completion_result(result)?;
+
+            if sent == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.sent += sent;
+
+            // A short send, keep going with the rest of the buffer
+            if self.sent < self.buffer.len() {
+                self.submit_send()?;
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WriteHalf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        match this.poll_sent(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // The bytes are owned by the operation, so the caller's buffer
+        // is free to change as soon as this returns
+        let n = buf.len().min(MAX_TRANSFER);
+
+        this.buffer.clear();
+        this.buffer.extend_from_slice(&buf[..n]);
+        this.sent = 0;
+
+        this.submit_send()?;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_sent(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_sent(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        match SockRef::from(&*self.fd).shutdown(Shutdown::Write) {
+            Err(err) if err.kind() != io::ErrorKind::NotConnected => Poll::Ready(Err(err)),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_close(cx)
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.read.fd.as_raw_fd()
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::node_id::NodeId;
+    use crate::socket::mtls::test_configs;
+    use crate::socket::{bind_async_server, connect_async, SecureSocketAsync, SocketConfig};
+
+    #[tokio::test]
+    async fn test_dropped_read_does_not_lose_data() {
+        let config = SocketConfig::default();
+
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+
+        let (mut write, _read) = SecureSocketAsync::new_plain(client.unwrap()).split();
+        let (_write, mut read) = SecureSocketAsync::new_plain(server.unwrap()).split();
+
+        let mut buf = [0; 5];
+
+        {
+            // Nothing has been sent yet, so the receive is cancelled when dropped
+            let pending = read.read_exact(&mut buf);
+            futures::pin_mut!(pending);
+            assert!(futures::poll!(pending).is_pending());
+        }
+
+        write.write_all(b"hello").await.unwrap();
+        write.flush().await.unwrap();
+
+        read.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_writes_own_their_bytes() {
+        let config = SocketConfig::default();
+
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+
+        let (mut write, _read) = SecureSocketAsync::new_plain(client.unwrap()).split();
+        let (_write, mut read) = SecureSocketAsync::new_plain(server.unwrap()).split();
+
+        // Every write is accepted as soon as it's submitted, even with a send still in flight
+        let mut buf = *b"hello";
+        assert_eq!(write.write(&buf).await.unwrap(), 5);
+
+        buf.copy_from_slice(b"world");
+        assert_eq!(write.write(&buf).await.unwrap(), 5);
+
+        write.flush().await.unwrap();
+
+        let mut received = [0; 10];
+        read.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"helloworld");
+    }
+
+    #[tokio::test]
+    async fn test_tls_split() {
+        let tls = test_configs(2);
+
+        let config = SocketConfig::default();
+
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+
+        let (client, server) = tokio::join!(
+            tls[1].connect_async(NodeId(0), client.unwrap()),
+            tls[0].accept_async(server.unwrap())
+        );
+
+        let (client_peer, client) = client.unwrap();
+        let (server_peer, server) = server.unwrap();
+
+        assert_eq!(client_peer, NodeId(0));
+        assert_eq!(server_peer, NodeId(1));
+
+        let (mut client_write, mut client_read) = client.split();
+        let (mut server_write, mut server_read) = server.split();
+
+        let payload = vec![3u8; 256 * 1024];
+
+        let client_side = async {
+            client_write.write_all(&payload).await.unwrap();
+            client_write.flush().await.unwrap();
+
+            let mut reply = [0; 4];
+            client_read.read_exact(&mut reply).await.unwrap();
+            reply
+        };
+
+        let server_side = async {
+            let mut received = vec![0; payload.len()];
+            server_read.read_exact(&mut received).await.unwrap();
+
+            server_write.write_all(b"done").await.unwrap();
+            server_write.flush().await.unwrap();
+            received
+        };
+
+        let (reply, received) = tokio::join!(client_side, server_side);
+
+        assert_eq!(&reply, b"done");
+        assert_eq!(received, payload);
+    }
+}
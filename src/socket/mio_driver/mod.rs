@@ -0,0 +1,1032 @@
+//! A ready made mio event loop for [MioSocket]s and [MioListener]s.
+//!
+//! The driver runs on its own thread. It accepts incoming connections, reads the
+//! length-prefixed frames of the [framing](crate::socket::framing) module from each
+//! connection and delivers them through a `channel::sync` channel, while writing
+//! the queued outbound frames whenever the sockets become writable.
+//!
+//! Connections can optionally be secured with mutual TLS, in which case the
+//! TLS sessions are driven without ever blocking the event loop.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use mio::event::Event;
+use mio::{Events, Interest, Poll, Token, Waker};
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, error, warn};
+
+use crate::channel::sync::{new_bounded_sync, new_unbounded_sync, ChannelSyncRx, ChannelSyncTx};
+use crate::channel::TrySendReturnError;
+use crate::node_id::NodeId;
+use crate::socket::framing::{FrameError, FrameHeader, FramingConfig};
+use crate::socket::mtls::{authenticated_peer, check_expected_peer, MutualTlsConfig};
+use crate::socket::{MioListener, MioSocket};
+
+/// The token of the listener, if the driver has one
+const LISTENER_TOKEN: Token = Token(0);
+
+/// The token used to wake the driver when there are new commands
+const WAKER_TOKEN: Token = Token(1);
+
+/// The first token handed out to connections
+const FIRST_CONNECTION_TOKEN: usize = 2;
+
+/// How often the driver retries delivering its events while the event channel is full
+const BACKLOG_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Error, Debug)]
+pub enum MioDriverError {
+    #[error("Failed to frame the message {0:?}")]
+    Frame(#[from] FrameError),
+    #[error("IO error in the mio driver {0:?}")]
+    Io(#[from] io::Error),
+    #[error("The mio driver has been stopped")]
+    Stopped,
+}
+
+/// Configuration of a [MioDriver]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct MioDriverConfig {
+    /// The framing of the messages exchanged over the connections
+    pub framing: FramingConfig,
+    /// The maximum amount of mio events handled per poll
+    pub events_capacity: usize,
+    /// The size of the buffer used to read from the (plain) sockets
+    pub read_chunk_size: usize,
+    /// The capacity of the channel through which events are delivered.
+    /// The driver never blocks on this channel: while it is full, the events are kept
+    /// by the driver and it stops reading from the sockets until they are delivered
+    pub event_channel_capacity: usize,
+}
+
+impl Default for MioDriverConfig {
+    fn default() -> Self {
+        Self {
+            framing: FramingConfig::default(),
+            events_capacity: 1024,
+            read_chunk_size: 64 * 1024,
+            event_channel_capacity: 1024,
+        }
+    }
+}
+
+/// The events delivered by a [MioDriver]
+#[derive(Debug)]
+pub enum DriverEvent {
+    /// A connection is ready to exchange messages. With TLS, this is only
+    /// delivered once the handshake is complete, and `peer` is the
+    /// authenticated identity of the other node
+    Connected { token: Token, peer: Option<NodeId> },
+    /// A whole message was received from a connection
+    Message { token: Token, payload: Vec<u8> },
+    /// The connection was closed, either by us, by the peer or because of an error
+    Disconnected { token: Token, error: Option<String> },
+}
+
+/// The requests made to the driver thread
+enum DriverCommand {
+    Register {
+        token: Token,
+        socket: MioSocket,
+        peer: Option<NodeId>,
+    },
+    Send {
+        token: Token,
+        header: [u8; FrameHeader::LENGTH],
+        payload: Vec<u8>,
+    },
+    Close(Token),
+    Shutdown,
+}
+
+/// A mio event loop that handles a set of framed connections.
+///
+/// Messages are sent through a [MioDriverHandle], and every received message
+/// (and every change in the state of the connections) is delivered through the
+/// event channel returned by [MioDriver::start]. Dropping the driver stops it,
+/// closing every connection.
+pub struct MioDriver {
+    handle: MioDriverHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Used to register connections with a [MioDriver] and send messages through them.
+#[derive(Clone)]
+pub struct MioDriverHandle {
+    shared: Arc<HandleShared>,
+}
+
+struct HandleShared {
+    commands: ChannelSyncTx<DriverCommand>,
+    waker: Waker,
+    next_token: AtomicUsize,
+    framing: FramingConfig,
+}
+
+impl MioDriver {
+    /// Start a driver, which accepts connections from the given listener (if any).
+    ///
+    /// When `tls` is given, every connection (accepted or registered) is
+    /// secured with mutual TLS.
+    pub fn start(
+        listener: Option<MioListener>,
+        tls: Option<Arc<MutualTlsConfig>>,
+        config: MioDriverConfig,
+    ) -> io::Result<(Self, ChannelSyncRx<DriverEvent>)> {
+        let poll = Poll::new()?;
+
+        let waker = Waker::new(poll.registry(), WAKER_TOKEN)?;
+
+        let (commands_tx, commands_rx) = new_unbounded_sync(Some("MioDriverCommands"));
+        let (events_tx, events_rx) =
+            new_bounded_sync(config.event_channel_capacity, Some("MioDriverEvents"));
+
+        let shared = Arc::new(HandleShared {
+            commands: commands_tx,
+            waker,
+            next_token: AtomicUsize::new(FIRST_CONNECTION_TOKEN),
+            framing: config.framing,
+        });
+
+        let mut event_loop = EventLoop {
+            poll,
+            listener,
+            tls,
+            config,
+            shared: shared.clone(),
+            commands: commands_rx,
+            events: EventSink {
+                tx: events_tx,
+                backlog: VecDeque::new(),
+            },
+            connections: HashMap::new(),
+            paused: HashSet::new(),
+            read_chunk: vec![0; config.read_chunk_size].into_boxed_slice(),
+        };
+
+        if let Some(listener) = &mut event_loop.listener {
+            event_loop
+                .poll
+                .registry()
+                .register(listener, LISTENER_TOKEN, Interest::READABLE)?;
+        }
+
+        let thread = thread::Builder::new()
+            .name("mio-driver".to_string())
+            .spawn(move || event_loop.run())?;
+
+        let driver = Self {
+            handle: MioDriverHandle { shared },
+            thread: Some(thread),
+        };
+
+        Ok((driver, events_rx))
+    }
+
+    pub fn handle(&self) -> MioDriverHandle {
+        self.handle.clone()
+    }
+}
+
+impl Drop for MioDriver {
+    fn drop(&mut self) {
+        // The driver might have already stopped on its own
+        let _ = self.handle.command(DriverCommand::Shutdown);
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The mio driver thread panicked");
+            }
+        }
+    }
+}
+
+impl MioDriverHandle {
+    /// Hand an (already connected) socket over to the driver, returning the
+    /// token the connection is known by.
+    ///
+    /// With TLS, we act as the client of the session and `peer` must be the node
+    /// we connected to. Otherwise, `peer` is just reported back in [DriverEvent::Connected]
+    pub fn register(
+        &self,
+        socket: MioSocket,
+        peer: Option<NodeId>,
+    ) -> Result<Token, MioDriverError> {
+        let token = self.shared.allocate_token();
+
+        self.command(DriverCommand::Register {
+            token,
+            socket,
+            peer,
+        })?;
+
+        Ok(token)
+    }
+
+    /// Queue a message to be sent through the given connection.
+    ///
+    /// Messages to an unknown (or already closed) connection are dropped
+    pub fn send(&self, token: Token, payload: Vec<u8>) -> Result<(), MioDriverError> {
+        let header = FrameHeader::for_payload(&payload, &self.shared.framing)?;
+
+        self.command(DriverCommand::Send {
+            token,
+            header: header.serialize(),
+            payload,
+        })
+    }
+
+    /// Close the given connection, after attempting to send the messages still queued
+    pub fn close(&self, token: Token) -> Result<(), MioDriverError> {
+        self.command(DriverCommand::Close(token))
+    }
+
+    fn command(&self, command: DriverCommand) -> Result<(), MioDriverError> {
+        self.shared
+            .commands
+            .send(command)
+            .map_err(|_| MioDriverError::Stopped)?;
+
+        self.shared.waker.wake()?;
+
+        Ok(())
+    }
+}
+
+impl HandleShared {
+    fn allocate_token(&self) -> Token {
+        Token(self.next_token.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The state of a connection handled by the driver
+struct DriverConnection {
+    socket: MioSocket,
+    tls: Option<Box<rustls::Connection>>,
+    /// The node we expect to be at the other end, if we know it
+    peer: Option<NodeId>,
+    /// Whether the connection was already announced with [DriverEvent::Connected]
+    established: bool,
+    /// The plaintext received which is yet to be delivered as frames
+    read_buf: Vec<u8>,
+    read_start: usize,
+    /// The plaintext queued to be sent, along with how much of the
+    /// first buffer has already been sent
+    outbound: VecDeque<Vec<u8>>,
+    sent: usize,
+    interest: Interest,
+}
+
+enum ReadOutcome {
+    /// Everything available was read
+    Open,
+    /// A whole frame (or more) is buffered, so reading stopped before the socket ran out
+    Full,
+    Closed,
+}
+
+impl DriverConnection {
+    fn new(socket: MioSocket, tls: Option<rustls::Connection>, peer: Option<NodeId>) -> Self {
+        Self {
+            socket,
+            tls: tls.map(Box::new),
+            peer,
+            established: false,
+            read_buf: Vec::new(),
+            read_start: 0,
+            outbound: VecDeque::new(),
+            sent: 0,
+            interest: Interest::READABLE,
+        }
+    }
+
+    /// Check whether the connection became ready to exchange messages,
+    /// returning the peer to announce it with
+    fn check_established(&mut self) -> anyhow::Result<Option<Option<NodeId>>> {
+        if self.established {
+            return Ok(None);
+        }
+
+        let peer = match &self.tls {
+            None => self.peer,
+            Some(tls) if tls.is_handshaking() => return Ok(None),
+            Some(tls) => {
+                let found = authenticated_peer(tls)?;
+
+                if let Some(expected) = self.peer {
+                    check_expected_peer(expected, found)?;
+                }
+
+                Some(found)
+            }
+        };
+
+        self.peer = peer;
+        self.established = true;
+
+        Ok(Some(peer))
+    }
+
+    /// Read what is available from the socket, stopping once `limit` bytes of
+    /// plaintext are buffered so a fast peer can't make us buffer without bound
+    fn read_available(&mut self, read_chunk: &mut [u8], limit: usize) -> io::Result<ReadOutcome> {
+        loop {
+            if self.read_buf.len() - self.read_start >= limit {
+                return Ok(ReadOutcome::Full);
+            }
+
+            let result = match &mut self.tls {
+                None => self.socket.read(read_chunk).map(|read| {
+                    self.read_buf.extend_from_slice(&read_chunk[..read]);
+
+                    read
+                }),
+                Some(tls) => tls.read_tls(&mut self.socket),
+            };
+
+            match result {
+                Ok(0) => return Ok(ReadOutcome::Closed),
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(ReadOutcome::Open),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+
+            if let Some(tls) = &mut self.tls {
+                let state = tls
+                    .process_new_packets()
+                    .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+
+                let available = state.plaintext_bytes_to_read();
+
+                if available > 0 {
+                    let start = self.read_buf.len();
+
+                    self.read_buf.resize(start + available, 0);
+
+                    tls.reader().read_exact(&mut self.read_buf[start..])?;
+                }
+
+                if state.peer_has_closed() {
+                    return Ok(ReadOutcome::Closed);
+                }
+            }
+        }
+    }
+
+    /// Take the next whole frame out of the received plaintext, if there is one
+    fn next_frame(&mut self, config: &FramingConfig) -> Result<Option<Vec<u8>>, FrameError> {
+        let available = &self.read_buf[self.read_start..];
+
+        let Some(header) = available.first_chunk::<{ FrameHeader::LENGTH }>() else {
+            return Ok(None);
+        };
+
+        // Validating the header before waiting for the payload, along with the read limit,
+        // means we never buffer much more than the maximum frame size
+        let header = FrameHeader::deserialize(header, config)?;
+
+        let frame_len = FrameHeader::LENGTH + header.payload_len();
+
+        if available.len() < frame_len {
+            return Ok(None);
+        }
+
+        let payload = available[FrameHeader::LENGTH..frame_len].to_vec();
+
+        header.verify_payload(&payload)?;
+
+        self.read_start += frame_len;
+
+        Ok(Some(payload))
+    }
+
+    /// Discard the plaintext of the frames that were already delivered
+    fn compact_read_buf(&mut self) {
+        self.read_buf.drain(..self.read_start);
+        self.read_start = 0;
+    }
+
+    /// Write as much of the outbound queue as the socket currently takes
+    fn write_available(&mut self) -> io::Result<()> {
+        loop {
+            if let Some(tls) = &mut self.tls {
+                while tls.wants_write() {
+                    match tls.write_tls(&mut self.socket) {
+                        Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                        Ok(_) => {}
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                        Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+
+            let Some(front) = self.outbound.front() else {
+                return Ok(());
+            };
+
+            let pending = &front[self.sent..];
+
+            let written = match &mut self.tls {
+                // The connection accepts no more plaintext while it has too much
+                // queued (during the handshake), we are woken up once it makes progress
+                Some(tls) => match tls.writer().write(pending)? {
+                    0 => return Ok(()),
+                    written => written,
+                },
+                None => match self.socket.write(pending) {
+                    Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                    Ok(written) => written,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                },
+            };
+
+            self.sent += written;
+
+            if self.sent == front.len() {
+                self.outbound.pop_front();
+                self.sent = 0;
+            }
+        }
+    }
+
+    fn wants_write(&self) -> bool {
+        !self.outbound.is_empty() || self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+
+    /// The interest the socket should be registered with, given its current state
+    fn desired_interest(&self) -> Interest {
+        if self.wants_write() {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        }
+    }
+}
+
+/// The state owned by the driver thread
+struct EventLoop {
+    poll: Poll,
+    listener: Option<MioListener>,
+    tls: Option<Arc<MutualTlsConfig>>,
+    config: MioDriverConfig,
+    shared: Arc<HandleShared>,
+    commands: ChannelSyncRx<DriverCommand>,
+    events: EventSink,
+    connections: HashMap<Token, DriverConnection>,
+    /// The connections which might have data to read, but weren't read
+    /// because the event channel is full
+    paused: HashSet<Token>,
+    read_chunk: Box<[u8]>,
+}
+
+/// Delivers the events of the driver without ever blocking it.
+///
+/// The events that don't fit in the channel are kept in order in the backlog.
+/// Reading stops while there is a backlog, so it only ever grows with the
+/// events that are not caused by reads (connections and disconnections)
+struct EventSink {
+    tx: ChannelSyncTx<DriverEvent>,
+    backlog: VecDeque<DriverEvent>,
+}
+
+impl EventSink {
+    fn emit(&mut self, event: DriverEvent) {
+        if !self.backlog.is_empty() {
+            self.backlog.push_back(event);
+
+            return;
+        }
+
+        match self.tx.try_send_return(event) {
+            Ok(()) => {}
+            Err(TrySendReturnError::Full(event) | TrySendReturnError::Timeout(event)) => {
+                self.backlog.push_back(event)
+            }
+            Err(TrySendReturnError::Disconnected(_)) => {
+                warn!("Mio driver event receiver was dropped, discarding event")
+            }
+        }
+    }
+
+    /// Try to deliver the backlog, returning whether it was emptied
+    fn flush(&mut self) -> bool {
+        while let Some(event) = self.backlog.pop_front() {
+            match self.tx.try_send_return(event) {
+                Ok(()) => {}
+                Err(TrySendReturnError::Full(event) | TrySendReturnError::Timeout(event)) => {
+                    self.backlog.push_front(event);
+
+                    return false;
+                }
+                Err(TrySendReturnError::Disconnected(_)) => {
+                    warn!("Mio driver event receiver was dropped, discarding events");
+
+                    self.backlog.clear();
+                }
+            }
+        }
+
+        true
+    }
+
+    fn is_blocked(&self) -> bool {
+        !self.backlog.is_empty()
+    }
+}
+
+impl EventLoop {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(self.config.events_capacity);
+
+        loop {
+            // The receiver draining the channel doesn't wake us up, so check back periodically
+            let timeout = self.events.is_blocked().then_some(BACKLOG_RETRY_INTERVAL);
+
+            if let Err(err) = self.poll.poll(&mut events, timeout) {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+
+                error!("Failed to poll the mio driver, stopping it {:?}", err);
+
+                break;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER_TOKEN => self.accept_connections(),
+                    WAKER_TOKEN => {}
+                    token => self.handle_connection_event(token, event),
+                }
+            }
+
+            if !self.handle_commands() {
+                break;
+            }
+
+            self.resume_reading();
+        }
+
+        debug!(
+            "Mio driver stopped, dropping {} connections",
+            self.connections.len()
+        );
+    }
+
+    /// Read the connections that were paused, once the backlog of events is delivered
+    fn resume_reading(&mut self) {
+        if !self.events.flush() {
+            return;
+        }
+
+        while let Some(&token) = self.paused.iter().next() {
+            if self.events.is_blocked() {
+                break;
+            }
+
+            self.paused.remove(&token);
+
+            self.read_connection(token);
+        }
+    }
+
+    fn accept_connections(&mut self) {
+        let Some(listener) = &self.listener else {
+            return;
+        };
+
+        loop {
+            let socket = match listener.accept() {
+                Ok(socket) => socket,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    error!("Failed to accept connection {:?}", err);
+
+                    break;
+                }
+            };
+
+            let tls = match &self.tls {
+                None => None,
+                Some(tls) => match tls.server_connection() {
+                    Ok(connection) => Some(connection.into()),
+                    Err(err) => {
+                        error!(
+                            "Failed to create TLS connection for accepted socket {:?}",
+                            err
+                        );
+
+                        continue;
+                    }
+                },
+            };
+
+            let token = self.shared.allocate_token();
+
+            self.add_connection(token, DriverConnection::new(socket, tls, None));
+        }
+    }
+
+    /// Process the commands sent through the handles.
+    /// Returns whether the driver should keep running
+    fn handle_commands(&mut self) -> bool {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                DriverCommand::Register {
+                    token,
+                    socket,
+                    peer,
+                } => {
+                    let tls = match (&self.tls, peer) {
+                        (None, _) => None,
+                        (Some(tls), Some(peer)) => match tls.client_connection(peer) {
+                            Ok(connection) => Some(connection.into()),
+                            Err(err) => {
+                                self.emit(DriverEvent::Disconnected {
+                                    token,
+                                    error: Some(format!("{err:?}")),
+                                });
+
+                                continue;
+                            }
+                        },
+                        (Some(_), None) => {
+                            self.emit(DriverEvent::Disconnected {
+                                token,
+                                error: Some("TLS connections require the peer's identity".into()),
+                            });
+
+                            continue;
+                        }
+                    };
+
+                    self.add_connection(token, DriverConnection::new(socket, tls, peer));
+                }
+                DriverCommand::Send {
+                    token,
+                    header,
+                    payload,
+                } => {
+                    let Some(connection) = self.connections.get_mut(&token) else {
+                        debug!("Dropping message to unknown connection {:?}", token);
+
+                        continue;
+                    };
+
+                    connection.outbound.push_back(header.to_vec());
+                    connection.outbound.push_back(payload);
+
+                    self.write_connection(token);
+                }
+                DriverCommand::Close(token) => {
+                    if let Some(connection) = self.connections.get_mut(&token) {
+                        if let Some(tls) = &mut connection.tls {
+                            tls.send_close_notify();
+                        }
+
+                        // Best effort, whatever the socket does not take right away is lost
+                        if let Err(err) = connection.write_available() {
+                            debug!("Failed to flush connection {:?} on close {:?}", token, err);
+                        }
+
+                        self.remove_connection(token, None);
+                    }
+                }
+                DriverCommand::Shutdown => return false,
+            }
+        }
+
+        true
+    }
+
+    fn add_connection(&mut self, token: Token, mut connection: DriverConnection) {
+        if let Err(err) =
+            self.poll
+                .registry()
+                .register(&mut connection.socket, token, connection.interest)
+        {
+            self.emit(DriverEvent::Disconnected {
+                token,
+                error: Some(format!("{err:?}")),
+            });
+
+            return;
+        }
+
+        self.connections.insert(token, connection);
+
+        // The client side of a TLS session has to speak first
+        self.write_connection(token);
+        self.check_established(token);
+    }
+
+    fn handle_connection_event(&mut self, token: Token, event: &Event) {
+        if event.is_readable() || event.is_read_closed() || event.is_error() {
+            self.read_connection(token);
+        }
+
+        if event.is_writable() {
+            self.write_connection(token);
+        }
+    }
+
+    fn read_connection(&mut self, token: Token) {
+        let limit = FrameHeader::LENGTH + self.config.framing.max_frame_size;
+
+        loop {
+            // The socket is edge triggered, so remember to come back to it
+            if self.events.is_blocked() {
+                self.paused.insert(token);
+
+                return;
+            }
+
+            let Some(connection) = self.connections.get_mut(&token) else {
+                return;
+            };
+
+            let outcome = match connection.read_available(&mut self.read_chunk, limit) {
+                Ok(outcome) => outcome,
+                Err(err) => {
+                    self.remove_connection(token, Some(format!("{err:?}")));
+
+                    return;
+                }
+            };
+
+            // Reading might have completed the handshake, or left records to send
+            self.write_connection(token);
+            self.check_established(token);
+
+            let Some(connection) = self.connections.get_mut(&token) else {
+                return;
+            };
+
+            loop {
+                match connection.next_frame(&self.config.framing) {
+                    Ok(Some(payload)) => self.events.emit(DriverEvent::Message { token, payload }),
+                    Ok(None) => break,
+                    Err(err) => {
+                        self.remove_connection(token, Some(format!("{err:?}")));
+
+                        return;
+                    }
+                }
+            }
+
+            connection.compact_read_buf();
+
+            match outcome {
+                ReadOutcome::Open => return,
+                ReadOutcome::Full => {}
+                ReadOutcome::Closed => {
+                    self.remove_connection(token, None);
+
+                    return;
+                }
+            }
+        }
+    }
+
+    fn write_connection(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        if let Err(err) = connection.write_available() {
+            self.remove_connection(token, Some(format!("{err:?}")));
+
+            return;
+        }
+
+        let interest = connection.desired_interest();
+
+        if interest != connection.interest {
+            if let Err(err) =
+                self.poll
+                    .registry()
+                    .reregister(&mut connection.socket, token, interest)
+            {
+                self.remove_connection(token, Some(format!("{err:?}")));
+
+                return;
+            }
+
+            connection.interest = interest;
+        }
+    }
+
+    fn check_established(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        match connection.check_established() {
+            Ok(Some(peer)) => self.emit(DriverEvent::Connected { token, peer }),
+            Ok(None) => {}
+            Err(err) => self.remove_connection(token, Some(format!("{err:?}"))),
+        }
+    }
+
+    fn remove_connection(&mut self, token: Token, error: Option<String>) {
+        let Some(mut connection) = self.connections.remove(&token) else {
+            return;
+        };
+
+        self.paused.remove(&token);
+
+        if let Some(error) = &error {
+            warn!("Closing connection {:?} due to {}", token, error);
+        }
+
+        if let Err(err) = self.poll.registry().deregister(&mut connection.socket) {
+            debug!("Failed to deregister connection {:?} {:?}", token, err);
+        }
+
+        self.emit(DriverEvent::Disconnected { token, error });
+    }
+
+    fn emit(&mut self, event: DriverEvent) {
+        self.events.emit(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::channel::sync::ChannelSyncRx;
+    use crate::node_id::NodeId;
+    use crate::socket::mtls::{test_configs, MutualTlsConfig};
+    use crate::socket::{bind_sync_server, connect_sync, SocketConfig};
+
+    use super::*;
+
+    fn next_event(events: &ChannelSyncRx<DriverEvent>) -> DriverEvent {
+        events
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Timed out waiting for a driver event")
+    }
+
+    fn tls_configs() -> Vec<Arc<MutualTlsConfig>> {
+        test_configs(2).into_iter().map(Arc::new).collect()
+    }
+
+    /// Exchange messages between a driver with a listener and a driver
+    /// with a registered outgoing connection
+    fn exchange_messages(tls: Option<Vec<Arc<MutualTlsConfig>>>) {
+        let secured = tls.is_some();
+
+        let (server_tls, client_tls) = match tls {
+            Some(tls) => (Some(tls[0].clone()), Some(tls[1].clone())),
+            None => (None, None),
+        };
+
+        let socket_config = SocketConfig::default();
+        let config = MioDriverConfig {
+            read_chunk_size: 1000,
+            ..Default::default()
+        };
+
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &socket_config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (server, server_events) =
+            MioDriver::start(Some(listener.into()), server_tls, config).unwrap();
+        let (client, client_events) = MioDriver::start(None, client_tls, config).unwrap();
+
+        let socket = connect_sync(addr, &socket_config).unwrap();
+        let client_token = client
+            .handle()
+            .register(socket.into(), Some(NodeId(0)))
+            .unwrap();
+
+        // Queued before the connection is established, so it is sent after the handshake
+        let large = (0..1_000_000).map(|i| i as u8).collect::<Vec<_>>();
+        client
+            .handle()
+            .send(client_token, b"hello".to_vec())
+            .unwrap();
+        client.handle().send(client_token, large.clone()).unwrap();
+
+        let DriverEvent::Connected { token, peer } = next_event(&client_events) else {
+            panic!("Expected the client connection to be established")
+        };
+        assert_eq!(token, client_token);
+        assert_eq!(peer, Some(NodeId(0)));
+
+        let DriverEvent::Connected {
+            token: server_token,
+            peer,
+        } = next_event(&server_events)
+        else {
+            panic!("Expected the server connection to be established")
+        };
+        assert_eq!(peer, secured.then_some(NodeId(1)));
+
+        for expected in [b"hello".to_vec(), large] {
+            match next_event(&server_events) {
+                DriverEvent::Message { token, payload } => {
+                    assert_eq!(token, server_token);
+                    assert!(payload == expected);
+                }
+                event => panic!("Expected a message, got {event:?}"),
+            }
+        }
+
+        server
+            .handle()
+            .send(server_token, b"reply".to_vec())
+            .unwrap();
+
+        match next_event(&client_events) {
+            DriverEvent::Message { token, payload } => {
+                assert_eq!(token, client_token);
+                assert_eq!(payload, b"reply");
+            }
+            event => panic!("Expected a message, got {event:?}"),
+        }
+
+        client.handle().close(client_token).unwrap();
+
+        assert!(matches!(
+            next_event(&client_events),
+            DriverEvent::Disconnected { error: None, .. }
+        ));
+        assert!(matches!(
+            next_event(&server_events),
+            DriverEvent::Disconnected { token, .. } if token == server_token
+        ));
+    }
+
+    #[test]
+    fn test_full_event_channel_does_not_block_the_driver() {
+        let socket_config = SocketConfig::default();
+        let config = MioDriverConfig {
+            event_channel_capacity: 2,
+            ..Default::default()
+        };
+
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &socket_config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (server, server_events) =
+            MioDriver::start(Some(listener.into()), None, config).unwrap();
+        let (client, _client_events) = MioDriver::start(None, None, config).unwrap();
+
+        let socket = connect_sync(addr, &socket_config).unwrap();
+        let token = client.handle().register(socket.into(), None).unwrap();
+
+        for i in 0..100u8 {
+            client.handle().send(token, vec![i; 1000]).unwrap();
+        }
+
+        let DriverEvent::Connected { .. } = next_event(&server_events) else {
+            panic!("Expected the server connection to be established")
+        };
+
+        // Every message arrives, in order, even though most had to wait for room in the channel
+        for i in 0..100u8 {
+            match next_event(&server_events) {
+                DriverEvent::Message { payload, .. } => assert_eq!(payload, vec![i; 1000]),
+                event => panic!("Expected a message, got {event:?}"),
+            }
+        }
+
+        for i in 0..100u8 {
+            client.handle().send(token, vec![i; 1000]).unwrap();
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+
+        // Nobody reads the events anymore, which must not keep the driver from stopping
+        drop(server);
+        drop(server_events);
+    }
+
+    #[test]
+    fn test_plain_exchange() {
+        exchange_messages(None);
+    }
+
+    #[test]
+    fn test_tls_exchange() {
+        exchange_messages(Some(tls_configs()));
+    }
+}
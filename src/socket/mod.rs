@@ -1,28 +1,24 @@
 //! Abstractions over different socket types of crates in the Rust ecosystem.
 
 use std::io;
-use std::io::{ErrorKind, Read, Write};
+use std::io::{Read, Write};
+use std::mem::ManuallyDrop;
 use std::net::SocketAddr;
-use std::ops::{Deref, DerefMut};
-use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
 use std::task::{Context as Cntx, Poll};
 
-use either::Either;
 use futures::{AsyncRead, AsyncWrite};
 
-use futures::io::{BufReader, BufWriter};
 use mio::event::Source;
 use mio::{Interest, Registry, Token};
-use tracing::error;
 
-use rustls::{ClientConnection, ServerConnection};
+use futures_rustls::TlsStream;
+use rustls::{ClientConnection, Connection, ServerConnection};
 use socket2::Socket;
-use tokio_rustls::TlsStream;
-use tokio_util::compat::{
-    Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt,
-};
+#[cfg(feature = "socket_tokio_tcp")]
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
 
 #[cfg(feature = "socket_tokio_tcp")]
 mod tokio_tcp;
@@ -30,13 +26,44 @@ mod tokio_tcp;
 #[cfg(feature = "socket_async_std_tcp")]
 mod async_std_tcp;
 
-#[cfg(feature = "socket_rio_tcp")]
-mod rio_tcp;
+#[cfg(feature = "socket_io_uring_tcp")]
+mod io_uring_tcp;
 
 mod std_tcp;
 
-const WRITE_BUFFER_SIZE: usize = 8 * 1024 * 1024;
-const READ_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+mod deadline;
+mod tls_async;
+mod tls_sync;
+
+pub mod admission;
+pub mod buffer_pool;
+pub mod capture;
+pub mod compression;
+pub mod config;
+pub mod dialer;
+pub mod framing;
+pub mod happy_eyeballs;
+pub mod identity;
+pub mod mio_driver;
+pub mod mtls;
+pub mod mux;
+pub mod node_auth;
+pub mod noise;
+#[cfg(feature = "socket_quic")]
+pub mod quic;
+pub mod secure_channel;
+pub mod shaping;
+pub mod sim;
+pub mod vectored;
+pub mod websocket;
+
+pub use buffer_pool::{BufferPool, PooledBufReader, PooledBufWriter};
+pub use config::{KeepaliveConfig, SocketConfig};
+pub use noise::{NoiseReadHalf, NoiseStream, NoiseWriteHalf};
+pub use secure_channel::{SecureChannel, SecureChannelType};
+pub use tls_async::{TlsReadHalfAsync, TlsWriteHalfAsync};
+pub use tls_sync::{TlsReadHalfSync, TlsWriteHalfSync};
+pub use websocket::{WebSocketReadHalf, WebSocketStream, WebSocketWriteHalf};
 
 /// A `Listener` represents a socket listening on new communications
 /// initiated by peer nodes in the BFT system.
@@ -47,14 +74,17 @@ pub struct AsyncListener {
     #[cfg(feature = "socket_async_std_tcp")]
     inner: async_std_tcp::Listener,
 
-    #[cfg(feature = "socket_rio_tcp")]
-    inner: rio_tcp::Listener,
+    #[cfg(feature = "socket_io_uring_tcp")]
+    inner: io_uring_tcp::Listener,
+
+    config: SocketConfig,
 }
 
 ///A listener. Differs from the other Listener as this is a synchronous listener and does not rely
 ///On async runtimes
 pub struct SyncListener {
     inner: std_tcp::Listener,
+    config: SocketConfig,
 }
 
 /// A `Socket` represents a connection between two peer processes
@@ -67,22 +97,35 @@ pub struct AsyncSocket {
     #[cfg(feature = "socket_async_std_tcp")]
     inner: async_std_tcp::Socket,
 
-    #[cfg(feature = "socket_rio_tcp")]
-    inner: rio_tcp::Socket,
+    #[cfg(feature = "socket_io_uring_tcp")]
+    inner: io_uring_tcp::Socket,
+
+    config: SocketConfig,
 }
 
 ///A SyncSocket represents a connection between two peers in the BFT system.
 /// This is a synchronous socket
 pub struct SyncSocket {
     inner: std_tcp::Socket,
+    config: SocketConfig,
 }
 
 pub struct MioSocket {
-    inner: mio::net::TcpStream,
+    inner: MioStream,
 }
 
 pub struct MioListener {
-    inner: mio::net::TcpListener,
+    inner: MioListenerInner,
+}
+
+enum MioStream {
+    Tcp(mio::net::TcpStream),
+    Unix(mio::net::UnixStream),
+}
+
+enum MioListenerInner {
+    Tcp(mio::net::TcpListener),
+    Unix(mio::net::UnixListener),
 }
 
 impl From<SyncSocket> for MioSocket {
@@ -99,13 +142,113 @@ impl From<SyncListener> for MioListener {
 
 impl From<mio::net::TcpStream> for MioSocket {
     fn from(value: mio::net::TcpStream) -> Self {
-        MioSocket { inner: value }
+        MioSocket {
+            inner: MioStream::Tcp(value),
+        }
+    }
+}
+
+impl From<mio::net::UnixStream> for MioSocket {
+    fn from(value: mio::net::UnixStream) -> Self {
+        MioSocket {
+            inner: MioStream::Unix(value),
+        }
     }
 }
 
 impl From<mio::net::TcpListener> for MioListener {
     fn from(value: mio::net::TcpListener) -> Self {
-        MioListener { inner: value }
+        MioListener {
+            inner: MioListenerInner::Tcp(value),
+        }
+    }
+}
+
+impl From<mio::net::UnixListener> for MioListener {
+    fn from(value: mio::net::UnixListener) -> Self {
+        MioListener {
+            inner: MioListenerInner::Unix(value),
+        }
+    }
+}
+
+impl MioSocket {
+    /// The underlying TCP stream, if this is not a Unix domain socket
+    pub fn as_tcp(&self) -> Option<&mio::net::TcpStream> {
+        match &self.inner {
+            MioStream::Tcp(stream) => Some(stream),
+            MioStream::Unix(_) => None,
+        }
+    }
+
+    /// The mutable counterpart of [Self::as_tcp]
+    pub fn as_tcp_mut(&mut self) -> Option<&mut mio::net::TcpStream> {
+        match &mut self.inner {
+            MioStream::Tcp(stream) => Some(stream),
+            MioStream::Unix(_) => None,
+        }
+    }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self.inner, MioStream::Unix(_))
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        match &self.inner {
+            MioStream::Tcp(stream) => stream.take_error(),
+            MioStream::Unix(stream) => stream.take_error(),
+        }
+    }
+}
+
+impl MioListener {
+    /// Accept a new connection, which is already in non blocking mode
+    pub fn accept(&self) -> io::Result<MioSocket> {
+        match &self.inner {
+            MioListenerInner::Tcp(listener) => listener.accept().map(|(s, _)| s.into()),
+            MioListenerInner::Unix(listener) => listener.accept().map(|(s, _)| s.into()),
+        }
+    }
+
+    /// The underlying TCP listener, if this is not a Unix domain listener
+    pub fn as_tcp(&self) -> Option<&mio::net::TcpListener> {
+        match &self.inner {
+            MioListenerInner::Tcp(listener) => Some(listener),
+            MioListenerInner::Unix(_) => None,
+        }
+    }
+
+    /// The mutable counterpart of [Self::as_tcp]
+    pub fn as_tcp_mut(&mut self) -> Option<&mut mio::net::TcpListener> {
+        match &mut self.inner {
+            MioListenerInner::Tcp(listener) => Some(listener),
+            MioListenerInner::Unix(_) => None,
+        }
+    }
+}
+
+impl Read for MioSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            MioStream::Tcp(stream) => stream.read(buf),
+            MioStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for MioSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            MioStream::Tcp(stream) => stream.write(buf),
+            MioStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            MioStream::Tcp(stream) => stream.flush(),
+            MioStream::Unix(stream) => stream.flush(),
+        }
     }
 }
 
@@ -116,7 +259,10 @@ impl Source for MioSocket {
         token: Token,
         interests: Interest,
     ) -> io::Result<()> {
-        self.inner.register(registry, token, interests)
+        match &mut self.inner {
+            MioStream::Tcp(stream) => stream.register(registry, token, interests),
+            MioStream::Unix(stream) => stream.register(registry, token, interests),
+        }
     }
 
     fn reregister(
@@ -125,11 +271,17 @@ impl Source for MioSocket {
         token: Token,
         interests: Interest,
     ) -> io::Result<()> {
-        self.inner.reregister(registry, token, interests)
+        match &mut self.inner {
+            MioStream::Tcp(stream) => stream.reregister(registry, token, interests),
+            MioStream::Unix(stream) => stream.reregister(registry, token, interests),
+        }
     }
 
     fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
-        self.inner.deregister(registry)
+        match &mut self.inner {
+            MioStream::Tcp(stream) => stream.deregister(registry),
+            MioStream::Unix(stream) => stream.deregister(registry),
+        }
     }
 }
 
@@ -140,7 +292,10 @@ impl Source for MioListener {
         token: Token,
         interests: Interest,
     ) -> io::Result<()> {
-        self.inner.register(registry, token, interests)
+        match &mut self.inner {
+            MioListenerInner::Tcp(listener) => listener.register(registry, token, interests),
+            MioListenerInner::Unix(listener) => listener.register(registry, token, interests),
+        }
     }
 
     fn reregister(
@@ -149,39 +304,17 @@ impl Source for MioListener {
         token: Token,
         interests: Interest,
     ) -> io::Result<()> {
-        self.inner.reregister(registry, token, interests)
+        match &mut self.inner {
+            MioListenerInner::Tcp(listener) => listener.reregister(registry, token, interests),
+            MioListenerInner::Unix(listener) => listener.reregister(registry, token, interests),
+        }
     }
 
     fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
-        self.inner.deregister(registry)
-    }
-}
-
-impl Deref for MioListener {
-    type Target = mio::net::TcpListener;
-
-    fn deref(&self) -> &Self::Target {
-        &self.inner
-    }
-}
-
-impl DerefMut for MioListener {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
-    }
-}
-
-impl Deref for MioSocket {
-    type Target = mio::net::TcpStream;
-
-    fn deref(&self) -> &Self::Target {
-        &self.inner
-    }
-}
-
-impl DerefMut for MioSocket {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+        match &mut self.inner {
+            MioListenerInner::Tcp(listener) => listener.deregister(registry),
+            MioListenerInner::Unix(listener) => listener.deregister(registry),
+        }
     }
 }
 
@@ -189,12 +322,12 @@ impl DerefMut for MioSocket {
 ///
 /// # Safety
 ///
-/// Safe when ever using all TCP streams except RIO.
-/// When using rio, this can only be called once
+/// Always safe. With the io_uring backend, this sets up the global ring
+/// up front (it's otherwise set up when the first socket is used)
 pub unsafe fn init() -> Result<(), io::Error> {
-    #[cfg(feature = "socket_rio_tcp")]
+    #[cfg(feature = "socket_io_uring_tcp")]
     {
-        rio_tcp::init()?;
+        io_uring_tcp::init()?;
     }
 
     Ok(())
@@ -204,66 +337,182 @@ pub unsafe fn init() -> Result<(), io::Error> {
 ///
 /// # Safety
 ///
-/// Safe when ever using all TCP streams except RIO.
-/// When using rio, this can only be called once and after the [init]
-/// function
+/// Always safe. The io_uring ring lives for as long as the process,
+/// since operations may still be in flight
 pub unsafe fn drop() -> Result<(), io::Error> {
-    #[cfg(feature = "socket_rio_tcp")]
+    #[cfg(feature = "socket_io_uring_tcp")]
     {
-        rio_tcp::drop()?;
+        io_uring_tcp::drop()?;
     }
 
     Ok(())
 }
 
 /// Creates a new `Listener` socket, bound to the address `addr`.
-pub async fn bind_async_server<A: Into<SocketAddr>>(addr: A) -> Result<AsyncListener, io::Error> {
+///
+/// The listener's options, as well as the options of every connection
+/// it accepts, are taken from `config`.
+pub async fn bind_async_server<A: Into<SocketAddr>>(
+    addr: A,
+    config: &SocketConfig,
+) -> Result<AsyncListener, io::Error> {
+    let listener = config::bind_std_listener(addr.into(), config)?;
+
     {
         #[cfg(feature = "socket_tokio_tcp")]
         {
-            tokio_tcp::bind(addr).await
+            tokio_tcp::from_std_listener(listener)
         }
 
         #[cfg(feature = "socket_async_std_tcp")]
         {
-            async_std_tcp::bind(addr).await
+            async_std_tcp::from_std_listener(listener)
         }
 
-        #[cfg(feature = "socket_rio_tcp")]
+        #[cfg(feature = "socket_io_uring_tcp")]
         {
-            rio_tcp::bind(addr).await
+            io_uring_tcp::from_std_listener(listener)
         }
     }
-    .and_then(|inner| set_listener_options(AsyncListener { inner }))
+    .map(|inner| AsyncListener {
+        inner,
+        config: *config,
+    })
 }
 
-pub fn bind_sync_server<A: Into<SocketAddr>>(addr: A) -> Result<SyncListener, io::Error> {
-    { std_tcp::bind(addr) }.and_then(|inner| set_listener_options_replica(SyncListener { inner }))
+/// Creates a new `Listener` on the Unix domain socket at `path`, for
+/// connections from processes in the same host.
+///
+/// A stale socket file left at `path` (with no one listening on it) is removed.
+/// Only the buffer sizes and the backlog of `config` apply to Unix domain sockets.
+pub async fn bind_async_server_unix<P: AsRef<Path>>(
+    path: P,
+    config: &SocketConfig,
+) -> Result<AsyncListener, io::Error> {
+    let listener = config::bind_std_unix_listener(path.as_ref(), config)?;
+
+    {
+        #[cfg(feature = "socket_tokio_tcp")]
+        {
+            tokio_tcp::from_std_unix_listener(listener)
+        }
+
+        #[cfg(feature = "socket_async_std_tcp")]
+        {
+            async_std_tcp::from_std_unix_listener(listener)
+        }
+
+        #[cfg(feature = "socket_io_uring_tcp")]
+        {
+            io_uring_tcp::from_std_unix_listener(listener)
+        }
+    }
+    .map(|inner| AsyncListener {
+        inner,
+        config: *config,
+    })
+}
+
+pub fn bind_sync_server<A: Into<SocketAddr>>(
+    addr: A,
+    config: &SocketConfig,
+) -> Result<SyncListener, io::Error> {
+    let listener = config::bind_std_listener(addr.into(), config)?;
+
+    Ok(SyncListener {
+        inner: std_tcp::Listener::from(listener),
+        config: *config,
+    })
+}
+
+pub fn bind_sync_server_unix<P: AsRef<Path>>(
+    path: P,
+    config: &SocketConfig,
+) -> Result<SyncListener, io::Error> {
+    let listener = config::bind_std_unix_listener(path.as_ref(), config)?;
+
+    Ok(SyncListener {
+        inner: std_tcp::Listener::from(listener),
+        config: *config,
+    })
 }
 
 /// Connects to the remote node pointed to by the address `addr`.
-pub async fn connect_async<A: Into<SocketAddr>>(addr: A) -> Result<AsyncSocket, io::Error> {
+pub async fn connect_async<A: Into<SocketAddr>>(
+    addr: A,
+    config: &SocketConfig,
+) -> Result<AsyncSocket, io::Error> {
     {
         #[cfg(feature = "socket_tokio_tcp")]
         {
-            tokio_tcp::connect(addr).await
+            tokio_tcp::connect(addr, config).await
         }
 
         #[cfg(feature = "socket_async_std_tcp")]
         {
-            async_std_tcp::connect(addr).await
+            async_std_tcp::connect(addr, config).await
         }
 
-        #[cfg(feature = "socket_rio_tcp")]
+        #[cfg(feature = "socket_io_uring_tcp")]
         {
-            rio_tcp::connect(addr).await
+            io_uring_tcp::connect(addr, config).await
         }
     }
-    .and_then(|inner| set_sockstream_options(AsyncSocket { inner }))
+    .and_then(|inner| set_sockstream_options(AsyncSocket::new(inner, *config)))
 }
 
-pub fn connect_sync<A: Into<SocketAddr>>(addr: A) -> Result<SyncSocket, io::Error> {
-    { std_tcp::connect(addr) }.and_then(|inner| set_sockstream_options_sync(SyncSocket { inner }))
+pub fn connect_sync<A: Into<SocketAddr>>(
+    addr: A,
+    config: &SocketConfig,
+) -> Result<SyncSocket, io::Error> {
+    config::connect_std_stream(addr.into(), config)
+        .map(std_tcp::Socket::from)
+        .and_then(|inner| set_sockstream_options_sync(SyncSocket::new(inner, *config)))
+}
+
+/// Connects to the node listening on the Unix domain socket at `path`.
+pub async fn connect_async_unix<P: AsRef<Path>>(
+    path: P,
+    config: &SocketConfig,
+) -> Result<AsyncSocket, io::Error> {
+    {
+        #[cfg(feature = "socket_tokio_tcp")]
+        {
+            tokio_tcp::connect_unix(path.as_ref(), config).await
+        }
+
+        #[cfg(feature = "socket_async_std_tcp")]
+        {
+            async_std_tcp::connect_unix(path.as_ref(), config).await
+        }
+
+        #[cfg(feature = "socket_io_uring_tcp")]
+        {
+            io_uring_tcp::connect_unix(path.as_ref(), config).await
+        }
+    }
+    .and_then(|inner| set_sockstream_options(AsyncSocket::new(inner, *config)))
+}
+
+/// Turn a TCP socket whose nonblocking connect completed into a [SyncSocket]
+fn sync_socket_from_connected(
+    sock: Socket,
+    config: &SocketConfig,
+) -> Result<SyncSocket, io::Error> {
+    sock.set_nonblocking(false)?;
+
+    let stream: std::net::TcpStream = sock.into();
+
+    set_sockstream_options_sync(SyncSocket::new(std_tcp::Socket::from(stream), *config))
+}
+
+pub fn connect_sync_unix<P: AsRef<Path>>(
+    path: P,
+    config: &SocketConfig,
+) -> Result<SyncSocket, io::Error> {
+    config::connect_std_unix_stream(path.as_ref(), config)
+        .map(std_tcp::Socket::from)
+        .and_then(|inner| set_sockstream_options_sync(SyncSocket::new(inner, *config)))
 }
 
 impl AsyncListener {
@@ -271,7 +520,15 @@ impl AsyncListener {
         self.inner
             .accept()
             .await
-            .and_then(|inner| set_sockstream_options(AsyncSocket { inner }))
+            .and_then(|inner| set_sockstream_options(AsyncSocket::new(inner, self.config)))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.inner.local_addr()
+    }
+
+    pub fn config(&self) -> &SocketConfig {
+        &self.config
     }
 }
 
@@ -279,24 +536,72 @@ impl SyncListener {
     pub fn accept(&self) -> Result<SyncSocket, io::Error> {
         self.inner
             .accept()
-            .and_then(|inner| set_sockstream_options_sync(SyncSocket { inner }))
+            .and_then(|inner| set_sockstream_options_sync(SyncSocket::new(inner, self.config)))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.inner.local_addr()
+    }
+
+    pub fn config(&self) -> &SocketConfig {
+        &self.config
     }
 }
 
 impl AsyncSocket {
+    fn new(
+        #[cfg(feature = "socket_tokio_tcp")] inner: tokio_tcp::Socket,
+        #[cfg(feature = "socket_async_std_tcp")] inner: async_std_tcp::Socket,
+        #[cfg(feature = "socket_io_uring_tcp")] inner: io_uring_tcp::Socket,
+        config: SocketConfig,
+    ) -> Self {
+        Self { inner, config }
+    }
+
+    pub fn config(&self) -> &SocketConfig {
+        &self.config
+    }
+
+    /// Whether this is a Unix domain socket (instead of a TCP socket)
+    pub fn is_unix(&self) -> bool {
+        self.inner.is_unix()
+    }
+
+    /// The address of the connected peer. Unix domain sockets have no socket address
+    pub fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        peer_socket_addr(self.inner.as_raw_fd())
+    }
+
+    /// Adapt this socket to tokio's IO traits
+    #[cfg(feature = "socket_tokio_tcp")]
     pub fn compat_layer(self) -> Compat<Self> {
         self.compat()
     }
 
     pub(super) fn split(self) -> (WriteHalfAsync, ReadHalfAsync) {
+        let config = self.config;
+
+        self.split_with_capacity(config.write_buffer_capacity, config.read_buffer_capacity)
+    }
+
+    /// Split the socket, buffering the halves with the given capacities
+    /// (a capacity of 0 leaves the half unbuffered)
+    fn split_with_capacity(
+        self,
+        write_capacity: usize,
+        read_capacity: usize,
+    ) -> (WriteHalfAsync, ReadHalfAsync) {
         #[cfg(feature = "socket_tokio_tcp")]
         let (write, read) = tokio_tcp::split_socket(self.inner);
         #[cfg(feature = "socket_async_std_tcp")]
         let (write, read) = async_std_tcp::split_socket(self.inner);
+        #[cfg(feature = "socket_io_uring_tcp")]
+        let (write, read) = io_uring_tcp::split_socket(self.inner);
 
         //Buffer both the connections
-        let write_buffered = BufWriter::with_capacity(WRITE_BUFFER_SIZE, write);
-        let read_buffered = BufReader::with_capacity(READ_BUFFER_SIZE, read);
+        let min_capacity = self.config.min_buffer_capacity;
+        let write_buffered = PooledBufWriter::new(write, min_capacity, write_capacity);
+        let read_buffered = PooledBufReader::new(read, min_capacity, read_capacity);
 
         (
             WriteHalfAsync {
@@ -354,7 +659,9 @@ pub enum SecureSocket {
 
 pub enum SecureSocketSync {
     Plain(SyncSocket),
-    Tls(Box<Either<ClientConnection, ServerConnection>>, SyncSocket),
+    Tls(Box<Connection>, SyncSocket),
+    Noise(Box<NoiseStream<SyncSocket>>),
+    WebSocket(Box<WebSocketStream<SyncSocket>>),
 }
 
 impl SecureSocketSync {
@@ -362,12 +669,28 @@ impl SecureSocketSync {
         Self::Plain(socket)
     }
 
-    pub fn new_tls_server(tls_conn: ServerConnection, socket: SyncSocket) -> Self {
-        Self::Tls(Box::new(Either::Right(tls_conn)), socket)
+    pub fn new_websocket(stream: WebSocketStream<SyncSocket>) -> Self {
+        Self::WebSocket(Box::new(stream))
     }
 
-    pub fn new_tls_client(tls_conn: ClientConnection, socket: SyncSocket) -> Self {
-        Self::Tls(Box::new(Either::Left(tls_conn)), socket)
+    /// Establish a TLS session over the given socket, as the server.
+    ///
+    /// Blocks until the TLS handshake is completed.
+    pub fn new_tls_server(tls_conn: ServerConnection, socket: SyncSocket) -> io::Result<Self> {
+        Self::new_tls(tls_conn.into(), socket)
+    }
+
+    /// Establish a TLS session over the given socket, as the client.
+    ///
+    /// Blocks until the TLS handshake is completed.
+    pub fn new_tls_client(tls_conn: ClientConnection, socket: SyncSocket) -> io::Result<Self> {
+        Self::new_tls(tls_conn.into(), socket)
+    }
+
+    fn new_tls(mut tls_conn: Connection, mut socket: SyncSocket) -> io::Result<Self> {
+        tls_sync::complete_handshake(&mut tls_conn, &mut socket)?;
+
+        Ok(Self::Tls(Box::new(tls_conn), socket))
     }
 
     pub fn split(self) -> (SecureWriteHalfSync, SecureReadHalfSync) {
@@ -381,83 +704,77 @@ impl SecureSocketSync {
                 )
             }
             SecureSocketSync::Tls(connection, socket) => {
-                let (write, read) = socket.split();
+                let (write, read) = tls_sync::split(*connection, socket);
 
-                let shared_conn = Arc::new(Mutex::new(*connection));
+                (
+                    SecureWriteHalfSync::Tls(write),
+                    SecureReadHalfSync::Tls(read),
+                )
+            }
+            SecureSocketSync::Noise(stream) => {
+                let (write, read) = stream.split();
+
+                (
+                    SecureWriteHalfSync::Noise(write),
+                    SecureReadHalfSync::Noise(read),
+                )
+            }
+            SecureSocketSync::WebSocket(stream) => {
+                let (write, read) = stream.split();
 
                 (
-                    SecureWriteHalfSync::Tls(shared_conn.clone(), write),
-                    SecureReadHalfSync::Tls(shared_conn, read),
+                    SecureWriteHalfSync::WebSocket(write),
+                    SecureReadHalfSync::WebSocket(read),
                 )
             }
         }
     }
 }
 
+impl From<SyncSocket> for SecureSocketSync {
+    fn from(socket: SyncSocket) -> Self {
+        Self::new_plain(socket)
+    }
+}
+
+impl From<WebSocketStream<SyncSocket>> for SecureSocketSync {
+    fn from(stream: WebSocketStream<SyncSocket>) -> Self {
+        Self::new_websocket(stream)
+    }
+}
+
+impl AsRawFd for SecureSocketSync {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            SecureSocketSync::Plain(socket) => socket.as_raw_fd(),
+            SecureSocketSync::Tls(_, socket) => socket.as_raw_fd(),
+            SecureSocketSync::Noise(noise) => noise.as_raw_fd(),
+            SecureSocketSync::WebSocket(websocket) => websocket.as_raw_fd(),
+        }
+    }
+}
+
 pub enum SecureWriteHalfSync {
     Plain(WriteHalfSync),
-    Tls(
-        Arc<Mutex<Either<ClientConnection, ServerConnection>>>,
-        WriteHalfSync,
-    ),
+    Tls(TlsWriteHalfSync),
+    Noise(NoiseWriteHalf<WriteHalfSync>),
+    WebSocket(WebSocketWriteHalf<WriteHalfSync>),
 }
 
 pub enum SecureReadHalfSync {
     Plain(ReadHalfSync),
-    Tls(
-        Arc<Mutex<Either<ClientConnection, ServerConnection>>>,
-        ReadHalfSync,
-    ),
+    Tls(TlsReadHalfSync),
+    Noise(NoiseReadHalf<ReadHalfSync>),
+    WebSocket(WebSocketReadHalf<ReadHalfSync, WriteHalfSync>),
 }
 
 impl Read for SecureReadHalfSync {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
             SecureReadHalfSync::Plain(plain) => std::io::Read::read(plain, buf),
-            SecureReadHalfSync::Tls(tls_conn, sock) => {
-                match &mut *tls_conn.lock().unwrap() {
-                    Either::Left(tls_conn) => {
-                        tls_conn.read_tls(sock)?;
-
-                        match tls_conn.process_new_packets() {
-                            Ok(state) => {
-                                //FIXME: Is this correct, since we can read a different
-                                // Amount of packets as in the read tls one above
-                                if state.plaintext_bytes_to_read() > 0 {
-                                    tls_conn.reader().read(buf)
-                                } else {
-                                    Ok(0)
-                                }
-                            }
-                            Err(err) => {
-                                error!("Failed to process new tls packets {:?}", err);
-
-                                Err(io::Error::new(ErrorKind::BrokenPipe, ""))
-                            }
-                        }
-                    }
-                    Either::Right(tls_conn) => {
-                        tls_conn.read_tls(sock)?;
-
-                        match tls_conn.process_new_packets() {
-                            Ok(state) => {
-                                //FIXME: Is this correct, since we can read a different
-                                // Amount of packets as in the read tls one above
-                                if state.plaintext_bytes_to_read() > 0 {
-                                    tls_conn.reader().read(buf)
-                                } else {
-                                    Ok(0)
-                                }
-                            }
-                            Err(err) => {
-                                error!("Failed to process new tls packets {:?}", err);
-
-                                Err(io::Error::new(ErrorKind::BrokenPipe, ""))
-                            }
-                        }
-                    }
-                }
-            }
+            SecureReadHalfSync::Tls(tls) => tls.read(buf),
+            SecureReadHalfSync::Noise(noise) => noise.read(buf),
+            SecureReadHalfSync::WebSocket(websocket) => websocket.read(buf),
         }
     }
 }
@@ -466,87 +783,27 @@ impl Write for SecureWriteHalfSync {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self {
             SecureWriteHalfSync::Plain(write_half) => write_half.write(buf),
-            SecureWriteHalfSync::Tls(tls_conn, socket) => {
-                match &mut *tls_conn.lock().unwrap() {
-                    Either::Left(tls_conn) => {
-                        tls_conn.writer().write_all(buf)?;
-
-                        //FIXME: Is this even correct? Will it write the correct amount of bytes?
-                        // Since we are returning a different result that can write a different
-                        // Amount of bytes, this can get confused and miss count it?
-                        match tls_conn.process_new_packets() {
-                            Ok(state) => {
-                                //FIXME: Is this correct, since we can read a different
-                                // Amount of packets as in the read tls one above
-
-                                if state.tls_bytes_to_write() > 0 {
-                                    tls_conn.write_tls(socket)
-                                } else {
-                                    Ok(0)
-                                }
-                            }
-                            Err(err) => {
-                                error!("Failed to process new tls packets {:?}", err);
-
-                                Err(io::Error::new(ErrorKind::BrokenPipe, ""))
-                            }
-                        }
-                    }
-                    Either::Right(tls_conn) => {
-                        tls_conn.writer().write_all(buf)?;
-
-                        //FIXME: Is this even correct? Will it write the correct amount of bytes?
-                        // Since we are returning a different result that can write a different
-                        // Amount of bytes, this can get confused and miss count it?
-                        match tls_conn.process_new_packets() {
-                            Ok(state) => {
-                                //FIXME: Is this correct, since we can read a different
-                                // Amount of packets as in the read tls one above
-
-                                if state.tls_bytes_to_write() > 0 {
-                                    tls_conn.write_tls(socket)
-                                } else {
-                                    Ok(0)
-                                }
-                            }
-                            Err(err) => {
-                                error!("Failed to process new tls packets {:?}", err);
-
-                                Err(io::Error::new(ErrorKind::BrokenPipe, ""))
-                            }
-                        }
-                    }
-                }
-            }
+            SecureWriteHalfSync::Tls(tls) => tls.write(buf),
+            SecureWriteHalfSync::Noise(noise) => noise.write(buf),
+            SecureWriteHalfSync::WebSocket(websocket) => websocket.write(buf),
         }
     }
 
     fn flush(&mut self) -> io::Result<()> {
         match self {
             SecureWriteHalfSync::Plain(socket) => socket.flush(),
-            SecureWriteHalfSync::Tls(tls_conn, socket2) => match &mut *tls_conn.lock().unwrap() {
-                Either::Left(tls_conn) => {
-                    while tls_conn.wants_write() {
-                        tls_conn.write_tls(socket2)?;
-                    }
-
-                    socket2.flush()
-                }
-                Either::Right(tls_conn) => {
-                    while tls_conn.wants_write() {
-                        tls_conn.write_tls(socket2)?;
-                    }
-
-                    socket2.flush()
-                }
-            },
+            SecureWriteHalfSync::Tls(tls) => tls.flush(),
+            SecureWriteHalfSync::Noise(noise) => noise.flush(),
+            SecureWriteHalfSync::WebSocket(websocket) => websocket.flush(),
         }
     }
 }
 
 pub enum SecureSocketAsync {
     Plain(AsyncSocket),
-    Tls(Box<Compat<TlsStream<Compat<AsyncSocket>>>>),
+    Tls(Box<TlsStream<AsyncSocket>>),
+    Noise(Box<NoiseStream<AsyncSocket>>),
+    WebSocket(Box<WebSocketStream<AsyncSocket>>),
 }
 
 impl SecureSocketAsync {
@@ -554,8 +811,12 @@ impl SecureSocketAsync {
         Self::Plain(socket)
     }
 
-    pub fn new_tls(socket: TlsStream<Compat<AsyncSocket>>) -> Self {
-        Self::Tls(Box::new(socket.compat()))
+    pub fn new_tls(socket: TlsStream<AsyncSocket>) -> Self {
+        Self::Tls(Box::new(socket))
+    }
+
+    pub fn new_websocket(stream: WebSocketStream<AsyncSocket>) -> Self {
+        Self::WebSocket(Box::new(stream))
     }
 
     pub fn split(self) -> (SecureWriteHalfAsync, SecureReadHalfAsync) {
@@ -569,43 +830,85 @@ impl SecureSocketAsync {
                 )
             }
             SecureSocketAsync::Tls(tls_stream) => {
-                //FIXME: We have found that TlsStreams really don't like to be used in duplex mode
-                // especially with async-tls, which is what we were using to maintain compatibility
-                // between async-std and tokio. This means that we currently have a pretty hard time
-                // implementing this.
-                // https://github.com/tokio-rs/tls/issues/40
-
-                let tls_stream = tls_stream.into_inner();
-
-                //Unfortunately in this situation I don't think we can use async socket's efficient OS level split
-                // Since the stream requires duplex access. So we must wrap this in a bilock from futures
-
-                //Wrap in bilock
-                let (read, write) = tokio::io::split(tls_stream);
-
-                //We have to wrap these at this point instead of at the lower level
-                // since we can't use the same method but that's fine I guess
-                let read_buffered = BufReader::with_capacity(READ_BUFFER_SIZE, read.compat());
-                let write_buffered =
-                    BufWriter::with_capacity(WRITE_BUFFER_SIZE, write.compat_write());
+                let (socket, connection): (AsyncSocket, Connection) = match *tls_stream {
+                    TlsStream::Client(stream) => {
+                        let (socket, connection) = stream.into_inner();
+                        (socket, connection.into())
+                    }
+                    TlsStream::Server(stream) => {
+                        let (socket, connection) = stream.into_inner();
+                        (socket, connection.into())
+                    }
+                };
+
+                let config = *socket.config();
+
+                // Each TLS half drives its own OS level half of the socket, so reading
+                // and writing never wait on each other. The buffering is done on top of
+                // the TLS halves, so the socket halves themselves are left unbuffered
+                let (write, read) = socket.split_with_capacity(0, 0);
+                let (write, read) = tls_async::split(connection, write, read);
+
+                let write_buffered = PooledBufWriter::new(
+                    write,
+                    config.min_buffer_capacity,
+                    config.write_buffer_capacity,
+                );
+                let read_buffered = PooledBufReader::new(
+                    read,
+                    config.min_buffer_capacity,
+                    config.read_buffer_capacity,
+                );
 
                 (
                     SecureWriteHalfAsync::Tls(write_buffered),
                     SecureReadHalfAsync::Tls(read_buffered),
                 )
             }
+            SecureSocketAsync::Noise(stream) => {
+                let (write, read) = stream.split();
+
+                (
+                    SecureWriteHalfAsync::Noise(write),
+                    SecureReadHalfAsync::Noise(read),
+                )
+            }
+            SecureSocketAsync::WebSocket(stream) => {
+                let (write, read) = stream.split();
+
+                (
+                    SecureWriteHalfAsync::WebSocket(write),
+                    SecureReadHalfAsync::WebSocket(read),
+                )
+            }
         }
     }
 }
 
+impl From<AsyncSocket> for SecureSocketAsync {
+    fn from(socket: AsyncSocket) -> Self {
+        Self::new_plain(socket)
+    }
+}
+
+impl From<WebSocketStream<AsyncSocket>> for SecureSocketAsync {
+    fn from(stream: WebSocketStream<AsyncSocket>) -> Self {
+        Self::new_websocket(stream)
+    }
+}
+
 pub enum SecureWriteHalfAsync {
     Plain(WriteHalfAsync),
-    Tls(BufWriter<Compat<tokio::io::WriteHalf<TlsStream<Compat<AsyncSocket>>>>>),
+    Tls(PooledBufWriter<TlsWriteHalfAsync>),
+    Noise(NoiseWriteHalf<WriteHalfAsync>),
+    WebSocket(WebSocketWriteHalf<WriteHalfAsync>),
 }
 
 pub enum SecureReadHalfAsync {
     Plain(ReadHalfAsync),
-    Tls(BufReader<Compat<tokio::io::ReadHalf<TlsStream<Compat<AsyncSocket>>>>>),
+    Tls(PooledBufReader<TlsReadHalfAsync>),
+    Noise(NoiseReadHalf<ReadHalfAsync>),
+    WebSocket(WebSocketReadHalf<ReadHalfAsync, WriteHalfAsync>),
 }
 
 impl AsyncWrite for SecureWriteHalfAsync {
@@ -617,6 +920,8 @@ impl AsyncWrite for SecureWriteHalfAsync {
         match &mut *self {
             SecureWriteHalfAsync::Plain(inner) => Pin::new(inner).poll_write(cx, buf),
             SecureWriteHalfAsync::Tls(inner) => Pin::new(inner).poll_write(cx, buf),
+            SecureWriteHalfAsync::Noise(inner) => Pin::new(inner).poll_write(cx, buf),
+            SecureWriteHalfAsync::WebSocket(inner) => Pin::new(inner).poll_write(cx, buf),
         }
     }
 
@@ -624,13 +929,17 @@ impl AsyncWrite for SecureWriteHalfAsync {
         match &mut *self {
             SecureWriteHalfAsync::Plain(inner) => Pin::new(inner).poll_flush(cx),
             SecureWriteHalfAsync::Tls(inner) => Pin::new(inner).poll_flush(cx),
+            SecureWriteHalfAsync::Noise(inner) => Pin::new(inner).poll_flush(cx),
+            SecureWriteHalfAsync::WebSocket(inner) => Pin::new(inner).poll_flush(cx),
         }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
         match &mut *self {
             SecureWriteHalfAsync::Plain(inner) => Pin::new(inner).poll_close(cx),
-            SecureWriteHalfAsync::Tls(inner) => Pin::new(inner).poll_flush(cx),
+            SecureWriteHalfAsync::Tls(inner) => Pin::new(inner).poll_close(cx),
+            SecureWriteHalfAsync::Noise(inner) => Pin::new(inner).poll_close(cx),
+            SecureWriteHalfAsync::WebSocket(inner) => Pin::new(inner).poll_close(cx),
         }
     }
 }
@@ -644,6 +953,8 @@ impl AsyncRead for SecureReadHalfAsync {
         match &mut *self {
             SecureReadHalfAsync::Plain(inner) => Pin::new(inner).poll_read(cx, buf),
             SecureReadHalfAsync::Tls(inner) => Pin::new(inner).poll_read(cx, buf),
+            SecureReadHalfAsync::Noise(inner) => Pin::new(inner).poll_read(cx, buf),
+            SecureReadHalfAsync::WebSocket(inner) => Pin::new(inner).poll_read(cx, buf),
         }
     }
 }
@@ -655,13 +966,15 @@ pub enum WriteHalf {
 
 pub struct WriteHalfAsync {
     #[cfg(feature = "socket_tokio_tcp")]
-    inner: BufWriter<tokio_tcp::WriteHalf>,
+    inner: PooledBufWriter<tokio_tcp::WriteHalf>,
     #[cfg(feature = "socket_async_std_tcp")]
-    inner: BufWriter<async_std_tcp::WriteHalf>,
+    inner: PooledBufWriter<async_std_tcp::WriteHalf>,
+    #[cfg(feature = "socket_io_uring_tcp")]
+    inner: PooledBufWriter<io_uring_tcp::WriteHalf>,
 }
 
 pub struct WriteHalfSync {
-    inner: io::BufWriter<std_tcp::WriteHalf>,
+    inner: PooledBufWriter<std_tcp::WriteHalf>,
 }
 
 pub enum ReadHalf {
@@ -671,13 +984,15 @@ pub enum ReadHalf {
 
 pub struct ReadHalfAsync {
     #[cfg(feature = "socket_tokio_tcp")]
-    inner: BufReader<tokio_tcp::ReadHalf>,
+    inner: PooledBufReader<tokio_tcp::ReadHalf>,
     #[cfg(feature = "socket_async_std_tcp")]
-    inner: BufReader<async_std_tcp::ReadHalf>,
+    inner: PooledBufReader<async_std_tcp::ReadHalf>,
+    #[cfg(feature = "socket_io_uring_tcp")]
+    inner: PooledBufReader<io_uring_tcp::ReadHalf>,
 }
 
 pub struct ReadHalfSync {
-    inner: io::BufReader<std_tcp::ReadHalf>,
+    inner: PooledBufReader<std_tcp::ReadHalf>,
 }
 
 impl AsyncRead for ReadHalfAsync {
@@ -724,13 +1039,46 @@ impl Write for WriteHalfSync {
     }
 }
 
+impl Drop for WriteHalfSync {
+    fn drop(&mut self) {
+        // Keep the behaviour of the `BufWriter` this used to be, which flushes on drop
+        let _ = self.inner.flush();
+    }
+}
+
 impl SyncSocket {
+    fn new(inner: std_tcp::Socket, config: SocketConfig) -> Self {
+        Self { inner, config }
+    }
+
+    /// Whether this is a Unix domain socket (instead of a TCP socket)
+    pub fn is_unix(&self) -> bool {
+        self.inner.is_unix()
+    }
+
+    /// The address of the connected peer. Unix domain sockets have no socket address
+    pub fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        peer_socket_addr(self.inner.as_raw_fd())
+    }
+
+    pub fn config(&self) -> &SocketConfig {
+        &self.config
+    }
+
     pub fn split(self) -> (WriteHalfSync, ReadHalfSync) {
         let (write, read) = std_tcp::split(self.inner);
 
-        let write_buffered = io::BufWriter::with_capacity(WRITE_BUFFER_SIZE, write);
+        let write_buffered = PooledBufWriter::new(
+            write,
+            self.config.min_buffer_capacity,
+            self.config.write_buffer_capacity,
+        );
 
-        let read_buffered = io::BufReader::with_capacity(READ_BUFFER_SIZE, read);
+        let read_buffered = PooledBufReader::new(
+            read,
+            self.config.min_buffer_capacity,
+            self.config.read_buffer_capacity,
+        );
 
         (
             WriteHalfSync {
@@ -743,6 +1091,12 @@ impl SyncSocket {
     }
 }
 
+impl AsRawFd for SyncSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
 impl Write for SyncSocket {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.inner.write(buf)
@@ -772,6 +1126,8 @@ impl AsyncRead for SecureSocketAsync {
         match &mut *self {
             SecureSocketAsync::Plain(plain) => Pin::new(plain).poll_read(cx, buf),
             SecureSocketAsync::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+            SecureSocketAsync::Noise(noise) => Pin::new(noise).poll_read(cx, buf),
+            SecureSocketAsync::WebSocket(websocket) => Pin::new(websocket).poll_read(cx, buf),
         }
     }
 }
@@ -785,6 +1141,8 @@ impl AsyncWrite for SecureSocketAsync {
         match &mut *self {
             SecureSocketAsync::Plain(plain) => Pin::new(plain).poll_write(cx, buf),
             SecureSocketAsync::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+            SecureSocketAsync::Noise(noise) => Pin::new(noise).poll_write(cx, buf),
+            SecureSocketAsync::WebSocket(websocket) => Pin::new(websocket).poll_write(cx, buf),
         }
     }
 
@@ -792,6 +1150,8 @@ impl AsyncWrite for SecureSocketAsync {
         match &mut *self {
             SecureSocketAsync::Plain(plain) => Pin::new(plain).poll_flush(cx),
             SecureSocketAsync::Tls(tls) => Pin::new(tls).poll_flush(cx),
+            SecureSocketAsync::Noise(noise) => Pin::new(noise).poll_flush(cx),
+            SecureSocketAsync::WebSocket(websocket) => Pin::new(websocket).poll_flush(cx),
         }
     }
 
@@ -799,78 +1159,250 @@ impl AsyncWrite for SecureSocketAsync {
         match &mut *self {
             SecureSocketAsync::Plain(plain) => Pin::new(plain).poll_close(cx),
             SecureSocketAsync::Tls(tls) => Pin::new(tls).poll_close(cx),
+            SecureSocketAsync::Noise(noise) => Pin::new(noise).poll_close(cx),
+            SecureSocketAsync::WebSocket(websocket) => Pin::new(websocket).poll_close(cx),
         }
     }
 }
 
-// set listener socket options; translated from BFT-SMaRt
+// set connection socket options, according to the socket's config.
+// The defaults are translated from BFT-SMaRt
+#[inline]
+fn set_sockstream_options(connection: AsyncSocket) -> Result<AsyncSocket, io::Error> {
+    apply_stream_options(
+        connection.inner.as_raw_fd(),
+        connection.inner.is_unix(),
+        &connection.config,
+    )?;
+
+    Ok(connection)
+}
+
 #[inline]
-fn set_listener_options(listener: AsyncListener) -> Result<AsyncListener, io::Error> {
-    let raw_fd = listener.inner.as_raw_fd();
-    let sock = unsafe { Socket::from_raw_fd(raw_fd) };
+fn set_sockstream_options_sync(connection: SyncSocket) -> Result<SyncSocket, io::Error> {
+    apply_stream_options(
+        connection.inner.as_raw_fd(),
+        connection.inner.is_unix(),
+        &connection.config,
+    )?;
 
-    sock.set_send_buffer_size(WRITE_BUFFER_SIZE)?;
-    sock.set_recv_buffer_size(READ_BUFFER_SIZE)?;
-    sock.set_reuse_address(true)?;
-    sock.set_keepalive(true)?;
-    sock.set_nodelay(true)?;
+    Ok(connection)
+}
 
-    // Prevent the socket from being closed when `sock` is dropped
-    std::mem::forget(sock);
+fn peer_socket_addr(raw_fd: RawFd) -> Result<SocketAddr, io::Error> {
+    // Prevent the socket from being closed when `sock` goes out of scope,
+    // as we do not own the file descriptor
+    let sock = ManuallyDrop::new(unsafe { Socket::from_raw_fd(raw_fd) });
 
-    Ok(listener)
+    sock.peer_addr()?.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Unix domain sockets have no socket address",
+        )
+    })
 }
 
-// set listener socket options; translated from BFT-SMaRt
 #[inline]
-fn set_listener_options_replica(listener: SyncListener) -> Result<SyncListener, io::Error> {
-    let raw_fd = listener.inner.as_raw_fd();
-    let sock = unsafe { Socket::from_raw_fd(raw_fd) };
+fn apply_stream_options(raw_fd: RawFd, unix: bool, config: &SocketConfig) -> Result<(), io::Error> {
+    // Prevent the socket from being closed when `sock` goes out of scope,
+    // as we do not own the file descriptor
+    let sock = ManuallyDrop::new(unsafe { Socket::from_raw_fd(raw_fd) });
+
+    if unix {
+        // The TCP options do not apply to Unix domain sockets
+        config.apply_buffer_sizes(&sock)
+    } else {
+        config.apply_stream_options(&sock)
+    }
+}
 
-    sock.set_send_buffer_size(WRITE_BUFFER_SIZE)?;
-    sock.set_recv_buffer_size(READ_BUFFER_SIZE)?;
-    sock.set_reuse_address(true)?;
-    sock.set_keepalive(true)?;
-    sock.set_nodelay(true)?;
+/// A socket bound to a loopback port without listening on it, so connecting to that
+/// port is refused for as long as the socket is kept. Listening on it later makes
+/// the port reachable, without the race of releasing and binding it again
+#[cfg(test)]
+pub(crate) fn bind_refusing() -> Socket {
+    let socket = Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
 
-    std::mem::forget(sock);
+    socket
+        .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+        .unwrap();
 
-    // ChannelOption.CONNECT_TIMEOUT_MILLIS ??
-    // ChannelOption.SO_BACKLOG ??
-    Ok(listener)
+    socket
 }
 
-// set connection socket options; translated from BFT-SMaRt
-#[inline]
-fn set_sockstream_options(connection: AsyncSocket) -> Result<AsyncSocket, io::Error> {
-    let raw_fd = connection.inner.as_raw_fd();
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::thread;
 
-    let sock = unsafe { Socket::from_raw_fd(raw_fd) };
+    use futures::{AsyncReadExt, AsyncWriteExt};
 
-    sock.set_send_buffer_size(WRITE_BUFFER_SIZE)?;
-    sock.set_recv_buffer_size(READ_BUFFER_SIZE)?;
-    sock.set_keepalive(true)?;
-    sock.set_nodelay(true)?;
+    use super::*;
+    use crate::node_id::NodeId;
+    use crate::socket::framing::FramingConfig;
+    use crate::socket::identity::test_node_keys;
+    use crate::socket::noise::NoiseConfig;
 
-    std::mem::forget(sock);
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("atlas-{}-{}.sock", std::process::id(), name))
+    }
 
-    // ChannelOption.CONNECT_TIMEOUT_MILLIS ??
-    Ok(connection)
-}
+    #[test]
+    fn test_unix_sync_framed() {
+        let path = socket_path("sync");
+        let config = SocketConfig::default();
 
-#[inline]
-fn set_sockstream_options_sync(connection: SyncSocket) -> Result<SyncSocket, io::Error> {
-    let raw_fd = connection.inner.as_raw_fd();
+        let listener = bind_sync_server_unix(&path, &config).unwrap();
 
-    let sock = unsafe { Socket::from_raw_fd(raw_fd) };
+        let client_path = path.clone();
+        let client = thread::spawn(move || {
+            let socket = connect_sync_unix(&client_path, &config).unwrap();
 
-    sock.set_send_buffer_size(WRITE_BUFFER_SIZE)?;
-    sock.set_recv_buffer_size(READ_BUFFER_SIZE)?;
-    sock.set_keepalive(true)?;
-    sock.set_nodelay(true)?;
+            assert!(socket.is_unix());
 
-    std::mem::forget(sock);
+            let (mut write, _read) =
+                SecureSocketSync::new_plain(socket).split_framed(FramingConfig::default());
 
-    // ChannelOption.CONNECT_TIMEOUT_MILLIS ??
-    Ok(connection)
+            write.write_frame(b"over unix").unwrap();
+            write.flush().unwrap();
+        });
+
+        let socket = listener.accept().unwrap();
+        let (_write, mut read) =
+            SecureSocketSync::new_plain(socket).split_framed(FramingConfig::default());
+
+        assert_eq!(read.read_frame().unwrap(), b"over unix");
+
+        client.join().unwrap();
+
+        // The socket file left behind is stale once the listener is gone
+        std::mem::drop(listener);
+
+        let listener = bind_sync_server_unix(&path, &config).unwrap();
+
+        assert!(bind_sync_server_unix(&path, &config).is_err());
+
+        // The lock taken while binding goes away along with its file
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+
+        assert!(!PathBuf::from(lock_path).exists());
+
+        std::mem::drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unix_secure_channel() {
+        let path = socket_path("noise");
+        let config = SocketConfig::default();
+
+        let (keys, directory) = test_node_keys(2);
+
+        let server = NoiseConfig::new(NodeId(0), keys[0].1.clone(), directory.clone()).unwrap();
+        let client = NoiseConfig::new(NodeId(1), keys[1].1.clone(), directory).unwrap();
+
+        let listener = bind_sync_server_unix(&path, &config).unwrap();
+
+        let client_path = path.clone();
+        let client = thread::spawn(move || {
+            let socket = connect_sync_unix(&client_path, &config).unwrap();
+
+            let (_, socket) = client.connect_sync(NodeId(0), socket).unwrap();
+            let (mut write, _read) = socket.split();
+
+            write.write_all(b"secret").unwrap();
+            write.flush().unwrap();
+        });
+
+        let (peer, socket) = server.accept_sync(listener.accept().unwrap()).unwrap();
+        let (_write, mut read) = socket.split();
+
+        let mut buf = [0; 6];
+        read.read_exact(&mut buf).unwrap();
+
+        assert_eq!(peer, NodeId(1));
+        assert_eq!(&buf, b"secret");
+
+        client.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unix_async() {
+        let path = socket_path("async");
+        let config = SocketConfig::default();
+
+        let listener = bind_async_server_unix(&path, &config).await.unwrap();
+
+        let client_path = path.clone();
+        let client = tokio::spawn(async move {
+            let socket = connect_async_unix(&client_path, &config).await.unwrap();
+            let (mut write, mut read) = socket.split();
+
+            write.write_all(b"ping").await.unwrap();
+            write.flush().await.unwrap();
+
+            let mut buf = [0; 4];
+            read.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let socket = listener.accept().await.unwrap();
+
+        assert!(socket.is_unix());
+
+        let (mut write, mut read) = socket.split();
+
+        let mut buf = [0; 4];
+        read.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(&buf, b"ping");
+
+        write.write_all(b"pong").await.unwrap();
+        write.flush().await.unwrap();
+
+        assert_eq!(&client.await.unwrap(), b"pong");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unix_mio_socket() {
+        let path = socket_path("mio");
+        let config = SocketConfig::default();
+
+        let listener = MioListener::from(bind_sync_server_unix(&path, &config).unwrap());
+
+        let mut client = MioSocket::from(connect_sync_unix(&path, &config).unwrap());
+
+        assert!(client.is_unix());
+        assert!(client.as_tcp().is_none());
+
+        client.write_all(b"mio").unwrap();
+
+        let mut accepted = loop {
+            match listener.accept() {
+                Ok(socket) => break socket,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(err) => panic!("{err:?}"),
+            }
+        };
+
+        let mut buf = [0; 3];
+        let mut read = 0;
+
+        while read < buf.len() {
+            match accepted.read(&mut buf[read..]) {
+                Ok(n) => read += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(err) => panic!("{err:?}"),
+            }
+        }
+
+        assert_eq!(&buf, b"mio");
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
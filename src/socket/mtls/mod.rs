@@ -0,0 +1,662 @@
+//! Mutual TLS between nodes, authenticated by their ed25519 identities.
+//!
+//! Every node issues itself a self-signed certificate, signed with its
+//! [KeyPair], which binds its [NodeId] (as the `node-<id>.atlas` DNS name).
+//! Peers are verified by pinning the public key we know for the node the
+//! certificate claims to be (from a [NodeKeyDirectory]), instead of trusting a web PKI.
+//! After the handshake, the authenticated [NodeId] of the peer is returned along with
+//! the secure socket, so replicas know who they are talking to without an extra round.
+
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use anyhow::Context;
+use futures_rustls::{TlsAcceptor, TlsConnector};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::ResolvesClientCert;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{CertifiedKey, Signer, SigningKey};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, CommonState, DigitallySignedStruct,
+    DistinguishedName, OtherError, ServerConfig, ServerConnection, SignatureAlgorithm,
+    SignatureScheme,
+};
+use thiserror::Error;
+use x509_parser::oid_registry::OID_SIG_ED25519;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::crypto::signature::{KeyPair, PublicKey, Signature};
+use crate::error::*;
+use crate::node_id::NodeId;
+use crate::socket::identity::NodeKeyDirectory;
+use crate::socket::{AsyncSocket, SecureSocketAsync, SecureSocketSync, SyncSocket};
+use crate::Err;
+
+const NODE_NAME_PREFIX: &str = "node-";
+const NODE_NAME_SUFFIX: &str = ".atlas";
+
+#[derive(Error, Debug)]
+pub enum MutualTlsError {
+    #[error("Failed to generate the node certificate {0:?}")]
+    CertificateGeneration(String),
+    #[error("Malformed node certificate {0:?}")]
+    MalformedCertificate(String),
+    #[error("The certificate does not bind a node id")]
+    MissingNodeId,
+    #[error("The certificate key is not an ed25519 key")]
+    UnsupportedKey,
+    #[error("Node {0:?} is not in the key directory")]
+    UnknownNode(NodeId),
+    #[error("The key presented by node {0:?} does not match the known key")]
+    KeyMismatch(NodeId),
+    #[error("Expected to be talking to {expected:?}, but the peer is {found:?}")]
+    UnexpectedPeer { expected: NodeId, found: NodeId },
+    #[error("The peer did not present a certificate")]
+    NoPeerCertificate,
+}
+
+/// The TLS configurations used to establish mutually authenticated
+/// connections with other nodes.
+pub struct MutualTlsConfig {
+    node_id: NodeId,
+    certificate: CertificateDer<'static>,
+    server_config: Arc<ServerConfig>,
+    client_config: Arc<ClientConfig>,
+}
+
+impl MutualTlsConfig {
+    /// Build the server and client configurations of this node.
+    ///
+    /// `directory` holds the public keys of the nodes we accept connections from
+    /// (and connect to). It is shared, so later insertions are taken into account.
+    pub fn new(
+        node_id: NodeId,
+        key_pair: Arc<KeyPair>,
+        directory: NodeKeyDirectory,
+    ) -> Result<Self> {
+        let certificate = generate_node_certificate(node_id, key_pair.clone())?;
+
+        let certified_key = Arc::new(CertifiedKey::new(
+            vec![certificate.clone()],
+            Arc::new(NodeKeySigner(key_pair)),
+        ));
+
+        let verifier = Arc::new(NodeIdentityVerifier { directory });
+
+        let resolver = Arc::new(NodeCertResolver(certified_key));
+
+        let server_config =
+            ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+                .with_client_cert_verifier(verifier.clone())
+                .with_cert_resolver(resolver.clone());
+
+        let client_config =
+            ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+                .dangerous()
+                .with_custom_certificate_verifier(verifier)
+                .with_client_cert_resolver(resolver);
+
+        Ok(Self {
+            node_id,
+            certificate,
+            server_config: Arc::new(server_config),
+            client_config: Arc::new(client_config),
+        })
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// The self-signed certificate of this node
+    pub fn certificate(&self) -> &CertificateDer<'static> {
+        &self.certificate
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.server_config.clone()
+    }
+
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.client_config.clone()
+    }
+
+    pub fn server_connection(&self) -> Result<ServerConnection> {
+        ServerConnection::new(self.server_config())
+            .context("Failed to create TLS server connection")
+    }
+
+    /// Create a client connection to the given node. The handshake
+    /// only succeeds if the server proves to be that node.
+    pub fn client_connection(&self, peer: NodeId) -> Result<ClientConnection> {
+        ClientConnection::new(self.client_config(), node_server_name(peer))
+            .context("Failed to create TLS client connection")
+    }
+
+    /// Establish a TLS session with a node that connected to us,
+    /// returning its authenticated identity
+    pub fn accept_sync(&self, socket: SyncSocket) -> Result<(NodeId, SecureSocketSync)> {
+        let secure_socket = SecureSocketSync::new_tls_server(self.server_connection()?, socket)?;
+
+        let peer = match &secure_socket {
+            SecureSocketSync::Tls(connection, _) => authenticated_peer(connection)?,
+            _ => unreachable!(),
+        };
+
+        Ok((peer, secure_socket))
+    }
+
+    /// Establish a TLS session with the node `peer`, which we connected to
+    pub fn connect_sync(
+        &self,
+        peer: NodeId,
+        socket: SyncSocket,
+    ) -> Result<(NodeId, SecureSocketSync)> {
+        let secure_socket =
+            SecureSocketSync::new_tls_client(self.client_connection(peer)?, socket)?;
+
+        let found = match &secure_socket {
+            SecureSocketSync::Tls(connection, _) => authenticated_peer(connection)?,
+            _ => unreachable!(),
+        };
+
+        check_expected_peer(peer, found)?;
+
+        Ok((found, secure_socket))
+    }
+
+    /// Establish a TLS session with a node that connected to us,
+    /// returning its authenticated identity
+    pub async fn accept_async(&self, socket: AsyncSocket) -> Result<(NodeId, SecureSocketAsync)> {
+        let stream = TlsAcceptor::from(self.server_config())
+            .accept(socket)
+            .await?;
+
+        let peer = authenticated_peer(stream.get_ref().1)?;
+
+        Ok((peer, SecureSocketAsync::new_tls(stream.into())))
+    }
+
+    /// Establish a TLS session with the node `peer`, which we connected to
+    pub async fn connect_async(
+        &self,
+        peer: NodeId,
+        socket: AsyncSocket,
+    ) -> Result<(NodeId, SecureSocketAsync)> {
+        let stream = TlsConnector::from(self.client_config())
+            .connect(node_server_name(peer), socket)
+            .await?;
+
+        let found = authenticated_peer(stream.get_ref().1)?;
+
+        check_expected_peer(peer, found)?;
+
+        Ok((found, SecureSocketAsync::new_tls(stream.into())))
+    }
+}
+
+/// The name under which a node's certificate is issued.
+/// This is the server name clients should use when connecting to `node`
+pub fn node_server_name(node: NodeId) -> ServerName<'static> {
+    ServerName::try_from(node_dns_name(node)).expect("Node names are valid DNS names")
+}
+
+/// Extract the authenticated identity of the peer from an established TLS connection,
+/// configured with a [MutualTlsConfig].
+pub fn authenticated_peer(connection: &CommonState) -> Result<NodeId> {
+    certificates_peer(connection.peer_certificates().unwrap_or_default())
+}
+
+/// Extract the node identity from the (already verified) certificate chain
+/// presented by a peer
+pub fn certificates_peer(certificates: &[CertificateDer<'_>]) -> Result<NodeId> {
+    let certificate = certificates
+        .first()
+        .ok_or(MutualTlsError::NoPeerCertificate)?;
+
+    Ok(parse_node_certificate(certificate)?.node)
+}
+
+pub(crate) fn check_expected_peer(expected: NodeId, found: NodeId) -> Result<()> {
+    if expected != found {
+        return Err!(MutualTlsError::UnexpectedPeer { expected, found });
+    }
+
+    Ok(())
+}
+
+pub(crate) fn node_dns_name(node: NodeId) -> String {
+    format!("{}{}{}", NODE_NAME_PREFIX, node.id(), NODE_NAME_SUFFIX)
+}
+
+fn parse_node_dns_name(name: &str) -> Option<NodeId> {
+    name.strip_prefix(NODE_NAME_PREFIX)?
+        .strip_suffix(NODE_NAME_SUFFIX)?
+        .parse::<u32>()
+        .ok()
+        .map(NodeId::from)
+}
+
+fn generate_node_certificate(
+    node: NodeId,
+    key_pair: Arc<KeyPair>,
+) -> std::result::Result<CertificateDer<'static>, MutualTlsError> {
+    let mut params = rcgen::CertificateParams::new(vec![node_dns_name(node)])
+        .map_err(|err| MutualTlsError::CertificateGeneration(format!("{err:?}")))?;
+
+    params.distinguished_name.push(
+        rcgen::DnType::CommonName,
+        format!("Atlas node {}", node.id()),
+    );
+
+    let signing_key = rcgen::KeyPair::from_remote(Box::new(NodeKeySigner(key_pair)))
+        .map_err(|err| MutualTlsError::CertificateGeneration(format!("{err:?}")))?;
+
+    let certificate = params
+        .self_signed(&signing_key)
+        .map_err(|err| MutualTlsError::CertificateGeneration(format!("{err:?}")))?;
+
+    Ok(certificate.der().clone())
+}
+
+/// The identity bound by a node certificate
+struct NodeCertificate {
+    node: NodeId,
+    public_key: Vec<u8>,
+}
+
+fn parse_node_certificate(
+    certificate: &CertificateDer<'_>,
+) -> std::result::Result<NodeCertificate, MutualTlsError> {
+    let (_, cert) = X509Certificate::from_der(certificate.as_ref())
+        .map_err(|err| MutualTlsError::MalformedCertificate(format!("{err:?}")))?;
+
+    let spki = cert.public_key();
+
+    if spki.algorithm.algorithm != OID_SIG_ED25519 {
+        return Err(MutualTlsError::UnsupportedKey);
+    }
+
+    let alt_names = cert
+        .subject_alternative_name()
+        .map_err(|err| MutualTlsError::MalformedCertificate(format!("{err:?}")))?
+        .ok_or(MutualTlsError::MissingNodeId)?;
+
+    let node = alt_names
+        .value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            GeneralName::DNSName(name) => parse_node_dns_name(name),
+            _ => None,
+        })
+        .ok_or(MutualTlsError::MissingNodeId)?;
+
+    Ok(NodeCertificate {
+        node,
+        public_key: spki.subject_public_key.data.to_vec(),
+    })
+}
+
+fn to_rustls_error(err: MutualTlsError) -> rustls::Error {
+    rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(err))))
+}
+
+/// Signs with the node's key pair, both for issuing the certificate and during the handshake
+struct NodeKeySigner(Arc<KeyPair>);
+
+impl rcgen::RemoteKeyPair for NodeKeySigner {
+    fn public_key(&self) -> &[u8] {
+        self.0.public_key_bytes()
+    }
+
+    fn sign(&self, msg: &[u8]) -> std::result::Result<Vec<u8>, rcgen::Error> {
+        self.0
+            .sign(msg)
+            .map(|signature| signature.as_ref().to_vec())
+            .map_err(|_| rcgen::Error::RemoteKeyError)
+    }
+
+    fn algorithm(&self) -> &'static rcgen::SignatureAlgorithm {
+        &rcgen::PKCS_ED25519
+    }
+}
+
+impl SigningKey for NodeKeySigner {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        if offered.contains(&SignatureScheme::ED25519) {
+            Some(Box::new(NodeKeySigner(self.0.clone())))
+        } else {
+            None
+        }
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::ED25519
+    }
+}
+
+impl Signer for NodeKeySigner {
+    fn sign(&self, message: &[u8]) -> std::result::Result<Vec<u8>, rustls::Error> {
+        self.0
+            .sign(message)
+            .map(|signature| signature.as_ref().to_vec())
+            .map_err(|err| rustls::Error::General(format!("Failed to sign {err:?}")))
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        SignatureScheme::ED25519
+    }
+}
+
+impl Debug for NodeKeySigner {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NodeKeySigner({:?})", self.0.public_key_bytes())
+    }
+}
+
+/// Always presents the node's own certificate
+struct NodeCertResolver(Arc<CertifiedKey>);
+
+impl ResolvesServerCert for NodeCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+impl ResolvesClientCert for NodeCertResolver {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        sigschemes
+            .contains(&SignatureScheme::ED25519)
+            .then(|| self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+impl Debug for NodeCertResolver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NodeCertResolver")
+    }
+}
+
+/// Verifies peers by pinning the key of the node their certificate binds
+struct NodeIdentityVerifier {
+    directory: NodeKeyDirectory,
+}
+
+impl NodeIdentityVerifier {
+    fn verify_node_certificate(
+        &self,
+        certificate: &CertificateDer<'_>,
+    ) -> std::result::Result<NodeId, MutualTlsError> {
+        let NodeCertificate { node, public_key } = parse_node_certificate(certificate)?;
+
+        if !self.directory.contains(&node) {
+            return Err(MutualTlsError::UnknownNode(node));
+        }
+
+        if !self.directory.matches(&node, &public_key) {
+            return Err(MutualTlsError::KeyMismatch(node));
+        }
+
+        Ok(node)
+    }
+
+    fn verify_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        if dss.scheme != SignatureScheme::ED25519 {
+            return Err(rustls::Error::PeerMisbehaved(
+                rustls::PeerMisbehaved::SignedHandshakeWithUnadvertisedSigScheme,
+            ));
+        }
+
+        let NodeCertificate { public_key, .. } =
+            parse_node_certificate(certificate).map_err(to_rustls_error)?;
+
+        let public_key = PublicKey::from_bytes(&public_key)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        let signature = Signature::from_bytes(dss.signature())
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadSignature))?;
+
+        public_key
+            .verify(message, &signature)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadSignature))?;
+
+        Ok(HandshakeSignatureValid::assertion())
+    }
+}
+
+impl ServerCertVerifier for NodeIdentityVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let node = self
+            .verify_node_certificate(end_entity)
+            .map_err(to_rustls_error)?;
+
+        // The server has to be the node we wanted to connect to
+        let expected = match server_name {
+            ServerName::DnsName(name) => parse_node_dns_name(name.as_ref()),
+            _ => None,
+        };
+
+        if expected != Some(node) {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName,
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        // Node connections are restricted to TLS 1.3
+        Err(rustls::Error::General(
+            "TLS 1.2 is not supported".to_string(),
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+impl ClientCertVerifier for NodeIdentityVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        self.verify_node_certificate(end_entity)
+            .map_err(to_rustls_error)?;
+
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        // Node connections are restricted to TLS 1.3
+        Err(rustls::Error::General(
+            "TLS 1.2 is not supported".to_string(),
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+impl Debug for NodeIdentityVerifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "NodeIdentityVerifier({} known nodes)",
+            self.directory.len()
+        )
+    }
+}
+
+/// Mutual TLS configs for the nodes `0..count`, which all know each other
+#[cfg(test)]
+pub(crate) fn test_configs(count: u32) -> Vec<MutualTlsConfig> {
+    let (nodes, directory) = crate::socket::identity::test_node_keys(count);
+
+    nodes
+        .into_iter()
+        .map(|(id, key)| MutualTlsConfig::new(id, key, directory.clone()).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::socket::identity::test_node_keys;
+    use crate::socket::{bind_sync_server, connect_sync, SocketConfig};
+
+    fn connected_pair() -> (SyncSocket, SyncSocket) {
+        let config = SocketConfig::default();
+
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || connect_sync(addr, &config).unwrap());
+        let server = listener.accept().unwrap();
+
+        (client.join().unwrap(), server)
+    }
+
+    #[test]
+    fn test_certificate_binds_node_id() {
+        let (nodes, known) = test_node_keys(8);
+        let (id, key) = nodes[7].clone();
+
+        let config = MutualTlsConfig::new(id, key.clone(), known).unwrap();
+
+        let parsed = parse_node_certificate(config.certificate()).unwrap();
+
+        assert_eq!(parsed.node, NodeId(7));
+        assert_eq!(parsed.public_key, key.public_key_bytes());
+    }
+
+    #[test]
+    fn test_mutual_authentication() {
+        let mut configs = test_configs(2);
+        let client_config = configs.pop().unwrap();
+        let server_config = configs.pop().unwrap();
+
+        let (client_sock, server_sock) = connected_pair();
+
+        let client_thread = thread::spawn(move || {
+            let (peer, socket) = client_config.connect_sync(NodeId(0), client_sock).unwrap();
+
+            let (mut write, _read) = socket.split();
+            write.write_all(b"hello").unwrap();
+            write.flush().unwrap();
+
+            peer
+        });
+
+        let (peer, socket) = server_config.accept_sync(server_sock).unwrap();
+
+        assert_eq!(peer, NodeId(1));
+
+        let (_write, mut read) = socket.split();
+
+        let mut buf = [0; 5];
+        read.read_exact(&mut buf).unwrap();
+
+        assert_eq!(&buf, b"hello");
+        assert_eq!(client_thread.join().unwrap(), NodeId(0));
+    }
+
+    #[test]
+    fn test_unknown_and_impersonating_nodes_are_rejected() {
+        let (nodes, known) = test_node_keys(2);
+
+        // The server only knows the legitimate key of node 1
+        let server_config =
+            MutualTlsConfig::new(nodes[0].0, nodes[0].1.clone(), known.clone()).unwrap();
+
+        let impostor_config = MutualTlsConfig::new(
+            NodeId(1),
+            Arc::new(KeyPair::generate_key_pair().unwrap()),
+            known.clone(),
+        )
+        .unwrap();
+
+        let (client_sock, server_sock) = connected_pair();
+
+        let client_thread =
+            thread::spawn(move || impostor_config.connect_sync(NodeId(0), client_sock));
+
+        assert!(server_config.accept_sync(server_sock).is_err());
+
+        let _ = client_thread.join().unwrap();
+
+        // A client that expects another node must refuse the server
+        let client_config = MutualTlsConfig::new(nodes[1].0, nodes[1].1.clone(), known).unwrap();
+
+        let (client_sock, server_sock) = connected_pair();
+
+        let server_thread = thread::spawn(move || server_config.accept_sync(server_sock));
+
+        assert!(client_config.connect_sync(NodeId(2), client_sock).is_err());
+
+        let _ = server_thread.join().unwrap();
+    }
+}
@@ -0,0 +1,1243 @@
+//! Logical streams multiplexed over a single peer connection.
+//!
+//! Every stream has a [StreamPriority], and the connection is shared between them in
+//! frames of at most [MuxConfig::max_frame_payload] bytes: the next frame always comes from
+//! the highest priority stream that has data to send (taking turns between streams with the
+//! same priority). A bulk transfer therefore only delays a latency sensitive message by the
+//! frame that is being sent when it is written.
+//!
+//! Each stream has its own flow control window. A peer can only send as much data on a stream
+//! as the other side has room for, and the window is handed back as the data is read, so a
+//! stream that is not being read never holds up the others.
+//!
+//! Both sides of a connection must use the same [MuxConfig]. The streams opened by
+//! the side that dialed the connection have odd ids, the ones of the listening side even ids.
+//!
+//! Frame layout (big endian):
+//! `| kind: u8 | priority: u8 | stream id: u32 | length: u32 |`, followed by `length` bytes
+//! of payload for data frames. For window updates, the length is the amount of credit given.
+//!
+//! [MuxStream]s implement both the blocking and the async IO traits. A multiplexer created
+//! with [Multiplexer::new_sync] runs its reading and writing on two threads of its own, while one
+//! created with [Multiplexer::new_async] is driven by the future it returns, which has to be polled
+//! (for example, by spawning it) for the streams to make progress.
+//!
+//! Flushing a stream completes once its data was written and flushed to the connection.
+//! When the writer stops, be it because the multiplexer was closed or because the connection
+//! failed, the connection is shut down, which also stops the reader.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context as Cntx, Poll, Waker};
+use std::thread;
+
+use futures::future::{self, Either};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use socket2::Socket;
+
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::socket::{SecureReadHalfAsync, SecureReadHalfSync, SecureSocketAsync, SecureSocketSync};
+use crate::socket::{SecureWriteHalfAsync, SecureWriteHalfSync};
+
+/// Length in bytes of the header of every frame
+const HEADER_LEN: usize = 10;
+
+const KIND_OPEN: u8 = 0;
+const KIND_DATA: u8 = 1;
+const KIND_WINDOW: u8 = 2;
+const KIND_CLOSE: u8 = 3;
+const KIND_RESET: u8 = 4;
+
+/// The default flow control window of every stream (256 KiB)
+const DEFAULT_STREAM_WINDOW: u32 = 256 * 1024;
+
+/// The default maximum payload of a data frame (16 KiB)
+const DEFAULT_MAX_FRAME_PAYLOAD: u32 = 16 * 1024;
+
+/// The default maximum amount of open streams
+const DEFAULT_MAX_STREAMS: usize = 1024;
+
+/// Which side of the connection we are
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MuxSide {
+    /// The side that connected
+    Dialer,
+    /// The side that accepted the connection
+    Listener,
+}
+
+/// The priority of a stream. Data of higher priority streams is always sent first
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub enum StreamPriority {
+    /// Latency sensitive protocol messages
+    High,
+    Normal,
+    /// Transfers that can wait, such as state transfer
+    Bulk,
+}
+
+/// Configuration of the multiplexer
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct MuxConfig {
+    /// How many bytes can be sent on a stream before the receiver reads them.
+    /// This is also how much can be written to a stream before writing blocks
+    pub stream_window: u32,
+    /// The maximum payload of a data frame
+    pub max_frame_payload: u32,
+    /// The maximum amount of open streams, counting the ones opened by both sides
+    pub max_streams: usize,
+}
+
+/// A connection carrying multiple logical streams.
+/// Cloning it gives another handle to the same connection
+#[derive(Clone)]
+pub struct Multiplexer {
+    shared: Arc<Shared>,
+}
+
+/// A logical stream of a [Multiplexer]
+pub struct MuxStream {
+    id: u32,
+    priority: StreamPriority,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<MuxState>,
+    changed: Condvar,
+}
+
+struct MuxState {
+    config: MuxConfig,
+    side: MuxSide,
+    next_id: u32,
+    streams: HashMap<u32, StreamState>,
+    // Streams opened by the peer that were not accepted yet
+    incoming: VecDeque<u32>,
+    // Frames that are sent before any data (stream opens, window updates, resets)
+    control: VecDeque<Vec<u8>>,
+    // The streams with something to send, for each priority
+    ready: [VecDeque<u32>; 3],
+    // The async tasks waiting for the state to change
+    waiters: Vec<Waker>,
+    // Frames handed to the writer, and how many of those it flushed to the connection
+    frames_sent: u64,
+    frames_flushed: u64,
+    shutdown: bool,
+    failed: Option<(ErrorKind, String)>,
+}
+
+struct StreamState {
+    priority: StreamPriority,
+    // Received data not yet read
+    recv: VecDeque<u8>,
+    // How much more the peer is allowed to send
+    recv_window: u32,
+    // Data that was read, but whose window was not given back yet
+    unacked: u32,
+    recv_closed: bool,
+    // Written data not yet sent
+    send: VecDeque<u8>,
+    // The last frame that carried data of this stream
+    last_frame: u64,
+    // How much more we are allowed to send
+    send_credit: u32,
+    closing: bool,
+    close_sent: bool,
+    reset: bool,
+    // Whether the stream is in the ready queue of its priority
+    queued: bool,
+    // Whether the handle of the stream was dropped
+    dropped: bool,
+}
+
+impl Default for MuxConfig {
+    fn default() -> Self {
+        Self {
+            stream_window: DEFAULT_STREAM_WINDOW,
+            max_frame_payload: DEFAULT_MAX_FRAME_PAYLOAD,
+            max_streams: DEFAULT_MAX_STREAMS,
+        }
+    }
+}
+
+impl StreamPriority {
+    fn index(self) -> usize {
+        match self {
+            StreamPriority::High => 0,
+            StreamPriority::Normal => 1,
+            StreamPriority::Bulk => 2,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        self.index() as u8
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(StreamPriority::High),
+            1 => Ok(StreamPriority::Normal),
+            2 => Ok(StreamPriority::Bulk),
+            _ => Err(protocol_error("Unknown stream priority")),
+        }
+    }
+}
+
+fn protocol_error(reason: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+fn header(kind: u8, priority: u8, id: u32, len: u32) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+
+    header[0] = kind;
+    header[1] = priority;
+    header[2..6].copy_from_slice(&id.to_be_bytes());
+    header[6..].copy_from_slice(&len.to_be_bytes());
+
+    header
+}
+
+fn parse_header(header: &[u8; HEADER_LEN]) -> (u8, u8, u32, u32) {
+    (
+        header[0],
+        header[1],
+        u32::from_be_bytes(header[2..6].try_into().unwrap()),
+        u32::from_be_bytes(header[6..].try_into().unwrap()),
+    )
+}
+
+impl StreamState {
+    fn new(priority: StreamPriority, config: &MuxConfig) -> Self {
+        Self {
+            priority,
+            recv: VecDeque::new(),
+            recv_window: config.stream_window,
+            unacked: 0,
+            recv_closed: false,
+            send: VecDeque::new(),
+            last_frame: 0,
+            send_credit: config.stream_window,
+            closing: false,
+            close_sent: false,
+            reset: false,
+            queued: false,
+            dropped: false,
+        }
+    }
+
+    /// Whether there is data we are allowed to send, or a close to send after it
+    fn sendable(&self) -> bool {
+        !self.reset
+            && if self.send.is_empty() {
+                self.closing && !self.close_sent
+            } else {
+                self.send_credit > 0
+            }
+    }
+}
+
+impl MuxState {
+    fn new(side: MuxSide, config: MuxConfig) -> Self {
+        Self {
+            config,
+            side,
+            next_id: match side {
+                MuxSide::Dialer => 1,
+                MuxSide::Listener => 2,
+            },
+            streams: HashMap::new(),
+            incoming: VecDeque::new(),
+            control: VecDeque::new(),
+            ready: Default::default(),
+            waiters: Vec::new(),
+            frames_sent: 0,
+            frames_flushed: 0,
+            shutdown: false,
+            failed: None,
+        }
+    }
+
+    fn connection_error(&self) -> Option<io::Error> {
+        if let Some((kind, reason)) = &self.failed {
+            return Some(io::Error::new(*kind, reason.clone()));
+        }
+
+        if self.shutdown {
+            return Some(io::Error::new(
+                ErrorKind::NotConnected,
+                "The multiplexer was closed",
+            ));
+        }
+
+        None
+    }
+
+    fn stream(&mut self, id: u32) -> &mut StreamState {
+        self.streams
+            .get_mut(&id)
+            .expect("Streams are kept while they have a handle")
+    }
+
+    /// Whether the stream was opened by us
+    fn is_local(&self, id: u32) -> bool {
+        (id % 2 == 1) == (self.side == MuxSide::Dialer)
+    }
+
+    fn open(&mut self, priority: StreamPriority) -> io::Result<u32> {
+        if let Some(err) = self.connection_error() {
+            return Err(err);
+        }
+
+        if self.streams.len() >= self.config.max_streams {
+            return Err(io::Error::new(ErrorKind::Other, "Too many open streams"));
+        }
+
+        let id = self.next_id;
+
+        self.next_id = id
+            .checked_add(2)
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "Ran out of stream ids"))?;
+
+        self.streams
+            .insert(id, StreamState::new(priority, &self.config));
+        self.control
+            .push_back(header(KIND_OPEN, priority.to_byte(), id, 0).to_vec());
+
+        Ok(id)
+    }
+
+    fn accept(&mut self) -> Option<io::Result<(u32, StreamPriority)>> {
+        if let Some(id) = self.incoming.pop_front() {
+            return Some(Ok((id, self.stream(id).priority)));
+        }
+
+        self.connection_error().map(Err)
+    }
+
+    fn read(&mut self, id: u32, buf: &mut [u8]) -> Option<io::Result<usize>> {
+        let window = self.config.stream_window;
+        let failed = self.connection_error();
+        let stream = self.stream(id);
+
+        if buf.is_empty() {
+            return Some(Ok(0));
+        }
+
+        if !stream.recv.is_empty() {
+            let len = buf.len().min(stream.recv.len());
+
+            for (byte, received) in buf.iter_mut().zip(stream.recv.drain(..len)) {
+                *byte = received;
+            }
+
+            stream.unacked += len as u32;
+
+            // Hand the window back in batches, instead of a frame per read
+            if stream.unacked >= window / 2 && !stream.recv_closed {
+                let credit = std::mem::take(&mut stream.unacked);
+
+                stream.recv_window += credit;
+
+                self.control
+                    .push_back(header(KIND_WINDOW, 0, id, credit).to_vec());
+            }
+
+            return Some(Ok(len));
+        }
+
+        if stream.recv_closed {
+            return Some(Ok(0));
+        }
+
+        if stream.reset {
+            return Some(Err(ErrorKind::ConnectionReset.into()));
+        }
+
+        failed.map(Err)
+    }
+
+    fn write(&mut self, id: u32, buf: &[u8]) -> Option<io::Result<usize>> {
+        let limit = self.config.stream_window as usize;
+        let failed = self.connection_error();
+        let stream = self.stream(id);
+
+        if stream.reset {
+            return Some(Err(ErrorKind::ConnectionReset.into()));
+        }
+
+        if let Some(err) = failed {
+            return Some(Err(err));
+        }
+
+        if stream.closing {
+            return Some(Err(ErrorKind::BrokenPipe.into()));
+        }
+
+        if buf.is_empty() {
+            return Some(Ok(0));
+        }
+
+        if stream.send.len() >= limit {
+            return None;
+        }
+
+        let len = buf.len().min(limit - stream.send.len());
+
+        stream.send.extend(&buf[..len]);
+
+        self.schedule(id);
+
+        Some(Ok(len))
+    }
+
+    /// Done once everything written to the stream was sent, and the writer flushed it
+    fn flushed(&mut self, id: u32) -> Option<io::Result<()>> {
+        let failed = self.connection_error();
+        let frames_flushed = self.frames_flushed;
+        let stream = self.stream(id);
+
+        if stream.reset {
+            return Some(Err(ErrorKind::ConnectionReset.into()));
+        }
+
+        if stream.send.is_empty() && stream.last_frame <= frames_flushed {
+            return Some(Ok(()));
+        }
+
+        failed.map(Err)
+    }
+
+    /// Done once the stream was closed on our side, after everything written to it was sent
+    fn close(&mut self, id: u32) -> Option<io::Result<()>> {
+        let failed = self.connection_error();
+        let stream = self.stream(id);
+
+        if stream.reset {
+            return Some(Err(ErrorKind::ConnectionReset.into()));
+        }
+
+        if stream.close_sent {
+            return Some(Ok(()));
+        }
+
+        if let Some(err) = failed {
+            return Some(Err(err));
+        }
+
+        stream.closing = true;
+
+        self.schedule(id);
+
+        None
+    }
+
+    fn drop_stream(&mut self, id: u32) {
+        let stream = self.stream(id);
+
+        stream.dropped = true;
+        stream.closing = true;
+
+        // Nobody will read what is left, so give its window back
+        let unread = stream.recv.len() as u32 + std::mem::take(&mut stream.unacked);
+
+        stream.recv.clear();
+
+        if unread > 0 && !stream.recv_closed {
+            self.control
+                .push_back(header(KIND_WINDOW, 0, id, unread).to_vec());
+        }
+
+        self.schedule(id);
+        self.remove_if_done(id);
+    }
+
+    fn remove_if_done(&mut self, id: u32) {
+        let done = self.streams.get(&id).is_some_and(|stream| {
+            stream.dropped && (stream.reset || (stream.close_sent && stream.recv_closed))
+        });
+
+        if done {
+            self.streams.remove(&id);
+        }
+    }
+
+    /// Put the stream in the ready queue of its priority, if it has something to send
+    fn schedule(&mut self, id: u32) {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+
+        if !stream.queued && stream.sendable() {
+            stream.queued = true;
+
+            self.ready[stream.priority.index()].push_back(id);
+        }
+    }
+
+    /// The next frame to send, if there is one
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if let Some(frame) = self.control.pop_front() {
+            self.frames_sent += 1;
+
+            return Some(frame);
+        }
+
+        let max_payload = self.config.max_frame_payload as usize;
+
+        for priority in 0..self.ready.len() {
+            while let Some(id) = self.ready[priority].pop_front() {
+                let Some(stream) = self.streams.get_mut(&id) else {
+                    continue;
+                };
+
+                stream.queued = false;
+
+                if !stream.sendable() {
+                    continue;
+                }
+
+                self.frames_sent += 1;
+                stream.last_frame = self.frames_sent;
+
+                let frame = if stream.send.is_empty() {
+                    stream.close_sent = true;
+
+                    header(KIND_CLOSE, 0, id, 0).to_vec()
+                } else {
+                    let len = stream
+                        .send
+                        .len()
+                        .min(stream.send_credit as usize)
+                        .min(max_payload);
+
+                    stream.send_credit -= len as u32;
+
+                    let mut frame = Vec::with_capacity(HEADER_LEN + len);
+
+                    frame.extend_from_slice(&header(KIND_DATA, 0, id, len as u32));
+                    frame.extend(stream.send.drain(..len));
+
+                    frame
+                };
+
+                // Back to the end of the queue, so streams of the same priority take turns
+                self.schedule(id);
+                self.remove_if_done(id);
+
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
+    /// The writer flushed every frame it was handed
+    fn writer_flushed(&mut self) {
+        self.frames_flushed = self.frames_sent;
+    }
+
+    /// Done once there is something to send (`true`) or the writer should stop (`false`)
+    fn writer_state(&mut self) -> Option<bool> {
+        if self.failed.is_some() {
+            return Some(false);
+        }
+
+        if !self.control.is_empty() || self.ready.iter().any(|ready| !ready.is_empty()) {
+            return Some(true);
+        }
+
+        // Data held back by the window of its stream is sent once the peer hands it back
+        let held_back = self
+            .streams
+            .values()
+            .any(|stream| !stream.reset && !stream.send.is_empty());
+
+        (self.shutdown && !held_back).then_some(false)
+    }
+
+    fn handle_frame(
+        &mut self,
+        kind: u8,
+        priority: u8,
+        id: u32,
+        len: u32,
+        payload: Vec<u8>,
+    ) -> io::Result<()> {
+        match kind {
+            KIND_OPEN => {
+                let priority = StreamPriority::from_byte(priority)?;
+
+                if self.is_local(id) || self.streams.contains_key(&id) {
+                    return Err(protocol_error("The peer opened an invalid stream"));
+                }
+
+                if self.streams.len() >= self.config.max_streams || self.shutdown {
+                    self.control
+                        .push_back(header(KIND_RESET, 0, id, 0).to_vec());
+
+                    return Ok(());
+                }
+
+                self.streams
+                    .insert(id, StreamState::new(priority, &self.config));
+                self.incoming.push_back(id);
+            }
+            KIND_DATA => {
+                // A stream we refused
+                let Some(stream) = self.streams.get_mut(&id) else {
+                    return Ok(());
+                };
+
+                if stream.recv_closed {
+                    return Err(protocol_error("The peer sent data after closing a stream"));
+                }
+
+                if len > stream.recv_window {
+                    return Err(protocol_error("The peer exceeded the window of a stream"));
+                }
+
+                if stream.dropped || stream.reset {
+                    self.control
+                        .push_back(header(KIND_WINDOW, 0, id, len).to_vec());
+                } else {
+                    stream.recv_window -= len;
+                    stream.recv.extend(payload);
+                }
+            }
+            KIND_WINDOW => {
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.send_credit = stream.send_credit.saturating_add(len);
+
+                    self.schedule(id);
+                }
+            }
+            KIND_CLOSE => {
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.recv_closed = true;
+                }
+
+                self.remove_if_done(id);
+            }
+            KIND_RESET => {
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.reset = true;
+                    stream.send.clear();
+                }
+
+                self.remove_if_done(id);
+            }
+            _ => return Err(protocol_error("Unknown multiplexer frame")),
+        }
+
+        Ok(())
+    }
+
+    /// Check the header of a frame before its payload is read.
+    /// Returns the length of the payload
+    fn payload_len(&self, kind: u8, len: u32) -> io::Result<usize> {
+        match kind {
+            KIND_DATA if len > self.config.max_frame_payload => {
+                Err(protocol_error("Multiplexer frame exceeds the maximum size"))
+            }
+            KIND_DATA => Ok(len as usize),
+            _ => Ok(0),
+        }
+    }
+
+    fn fail(&mut self, result: &io::Result<()>) {
+        if self.failed.is_some() {
+            return;
+        }
+
+        self.failed = Some(match result {
+            Ok(()) => (
+                ErrorKind::ConnectionAborted,
+                "The multiplexed connection was closed".to_string(),
+            ),
+            Err(err) => (err.kind(), err.to_string()),
+        });
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, MuxState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wake everyone that is waiting, so they check whether they can make progress
+    fn notify(&self, state: &mut MuxState) {
+        for waker in state.waiters.drain(..) {
+            waker.wake();
+        }
+
+        self.changed.notify_all();
+    }
+
+    fn update<T>(&self, op: impl FnOnce(&mut MuxState) -> T) -> T {
+        let mut state = self.lock();
+
+        let result = op(&mut state);
+
+        self.notify(&mut state);
+
+        result
+    }
+
+    /// Block until `op` is done
+    fn wait<T>(&self, mut op: impl FnMut(&mut MuxState) -> Option<T>) -> T {
+        let mut state = self.lock();
+
+        loop {
+            if let Some(result) = op(&mut state) {
+                self.notify(&mut state);
+
+                return result;
+            }
+
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// The async counterpart of [Self::wait]
+    fn poll<T>(&self, cx: &mut Cntx<'_>, op: impl FnOnce(&mut MuxState) -> Option<T>) -> Poll<T> {
+        let mut state = self.lock();
+
+        match op(&mut state) {
+            Some(result) => {
+                self.notify(&mut state);
+
+                Poll::Ready(result)
+            }
+            None => {
+                state.waiters.push(cx.waker().clone());
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Multiplexer {
+    fn new(side: MuxSide, config: MuxConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(MuxState::new(side, config)),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Multiplex the given connection, reading and writing it on threads of its own
+    pub fn new_sync(
+        socket: SecureSocketSync,
+        side: MuxSide,
+        config: MuxConfig,
+    ) -> io::Result<Self> {
+        let mux = Self::new(side, config);
+
+        // Safety: the descriptor is owned by `socket`, which outlives this borrow
+        let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+
+        // A descriptor of its own, as the read half may already be dropped when the writer stops
+        let connection = Socket::from(fd.try_clone_to_owned()?);
+
+        let (write, read) = socket.split();
+
+        let shared = mux.shared.clone();
+
+        thread::Builder::new()
+            .name("Atlas-Mux-Reader".to_string())
+            .spawn(move || {
+                let result = read_loop_sync(&shared, read);
+
+                shared.update(|state| state.fail(&result));
+            })?;
+
+        let shared = mux.shared.clone();
+
+        thread::Builder::new()
+            .name("Atlas-Mux-Writer".to_string())
+            .spawn(move || {
+                let result = write_loop_sync(&shared, write);
+
+                shared.update(|state| state.fail(&result));
+
+                // Otherwise the reader stays blocked on the connection forever
+                let _ = connection.shutdown(Shutdown::Both);
+            })?;
+
+        Ok(mux)
+    }
+
+    /// Multiplex the given connection. The returned future drives the connection,
+    /// and completes once it is closed
+    pub fn new_async(
+        socket: SecureSocketAsync,
+        side: MuxSide,
+        config: MuxConfig,
+    ) -> (Self, impl Future<Output = io::Result<()>> + Send + 'static) {
+        let mux = Self::new(side, config);
+        let (write, read) = socket.split();
+
+        let shared = mux.shared.clone();
+
+        let driver = async move {
+            let reader = read_loop_async(&shared, read);
+            let writer = write_loop_async(&shared, write);
+
+            futures::pin_mut!(reader, writer);
+
+            let result = match future::select(reader, writer).await {
+                Either::Left((result, _)) | Either::Right((result, _)) => result,
+            };
+
+            shared.update(|state| state.fail(&result));
+
+            result
+        };
+
+        (mux, driver)
+    }
+
+    /// Open a new stream with the given priority
+    pub fn open(&self, priority: StreamPriority) -> io::Result<MuxStream> {
+        let id = self.shared.update(|state| state.open(priority))?;
+
+        Ok(self.stream(id, priority))
+    }
+
+    /// Accept the next stream opened by the peer, blocking until there is one
+    pub fn accept(&self) -> io::Result<MuxStream> {
+        let (id, priority) = self.shared.wait(MuxState::accept)?;
+
+        Ok(self.stream(id, priority))
+    }
+
+    /// The async counterpart of [Self::accept]
+    pub async fn accept_async(&self) -> io::Result<MuxStream> {
+        let (id, priority) = future::poll_fn(|cx| self.shared.poll(cx, MuxState::accept)).await?;
+
+        Ok(self.stream(id, priority))
+    }
+
+    /// Stop the multiplexer, once the data already written to the streams is sent.
+    /// Data the peer has no window for yet keeps the connection open until it reads it
+    pub fn close(&self) {
+        self.shared.update(|state| state.shutdown = true);
+    }
+
+    fn stream(&self, id: u32, priority: StreamPriority) -> MuxStream {
+        MuxStream {
+            id,
+            priority,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn priority(&self) -> StreamPriority {
+        self.priority
+    }
+
+    /// Close our side of the stream, blocking until everything written to it was sent.
+    /// The peer reads the end of the stream after the data
+    pub fn finish(&mut self) -> io::Result<()> {
+        let id = self.id;
+
+        self.shared.wait(|state| state.close(id))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let id = self.id;
+
+        self.shared.update(|state| state.drop_stream(id));
+    }
+}
+
+impl Read for MuxStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let id = self.id;
+
+        self.shared.wait(|state| state.read(id, buf))
+    }
+}
+
+impl Write for MuxStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let id = self.id;
+
+        self.shared.wait(|state| state.write(id, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let id = self.id;
+
+        self.shared.wait(|state| state.flushed(id))
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let id = self.id;
+
+        self.shared.poll(cx, |state| state.read(id, buf))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Cntx<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let id = self.id;
+
+        self.shared.poll(cx, |state| state.write(id, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let id = self.id;
+
+        self.shared.poll(cx, |state| state.flushed(id))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let id = self.id;
+
+        self.shared.poll(cx, |state| state.close(id))
+    }
+}
+
+fn read_loop_sync(shared: &Shared, mut read: SecureReadHalfSync) -> io::Result<()> {
+    let mut header = [0; HEADER_LEN];
+
+    loop {
+        match read.read_exact(&mut header) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+
+        let (kind, priority, id, len) = parse_header(&header);
+
+        let mut payload = vec![0; shared.lock().payload_len(kind, len)?];
+
+        read.read_exact(&mut payload)?;
+
+        shared.update(|state| state.handle_frame(kind, priority, id, len, payload))?;
+    }
+}
+
+fn write_loop_sync(shared: &Shared, mut write: SecureWriteHalfSync) -> io::Result<()> {
+    loop {
+        match shared.update(MuxState::next_frame) {
+            Some(frame) => write.write_all(&frame)?,
+            None => {
+                // Only flush once there is nothing else to send
+                write.flush()?;
+
+                shared.update(MuxState::writer_flushed);
+
+                if !shared.wait(MuxState::writer_state) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn read_loop_async(shared: &Shared, mut read: SecureReadHalfAsync) -> io::Result<()> {
+    let mut header = [0; HEADER_LEN];
+
+    loop {
+        match read.read_exact(&mut header).await {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+
+        let (kind, priority, id, len) = parse_header(&header);
+
+        let mut payload = vec![0; shared.lock().payload_len(kind, len)?];
+
+        read.read_exact(&mut payload).await?;
+
+        shared.update(|state| state.handle_frame(kind, priority, id, len, payload))?;
+    }
+}
+
+async fn write_loop_async(shared: &Shared, mut write: SecureWriteHalfAsync) -> io::Result<()> {
+    loop {
+        let next = shared.update(MuxState::next_frame);
+
+        match next {
+            Some(frame) => write.write_all(&frame).await?,
+            None => {
+                write.flush().await?;
+
+                shared.update(MuxState::writer_flushed);
+
+                if !future::poll_fn(|cx| shared.poll(cx, MuxState::writer_state)).await {
+                    return write.close().await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::thread;
+
+    use super::{
+        parse_header, Multiplexer, MuxConfig, MuxSide, MuxState, StreamPriority,
+        DEFAULT_MAX_FRAME_PAYLOAD, HEADER_LEN, KIND_DATA,
+    };
+    use crate::socket::{
+        bind_async_server, bind_sync_server, connect_async, connect_sync, SecureSocketAsync,
+        SecureSocketSync, SocketConfig,
+    };
+
+    fn connected_sync(config: MuxConfig) -> (Multiplexer, Multiplexer) {
+        let socket_config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &socket_config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let dialer = connect_sync(addr, &socket_config).unwrap();
+        let accepted = listener.accept().unwrap();
+
+        (
+            Multiplexer::new_sync(SecureSocketSync::new_plain(dialer), MuxSide::Dialer, config)
+                .unwrap(),
+            Multiplexer::new_sync(
+                SecureSocketSync::new_plain(accepted),
+                MuxSide::Listener,
+                config,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_higher_priorities_are_sent_first() {
+        let mut state = MuxState::new(MuxSide::Dialer, MuxConfig::default());
+
+        let bulk = state.open(StreamPriority::Bulk).unwrap();
+        let high = state.open(StreamPriority::High).unwrap();
+
+        // The opens go out first
+        assert!(state.next_frame().is_some());
+        assert!(state.next_frame().is_some());
+
+        state.write(bulk, &[0; 100_000]).unwrap().unwrap();
+        state.write(high, b"urgent").unwrap().unwrap();
+
+        let frame = state.next_frame().unwrap();
+        let (kind, _, id, len) = parse_header(frame[..HEADER_LEN].try_into().unwrap());
+
+        assert_eq!((kind, id, len), (KIND_DATA, high, 6));
+
+        let frame = state.next_frame().unwrap();
+        let (_, _, id, len) = parse_header(frame[..HEADER_LEN].try_into().unwrap());
+
+        assert_eq!((id, len), (bulk, DEFAULT_MAX_FRAME_PAYLOAD));
+    }
+
+    #[test]
+    fn test_flush_waits_for_the_writer() {
+        let mut state = MuxState::new(MuxSide::Dialer, MuxConfig::default());
+
+        let id = state.open(StreamPriority::Normal).unwrap();
+        state.write(id, b"data").unwrap().unwrap();
+
+        assert!(state.flushed(id).is_none());
+
+        // The open and the data frame were handed to the writer, but not flushed yet
+        assert!(state.next_frame().is_some());
+        assert!(state.next_frame().is_some());
+        assert!(state.flushed(id).is_none());
+
+        state.writer_flushed();
+        assert!(state.flushed(id).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_close_shuts_the_connection_down() {
+        let (dialer, listener) = connected_sync(MuxConfig::default());
+
+        dialer.close();
+
+        // The peer only notices once the connection is shut down
+        assert!(listener.accept().is_err());
+        assert!(dialer.open(StreamPriority::Normal).is_err());
+    }
+
+    #[test]
+    fn test_close_sends_the_data_held_back_by_the_window() {
+        let config = MuxConfig {
+            stream_window: 4 * 1024,
+            max_frame_payload: 1024,
+            ..Default::default()
+        };
+
+        let (dialer, listener) = connected_sync(config);
+
+        let mut stream = dialer.open(StreamPriority::Normal).unwrap();
+        let payload: Vec<u8> = (0..8 * 1024).map(|i| i as u8).collect();
+
+        // Twice the window, so half of it waits for the peer to read the rest
+        stream.write_all(&payload).unwrap();
+        drop(stream);
+
+        dialer.close();
+
+        let mut accepted = listener.accept().unwrap();
+
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).unwrap();
+        assert_eq!(received, payload);
+    }
+
+    #[test]
+    fn test_streams_are_independent() {
+        let (dialer, listener) = connected_sync(MuxConfig::default());
+
+        let mut first = dialer.open(StreamPriority::Normal).unwrap();
+        let mut second = dialer.open(StreamPriority::High).unwrap();
+
+        first.write_all(b"first").unwrap();
+        second.write_all(b"second").unwrap();
+        first.finish().unwrap();
+        second.finish().unwrap();
+
+        let mut accepted_first = listener.accept().unwrap();
+        let mut accepted_second = listener.accept().unwrap();
+
+        assert_eq!(accepted_first.priority(), StreamPriority::Normal);
+        assert_eq!(accepted_second.priority(), StreamPriority::High);
+
+        let mut received = Vec::new();
+        accepted_second.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"second");
+
+        received.clear();
+        accepted_first.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"first");
+    }
+
+    #[test]
+    fn test_unread_bulk_does_not_block_other_streams() {
+        let config = MuxConfig {
+            stream_window: 4 * 1024,
+            max_frame_payload: 1024,
+            ..Default::default()
+        };
+
+        let (dialer, listener) = connected_sync(config);
+
+        let mut bulk = dialer.open(StreamPriority::Bulk).unwrap();
+        let payload: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+
+        let sender = {
+            let payload = payload.clone();
+
+            thread::spawn(move || {
+                bulk.write_all(&payload).unwrap();
+                bulk.finish().unwrap();
+            })
+        };
+
+        let mut accepted_bulk = listener.accept().unwrap();
+
+        // The bulk stream is stuck on its window, yet other streams still get through
+        let mut high = dialer.open(StreamPriority::High).unwrap();
+        high.write_all(b"ping").unwrap();
+        high.flush().unwrap();
+
+        let mut accepted_high = listener.accept().unwrap();
+        let mut message = [0; 4];
+        accepted_high.read_exact(&mut message).unwrap();
+        assert_eq!(&message, b"ping");
+
+        let mut received = Vec::new();
+        accepted_bulk.read_to_end(&mut received).unwrap();
+        assert_eq!(received, payload);
+
+        sender.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_round_trip() {
+        let socket_config = SocketConfig::default();
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &socket_config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (dialer, accepted) =
+            tokio::join!(connect_async(addr, &socket_config), listener.accept());
+
+        let (dialer, dialer_driver) = Multiplexer::new_async(
+            SecureSocketAsync::new_plain(dialer.unwrap()),
+            MuxSide::Dialer,
+            MuxConfig::default(),
+        );
+        let (listener, listener_driver) = Multiplexer::new_async(
+            SecureSocketAsync::new_plain(accepted.unwrap()),
+            MuxSide::Listener,
+            MuxConfig::default(),
+        );
+
+        tokio::spawn(dialer_driver);
+        tokio::spawn(listener_driver);
+
+        // Streams implement both IO trait families, so the async ones are named explicitly
+        let mut stream = dialer.open(StreamPriority::Normal).unwrap();
+        futures::AsyncWriteExt::write_all(&mut stream, b"request")
+            .await
+            .unwrap();
+        futures::AsyncWriteExt::flush(&mut stream).await.unwrap();
+
+        let mut accepted = listener.accept_async().await.unwrap();
+        let mut request = [0; 7];
+        futures::AsyncReadExt::read_exact(&mut accepted, &mut request)
+            .await
+            .unwrap();
+        assert_eq!(&request, b"request");
+
+        futures::AsyncWriteExt::write_all(&mut accepted, b"reply")
+            .await
+            .unwrap();
+        futures::AsyncWriteExt::close(&mut accepted).await.unwrap();
+
+        let mut reply = Vec::new();
+        futures::AsyncReadExt::read_to_end(&mut stream, &mut reply)
+            .await
+            .unwrap();
+        assert_eq!(reply, b"reply");
+
+        dialer.close();
+        listener.close();
+    }
+}
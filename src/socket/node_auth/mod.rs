@@ -0,0 +1,686 @@
+//! Authentication of the node on the other end of a plain socket.
+//!
+//! Plain sockets carry no identity, so this optional handshake lets each side
+//! prove which node it is. Both sides send a hello with their [NodeId], their
+//! [NodeType] and a random nonce, and then reply with a signature (made with their
+//! node key) over both hellos. Each signature is checked against the keys in the
+//! [NodeKeyDirectory], so a node can only claim an identity whose key it owns, and
+//! since the peer's fresh nonce is signed, old handshakes can't be replayed.
+//! The claimed node type must match the one registered for the node in the directory.
+//!
+//! The handshake must complete within the configured timeout, so a peer that
+//! connects and then stays silent can't hold the connection (or a thread) forever.
+//!
+//! The handshake runs over plain sockets as well as over [WebSocket](crate::socket::websocket)
+//! streams.
+//!
+//! This only authenticates the peer at connection time. The traffic that follows is
+//! neither encrypted nor integrity protected, so use TLS or Noise when that matters.
+//!
+//! Wire layout of the hello (big endian):
+//! `| magic: u16 | version: u16 | node id: u32 | node type: u8 | nonce: [u8; 32] |`,
+//! followed by the signature once the peer's hello has been received.
+
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use thiserror::Error;
+
+use crate::crypto::signature::{KeyPair, Signature};
+use crate::error::*;
+use crate::node_id::{NodeId, NodeType};
+use crate::socket::deadline::{with_timeout, DeadlineSocket, DEFAULT_HANDSHAKE_TIMEOUT};
+use crate::socket::identity::NodeKeyDirectory;
+use crate::socket::{
+    SecureReadHalfAsync, SecureReadHalfSync, SecureSocketAsync, SecureSocketSync,
+    SecureWriteHalfAsync, SecureWriteHalfSync,
+};
+use crate::Err;
+
+/// The magic number that starts every hello
+const HELLO_MAGIC: u16 = 0xA7A5;
+
+/// The current version of the handshake
+const HELLO_VERSION: u16 = 1;
+
+const NONCE_LEN: usize = 32;
+
+const HELLO_LEN: usize = 2 + 2 + 4 + 1 + NONCE_LEN;
+
+/// The context of the handshake signatures,
+/// so they can't be mistaken for any other signature made with the node key
+const SIGNATURE_CONTEXT: &[u8] = b"atlas-node-auth-v1";
+
+/// The name of the handshake, for its timeout error
+const HANDSHAKE: &str = "node authentication";
+
+#[derive(Error, Debug)]
+pub enum NodeAuthError {
+    #[error("Invalid hello magic {0:#x}")]
+    InvalidMagic(u16),
+    #[error("Unsupported handshake version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Unknown node type {0}")]
+    UnknownNodeType(u8),
+    #[error("The peer sent back our own nonce")]
+    ReflectedNonce,
+    #[error("Node {0:?} is not in the key directory")]
+    UnknownNode(NodeId),
+    #[error("Node {0:?} has no registered node type")]
+    UnregisteredNodeType(NodeId),
+    #[error("Node {node:?} claims to be a {claimed:?}, but is registered as a {registered:?}")]
+    UnexpectedNodeType {
+        node: NodeId,
+        claimed: NodeType,
+        registered: NodeType,
+    },
+    #[error("The handshake of node {0:?} is not signed by its node key")]
+    InvalidSignature(NodeId),
+    #[error("Expected to be talking to {expected:?}, but the peer is {found:?}")]
+    UnexpectedPeer { expected: NodeId, found: NodeId },
+}
+
+/// The identity of a peer, as proven by the handshake
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AuthenticatedPeer {
+    pub node_id: NodeId,
+    pub node_type: NodeType,
+}
+
+/// The configuration used to authenticate plain connections with other nodes
+pub struct NodeAuthConfig {
+    node_id: NodeId,
+    node_type: NodeType,
+    key_pair: Arc<KeyPair>,
+    directory: NodeKeyDirectory,
+    handshake_timeout: Option<Duration>,
+}
+
+/// The halves of an authenticated synchronous connection
+pub type AuthenticatedSync = (AuthenticatedPeer, SecureWriteHalfSync, SecureReadHalfSync);
+
+/// The halves of an authenticated asynchronous connection
+pub type AuthenticatedAsync = (AuthenticatedPeer, SecureWriteHalfAsync, SecureReadHalfAsync);
+
+/// The hello of one of the sides of the handshake
+struct Hello {
+    bytes: [u8; HELLO_LEN],
+    peer: AuthenticatedPeer,
+}
+
+impl NodeAuthConfig {
+    /// The type of every node we authenticate must be registered in the `directory`
+    /// (see [NodeKeyDirectory::insert_with_type])
+    pub fn new(
+        node_id: NodeId,
+        node_type: NodeType,
+        key_pair: Arc<KeyPair>,
+        directory: NodeKeyDirectory,
+    ) -> Self {
+        Self {
+            node_id,
+            node_type,
+            key_pair,
+            directory,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+        }
+    }
+
+    /// Change the time a handshake has to complete. `None` waits for the peer forever
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn node_type(&self) -> NodeType {
+        self.node_type
+    }
+
+    /// Authenticate a node that connected to us, returning its identity
+    /// along with the halves of the connection.
+    ///
+    /// The socket is either a [SyncSocket](crate::socket::SyncSocket) or a
+    /// [WebSocketStream](crate::socket::WebSocketStream) over one
+    pub fn accept_sync<S>(&self, socket: S) -> Result<AuthenticatedSync>
+    where
+        S: Read + Write + AsRawFd + Into<SecureSocketSync>,
+    {
+        self.authenticate_sync(None, socket)
+    }
+
+    /// Authenticate the node `peer`, which we connected to
+    pub fn connect_sync<S>(&self, peer: NodeId, socket: S) -> Result<AuthenticatedSync>
+    where
+        S: Read + Write + AsRawFd + Into<SecureSocketSync>,
+    {
+        self.authenticate_sync(Some(peer), socket)
+    }
+
+    /// Authenticate a node that connected to us, returning its identity
+    /// along with the halves of the connection.
+    ///
+    /// The socket is either an [AsyncSocket](crate::socket::AsyncSocket) or a
+    /// [WebSocketStream](crate::socket::WebSocketStream) over one
+    pub async fn accept_async<S>(&self, socket: S) -> Result<AuthenticatedAsync>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Into<SecureSocketAsync>,
+    {
+        self.authenticate_async(None, socket).await
+    }
+
+    /// Authenticate the node `peer`, which we connected to
+    pub async fn connect_async<S>(&self, peer: NodeId, socket: S) -> Result<AuthenticatedAsync>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Into<SecureSocketAsync>,
+    {
+        self.authenticate_async(Some(peer), socket).await
+    }
+
+    fn authenticate_sync<S>(
+        &self,
+        expected: Option<NodeId>,
+        mut socket: S,
+    ) -> Result<AuthenticatedSync>
+    where
+        S: Read + Write + AsRawFd + Into<SecureSocketSync>,
+    {
+        let mut deadline = DeadlineSocket::new(&mut socket, self.handshake_timeout, HANDSHAKE);
+
+        let result = self.handshake_sync(expected, &mut deadline);
+
+        deadline.finish()?;
+
+        let theirs = result?;
+
+        let (write, read) = socket.into().split();
+
+        Ok((theirs.peer, write, read))
+    }
+
+    async fn authenticate_async<S>(
+        &self,
+        expected: Option<NodeId>,
+        mut socket: S,
+    ) -> Result<AuthenticatedAsync>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Into<SecureSocketAsync>,
+    {
+        let handshake = self.handshake_async(expected, &mut socket);
+
+        let theirs = with_timeout(self.handshake_timeout, HANDSHAKE, handshake).await?;
+
+        let (write, read) = socket.into().split();
+
+        Ok((theirs.peer, write, read))
+    }
+
+    /// Exchange and verify the hellos, returning the peer's
+    fn handshake_sync<S>(&self, expected: Option<NodeId>, socket: &mut S) -> Result<Hello>
+    where
+        S: Read + Write,
+    {
+        let ours = self.hello();
+
+        // Both sides send their hello before reading, so there is no need to
+        // know which side speaks first
+        socket.write_all(&ours.bytes)?;
+        socket.flush()?;
+
+        let mut theirs = [0; HELLO_LEN];
+        socket.read_exact(&mut theirs)?;
+
+        let theirs = self.parse_hello(&ours, theirs, expected)?;
+
+        socket.write_all(self.sign(&ours, &theirs)?.as_ref())?;
+        socket.flush()?;
+
+        let mut signature = [0; Signature::LENGTH];
+        socket.read_exact(&mut signature)?;
+
+        self.verify(&ours, &theirs, &signature)?;
+
+        Ok(theirs)
+    }
+
+    async fn handshake_async<S>(&self, expected: Option<NodeId>, socket: &mut S) -> Result<Hello>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let ours = self.hello();
+
+        socket.write_all(&ours.bytes).await?;
+        socket.flush().await?;
+
+        let mut theirs = [0; HELLO_LEN];
+        socket.read_exact(&mut theirs).await?;
+
+        let theirs = self.parse_hello(&ours, theirs, expected)?;
+
+        socket
+            .write_all(self.sign(&ours, &theirs)?.as_ref())
+            .await?;
+        socket.flush().await?;
+
+        let mut signature = [0; Signature::LENGTH];
+        socket.read_exact(&mut signature).await?;
+
+        self.verify(&ours, &theirs, &signature)?;
+
+        Ok(theirs)
+    }
+
+    fn hello(&self) -> Hello {
+        let mut bytes = [0; HELLO_LEN];
+
+        bytes[0..2].copy_from_slice(&HELLO_MAGIC.to_be_bytes());
+        bytes[2..4].copy_from_slice(&HELLO_VERSION.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.node_id.id().to_be_bytes());
+        bytes[8] = node_type_to_byte(self.node_type);
+
+        OsRng.fill_bytes(&mut bytes[9..]);
+
+        Hello {
+            bytes,
+            peer: AuthenticatedPeer {
+                node_id: self.node_id,
+                node_type: self.node_type,
+            },
+        }
+    }
+
+    /// Parse the hello sent by the peer. The identity it claims is
+    /// only trusted once its signature has been verified
+    fn parse_hello(
+        &self,
+        ours: &Hello,
+        bytes: [u8; HELLO_LEN],
+        expected: Option<NodeId>,
+    ) -> Result<Hello> {
+        let magic = u16::from_be_bytes([bytes[0], bytes[1]]);
+
+        if magic != HELLO_MAGIC {
+            return Err!(NodeAuthError::InvalidMagic(magic));
+        }
+
+        let version = u16::from_be_bytes([bytes[2], bytes[3]]);
+
+        if version != HELLO_VERSION {
+            return Err!(NodeAuthError::UnsupportedVersion(version));
+        }
+
+        let node_id = NodeId(u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]));
+
+        let node_type = node_type_from_byte(bytes[8])?;
+
+        if bytes[9..] == ours.bytes[9..] {
+            return Err!(NodeAuthError::ReflectedNonce);
+        }
+
+        if let Some(expected) = expected {
+            if expected != node_id {
+                return Err!(NodeAuthError::UnexpectedPeer {
+                    expected,
+                    found: node_id,
+                });
+            }
+        }
+
+        if !self.directory.contains(&node_id) {
+            return Err!(NodeAuthError::UnknownNode(node_id));
+        }
+
+        // The claimed type is signed along with the rest of the hello,
+        // but only the directory can tell whether the node is entitled to it
+        match self.directory.node_type(&node_id) {
+            Some(registered) if registered == node_type => {}
+            Some(registered) => {
+                return Err!(NodeAuthError::UnexpectedNodeType {
+                    node: node_id,
+                    claimed: node_type,
+                    registered,
+                })
+            }
+            None => return Err!(NodeAuthError::UnregisteredNodeType(node_id)),
+        }
+
+        Ok(Hello {
+            bytes,
+            peer: AuthenticatedPeer { node_id, node_type },
+        })
+    }
+
+    fn sign(&self, ours: &Hello, theirs: &Hello) -> Result<Signature> {
+        self.key_pair.sign(&signed_message(ours, theirs))
+    }
+
+    /// Check that the peer signed both hellos (its own first) with its node key
+    fn verify(&self, ours: &Hello, theirs: &Hello, signature: &[u8]) -> Result<()> {
+        let node = theirs.peer.node_id;
+
+        let key = self
+            .directory
+            .get(&node)
+            .ok_or(NodeAuthError::UnknownNode(node))?;
+
+        let signature =
+            Signature::from_bytes(signature).map_err(|_| NodeAuthError::InvalidSignature(node))?;
+
+        key.verify(&signed_message(theirs, ours), &signature)
+            .map_err(|_| NodeAuthError::InvalidSignature(node))?;
+
+        Ok(())
+    }
+}
+
+/// The message signed by `signer`, binding its identity to the nonce of the other side
+fn signed_message(signer: &Hello, other: &Hello) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, &signer.bytes, &other.bytes].concat()
+}
+
+fn node_type_to_byte(node_type: NodeType) -> u8 {
+    match node_type {
+        NodeType::Replica => 0,
+        NodeType::Client => 1,
+    }
+}
+
+fn node_type_from_byte(byte: u8) -> std::result::Result<NodeType, NodeAuthError> {
+    match byte {
+        0 => Ok(NodeType::Replica),
+        1 => Ok(NodeType::Client),
+        _ => Err(NodeAuthError::UnknownNodeType(byte)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{ErrorKind, Read, Write};
+    use std::sync::Arc;
+    use std::thread;
+
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::crypto::signature::{KeyPair, PublicKey};
+    use crate::node_id::{NodeId, NodeType};
+    use crate::socket::identity::test_node_keys;
+    use crate::socket::websocket::WebSocketConfig;
+    use crate::socket::{
+        bind_async_server, bind_sync_server, connect_async, connect_sync, SocketConfig,
+    };
+
+    use super::*;
+
+    /// The configs of a replica (node 0) and a client (node 1)
+    fn configs() -> Vec<NodeAuthConfig> {
+        let (nodes, directory) = test_node_keys(2);
+
+        nodes
+            .into_iter()
+            .zip([NodeType::Replica, NodeType::Client])
+            .map(|((id, key), node_type)| {
+                directory.insert_with_type(id, node_type, PublicKey::from(key.public_key()));
+
+                NodeAuthConfig::new(id, node_type, key, directory.clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sync_authentication() {
+        let mut configs = configs();
+        let client_config = configs.pop().unwrap();
+        let server_config = configs.pop().unwrap();
+
+        let config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap();
+
+            let (peer, mut write, mut read) = server_config.accept_sync(socket).unwrap();
+
+            let mut message = [0; 5];
+            read.read_exact(&mut message).unwrap();
+            write.write_all(&message).unwrap();
+            write.flush().unwrap();
+
+            peer
+        });
+
+        let socket = connect_sync(addr, &config).unwrap();
+        let (peer, mut write, mut read) = client_config.connect_sync(NodeId(0), socket).unwrap();
+
+        write.write_all(b"hello").unwrap();
+        write.flush().unwrap();
+
+        let mut echo = [0; 5];
+        read.read_exact(&mut echo).unwrap();
+
+        assert_eq!(&echo, b"hello");
+        assert_eq!(
+            peer,
+            AuthenticatedPeer {
+                node_id: NodeId(0),
+                node_type: NodeType::Replica
+            }
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            AuthenticatedPeer {
+                node_id: NodeId(1),
+                node_type: NodeType::Client
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_async_authentication() {
+        let mut configs = configs();
+        let client_config = configs.pop().unwrap();
+        let server_config = configs.pop().unwrap();
+
+        let config = SocketConfig::default();
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+
+        let (client, server) = tokio::join!(
+            client_config.connect_async(NodeId(0), client.unwrap()),
+            server_config.accept_async(server.unwrap())
+        );
+
+        let (client_peer, mut write, _client_read) = client.unwrap();
+        let (server_peer, _server_write, mut read) = server.unwrap();
+
+        write.write_all(b"hello").await.unwrap();
+        write.flush().await.unwrap();
+
+        let mut message = [0; 5];
+        read.read_exact(&mut message).await.unwrap();
+
+        assert_eq!(&message, b"hello");
+        assert_eq!(client_peer.node_id, NodeId(0));
+        assert_eq!(server_peer.node_id, NodeId(1));
+        assert_eq!(server_peer.node_type, NodeType::Client);
+    }
+
+    #[tokio::test]
+    async fn test_authentication_over_websocket() {
+        let mut configs = configs();
+        let client_config = configs.pop().unwrap();
+        let server_config = configs.pop().unwrap();
+
+        let config = SocketConfig::default();
+        let websocket_config = WebSocketConfig::default();
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+
+        let (client, server) = tokio::join!(
+            websocket_config.connect_async(client.unwrap(), "localhost"),
+            websocket_config.accept_async(server.unwrap())
+        );
+
+        let (client, server) = tokio::join!(
+            client_config.connect_async(NodeId(0), client.unwrap()),
+            server_config.accept_async(server.unwrap())
+        );
+
+        let (_client_peer, mut write, _client_read) = client.unwrap();
+        let (server_peer, _server_write, mut read) = server.unwrap();
+
+        write.write_all(b"hello").await.unwrap();
+        write.flush().await.unwrap();
+
+        let mut message = [0; 5];
+        read.read_exact(&mut message).await.unwrap();
+
+        assert_eq!(&message, b"hello");
+        assert_eq!(server_peer.node_id, NodeId(1));
+    }
+
+    #[test]
+    fn test_impersonation_is_rejected() {
+        let mut configs = configs();
+        let client_config = configs.pop().unwrap();
+        let server_config = configs.pop().unwrap();
+
+        // Claims to be node 1, but signs with a key that is not in the directory
+        let impostor = NodeAuthConfig::new(
+            NodeId(1),
+            NodeType::Client,
+            Arc::new(KeyPair::generate_key_pair().unwrap()),
+            client_config.directory.clone(),
+        );
+
+        let config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap();
+
+            server_config.accept_sync(socket).map(|(peer, _, _)| peer)
+        });
+
+        let socket = connect_sync(addr, &config).unwrap();
+
+        // The impostor can verify the server, but the server must refuse the impostor
+        assert!(impostor.connect_sync(NodeId(0), socket).is_ok());
+
+        let err = server.join().unwrap().unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<NodeAuthError>(),
+            Some(NodeAuthError::InvalidSignature(NodeId(1)))
+        ));
+    }
+
+    #[test]
+    fn test_unregistered_node_type_is_rejected() {
+        let mut configs = configs();
+        let client_config = configs.pop().unwrap();
+        let server_config = configs.pop().unwrap();
+
+        // Node 1 owns its key, but claims to be a replica while registered as a client
+        let promoted = NodeAuthConfig::new(
+            NodeId(1),
+            NodeType::Replica,
+            client_config.key_pair.clone(),
+            client_config.directory.clone(),
+        );
+
+        let config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap();
+
+            server_config.accept_sync(socket).map(|(peer, _, _)| peer)
+        });
+
+        let socket = connect_sync(addr, &config).unwrap();
+
+        // The server gives up before sending its signature, so the client fails too
+        assert!(promoted.connect_sync(NodeId(0), socket).is_err());
+
+        let err = server.join().unwrap().unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<NodeAuthError>(),
+            Some(NodeAuthError::UnexpectedNodeType {
+                node: NodeId(1),
+                claimed: NodeType::Replica,
+                registered: NodeType::Client,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_silent_peer_times_out() {
+        let server_config = configs()
+            .remove(0)
+            .with_handshake_timeout(Some(Duration::from_millis(200)));
+
+        let config = SocketConfig::default();
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Connects, but never says a word
+        let _silent = connect_sync(addr, &config).unwrap();
+
+        let socket = listener.accept().unwrap();
+
+        let err = server_config.accept_sync(socket).map(|_| ()).unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(ErrorKind::TimedOut)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_times_out_async() {
+        let server_config = configs()
+            .remove(0)
+            .with_handshake_timeout(Some(Duration::from_millis(200)));
+
+        let config = SocketConfig::default();
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (silent, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+        let _silent = silent.unwrap();
+
+        let err = server_config
+            .accept_async(server.unwrap())
+            .await
+            .map(|_| ())
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(ErrorKind::TimedOut)
+        );
+    }
+}
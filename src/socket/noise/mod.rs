@@ -0,0 +1,976 @@
+//! Secure channels based on the Noise protocol framework.
+//!
+//! A lighter alternative to TLS for replica to replica links, which does not
+//! require certificates. We use the `XX` handshake pattern, with X25519 static keys
+//! that are generated on startup. Each node proves it owns its static key by signing it
+//! with its ed25519 node key, and sending the signature (along with its [NodeId]) as the
+//! handshake payload. Peers check this signature against the keys in the [NodeKeyDirectory].
+//!
+//! On the wire, every Noise message is prefixed by its length, as a big endian `u16`.
+//!
+//! The handshake must complete within the configured timeout, so a peer that
+//! connects and then stays silent can't hold the connection (or a thread) forever.
+
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context as Cntx, Poll};
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use snow::{HandshakeState, StatelessTransportState};
+use thiserror::Error;
+
+use crate::crypto::signature::{KeyPair, Signature};
+use crate::error::*;
+use crate::node_id::NodeId;
+use crate::socket::deadline::{with_timeout, DeadlineSocket, DEFAULT_HANDSHAKE_TIMEOUT};
+use crate::socket::identity::NodeKeyDirectory;
+use crate::socket::{
+    AsyncSocket, ReadHalfAsync, ReadHalfSync, SecureSocketAsync, SecureSocketSync, SyncSocket,
+    WriteHalfAsync, WriteHalfSync,
+};
+use crate::Err;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+const NOISE_PROLOGUE: &[u8] = b"atlas-noise-v1";
+
+/// The context of the signatures over the static keys,
+/// so they can't be mistaken for any other signature made with the node key
+const STATIC_KEY_CONTEXT: &[u8] = b"atlas-noise-static-key";
+
+/// The maximum size of a Noise message
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// The size of the authentication tag of every transport message
+const TAG_LEN: usize = 16;
+
+/// The maximum amount of plaintext we can send in a single transport message
+const MAX_PLAINTEXT_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+const LENGTH_PREFIX_LEN: usize = std::mem::size_of::<u16>();
+
+/// The size of the identity payload (node id + signature of the static key)
+const IDENTITY_LEN: usize = std::mem::size_of::<u32>() + Signature::LENGTH;
+
+/// The name of the handshake, for its timeout error
+const HANDSHAKE: &str = "Noise";
+
+#[derive(Error, Debug)]
+pub enum NoiseError {
+    #[error("Noise protocol error {0:?}")]
+    Protocol(#[from] snow::Error),
+    #[error("Malformed identity payload of length {0}")]
+    MalformedIdentity(usize),
+    #[error("The peer did not send its identity")]
+    MissingIdentity,
+    #[error("Node {0:?} is not in the key directory")]
+    UnknownNode(NodeId),
+    #[error("The static key of node {0:?} is not signed by its node key")]
+    InvalidSignature(NodeId),
+    #[error("Expected to be talking to {expected:?}, but the peer is {found:?}")]
+    UnexpectedPeer { expected: NodeId, found: NodeId },
+}
+
+/// The configuration used to establish Noise channels with other nodes
+pub struct NoiseConfig {
+    node_id: NodeId,
+    directory: NodeKeyDirectory,
+    static_private_key: Vec<u8>,
+    // Our node id and the signature of our static key, sent during the handshake
+    identity: Vec<u8>,
+    handshake_timeout: Option<Duration>,
+}
+
+/// A socket over which a Noise session was established
+pub struct NoiseStream<S> {
+    socket: S,
+    peer: NodeId,
+    read: NoiseReadState,
+    write: NoiseWriteState,
+}
+
+/// The read half of a Noise stream
+pub struct NoiseReadHalf<R> {
+    socket: R,
+    state: NoiseReadState,
+}
+
+/// The write half of a Noise stream
+pub struct NoiseWriteHalf<W> {
+    socket: W,
+    state: NoiseWriteState,
+}
+
+struct NoiseReadState {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    // The message being received, including its length prefix
+    message: Box<[u8]>,
+    received: usize,
+    plaintext: Vec<u8>,
+    consumed: usize,
+}
+
+struct NoiseWriteState {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    // An encrypted message (with its length prefix) not yet fully written to the socket
+    pending: Vec<u8>,
+    written: usize,
+}
+
+struct Handshake<'a> {
+    config: &'a NoiseConfig,
+    state: HandshakeState,
+    peer: Option<NodeId>,
+    sent_messages: usize,
+}
+
+impl NoiseConfig {
+    pub fn new(
+        node_id: NodeId,
+        key_pair: Arc<KeyPair>,
+        directory: NodeKeyDirectory,
+    ) -> Result<Self> {
+        let static_keys = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+
+        let signature = key_pair.sign(&static_key_message(&static_keys.public))?;
+
+        let mut identity = Vec::with_capacity(IDENTITY_LEN);
+
+        identity.extend_from_slice(&node_id.id().to_be_bytes());
+        identity.extend_from_slice(signature.as_ref());
+
+        Ok(Self {
+            node_id,
+            directory,
+            static_private_key: static_keys.private,
+            identity,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+        })
+    }
+
+    /// The time the handshake has to complete, or [None] to wait forever
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Establish a Noise session with a node that connected to us,
+    /// returning its authenticated identity
+    pub fn accept_sync(&self, mut socket: SyncSocket) -> Result<(NodeId, SecureSocketSync)> {
+        let mut handshake = self.handshake(false)?;
+
+        self.complete_sync(&mut handshake, &mut socket)?;
+
+        let stream = handshake.into_stream(socket)?;
+
+        Ok((stream.peer(), SecureSocketSync::Noise(Box::new(stream))))
+    }
+
+    /// Establish a Noise session with the node `peer`, which we connected to
+    pub fn connect_sync(
+        &self,
+        peer: NodeId,
+        mut socket: SyncSocket,
+    ) -> Result<(NodeId, SecureSocketSync)> {
+        let mut handshake = self.handshake(true)?;
+
+        self.complete_sync(&mut handshake, &mut socket)?;
+
+        let stream = handshake.into_stream(socket)?;
+
+        check_expected_peer(peer, stream.peer())?;
+
+        Ok((stream.peer(), SecureSocketSync::Noise(Box::new(stream))))
+    }
+
+    /// Establish a Noise session with a node that connected to us,
+    /// returning its authenticated identity
+    pub async fn accept_async(
+        &self,
+        mut socket: AsyncSocket,
+    ) -> Result<(NodeId, SecureSocketAsync)> {
+        let mut handshake = self.handshake(false)?;
+
+        with_timeout(
+            self.handshake_timeout,
+            HANDSHAKE,
+            handshake.complete_async(&mut socket),
+        )
+        .await?;
+
+        let stream = handshake.into_stream(socket)?;
+
+        Ok((stream.peer(), SecureSocketAsync::Noise(Box::new(stream))))
+    }
+
+    /// Establish a Noise session with the node `peer`, which we connected to
+    pub async fn connect_async(
+        &self,
+        peer: NodeId,
+        mut socket: AsyncSocket,
+    ) -> Result<(NodeId, SecureSocketAsync)> {
+        let mut handshake = self.handshake(true)?;
+
+        with_timeout(
+            self.handshake_timeout,
+            HANDSHAKE,
+            handshake.complete_async(&mut socket),
+        )
+        .await?;
+
+        let stream = handshake.into_stream(socket)?;
+
+        check_expected_peer(peer, stream.peer())?;
+
+        Ok((stream.peer(), SecureSocketAsync::Noise(Box::new(stream))))
+    }
+
+    /// Run the handshake over `socket`, bounded by the handshake timeout
+    fn complete_sync(&self, handshake: &mut Handshake<'_>, socket: &mut SyncSocket) -> Result<()> {
+        let mut deadline = DeadlineSocket::new(socket, self.handshake_timeout, HANDSHAKE);
+
+        let result = handshake.complete_sync(&mut deadline);
+
+        deadline.finish()?;
+
+        result
+    }
+
+    fn handshake(&self, initiator: bool) -> Result<Handshake<'_>> {
+        let builder = snow::Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&self.static_private_key)
+            .prologue(NOISE_PROLOGUE);
+
+        let state = if initiator {
+            builder.build_initiator()?
+        } else {
+            builder.build_responder()?
+        };
+
+        Ok(Handshake {
+            config: self,
+            state,
+            peer: None,
+            sent_messages: 0,
+        })
+    }
+
+    /// Check the identity payload sent by the peer, which must sign the static key
+    /// it used in the handshake
+    fn verify_identity(
+        &self,
+        payload: &[u8],
+        remote_static: Option<&[u8]>,
+    ) -> std::result::Result<NodeId, NoiseError> {
+        if payload.len() != IDENTITY_LEN {
+            return Err(NoiseError::MalformedIdentity(payload.len()));
+        }
+
+        let remote_static = remote_static.ok_or(NoiseError::MissingIdentity)?;
+
+        let (node, signature) = payload.split_at(std::mem::size_of::<u32>());
+
+        let node = NodeId(u32::from_be_bytes(node.try_into().unwrap()));
+
+        let key = self
+            .directory
+            .get(&node)
+            .ok_or(NoiseError::UnknownNode(node))?;
+
+        let signature =
+            Signature::from_bytes(signature).map_err(|_| NoiseError::InvalidSignature(node))?;
+
+        key.verify(&static_key_message(remote_static), &signature)
+            .map_err(|_| NoiseError::InvalidSignature(node))?;
+
+        Ok(node)
+    }
+}
+
+fn static_key_message(static_key: &[u8]) -> Vec<u8> {
+    [STATIC_KEY_CONTEXT, static_key].concat()
+}
+
+fn check_expected_peer(expected: NodeId, found: NodeId) -> Result<()> {
+    if expected != found {
+        return Err!(NoiseError::UnexpectedPeer { expected, found });
+    }
+
+    Ok(())
+}
+
+impl Handshake<'_> {
+    /// Write the next handshake message (with its length prefix) into `message`
+    fn write_message(&mut self, message: &mut Vec<u8>) -> Result<()> {
+        // The first message of the initiator is not encrypted and does not carry
+        // its static key, so we can't send our identity yet
+        let payload = if self.state.is_initiator() && self.sent_messages == 0 {
+            &[][..]
+        } else {
+            &self.config.identity[..]
+        };
+
+        message.resize(LENGTH_PREFIX_LEN + MAX_MESSAGE_LEN, 0);
+
+        let len = self
+            .state
+            .write_message(payload, &mut message[LENGTH_PREFIX_LEN..])?;
+
+        message[..LENGTH_PREFIX_LEN].copy_from_slice(&(len as u16).to_be_bytes());
+        message.truncate(LENGTH_PREFIX_LEN + len);
+
+        self.sent_messages += 1;
+
+        Ok(())
+    }
+
+    fn read_message(&mut self, message: &[u8]) -> Result<()> {
+        let mut payload = vec![0; MAX_MESSAGE_LEN];
+
+        let len = self.state.read_message(message, &mut payload)?;
+
+        if len > 0 {
+            let peer = self
+                .config
+                .verify_identity(&payload[..len], self.state.get_remote_static())?;
+
+            self.peer = Some(peer);
+        }
+
+        Ok(())
+    }
+
+    fn complete_sync<S: Read + Write>(&mut self, socket: &mut S) -> Result<()> {
+        let mut message = Vec::new();
+
+        while !self.state.is_handshake_finished() {
+            if self.state.is_my_turn() {
+                self.write_message(&mut message)?;
+
+                socket.write_all(&message)?;
+                socket.flush()?;
+            } else {
+                let mut len = [0; LENGTH_PREFIX_LEN];
+                socket.read_exact(&mut len)?;
+
+                message.resize(u16::from_be_bytes(len) as usize, 0);
+                socket.read_exact(&mut message)?;
+
+                self.read_message(&message)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn complete_async(&mut self, socket: &mut AsyncSocket) -> Result<()> {
+        let mut message = Vec::new();
+
+        while !self.state.is_handshake_finished() {
+            if self.state.is_my_turn() {
+                self.write_message(&mut message)?;
+
+                socket.write_all(&message).await?;
+                socket.flush().await?;
+            } else {
+                let mut len = [0; LENGTH_PREFIX_LEN];
+                socket.read_exact(&mut len).await?;
+
+                message.resize(u16::from_be_bytes(len) as usize, 0);
+                socket.read_exact(&mut message).await?;
+
+                self.read_message(&message)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn into_stream<S>(self, socket: S) -> Result<NoiseStream<S>> {
+        let peer = self.peer.ok_or(NoiseError::MissingIdentity)?;
+
+        let transport = Arc::new(self.state.into_stateless_transport_mode()?);
+
+        Ok(NoiseStream {
+            socket,
+            peer,
+            read: NoiseReadState::new(transport.clone()),
+            write: NoiseWriteState::new(transport),
+        })
+    }
+}
+
+impl<S> NoiseStream<S> {
+    /// The authenticated node on the other end of the stream
+    pub fn peer(&self) -> NodeId {
+        self.peer
+    }
+}
+
+impl NoiseStream<SyncSocket> {
+    pub(super) fn split(self) -> (NoiseWriteHalf<WriteHalfSync>, NoiseReadHalf<ReadHalfSync>) {
+        let (write, read) = self.socket.split();
+
+        (
+            NoiseWriteHalf {
+                socket: write,
+                state: self.write,
+            },
+            NoiseReadHalf {
+                socket: read,
+                state: self.read,
+            },
+        )
+    }
+}
+
+impl NoiseStream<AsyncSocket> {
+    pub(super) fn split(self) -> (NoiseWriteHalf<WriteHalfAsync>, NoiseReadHalf<ReadHalfAsync>) {
+        let (write, read) = self.socket.split();
+
+        (
+            NoiseWriteHalf {
+                socket: write,
+                state: self.write,
+            },
+            NoiseReadHalf {
+                socket: read,
+                state: self.read,
+            },
+        )
+    }
+}
+
+impl NoiseReadState {
+    fn new(transport: Arc<StatelessTransportState>) -> Self {
+        Self {
+            transport,
+            nonce: 0,
+            message: vec![0; LENGTH_PREFIX_LEN + MAX_MESSAGE_LEN].into_boxed_slice(),
+            received: 0,
+            plaintext: Vec::with_capacity(MAX_PLAINTEXT_LEN),
+            consumed: 0,
+        }
+    }
+
+    /// The amount of bytes we need to have received to complete the
+    /// length prefix or, once we know it, the current message
+    fn expected_len(&self) -> usize {
+        if self.received < LENGTH_PREFIX_LEN {
+            LENGTH_PREFIX_LEN
+        } else {
+            let len = u16::from_be_bytes([self.message[0], self.message[1]]);
+
+            LENGTH_PREFIX_LEN + len as usize
+        }
+    }
+
+    fn decrypt_message(&mut self) -> io::Result<()> {
+        self.plaintext.resize(MAX_MESSAGE_LEN, 0);
+
+        let len = self
+            .transport
+            .read_message(
+                self.nonce,
+                &self.message[LENGTH_PREFIX_LEN..self.received],
+                &mut self.plaintext,
+            )
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+
+        self.nonce += 1;
+        self.plaintext.truncate(len);
+        self.consumed = 0;
+        self.received = 0;
+
+        Ok(())
+    }
+
+    /// Read decrypted data into `buf`, reading from the socket with `read_socket`
+    /// whenever we need more ciphertext
+    fn poll_read_with(
+        &mut self,
+        buf: &mut [u8],
+        mut read_socket: impl FnMut(&mut [u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            if self.consumed < self.plaintext.len() {
+                let len = buf.len().min(self.plaintext.len() - self.consumed);
+
+                buf[..len].copy_from_slice(&self.plaintext[self.consumed..self.consumed + len]);
+                self.consumed += len;
+
+                return Poll::Ready(Ok(len));
+            }
+
+            let expected = self.expected_len();
+
+            if self.received >= LENGTH_PREFIX_LEN && self.received == expected {
+                self.decrypt_message()?;
+
+                continue;
+            }
+
+            let read = ready!(read_socket(&mut self.message[self.received..expected]))?;
+
+            if read == 0 {
+                // Only a close in between messages is a clean EOF
+                return if self.received == 0 {
+                    Poll::Ready(Ok(0))
+                } else {
+                    Poll::Ready(Err(ErrorKind::UnexpectedEof.into()))
+                };
+            }
+
+            self.received += read;
+        }
+    }
+}
+
+impl NoiseWriteState {
+    fn new(transport: Arc<StatelessTransportState>) -> Self {
+        Self {
+            transport,
+            nonce: 0,
+            pending: Vec::with_capacity(LENGTH_PREFIX_LEN + MAX_MESSAGE_LEN),
+            written: 0,
+        }
+    }
+
+    /// Encrypt as much of `buf` as fits in a message, returning the amount of plaintext consumed.
+    /// Must only be called once the previous message was fully written
+    fn encrypt_message(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let plaintext = &buf[..buf.len().min(MAX_PLAINTEXT_LEN)];
+        let len = plaintext.len() + TAG_LEN;
+
+        self.pending.resize(LENGTH_PREFIX_LEN + len, 0);
+        self.pending[..LENGTH_PREFIX_LEN].copy_from_slice(&(len as u16).to_be_bytes());
+
+        self.transport
+            .write_message(
+                self.nonce,
+                plaintext,
+                &mut self.pending[LENGTH_PREFIX_LEN..],
+            )
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+
+        self.nonce += 1;
+        self.written = 0;
+
+        Ok(plaintext.len())
+    }
+
+    /// Write the pending message to the socket with `write_socket`
+    fn poll_drain_with(
+        &mut self,
+        mut write_socket: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let written = ready!(write_socket(&self.pending[self.written..]))?;
+
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+
+            self.written += written;
+        }
+
+        self.pending.clear();
+        self.written = 0;
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write<W: AsyncWrite + Unpin>(
+        &mut self,
+        socket: &mut W,
+        cx: &mut Cntx<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        ready!(self.poll_drain_with(|pending| Pin::new(&mut *socket).poll_write(cx, pending)))?;
+
+        Poll::Ready(self.encrypt_message(buf))
+    }
+
+    fn poll_flush<W: AsyncWrite + Unpin>(
+        &mut self,
+        socket: &mut W,
+        cx: &mut Cntx<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain_with(|pending| Pin::new(&mut *socket).poll_write(cx, pending)))?;
+
+        Pin::new(socket).poll_flush(cx)
+    }
+
+    fn poll_close<W: AsyncWrite + Unpin>(
+        &mut self,
+        socket: &mut W,
+        cx: &mut Cntx<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain_with(|pending| Pin::new(&mut *socket).poll_write(cx, pending)))?;
+
+        Pin::new(socket).poll_close(cx)
+    }
+
+    fn write<W: Write>(&mut self, socket: &mut W, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let consumed = self.encrypt_message(buf)?;
+
+        socket.write_all(&self.pending)?;
+        self.pending.clear();
+
+        Ok(consumed)
+    }
+}
+
+fn poll_blocking<T>(poll: Poll<io::Result<T>>) -> io::Result<T> {
+    match poll {
+        Poll::Ready(result) => result,
+        Poll::Pending => unreachable!("Blocking IO is always ready"),
+    }
+}
+
+impl<R: Read> Read for NoiseReadHalf<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let socket = &mut self.socket;
+
+        poll_blocking(
+            self.state
+                .poll_read_with(buf, |message| Poll::Ready(socket.read(message))),
+        )
+    }
+}
+
+impl<W: Write> Write for NoiseWriteHalf<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state.write(&mut self.socket, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for NoiseReadHalf<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let socket = &mut this.socket;
+
+        this.state
+            .poll_read_with(buf, |message| Pin::new(&mut *socket).poll_read(cx, message))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for NoiseWriteHalf<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        this.state.poll_write(&mut this.socket, cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        this.state.poll_flush(&mut this.socket, cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        this.state.poll_close(&mut this.socket, cx)
+    }
+}
+
+impl<S: AsRawFd> AsRawFd for NoiseStream<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let socket = &mut this.socket;
+
+        this.read
+            .poll_read_with(buf, |message| Pin::new(&mut *socket).poll_read(cx, message))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        this.write.poll_write(&mut this.socket, cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        this.write.poll_flush(&mut this.socket, cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        this.write.poll_close(&mut this.socket, cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::crypto::signature::PublicKey;
+    use crate::socket::identity::test_node_keys;
+    use crate::socket::{
+        bind_async_server, bind_sync_server, connect_async, connect_sync, SocketConfig,
+    };
+
+    const MESSAGE_SIZE: usize = 4 * 1024 * 1024 + 3;
+
+    fn connected_pair() -> (SyncSocket, SyncSocket) {
+        let config = SocketConfig::default();
+
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &config).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || connect_sync(addr, &config).unwrap());
+        let server = listener.accept().unwrap();
+
+        (client.join().unwrap(), server)
+    }
+
+    fn message(seed: u8) -> Vec<u8> {
+        (0..MESSAGE_SIZE)
+            .map(|i| (i as u8).wrapping_mul(17).wrapping_add(seed))
+            .collect()
+    }
+
+    #[test]
+    fn test_sync_full_duplex() {
+        let (nodes, known) = test_node_keys(2);
+
+        let server = NoiseConfig::new(nodes[0].0, nodes[0].1.clone(), known.clone()).unwrap();
+        let client = NoiseConfig::new(nodes[1].0, nodes[1].1.clone(), known).unwrap();
+
+        let (client_sock, server_sock) = connected_pair();
+
+        let client_thread = thread::spawn(move || client.connect_sync(NodeId(0), client_sock));
+
+        let (client_id, server_socket) = server.accept_sync(server_sock).unwrap();
+        let (server_id, client_socket) = client_thread.join().unwrap().unwrap();
+
+        assert_eq!(client_id, NodeId(1));
+        assert_eq!(server_id, NodeId(0));
+
+        let handles = [(client_socket, 1u8), (server_socket, 2u8)].map(|(socket, seed)| {
+            let (mut write, mut read) = socket.split();
+
+            let writer = thread::spawn(move || {
+                write.write_all(&message(seed)).unwrap();
+                write.flush().unwrap();
+            });
+
+            let reader = thread::spawn(move || {
+                let mut received = vec![0; MESSAGE_SIZE];
+                read.read_exact(&mut received).unwrap();
+                received
+            });
+
+            (writer, reader, seed)
+        });
+
+        for (writer, reader, seed) in handles {
+            writer.join().unwrap();
+
+            let other_seed = if seed == 1 { 2 } else { 1 };
+
+            assert!(reader.join().unwrap() == message(other_seed));
+        }
+    }
+
+    #[test]
+    fn test_unknown_node_is_rejected() {
+        let (nodes, known) = test_node_keys(2);
+
+        // The server does not know node 1
+        let partial = NodeKeyDirectory::new();
+        partial.insert(nodes[0].0, PublicKey::from(nodes[0].1.public_key()));
+
+        let server = NoiseConfig::new(nodes[0].0, nodes[0].1.clone(), partial).unwrap();
+        let client = NoiseConfig::new(nodes[1].0, nodes[1].1.clone(), known).unwrap();
+
+        let (client_sock, server_sock) = connected_pair();
+
+        let client_thread = thread::spawn(move || client.connect_sync(NodeId(0), client_sock));
+
+        assert!(server.accept_sync(server_sock).is_err());
+
+        let _ = client_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_impersonation_is_rejected() {
+        let (nodes, known) = test_node_keys(2);
+        let impostor_key = Arc::new(KeyPair::generate_key_pair().unwrap());
+
+        let server = NoiseConfig::new(nodes[0].0, nodes[0].1.clone(), known.clone()).unwrap();
+
+        // Claims to be node 1, but signs with another key
+        let impostor = NoiseConfig::new(NodeId(1), impostor_key, known).unwrap();
+
+        let (client_sock, server_sock) = connected_pair();
+
+        let client_thread = thread::spawn(move || impostor.connect_sync(NodeId(0), client_sock));
+
+        let err = server.accept_sync(server_sock).err().unwrap();
+
+        assert!(matches!(
+            err.downcast_ref::<NoiseError>(),
+            Some(NoiseError::InvalidSignature(_))
+        ));
+
+        let _ = client_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_silent_peer_times_out() {
+        let (nodes, known) = test_node_keys(1);
+
+        let server = NoiseConfig::new(nodes[0].0, nodes[0].1.clone(), known)
+            .unwrap()
+            .with_handshake_timeout(Some(Duration::from_millis(200)));
+
+        // Connects, but never says a word
+        let (_silent, server_sock) = connected_pair();
+
+        let err = server.accept_sync(server_sock).map(|_| ()).unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(ErrorKind::TimedOut)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_times_out_async() {
+        let (nodes, known) = test_node_keys(1);
+
+        let server = NoiseConfig::new(nodes[0].0, nodes[0].1.clone(), known)
+            .unwrap()
+            .with_handshake_timeout(Some(Duration::from_millis(200)));
+
+        let config = SocketConfig::default();
+
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (silent, server_sock) = tokio::join!(connect_async(addr, &config), listener.accept());
+        let _silent = silent.unwrap();
+
+        let err = server
+            .accept_async(server_sock.unwrap())
+            .await
+            .map(|_| ())
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(ErrorKind::TimedOut)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_async_round_trip() {
+        let (nodes, known) = test_node_keys(2);
+
+        let server = NoiseConfig::new(nodes[0].0, nodes[0].1.clone(), known.clone()).unwrap();
+        let client = NoiseConfig::new(nodes[1].0, nodes[1].1.clone(), known).unwrap();
+
+        let config = SocketConfig::default();
+
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client_task = tokio::spawn(async move {
+            let socket = connect_async(addr, &config).await.unwrap();
+
+            let (_, socket) = client.connect_async(NodeId(0), socket).await.unwrap();
+
+            let (mut write, mut read) = socket.split();
+
+            write.write_all(&message(1)).await.unwrap();
+            write.flush().await.unwrap();
+
+            let mut reply = [0; 5];
+            read.read_exact(&mut reply).await.unwrap();
+
+            reply
+        });
+
+        let socket = listener.accept().await.unwrap();
+
+        let (peer, socket) = server.accept_async(socket).await.unwrap();
+
+        assert_eq!(peer, NodeId(1));
+
+        let (mut write, mut read) = socket.split();
+
+        let mut received = vec![0; MESSAGE_SIZE];
+        read.read_exact(&mut received).await.unwrap();
+
+        assert!(received == message(1));
+
+        write.write_all(b"reply").await.unwrap();
+        write.flush().await.unwrap();
+
+        assert_eq!(&client_task.await.unwrap(), b"reply");
+    }
+}
@@ -0,0 +1,389 @@
+//! A QUIC transport, which multiplexes several independent streams over a
+//! single connection per peer.
+//!
+//! Unlike with a TCP connection, a large message sent on one stream does not
+//! hold back the messages sent on the other streams of the same connection.
+//! The connections are authenticated with the same node identities used by
+//! [MutualTlsConfig].
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use futures::{AsyncRead, AsyncWrite};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Endpoint, IdleTimeout, TransportConfig, VarInt};
+use rustls::pki_types::CertificateDer;
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::error::*;
+use crate::node_id::NodeId;
+use crate::socket::framing::{FramedReadHalfAsync, FramedWriteHalfAsync, FramingConfig};
+use crate::socket::mtls::{certificates_peer, check_expected_peer, node_dns_name, MutualTlsConfig};
+use crate::Err;
+
+/// The application protocol negotiated by our QUIC connections
+const ALPN_PROTOCOL: &[u8] = b"atlas";
+
+#[derive(Error, Debug)]
+pub enum QuicError {
+    #[error("The peer did not present a certificate chain")]
+    NoPeerIdentity,
+    #[error("The endpoint has been closed")]
+    EndpointClosed,
+}
+
+/// The transport parameters of the QUIC connections
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct QuicConfig {
+    /// How many streams the peer may have open at the same time on a connection
+    pub max_concurrent_streams: u32,
+    /// How often to send keep alive packets. `None` disables them
+    pub keep_alive_interval: Option<Duration>,
+    /// How long a connection may be idle before it is closed
+    pub idle_timeout: Duration,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: 128,
+            keep_alive_interval: Some(Duration::from_secs(5)),
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl QuicConfig {
+    fn transport_config(&self) -> Result<Arc<TransportConfig>> {
+        let mut transport = TransportConfig::default();
+
+        transport
+            .max_concurrent_bidi_streams(VarInt::from_u32(self.max_concurrent_streams))
+            .max_concurrent_uni_streams(VarInt::from_u32(0))
+            .keep_alive_interval(self.keep_alive_interval)
+            .max_idle_timeout(Some(
+                IdleTimeout::try_from(self.idle_timeout).context("Invalid QUIC idle timeout")?,
+            ));
+
+        Ok(Arc::new(transport))
+    }
+}
+
+/// A QUIC endpoint, which both accepts connections from and makes
+/// connections to other nodes, over a single UDP socket.
+///
+/// Must be used from within a tokio runtime.
+pub struct QuicEndpoint {
+    node_id: NodeId,
+    endpoint: Endpoint,
+}
+
+impl QuicEndpoint {
+    /// Bind a new endpoint to the given address, authenticating the connections
+    /// with the TLS configurations of `tls`
+    pub fn bind(addr: SocketAddr, tls: &MutualTlsConfig, config: &QuicConfig) -> Result<Self> {
+        let transport = config.transport_config()?;
+
+        let mut server_crypto = (*tls.server_config()).clone();
+        server_crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+        let mut client_crypto = (*tls.client_config()).clone();
+        client_crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(
+            QuicServerConfig::try_from(server_crypto)
+                .context("TLS server configuration is not usable with QUIC")?,
+        ));
+        server_config.transport_config(transport.clone());
+
+        let mut client_config = quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(client_crypto)
+                .context("TLS client configuration is not usable with QUIC")?,
+        ));
+        client_config.transport_config(transport);
+
+        let mut endpoint =
+            Endpoint::server(server_config, addr).context("Failed to bind QUIC endpoint")?;
+
+        endpoint.set_default_client_config(client_config);
+
+        Ok(Self {
+            node_id: tls.node_id(),
+            endpoint,
+        })
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Connect to the node `peer`, listening at `addr`.
+    /// The connection is only established if the peer proves to be that node
+    pub async fn connect(&self, peer: NodeId, addr: SocketAddr) -> Result<QuicConnection> {
+        let connection = self
+            .endpoint
+            .connect(addr, &node_dns_name(peer))
+            .context("Failed to start QUIC connection")?
+            .await
+            .context("Failed to establish QUIC connection")?;
+
+        let found = connection_peer(&connection)?;
+
+        check_expected_peer(peer, found)?;
+
+        Ok(QuicConnection {
+            peer: found,
+            connection,
+        })
+    }
+
+    /// Accept the next connection made to this endpoint, returning once
+    /// the peer has been authenticated
+    pub async fn accept(&self) -> Result<QuicConnection> {
+        let incoming = match self.endpoint.accept().await {
+            Some(incoming) => incoming,
+            None => return Err!(QuicError::EndpointClosed),
+        };
+
+        let connection = incoming
+            .await
+            .context("Failed to establish QUIC connection")?;
+
+        let peer = connection_peer(&connection)?;
+
+        Ok(QuicConnection { peer, connection })
+    }
+
+    /// Close every connection of this endpoint, and stop accepting new ones
+    pub fn close(&self) {
+        self.endpoint.close(VarInt::from_u32(0), b"");
+    }
+
+    /// Wait for the connections of this endpoint to be cleanly shut down
+    pub async fn wait_idle(&self) {
+        self.endpoint.wait_idle().await
+    }
+}
+
+/// An authenticated QUIC connection to another node.
+///
+/// Cloning yields a handle to the same connection.
+#[derive(Clone)]
+pub struct QuicConnection {
+    peer: NodeId,
+    connection: quinn::Connection,
+}
+
+impl QuicConnection {
+    /// The authenticated identity of the node on the other end
+    pub fn peer(&self) -> NodeId {
+        self.peer
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    /// Open a new stream to the peer.
+    ///
+    /// The peer only learns about the stream (in [Self::accept_stream])
+    /// once something has been written to it
+    pub async fn open_stream(&self) -> Result<QuicStream> {
+        let (send, recv) = self
+            .connection
+            .open_bi()
+            .await
+            .context("Failed to open QUIC stream")?;
+
+        Ok(QuicStream { send, recv })
+    }
+
+    /// Accept the next stream opened by the peer
+    pub async fn accept_stream(&self) -> Result<QuicStream> {
+        let (send, recv) = self
+            .connection
+            .accept_bi()
+            .await
+            .context("Failed to accept QUIC stream")?;
+
+        Ok(QuicStream { send, recv })
+    }
+
+    pub fn close(&self) {
+        self.connection.close(VarInt::from_u32(0), b"");
+    }
+}
+
+/// A bidirectional stream of a [QuicConnection]
+pub struct QuicStream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+}
+
+impl QuicStream {
+    pub fn split(self) -> (QuicWriteHalf, QuicReadHalf) {
+        (
+            QuicWriteHalf { inner: self.send },
+            QuicReadHalf { inner: self.recv },
+        )
+    }
+
+    /// Split this stream into halves that read and write whole frames
+    pub fn split_framed(
+        self,
+        config: FramingConfig,
+    ) -> (
+        FramedWriteHalfAsync<QuicWriteHalf>,
+        FramedReadHalfAsync<QuicReadHalf>,
+    ) {
+        let (write, read) = self.split();
+
+        (
+            FramedWriteHalfAsync::new(write, config),
+            FramedReadHalfAsync::new(read, config),
+        )
+    }
+}
+
+pub struct QuicWriteHalf {
+    inner: quinn::SendStream,
+}
+
+pub struct QuicReadHalf {
+    inner: quinn::RecvStream,
+}
+
+impl AsyncWrite for QuicWriteHalf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.inner), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.inner), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_close(Pin::new(&mut self.inner), cx)
+    }
+}
+
+impl AsyncRead for QuicReadHalf {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncRead::poll_read(Pin::new(&mut self.inner), cx, buf)
+    }
+}
+
+fn connection_peer(connection: &quinn::Connection) -> Result<NodeId> {
+    let certificates = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .ok_or(QuicError::NoPeerIdentity)?;
+
+    certificates_peer(&certificates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::mtls::test_configs;
+
+    fn endpoints(count: u32) -> Vec<QuicEndpoint> {
+        test_configs(count)
+            .iter()
+            .map(|tls| {
+                QuicEndpoint::bind(([127, 0, 0, 1], 0).into(), tls, &QuicConfig::default()).unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_streams_are_independent() {
+        let nodes = endpoints(2);
+        let addr = nodes[0].local_addr().unwrap();
+
+        let (server, client) = tokio::join!(nodes[0].accept(), nodes[1].connect(NodeId(0), addr));
+        let (server, client) = (server.unwrap(), client.unwrap());
+
+        assert_eq!(server.peer(), NodeId(1));
+        assert_eq!(client.peer(), NodeId(0));
+
+        let config = FramingConfig::default();
+
+        // A large transfer is started, but only partially sent
+        let (mut bulk_write, _) = client.open_stream().await.unwrap().split();
+        let large = vec![7u8; 4 * 1024 * 1024];
+        futures::AsyncWriteExt::write_all(&mut bulk_write, &(large.len() as u64).to_be_bytes())
+            .await
+            .unwrap();
+        futures::AsyncWriteExt::write_all(&mut bulk_write, &large[..1024])
+            .await
+            .unwrap();
+
+        let (_, mut bulk_read) = server.accept_stream().await.unwrap().split();
+
+        // Meanwhile, small messages flow on another stream
+        let (mut small_write, _) = client.open_stream().await.unwrap().split_framed(config);
+        small_write.write_frame(b"consensus").await.unwrap();
+        small_write.flush().await.unwrap();
+
+        let (_, mut small_read) = server.accept_stream().await.unwrap().split_framed(config);
+        assert_eq!(small_read.read_frame().await.unwrap(), b"consensus");
+
+        // Then the large transfer completes, as the receiver drains it
+        let send = async {
+            futures::AsyncWriteExt::write_all(&mut bulk_write, &large[1024..]).await?;
+            futures::AsyncWriteExt::close(&mut bulk_write).await
+        };
+
+        let receive = async {
+            let mut len = [0; 8];
+            futures::AsyncReadExt::read_exact(&mut bulk_read, &mut len).await?;
+
+            let mut received = Vec::new();
+            futures::AsyncReadExt::read_to_end(&mut bulk_read, &mut received).await?;
+
+            io::Result::Ok((len, received))
+        };
+
+        let (sent, received) = tokio::join!(send, receive);
+        sent.unwrap();
+        let (len, received) = received.unwrap();
+
+        assert_eq!(u64::from_be_bytes(len) as usize, received.len());
+        assert_eq!(received, large);
+    }
+
+    #[tokio::test]
+    async fn test_connect_rejects_unexpected_peer() {
+        let nodes = endpoints(3);
+        let addr = nodes[0].local_addr().unwrap();
+
+        // Node 0 is listening, but node 1 expects node 2 at that address
+        let (_, client) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(5), nodes[0].accept()),
+            nodes[1].connect(NodeId(2), addr)
+        );
+
+        assert!(client.is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Selection of the secure channel used between nodes.
+
+use std::sync::Arc;
+
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::signature::KeyPair;
+use crate::error::*;
+use crate::node_id::NodeId;
+use crate::socket::identity::NodeKeyDirectory;
+use crate::socket::mtls::MutualTlsConfig;
+use crate::socket::noise::NoiseConfig;
+use crate::socket::{AsyncSocket, SecureSocketAsync, SecureSocketSync, SyncSocket};
+
+/// The kind of secure channel to establish over the node connections,
+/// so it can be chosen from the configuration
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub enum SecureChannelType {
+    /// No encryption nor authentication
+    Plain,
+    /// Mutual TLS, with certificates issued from the node keys
+    #[default]
+    Tls,
+    /// The Noise protocol, with static keys signed by the node keys
+    Noise,
+}
+
+/// A secure channel configuration, ready to establish connections
+#[derive(Clone)]
+pub enum SecureChannel {
+    Plain,
+    Tls(Arc<MutualTlsConfig>),
+    Noise(Arc<NoiseConfig>),
+}
+
+impl SecureChannel {
+    pub fn new(
+        channel_type: SecureChannelType,
+        node_id: NodeId,
+        key_pair: Arc<KeyPair>,
+        directory: NodeKeyDirectory,
+    ) -> Result<Self> {
+        Ok(match channel_type {
+            SecureChannelType::Plain => SecureChannel::Plain,
+            SecureChannelType::Tls => SecureChannel::Tls(Arc::new(MutualTlsConfig::new(
+                node_id, key_pair, directory,
+            )?)),
+            SecureChannelType::Noise => {
+                SecureChannel::Noise(Arc::new(NoiseConfig::new(node_id, key_pair, directory)?))
+            }
+        })
+    }
+
+    pub fn channel_type(&self) -> SecureChannelType {
+        match self {
+            SecureChannel::Plain => SecureChannelType::Plain,
+            SecureChannel::Tls(_) => SecureChannelType::Tls,
+            SecureChannel::Noise(_) => SecureChannelType::Noise,
+        }
+    }
+
+    /// Establish the secure channel over a connection we accepted.
+    ///
+    /// Returns the authenticated identity of the peer, which is `None` for plain channels
+    pub fn accept_sync(&self, socket: SyncSocket) -> Result<(Option<NodeId>, SecureSocketSync)> {
+        match self {
+            SecureChannel::Plain => Ok((None, SecureSocketSync::new_plain(socket))),
+            SecureChannel::Tls(config) => config
+                .accept_sync(socket)
+                .map(|(peer, socket)| (Some(peer), socket)),
+            SecureChannel::Noise(config) => config
+                .accept_sync(socket)
+                .map(|(peer, socket)| (Some(peer), socket)),
+        }
+    }
+
+    /// Establish the secure channel over a connection we made to `peer`.
+    ///
+    /// Returns the authenticated identity of the peer, which is `None` for plain channels
+    pub fn connect_sync(
+        &self,
+        peer: NodeId,
+        socket: SyncSocket,
+    ) -> Result<(Option<NodeId>, SecureSocketSync)> {
+        match self {
+            SecureChannel::Plain => Ok((None, SecureSocketSync::new_plain(socket))),
+            SecureChannel::Tls(config) => config
+                .connect_sync(peer, socket)
+                .map(|(peer, socket)| (Some(peer), socket)),
+            SecureChannel::Noise(config) => config
+                .connect_sync(peer, socket)
+                .map(|(peer, socket)| (Some(peer), socket)),
+        }
+    }
+
+    /// The async version of [SecureChannel::accept_sync]
+    pub async fn accept_async(
+        &self,
+        socket: AsyncSocket,
+    ) -> Result<(Option<NodeId>, SecureSocketAsync)> {
+        match self {
+            SecureChannel::Plain => Ok((None, SecureSocketAsync::new_plain(socket))),
+            SecureChannel::Tls(config) => config
+                .accept_async(socket)
+                .await
+                .map(|(peer, socket)| (Some(peer), socket)),
+            SecureChannel::Noise(config) => config
+                .accept_async(socket)
+                .await
+                .map(|(peer, socket)| (Some(peer), socket)),
+        }
+    }
+
+    /// The async version of [SecureChannel::connect_sync]
+    pub async fn connect_async(
+        &self,
+        peer: NodeId,
+        socket: AsyncSocket,
+    ) -> Result<(Option<NodeId>, SecureSocketAsync)> {
+        match self {
+            SecureChannel::Plain => Ok((None, SecureSocketAsync::new_plain(socket))),
+            SecureChannel::Tls(config) => config
+                .connect_async(peer, socket)
+                .await
+                .map(|(peer, socket)| (Some(peer), socket)),
+            SecureChannel::Noise(config) => config
+                .connect_async(peer, socket)
+                .await
+                .map(|(peer, socket)| (Some(peer), socket)),
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! Bandwidth shaping of the socket write halves.
+//!
+//! Each shaped write half owns a token bucket, which is refilled at the configured
+//! rate and holds at most `burst_bytes` tokens. Every byte handed to the inner half
+//! takes a token, so a connection can only burst up to the bucket's capacity
+//! before being held to its rate.
+//!
+//! The bytes are charged when they are written, so flushing never waits on the
+//! bucket: it just pushes out what was already accepted.
+
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::io;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::AsyncWrite;
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::async_runtime;
+use crate::node_id::NodeType;
+use crate::socket::{WriteHalfAsync, WriteHalfSync};
+
+/// The rate at which a connection may send data
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct RateLimit {
+    /// The sustained rate of the connection
+    pub bytes_per_second: u64,
+    /// How many bytes may be sent at once, after the connection has been idle
+    pub burst_bytes: u64,
+}
+
+/// The rate limits applied to the connections to each type of node.
+/// A `None` limit leaves those connections unshaped
+#[derive(Copy, Clone, Debug, Default)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+pub struct ShapingConfig {
+    pub replica: Option<RateLimit>,
+    pub client: Option<RateLimit>,
+}
+
+impl ShapingConfig {
+    /// The limit of the connections to nodes of the given type
+    pub fn limit_for(&self, node_type: NodeType) -> Option<RateLimit> {
+        match node_type {
+            NodeType::Replica => self.replica,
+            NodeType::Client => self.client,
+        }
+    }
+}
+
+/// A token bucket, where each token allows a byte to be sent
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a bucket, which starts full
+    pub fn new(limit: RateLimit) -> Self {
+        let limit = RateLimit {
+            bytes_per_second: limit.bytes_per_second.max(1),
+            burst_bytes: limit.burst_bytes.max(1),
+        };
+
+        Self {
+            limit,
+            tokens: limit.burst_bytes as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.bytes_per_second as f64)
+            .min(self.limit.burst_bytes as f64);
+        self.last_refill = now;
+    }
+
+    /// Take the tokens to send up to `wanted` bytes.
+    ///
+    /// Returns how many bytes may be sent, or how long to wait until tokens for
+    /// `wanted` bytes (or for a full burst, if that is smaller) are available
+    pub fn try_take(&mut self, wanted: usize, now: Instant) -> Result<usize, Duration> {
+        self.refill(now);
+
+        // Waiting for enough tokens for the whole write (instead of a single one)
+        // avoids waking up for every few bytes
+        let needed = (wanted as u64).min(self.limit.burst_bytes) as f64;
+
+        if self.tokens >= needed {
+            let granted = (self.tokens.floor() as usize).min(wanted);
+
+            self.tokens -= granted as f64;
+
+            Ok(granted)
+        } else {
+            let missing = needed - self.tokens;
+
+            Err(Duration::from_secs_f64(
+                missing / self.limit.bytes_per_second as f64,
+            ))
+        }
+    }
+
+    /// Give back tokens that were taken but not used
+    pub fn refund(&mut self, tokens: usize) {
+        self.tokens = (self.tokens + tokens as f64).min(self.limit.burst_bytes as f64);
+    }
+}
+
+/// The throttling statistics of a shaped write half.
+///
+/// These are shared with whoever holds a reference to them, so they can be
+/// monitored while the half is in use.
+#[derive(Default)]
+pub struct ShapingStats {
+    bytes_written: AtomicU64,
+    throttled_writes: AtomicU64,
+    throttled_nanos: AtomicU64,
+}
+
+impl ShapingStats {
+    /// The total amount of bytes that went through the half
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// How many times a write had to wait for tokens
+    pub fn throttled_writes(&self) -> u64 {
+        self.throttled_writes.load(Ordering::Relaxed)
+    }
+
+    /// The total time writes spent waiting for tokens
+    pub fn throttled_time(&self) -> Duration {
+        Duration::from_nanos(self.throttled_nanos.load(Ordering::Relaxed))
+    }
+
+    fn record_written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn record_throttle(&self, wait: Duration) {
+        self.throttled_writes.fetch_add(1, Ordering::Relaxed);
+        self.throttled_nanos
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Debug for ShapingStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShapingStats")
+            .field("bytes_written", &self.bytes_written())
+            .field("throttled_writes", &self.throttled_writes())
+            .field("throttled_time", &self.throttled_time())
+            .finish()
+    }
+}
+
+/// A write half whose throughput is limited by a [TokenBucket]
+pub struct ShapedWriteHalfSync<W = WriteHalfSync> {
+    inner: W,
+    bucket: Option<TokenBucket>,
+    stats: Arc<ShapingStats>,
+}
+
+/// The async counterpart of [ShapedWriteHalfSync]
+pub struct ShapedWriteHalfAsync<W = WriteHalfAsync> {
+    inner: W,
+    bucket: Option<TokenBucket>,
+    stats: Arc<ShapingStats>,
+    // The wait for tokens the current write is blocked on
+    delay: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl<W> ShapedWriteHalfSync<W>
+where
+    W: Write,
+{
+    /// Wrap the given half. A `None` limit leaves it unshaped (but still counted)
+    pub fn new(inner: W, limit: Option<RateLimit>) -> Self {
+        Self {
+            inner,
+            bucket: limit.map(TokenBucket::new),
+            stats: Arc::default(),
+        }
+    }
+
+    pub fn limit(&self) -> Option<RateLimit> {
+        self.bucket.as_ref().map(TokenBucket::limit)
+    }
+
+    /// Change the limit of this half. The new bucket starts full
+    pub fn set_limit(&mut self, limit: Option<RateLimit>) {
+        self.bucket = limit.map(TokenBucket::new);
+    }
+
+    pub fn stats(&self) -> Arc<ShapingStats> {
+        self.stats.clone()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W> Write for ShapedWriteHalfSync<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(bucket) = &mut self.bucket else {
+            let written = self.inner.write(buf)?;
+
+            self.stats.record_written(written);
+
+            return Ok(written);
+        };
+
+        if buf.is_empty() {
+            return self.inner.write(buf);
+        }
+
+        loop {
+            match bucket.try_take(buf.len(), Instant::now()) {
+                Ok(granted) => {
+                    let written = match self.inner.write(&buf[..granted]) {
+                        Ok(written) => written,
+                        Err(err) => {
+                            bucket.refund(granted);
+
+                            return Err(err);
+                        }
+                    };
+
+                    bucket.refund(granted - written);
+
+                    self.stats.record_written(written);
+
+                    return Ok(written);
+                }
+                Err(wait) => {
+                    self.stats.record_throttle(wait);
+
+                    thread::sleep(wait);
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W> ShapedWriteHalfAsync<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Wrap the given half. A `None` limit leaves it unshaped (but still counted)
+    pub fn new(inner: W, limit: Option<RateLimit>) -> Self {
+        Self {
+            inner,
+            bucket: limit.map(TokenBucket::new),
+            stats: Arc::default(),
+            delay: None,
+        }
+    }
+
+    pub fn limit(&self) -> Option<RateLimit> {
+        self.bucket.as_ref().map(TokenBucket::limit)
+    }
+
+    /// Change the limit of this half. The new bucket starts full
+    pub fn set_limit(&mut self, limit: Option<RateLimit>) {
+        self.bucket = limit.map(TokenBucket::new);
+        self.delay = None;
+    }
+
+    pub fn stats(&self) -> Arc<ShapingStats> {
+        self.stats.clone()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W> AsyncWrite for ShapedWriteHalfAsync<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let Some(bucket) = &mut this.bucket else {
+            let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+
+            this.stats.record_written(written);
+
+            return Poll::Ready(Ok(written));
+        };
+
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        loop {
+            if let Some(delay) = &mut this.delay {
+                ready!(delay.as_mut().poll(cx));
+
+                this.delay = None;
+            }
+
+            match bucket.try_take(buf.len(), Instant::now()) {
+                Ok(granted) => {
+                    let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]);
+
+                    // Whatever the inner half did not take is not charged
+                    let written = match &result {
+                        Poll::Ready(Ok(written)) => *written,
+                        _ => 0,
+                    };
+
+                    bucket.refund(granted - written);
+
+                    this.stats.record_written(written);
+
+                    return result;
+                }
+                Err(wait) => {
+                    this.stats.record_throttle(wait);
+
+                    this.delay = Some(Box::pin(async_runtime::sleep(wait)));
+                }
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    use futures::AsyncWriteExt;
+
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        bytes_per_second: 100_000,
+        burst_bytes: 10_000,
+    };
+
+    fn assert_wait(result: Result<usize, Duration>, expected: Duration) {
+        let wait = result.expect_err("Expected to have to wait for tokens");
+
+        assert!(
+            wait.abs_diff(expected) < Duration::from_micros(1),
+            "{wait:?}"
+        );
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(LIMIT);
+        let start = bucket.last_refill;
+
+        // The bucket starts full, so a burst goes through right away
+        assert_eq!(bucket.try_take(25_000, start), Ok(10_000));
+
+        // Empty, so we have to wait for a full burst to be refilled
+        assert_wait(bucket.try_take(25_000, start), Duration::from_millis(100));
+
+        // Small writes only wait for what they need
+        let later = start + Duration::from_millis(5);
+        assert_eq!(bucket.try_take(500, later), Ok(500));
+
+        bucket.refund(200);
+        assert_wait(bucket.try_take(25_000, later), Duration::from_millis(98));
+
+        // It never holds more than a burst
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.try_take(25_000, much_later), Ok(10_000));
+    }
+
+    #[test]
+    fn test_sync_write_is_shaped() {
+        let mut shaped = ShapedWriteHalfSync::new(Vec::new(), Some(LIMIT));
+
+        let start = Instant::now();
+
+        // The first burst is free, the remaining 20 KB take 200ms
+        shaped.write_all(&[1; 30_000]).unwrap();
+        shaped.flush().unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(190));
+
+        let stats = shaped.stats();
+        assert_eq!(stats.bytes_written(), 30_000);
+        assert!(stats.throttled_writes() >= 2);
+        assert_eq!(shaped.into_inner(), vec![1; 30_000]);
+    }
+
+    #[tokio::test]
+    async fn test_async_write_is_shaped() {
+        let mut shaped = ShapedWriteHalfAsync::new(Vec::new(), Some(LIMIT));
+
+        let start = Instant::now();
+
+        shaped.write_all(&[2; 30_000]).await.unwrap();
+        shaped.flush().await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(190));
+
+        let stats = shaped.stats();
+        assert_eq!(stats.bytes_written(), 30_000);
+        assert!(stats.throttled_time() >= Duration::from_millis(190));
+        assert_eq!(shaped.into_inner(), vec![2; 30_000]);
+    }
+
+    #[test]
+    fn test_limits_per_node_type() {
+        let config = ShapingConfig {
+            replica: None,
+            client: Some(LIMIT),
+        };
+
+        assert_eq!(config.limit_for(NodeType::Replica), None);
+        assert_eq!(config.limit_for(NodeType::Client), Some(LIMIT));
+    }
+}
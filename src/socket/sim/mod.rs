@@ -0,0 +1,1028 @@
+//! An in-memory simulated network, for testing protocols between nodes
+//! without real sockets.
+//!
+//! Nodes listen and connect by their [NodeId]. Connections behave like the
+//! read and write halves of the real sockets (implementing both the blocking and
+//! the async IO traits), but every segment goes through the simulated network,
+//! which can delay, drop, reorder and duplicate it, or partition nodes from each other.
+//!
+//! A segment is what the writer sends with each `flush` (or `close`). Faults are
+//! applied to whole segments, so protocols that flush after every message (such as the
+//! [framing](crate::socket::framing) halves) see whole messages being lost or repeated.
+//!
+//! All the random decisions are taken from RNGs seeded from the network seed
+//! (one for each directed link), so a failing run can be reproduced with the same seed.
+//! Delivery order does not depend on timing either: segments of a link are delivered
+//! in the order they were sent (regardless of jitter), and a reordered segment is held back
+//! until a random amount of the segments sent after it were delivered.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::{Context as Cntx, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{AsyncRead, AsyncWrite};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::node_id::NodeId;
+
+/// How long the delivery thread waits, at most, before checking whether
+/// the network was dropped
+const DELIVERY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The maximum amount of segments that can overtake a reordered segment
+const MAX_OVERTAKES: u32 = 3;
+
+/// The behaviour of a (directed) link between two nodes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LinkConfig {
+    /// The base delay of every segment
+    pub latency: Duration,
+    /// A random delay, up to this value, added to the latency of each segment.
+    /// Does not reorder segments by itself
+    pub jitter: Duration,
+    /// The probability of a segment being dropped
+    pub drop_probability: f64,
+    /// The probability of a segment being delivered twice
+    pub duplicate_probability: f64,
+    /// The probability of a segment being delivered after (some of) the ones sent after it
+    pub reorder_probability: f64,
+    /// How long a reordered segment is held back, at most, when no other
+    /// segments are sent after it
+    pub reorder_delay: Duration,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            drop_probability: 0.0,
+            duplicate_probability: 0.0,
+            reorder_probability: 0.0,
+            reorder_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+/// A simulated network. Cloning it yields a handle to the same network
+#[derive(Clone)]
+pub struct SimNetwork {
+    shared: Arc<NetworkShared>,
+}
+
+/// Accepts the connections made to a node
+pub struct SimListener {
+    node: NodeId,
+    shared: Arc<NetworkShared>,
+    queue: Arc<AcceptQueue>,
+}
+
+/// A connection between two nodes of the simulated network
+pub struct SimSocket {
+    write: SimWriteHalf,
+    read: SimReadHalf,
+}
+
+/// The write half of a simulated connection
+pub struct SimWriteHalf {
+    shared: Arc<NetworkShared>,
+    pipe: Arc<Pipe>,
+    // The data written since the last flush, which will become the next segment
+    buffer: Vec<u8>,
+    closed: bool,
+}
+
+/// The read half of a simulated connection
+pub struct SimReadHalf {
+    pipe: Arc<Pipe>,
+}
+
+struct NetworkShared {
+    seed: u64,
+    state: Mutex<NetworkState>,
+    deliveries: Mutex<BinaryHeap<Reverse<Delivery>>>,
+    delivery_cond: Condvar,
+}
+
+struct NetworkState {
+    default_link: LinkConfig,
+    links: HashMap<(NodeId, NodeId), LinkConfig>,
+    rngs: HashMap<(NodeId, NodeId), StdRng>,
+    // Directed links that are currently cut
+    blocked: HashSet<(NodeId, NodeId)>,
+    listeners: HashMap<NodeId, Weak<AcceptQueue>>,
+    delivery_seq: u64,
+}
+
+#[derive(Default)]
+struct AcceptQueue {
+    state: Mutex<AcceptState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct AcceptState {
+    pending: VecDeque<SimSocket>,
+    waker: Option<Waker>,
+}
+
+/// One direction of a connection
+struct Pipe {
+    from: NodeId,
+    to: NodeId,
+    state: Mutex<PipeState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    segments: VecDeque<Vec<u8>>,
+    // How much of the first segment was already read
+    offset: usize,
+    eof: bool,
+    reader_dropped: bool,
+    waker: Option<Waker>,
+    // The delivery time of the last in order segment, so jitter does not reorder segments
+    last_delivery: Option<Instant>,
+    // Reordered segments, waiting for the segments that will overtake them
+    held: Vec<HeldSegment>,
+}
+
+struct HeldSegment {
+    overtakes_left: u32,
+    segment: HeldSlot,
+}
+
+/// A held segment is scheduled twice (once when it is overtaken, and once when its
+/// delay runs out), and is delivered by whichever comes first
+type HeldSlot = Arc<Mutex<Option<Vec<u8>>>>;
+
+enum Payload {
+    Segment(Vec<u8>),
+    Held(HeldSlot),
+    Eof,
+}
+
+struct Delivery {
+    at: Instant,
+    seq: u64,
+    pipe: Arc<Pipe>,
+    payload: Payload,
+}
+
+impl SimNetwork {
+    /// Create a network where every link behaves according to `default_link`,
+    /// with all random decisions derived from `seed`
+    pub fn new(seed: u64, default_link: LinkConfig) -> Self {
+        let shared = Arc::new(NetworkShared {
+            seed,
+            state: Mutex::new(NetworkState {
+                default_link,
+                links: Default::default(),
+                rngs: Default::default(),
+                blocked: Default::default(),
+                listeners: Default::default(),
+                delivery_seq: 0,
+            }),
+            deliveries: Mutex::new(BinaryHeap::new()),
+            delivery_cond: Condvar::new(),
+        });
+
+        let weak = Arc::downgrade(&shared);
+
+        thread::Builder::new()
+            .name("sim-network-delivery".to_string())
+            .spawn(move || delivery_loop(weak))
+            .expect("Failed to spawn the simulated network delivery thread");
+
+        Self { shared }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.shared.seed
+    }
+
+    /// Change the behaviour of every link without a specific configuration
+    pub fn set_default_link(&self, config: LinkConfig) {
+        self.shared.state.lock().unwrap().default_link = config;
+    }
+
+    /// Change the behaviour of the link from `from` to `to` (only in that direction)
+    pub fn set_link(&self, from: NodeId, to: NodeId, config: LinkConfig) {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .links
+            .insert((from, to), config);
+    }
+
+    /// Cut the communication from `from` to `to`.
+    /// Segments in flight on that link are lost
+    pub fn block(&self, from: NodeId, to: NodeId) {
+        self.shared.state.lock().unwrap().blocked.insert((from, to));
+    }
+
+    pub fn unblock(&self, from: NodeId, to: NodeId) {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .blocked
+            .remove(&(from, to));
+    }
+
+    /// Cut the communication (in both directions) between every node in `side_a`
+    /// and every node in `side_b`
+    pub fn partition(&self, side_a: &[NodeId], side_b: &[NodeId]) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        for a in side_a {
+            for b in side_b {
+                state.blocked.insert((*a, *b));
+                state.blocked.insert((*b, *a));
+            }
+        }
+    }
+
+    /// Restore every cut link
+    pub fn heal(&self) {
+        self.shared.state.lock().unwrap().blocked.clear();
+    }
+
+    /// Start accepting connections to `node`
+    pub fn listen(&self, node: NodeId) -> io::Result<SimListener> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state
+            .listeners
+            .get(&node)
+            .is_some_and(|listener| listener.strong_count() > 0)
+        {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("{node:?} is already listening"),
+            ));
+        }
+
+        let queue = Arc::new(AcceptQueue::default());
+
+        state.listeners.insert(node, Arc::downgrade(&queue));
+
+        Ok(SimListener {
+            node,
+            shared: self.shared.clone(),
+            queue,
+        })
+    }
+
+    /// Connect `from` to the node `to`, which must be listening
+    pub fn connect(&self, from: NodeId, to: NodeId) -> io::Result<SimSocket> {
+        let queue = {
+            let state = self.shared.state.lock().unwrap();
+
+            if state.blocked.contains(&(from, to)) || state.blocked.contains(&(to, from)) {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("{from:?} is partitioned from {to:?}"),
+                ));
+            }
+
+            state
+                .listeners
+                .get(&to)
+                .and_then(Weak::upgrade)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::ConnectionRefused,
+                        format!("{to:?} is not listening"),
+                    )
+                })?
+        };
+
+        let outgoing = Arc::new(Pipe::new(from, to));
+        let incoming = Arc::new(Pipe::new(to, from));
+
+        let accepted = SimSocket::new(self.shared.clone(), incoming.clone(), outgoing.clone());
+
+        {
+            let mut accept_state = queue.state.lock().unwrap();
+
+            accept_state.pending.push_back(accepted);
+
+            if let Some(waker) = accept_state.waker.take() {
+                waker.wake();
+            }
+        }
+
+        queue.cond.notify_one();
+
+        Ok(SimSocket::new(self.shared.clone(), outgoing, incoming))
+    }
+}
+
+impl NetworkShared {
+    /// Decide the fate of a segment sent through the pipe, scheduling its deliveries
+    fn send(&self, pipe: &Arc<Pipe>, segment: Vec<u8>) {
+        let now = Instant::now();
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.blocked.contains(&(pipe.from, pipe.to)) {
+            return;
+        }
+
+        let link = state.link(pipe);
+        let seed = self.seed;
+
+        let rng = state
+            .rngs
+            .entry((pipe.from, pipe.to))
+            .or_insert_with(|| StdRng::seed_from_u64(link_seed(seed, pipe.from, pipe.to)));
+
+        if rng.gen_bool(link.drop_probability) {
+            return;
+        }
+
+        let mut pipe_state = pipe.state.lock().unwrap();
+
+        let mut at = now + link.latency + link.jitter.mul_f64(rng.gen::<f64>());
+
+        if let Some(last) = pipe_state.last_delivery {
+            at = at.max(last);
+        }
+
+        let mut deliveries = Vec::new();
+
+        if rng.gen_bool(link.reorder_probability) {
+            let slot = Arc::new(Mutex::new(Some(segment)));
+
+            pipe_state.held.push(HeldSegment {
+                overtakes_left: rng.gen_range(1, MAX_OVERTAKES + 1),
+                segment: slot.clone(),
+            });
+
+            deliveries.push((at + link.reorder_delay, Payload::Held(slot)));
+        } else {
+            if rng.gen_bool(link.duplicate_probability) {
+                deliveries.push((at, Payload::Segment(segment.clone())));
+            }
+
+            deliveries.push((at, Payload::Segment(segment)));
+
+            pipe_state.last_delivery = Some(at);
+
+            // Release the held segments that have now been overtaken enough times
+            pipe_state.held.retain_mut(|held| {
+                held.overtakes_left -= 1;
+
+                if held.overtakes_left == 0 {
+                    deliveries.push((at, Payload::Held(held.segment.clone())));
+                }
+
+                held.overtakes_left > 0
+            });
+        }
+
+        drop(pipe_state);
+
+        self.schedule(&mut state, pipe, deliveries);
+    }
+
+    /// The end of the stream is always delivered, after every segment sent before it
+    fn close(&self, pipe: &Arc<Pipe>) {
+        let mut state = self.state.lock().unwrap();
+
+        let link = state.link(pipe);
+
+        let mut pipe_state = pipe.state.lock().unwrap();
+
+        let mut at = Instant::now() + link.latency;
+
+        if let Some(last) = pipe_state.last_delivery {
+            at = at.max(last);
+        }
+
+        let mut deliveries: Vec<_> = pipe_state
+            .held
+            .drain(..)
+            .map(|held| (at, Payload::Held(held.segment)))
+            .collect();
+
+        deliveries.push((at, Payload::Eof));
+
+        drop(pipe_state);
+
+        self.schedule(&mut state, pipe, deliveries);
+    }
+
+    fn schedule(
+        &self,
+        state: &mut NetworkState,
+        pipe: &Arc<Pipe>,
+        deliveries: Vec<(Instant, Payload)>,
+    ) {
+        let mut queue = self.deliveries.lock().unwrap();
+
+        for (at, payload) in deliveries {
+            state.delivery_seq += 1;
+
+            queue.push(Reverse(Delivery {
+                at,
+                seq: state.delivery_seq,
+                pipe: pipe.clone(),
+                payload,
+            }));
+        }
+
+        self.delivery_cond.notify_one();
+    }
+
+    fn deliver(&self, delivery: Delivery) {
+        let Delivery { pipe, payload, .. } = delivery;
+
+        let segment = match payload {
+            Payload::Segment(segment) => Some(segment),
+            Payload::Held(slot) => match slot.lock().unwrap().take() {
+                Some(segment) => Some(segment),
+                // Already delivered
+                None => return,
+            },
+            Payload::Eof => None,
+        };
+
+        if segment.is_some()
+            && self
+                .state
+                .lock()
+                .unwrap()
+                .blocked
+                .contains(&(pipe.from, pipe.to))
+        {
+            return;
+        }
+
+        pipe.push(segment);
+    }
+}
+
+impl NetworkState {
+    fn link(&self, pipe: &Pipe) -> LinkConfig {
+        self.links
+            .get(&(pipe.from, pipe.to))
+            .copied()
+            .unwrap_or(self.default_link)
+    }
+}
+
+fn link_seed(seed: u64, from: NodeId, to: NodeId) -> u64 {
+    let link = ((from.0 as u64) << 32) | to.0 as u64;
+
+    (seed ^ link).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
+
+fn delivery_loop(shared: Weak<NetworkShared>) {
+    while let Some(shared) = shared.upgrade() {
+        let mut queue = shared.deliveries.lock().unwrap();
+
+        let now = Instant::now();
+
+        let mut due = Vec::new();
+
+        while queue.peek().is_some_and(|Reverse(next)| next.at <= now) {
+            due.push(queue.pop().unwrap().0);
+        }
+
+        if due.is_empty() {
+            let timeout = queue
+                .peek()
+                .map_or(DELIVERY_POLL_INTERVAL, |Reverse(next)| next.at - now)
+                .min(DELIVERY_POLL_INTERVAL);
+
+            drop(shared.delivery_cond.wait_timeout(queue, timeout).unwrap());
+
+            continue;
+        }
+
+        drop(queue);
+
+        for delivery in due {
+            shared.deliver(delivery);
+        }
+    }
+}
+
+impl PartialEq for Delivery {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Delivery {}
+
+impl PartialOrd for Delivery {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delivery {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.at.cmp(&other.at).then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl Pipe {
+    fn new(from: NodeId, to: NodeId) -> Self {
+        Self {
+            from,
+            to,
+            state: Mutex::new(PipeState::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn push(&self, segment: Option<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.reader_dropped {
+            return;
+        }
+
+        match segment {
+            Some(segment) if !segment.is_empty() => state.segments.push_back(segment),
+            Some(_) => return,
+            None => state.eof = true,
+        }
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+
+        drop(state);
+
+        self.cond.notify_all();
+    }
+}
+
+impl PipeState {
+    fn read_into(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let segment = self.segments.front()?;
+
+        let len = buf.len().min(segment.len() - self.offset);
+
+        buf[..len].copy_from_slice(&segment[self.offset..self.offset + len]);
+
+        self.offset += len;
+
+        if self.offset == segment.len() {
+            self.segments.pop_front();
+            self.offset = 0;
+        }
+
+        Some(len)
+    }
+}
+
+impl SimListener {
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    /// Block until a node connects to us
+    pub fn accept(&self) -> io::Result<SimSocket> {
+        let mut state = self.queue.state.lock().unwrap();
+
+        loop {
+            if let Some(socket) = state.pending.pop_front() {
+                return Ok(socket);
+            }
+
+            state = self.queue.cond.wait(state).unwrap();
+        }
+    }
+
+    pub async fn accept_async(&self) -> io::Result<SimSocket> {
+        futures::future::poll_fn(|cx| {
+            let mut state = self.queue.state.lock().unwrap();
+
+            match state.pending.pop_front() {
+                Some(socket) => Poll::Ready(Ok(socket)),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state
+            .listeners
+            .get(&self.node)
+            .is_some_and(|listener| std::ptr::eq(listener.as_ptr(), Arc::as_ptr(&self.queue)))
+        {
+            state.listeners.remove(&self.node);
+        }
+    }
+}
+
+impl SimSocket {
+    fn new(shared: Arc<NetworkShared>, outgoing: Arc<Pipe>, incoming: Arc<Pipe>) -> Self {
+        Self {
+            write: SimWriteHalf {
+                shared,
+                pipe: outgoing,
+                buffer: Vec::new(),
+                closed: false,
+            },
+            read: SimReadHalf { pipe: incoming },
+        }
+    }
+
+    /// The node on this end of the connection
+    pub fn local_node(&self) -> NodeId {
+        self.write.pipe.from
+    }
+
+    /// The node on the other end of the connection
+    pub fn peer_node(&self) -> NodeId {
+        self.write.pipe.to
+    }
+
+    pub fn split(self) -> (SimWriteHalf, SimReadHalf) {
+        (self.write, self.read)
+    }
+}
+
+impl SimWriteHalf {
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+
+        if !self.buffer.is_empty() {
+            let segment = std::mem::take(&mut self.buffer);
+
+            self.shared.send(&self.pipe, segment);
+        }
+
+        Ok(())
+    }
+
+    fn buffer(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+
+        if self.pipe.state.lock().unwrap().reader_dropped {
+            return Err(ErrorKind::ConnectionReset.into());
+        }
+
+        self.buffer.extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    /// Send what is left and signal the end of the stream to the peer
+    fn close(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.send_buffered()?;
+
+        self.closed = true;
+
+        self.shared.close(&self.pipe);
+
+        Ok(())
+    }
+}
+
+impl Drop for SimWriteHalf {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl Drop for SimReadHalf {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock().unwrap();
+
+        state.reader_dropped = true;
+        state.segments.clear();
+    }
+}
+
+impl Write for SimWriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffered()
+    }
+}
+
+impl Read for SimReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.pipe.state.lock().unwrap();
+
+        loop {
+            if let Some(read) = state.read_into(buf) {
+                return Ok(read);
+            }
+
+            if state.eof {
+                return Ok(0);
+            }
+
+            state = self.pipe.cond.wait(state).unwrap();
+        }
+    }
+}
+
+impl AsyncWrite for SimWriteHalf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Cntx<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.buffer(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.send_buffered())
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.close())
+    }
+}
+
+impl AsyncRead for SimReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut state = self.pipe.state.lock().unwrap();
+
+        if let Some(read) = state.read_into(buf) {
+            return Poll::Ready(Ok(read));
+        }
+
+        if state.eof {
+            return Poll::Ready(Ok(0));
+        }
+
+        state.waker = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl Write for SimSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+impl Read for SimSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read.read(buf)
+    }
+}
+
+impl AsyncWrite for SimSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_close(cx)
+    }
+}
+
+impl AsyncRead for SimSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::socket::framing::{FramedReadHalfSync, FramedWriteHalfSync, FramingConfig};
+
+    const MESSAGES: u32 = 200;
+
+    fn connect(network: &SimNetwork, from: u32, to: u32) -> (SimSocket, SimSocket) {
+        let listener = network.listen(NodeId(to)).unwrap();
+
+        let client = network.connect(NodeId(from), NodeId(to)).unwrap();
+        let server = listener.accept().unwrap();
+
+        (client, server)
+    }
+
+    /// Send numbered frames from one node to the other, returning
+    /// the numbers of the frames that were received, in order
+    fn exchange(seed: u64, link: LinkConfig) -> Vec<u32> {
+        let network = SimNetwork::new(seed, link);
+
+        let (client, server) = connect(&network, 0, 1);
+
+        let (write, _read) = client.split();
+        let (_write, read) = server.split();
+
+        let mut write = FramedWriteHalfSync::new(write, FramingConfig::default());
+        let mut read = FramedReadHalfSync::new(read, FramingConfig::default());
+
+        for i in 0..MESSAGES {
+            write.write_frame(&i.to_be_bytes()).unwrap();
+            write.flush().unwrap();
+        }
+
+        drop(write);
+
+        let mut received = Vec::new();
+
+        while let Ok(frame) = read.read_frame() {
+            received.push(u32::from_be_bytes(frame.try_into().unwrap()));
+        }
+
+        received
+    }
+
+    #[test]
+    fn test_faults_are_reproducible() {
+        let link = LinkConfig {
+            jitter: Duration::from_millis(2),
+            drop_probability: 0.2,
+            duplicate_probability: 0.2,
+            reorder_probability: 0.2,
+            reorder_delay: Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        let first = exchange(42, link);
+
+        assert_eq!(first, exchange(42, link));
+
+        let mut sorted = first.clone();
+        sorted.sort();
+        sorted.dedup();
+
+        // Some frames were dropped, duplicated and reordered
+        assert!(sorted.len() < MESSAGES as usize);
+        assert!(first.len() > sorted.len());
+        assert!(first.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn test_reliable_link_keeps_order() {
+        let link = LinkConfig {
+            latency: Duration::from_millis(1),
+            jitter: Duration::from_millis(3),
+            ..Default::default()
+        };
+
+        assert_eq!(exchange(7, link), (0..MESSAGES).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_latency() {
+        let network = SimNetwork::new(
+            0,
+            LinkConfig {
+                latency: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+
+        let (mut client, mut server) = connect(&network, 0, 1);
+
+        let start = Instant::now();
+
+        client.write_all(b"ping").unwrap();
+        client.flush().unwrap();
+
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+
+        assert_eq!(&buf, b"ping");
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_partition() {
+        let network = SimNetwork::new(0, LinkConfig::default());
+
+        let (mut client, mut server) = connect(&network, 0, 1);
+        let listener = network.listen(NodeId(0)).unwrap();
+
+        // Node 0 is reachable before the partition
+        network.connect(NodeId(2), NodeId(0)).unwrap();
+        listener.accept().unwrap();
+
+        network.partition(&[NodeId(0)], &[NodeId(1), NodeId(2)]);
+
+        client.write_all(b"lost").unwrap();
+        client.flush().unwrap();
+
+        let err = network.connect(NodeId(2), NodeId(0)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        thread::sleep(Duration::from_millis(20));
+
+        network.heal();
+
+        client.write_all(b"sent").unwrap();
+        client.flush().unwrap();
+
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+
+        assert_eq!(&buf, b"sent");
+    }
+
+    #[tokio::test]
+    async fn test_async_halves() {
+        use futures::{AsyncReadExt, AsyncWriteExt};
+
+        let network = SimNetwork::new(0, LinkConfig::default());
+
+        let listener = network.listen(NodeId(1)).unwrap();
+
+        let mut client = network.connect(NodeId(0), NodeId(1)).unwrap();
+        let server = listener.accept_async().await.unwrap();
+
+        assert_eq!(server.peer_node(), NodeId(0));
+
+        let reader = tokio::spawn(async move {
+            let (_write, mut read) = server.split();
+
+            let mut received = Vec::new();
+            AsyncReadExt::read_to_end(&mut read, &mut received)
+                .await
+                .unwrap();
+
+            received
+        });
+
+        // The sockets implement both the blocking and async traits
+        AsyncWriteExt::write_all(&mut client, b"hello ")
+            .await
+            .unwrap();
+        AsyncWriteExt::flush(&mut client).await.unwrap();
+        AsyncWriteExt::write_all(&mut client, b"world")
+            .await
+            .unwrap();
+        AsyncWriteExt::close(&mut client).await.unwrap();
+
+        assert_eq!(reader.await.unwrap(), b"hello world");
+    }
+}
@@ -1,103 +1,189 @@
 use crate::socket::{MioListener, MioSocket};
 
 use std::io;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, IoSlice, Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::ops::{Deref, DerefMut};
+use std::os::unix::net::{UnixListener, UnixStream};
 
 pub struct Socket {
-    inner: TcpStream,
+    inner: Stream,
 }
 
 pub struct Listener {
-    inner: TcpListener,
+    inner: StdListener,
 }
 
-pub fn bind<A: Into<SocketAddr>>(addr: A) -> Result<Listener, io::Error> {
-    let listener = TcpListener::bind(addr.into()).map(Listener::new)?;
+/// Both TCP and Unix domain sockets are supported, so co-located
+/// replicas can skip the TCP loopback
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
 
-    Ok(listener)
+enum StdListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
 }
 
-pub fn connect<A: Into<SocketAddr>>(addr: A) -> Result<Socket, io::Error> {
-    let socket = TcpStream::connect(addr.into()).map(Socket::new)?;
+impl From<TcpListener> for Listener {
+    fn from(inner: TcpListener) -> Self {
+        Listener::new(StdListener::Tcp(inner))
+    }
+}
+
+impl From<UnixListener> for Listener {
+    fn from(inner: UnixListener) -> Self {
+        Listener::new(StdListener::Unix(inner))
+    }
+}
+
+impl From<TcpStream> for Socket {
+    fn from(inner: TcpStream) -> Self {
+        Socket::new(Stream::Tcp(inner))
+    }
+}
 
-    Ok(socket)
+impl From<UnixStream> for Socket {
+    fn from(inner: UnixStream) -> Self {
+        Socket::new(Stream::Unix(inner))
+    }
 }
 
 impl Listener {
-    fn new(inner: TcpListener) -> Self {
+    fn new(inner: StdListener) -> Self {
         Listener { inner }
     }
 
     pub fn accept(&self) -> Result<Socket, io::Error> {
-        let socket = self.inner.accept().map(|(s, _)| Socket::new(s))?;
+        match &self.inner {
+            StdListener::Tcp(listener) => listener.accept().map(|(s, _)| Socket::from(s)),
+            StdListener::Unix(listener) => listener.accept().map(|(s, _)| Socket::from(s)),
+        }
+    }
 
-        Ok(socket)
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        match &self.inner {
+            StdListener::Tcp(listener) => listener.local_addr(),
+            StdListener::Unix(_) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Unix domain listeners have no socket address",
+            )),
+        }
     }
 }
 
 impl Socket {
-    fn new(inner: TcpStream) -> Self {
+    fn new(inner: Stream) -> Self {
         Socket { inner }
     }
-}
-impl Deref for Socket {
-    type Target = TcpStream;
 
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+    pub fn is_unix(&self) -> bool {
+        matches!(self.inner, Stream::Unix(_))
+    }
+
+    /// The underlying TCP stream, if this is not a Unix domain socket
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match &self.inner {
+            Stream::Tcp(stream) => Some(stream),
+            Stream::Unix(_) => None,
+        }
     }
-}
 
-impl DerefMut for Socket {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+    /// The mutable counterpart of [Self::as_tcp]
+    pub fn as_tcp_mut(&mut self) -> Option<&mut TcpStream> {
+        match &mut self.inner {
+            Stream::Tcp(stream) => Some(stream),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        let inner = match &self.inner {
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+        };
+
+        Ok(Socket { inner })
     }
 }
 
 impl Write for Socket {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.inner.write(buf)
+        match &mut self.inner {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match &mut self.inner {
+            Stream::Tcp(stream) => stream.write_vectored(bufs),
+            Stream::Unix(stream) => stream.write_vectored(bufs),
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
+        match &mut self.inner {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
     }
 }
 
 impl Read for Socket {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        std::io::Read::read(&mut self.inner, buf)
+        match &mut self.inner {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
     }
 
     fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
-        std::io::Read::read_exact(&mut self.inner, buf)
+        match &mut self.inner {
+            Stream::Tcp(stream) => stream.read_exact(buf),
+            Stream::Unix(stream) => stream.read_exact(buf),
+        }
     }
 }
 
 impl From<Socket> for MioSocket {
     fn from(value: Socket) -> Self {
-        value
-            .inner
-            .set_nonblocking(true)
-            .expect("Failed to set non-blocking");
-
-        MioSocket {
-            inner: mio::net::TcpStream::from_std(value.inner),
+        match value.inner {
+            Stream::Tcp(stream) => {
+                stream
+                    .set_nonblocking(true)
+                    .expect("Failed to set non-blocking");
+
+                mio::net::TcpStream::from_std(stream).into()
+            }
+            Stream::Unix(stream) => {
+                stream
+                    .set_nonblocking(true)
+                    .expect("Failed to set non-blocking");
+
+                mio::net::UnixStream::from_std(stream).into()
+            }
         }
     }
 }
 
 impl From<Listener> for MioListener {
     fn from(value: Listener) -> Self {
-        value
-            .inner
-            .set_nonblocking(true)
-            .expect("Failed to set non-blocking");
-
-        MioListener {
-            inner: mio::net::TcpListener::from_std(value.inner),
+        match value.inner {
+            StdListener::Tcp(listener) => {
+                listener
+                    .set_nonblocking(true)
+                    .expect("Failed to set non-blocking");
+
+                mio::net::TcpListener::from_std(listener).into()
+            }
+            StdListener::Unix(listener) => {
+                listener
+                    .set_nonblocking(true)
+                    .expect("Failed to set non-blocking");
+
+                mio::net::UnixListener::from_std(listener).into()
+            }
         }
     }
 }
@@ -121,20 +207,19 @@ impl Write for WriteHalf {
         self.inner.write(buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.inner.write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.inner.flush()
     }
 }
 
 pub(super) fn split(socket: Socket) -> (WriteHalf, ReadHalf) {
-    let new_socket = socket.inner.try_clone().expect("Failed to split socket");
+    let new_socket = socket.try_clone().expect("Failed to split socket");
 
-    (
-        WriteHalf {
-            inner: Socket { inner: new_socket },
-        },
-        ReadHalf { inner: socket },
-    )
+    (WriteHalf { inner: new_socket }, ReadHalf { inner: socket })
 }
 
 #[cfg(windows)]
@@ -146,15 +231,23 @@ mod sys {
 mod sys {
     use std::os::unix::io::{AsRawFd, RawFd};
 
+    use super::{StdListener, Stream};
+
     impl AsRawFd for super::Socket {
         fn as_raw_fd(&self) -> RawFd {
-            self.inner.as_raw_fd()
+            match &self.inner {
+                Stream::Tcp(stream) => stream.as_raw_fd(),
+                Stream::Unix(stream) => stream.as_raw_fd(),
+            }
         }
     }
 
     impl AsRawFd for super::Listener {
         fn as_raw_fd(&self) -> RawFd {
-            self.inner.as_raw_fd()
+            match &self.inner {
+                StdListener::Tcp(listener) => listener.as_raw_fd(),
+                StdListener::Unix(listener) => listener.as_raw_fd(),
+            }
         }
     }
 }
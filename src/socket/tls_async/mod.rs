@@ -0,0 +1,412 @@
+//! Full duplex asynchronous TLS streams.
+//!
+//! The async counterpart of the synchronous TLS halves. Each half drives its own
+//! direction of the rustls [Connection] over its own (OS level) half of the socket,
+//! so a read waiting on the socket never holds up a write, and vice versa.
+//! The connection lock is only ever held while encrypting or decrypting data
+//! in memory, and never across a poll of the socket.
+//!
+//! TLS records produced while reading (alerts, key update replies) are handed to
+//! the state of the write half by the read half, which sends them itself when the
+//! write half is idle, or wakes the write half up when it's waiting on the socket.
+
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::{ready, AsyncRead, AsyncWrite};
+use rustls::Connection;
+use tracing::{debug, error};
+
+use crate::socket::{ReadHalfAsync, WriteHalfAsync};
+
+/// The size of the buffer used to read TLS records from the socket
+/// (a TLS record holds at most 16 KiB of plaintext plus some overhead)
+const TLS_READ_BUFFER_SIZE: usize = 32 * 1024;
+
+/// The read half of an asynchronous TLS stream
+pub struct TlsReadHalfAsync {
+    connection: Arc<Mutex<Connection>>,
+    writer: Arc<Mutex<TlsWriter>>,
+    socket: ReadHalfAsync,
+    // Ciphertext that was read from the socket but not yet fed to the connection
+    tls_buf: Box<[u8]>,
+    tls_start: usize,
+    tls_end: usize,
+}
+
+/// The write half of an asynchronous TLS stream
+pub struct TlsWriteHalfAsync {
+    connection: Arc<Mutex<Connection>>,
+    writer: Arc<Mutex<TlsWriter>>,
+}
+
+/// The write side of the socket, shared by both halves.
+///
+/// Lock ordering: `writer` is always acquired before `connection`, and the
+/// `connection` lock is released before polling the socket.
+struct TlsWriter {
+    socket: WriteHalfAsync,
+    // Encrypted records which are yet to be written to the socket,
+    // so we do not hold the connection lock while writing them
+    outgoing: Vec<u8>,
+    sent: usize,
+    // The task of the write half, while it waits for the socket
+    waiting: Option<Waker>,
+}
+
+pub(super) fn split(
+    connection: Connection,
+    write: WriteHalfAsync,
+    read: ReadHalfAsync,
+) -> (TlsWriteHalfAsync, TlsReadHalfAsync) {
+    let connection = Arc::new(Mutex::new(connection));
+
+    let writer = Arc::new(Mutex::new(TlsWriter {
+        socket: write,
+        outgoing: Vec::new(),
+        sent: 0,
+        waiting: None,
+    }));
+
+    (
+        TlsWriteHalfAsync {
+            connection: connection.clone(),
+            writer: writer.clone(),
+        },
+        TlsReadHalfAsync {
+            connection,
+            writer,
+            socket: read,
+            tls_buf: vec![0; TLS_READ_BUFFER_SIZE].into_boxed_slice(),
+            tls_start: 0,
+            tls_end: 0,
+        },
+    )
+}
+
+impl TlsWriter {
+    /// Move the records queued in the connection (including the ones
+    /// produced by the read half) to our outgoing buffer
+    fn collect_pending(&mut self, connection: &mut Connection) -> io::Result<()> {
+        while connection.wants_write() {
+            connection.write_tls(&mut self.outgoing)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the records in the outgoing buffer to the socket
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.sent < self.outgoing.len() {
+            let written =
+                ready!(Pin::new(&mut self.socket).poll_write(cx, &self.outgoing[self.sent..]))?;
+
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+
+            self.sent += written;
+        }
+
+        self.outgoing.clear();
+        self.sent = 0;
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// [Self::poll_drain], on behalf of the write half
+    fn poll_drain_writer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = self.poll_drain(cx);
+
+        // The socket only keeps the waker of the last task polling it,
+        // so the read half must know to leave the socket to us
+        self.waiting = result.is_pending().then(|| cx.waker().clone());
+
+        result
+    }
+}
+
+impl AsyncWrite for TlsWriteHalfAsync {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+
+        // Bound the ciphertext we hold to the records of a single write
+        ready!(writer.poll_drain_writer(cx))?;
+
+        let written = {
+            let mut connection = self.connection.lock().unwrap();
+
+            // The amount of plaintext accepted by the connection, which is what
+            // the caller is interested in (not the size of the TLS records)
+            let written = connection.writer().write(buf)?;
+
+            writer.collect_pending(&mut connection)?;
+
+            written
+        };
+
+        // Start sending the records right away. Whatever the socket does not
+        // take now is sent on the next write or flush
+        if let Poll::Ready(Err(err)) = writer.poll_drain_writer(cx) {
+            return Poll::Ready(Err(err));
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut writer = self.writer.lock().unwrap();
+
+        writer.collect_pending(&mut self.connection.lock().unwrap())?;
+
+        ready!(writer.poll_drain_writer(cx))?;
+
+        Pin::new(&mut writer.socket).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut writer = self.writer.lock().unwrap();
+
+        {
+            let mut connection = self.connection.lock().unwrap();
+
+            // Only queues the alert the first time, as rustls keeps track of it
+            connection.send_close_notify();
+
+            writer.collect_pending(&mut connection)?;
+        }
+
+        ready!(writer.poll_drain_writer(cx))?;
+
+        Pin::new(&mut writer.socket).poll_close(cx)
+    }
+}
+
+impl TlsReadHalfAsync {
+    /// Get the records produced while reading on their way to the peer.
+    ///
+    /// If the write half is waiting on the socket it is woken up to send them,
+    /// otherwise we send them ourselves. In that case a socket that isn't ready wakes
+    /// up this task, so they keep going out on the following reads. Errors are
+    /// left for the write half to report
+    fn send_pending(&mut self, cx: &mut Context<'_>) {
+        let mut writer = self.writer.lock().unwrap();
+
+        if let Err(err) = writer.collect_pending(&mut self.connection.lock().unwrap()) {
+            debug!(
+                "Failed to queue the records produced while reading: {:?}",
+                err
+            );
+
+            return;
+        }
+
+        if writer.outgoing.is_empty() {
+            return;
+        }
+
+        if let Some(waker) = writer.waiting.take() {
+            waker.wake();
+        } else if let Poll::Ready(Err(err)) = writer.poll_drain(cx) {
+            debug!(
+                "Failed to send the records produced while reading: {:?}",
+                err
+            );
+        }
+    }
+}
+
+impl AsyncRead for TlsReadHalfAsync {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            // Reply to the peer, if required (alerts, key updates, etc.)
+            this.send_pending(cx);
+
+            {
+                let mut connection = this.connection.lock().unwrap();
+
+                match connection.reader().read(buf) {
+                    // Ok(0) means the peer cleanly closed the connection
+                    Ok(read) => return Poll::Ready(Ok(read)),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    Err(err) => return Poll::Ready(Err(err)),
+                }
+
+                if this.tls_start < this.tls_end {
+                    let mut pending = &this.tls_buf[this.tls_start..this.tls_end];
+
+                    // Records might be split across socket reads, rustls will keep
+                    // partial records buffered until they are completed
+                    this.tls_start += connection.read_tls(&mut pending)?;
+
+                    if let Err(err) = connection.process_new_packets() {
+                        error!("Failed to process new tls packets {:?}", err);
+
+                        drop(connection);
+
+                        // Let the peer know with the alert rustls queued
+                        this.send_pending(cx);
+
+                        return Poll::Ready(Err(io::Error::new(ErrorKind::InvalidData, err)));
+                    }
+
+                    continue;
+                }
+            }
+
+            // We have no plaintext nor ciphertext available, so we have to wait for the socket.
+            // The connection lock is not held here, so the write half can keep going
+            let read = ready!(Pin::new(&mut this.socket).poll_read(cx, &mut this.tls_buf))?;
+
+            this.tls_start = 0;
+            this.tls_end = read;
+
+            if read == 0 {
+                // Let rustls know the socket was closed, so the next read reports either a
+                // clean close (close_notify received) or an unexpected EOF
+                let result = {
+                    let mut connection = this.connection.lock().unwrap();
+
+                    connection.read_tls(&mut io::empty())?;
+
+                    connection.process_new_packets().map(|_| ())
+                };
+
+                // The peer might still be reading, so let it know of any alert
+                this.send_pending(cx);
+
+                result.map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::node_id::NodeId;
+    use crate::socket::mtls::test_configs;
+    use crate::socket::{bind_async_server, connect_async, SecureSocketAsync, SocketConfig};
+
+    const MESSAGE_SIZE: usize = 4 * 1024 * 1024 + 7;
+
+    async fn tls_pair() -> (SecureSocketAsync, SecureSocketAsync) {
+        let tls = test_configs(2);
+
+        // Small buffers, so records get split across several socket reads
+        let config = SocketConfig {
+            write_buffer_capacity: 4096,
+            read_buffer_capacity: 1000,
+            ..Default::default()
+        };
+
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+
+        let (client, server) = tokio::join!(
+            tls[1].connect_async(NodeId(0), client.unwrap()),
+            tls[0].accept_async(server.unwrap())
+        );
+
+        (client.unwrap().1, server.unwrap().1)
+    }
+
+    fn message(seed: u8) -> Vec<u8> {
+        (0..MESSAGE_SIZE)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_full_duplex_transfer() {
+        let (client, server) = tls_pair().await;
+
+        let (mut client_write, mut client_read) = client.split();
+        let (mut server_write, mut server_read) = server.split();
+
+        // Both sides write a large message at the same time, while the other side reads it.
+        // This deadlocks if reading and writing can't make progress independently
+        let sides = [
+            tokio::spawn(async move {
+                let send = async {
+                    client_write.write_all(&message(1)).await?;
+                    client_write.flush().await
+                };
+
+                let receive = async {
+                    let mut received = vec![0; MESSAGE_SIZE];
+                    client_read.read_exact(&mut received).await?;
+                    std::io::Result::Ok(received)
+                };
+
+                let (sent, received) = futures::join!(send, receive);
+                sent.unwrap();
+                (received.unwrap(), message(2))
+            }),
+            tokio::spawn(async move {
+                let send = async {
+                    server_write.write_all(&message(2)).await?;
+                    server_write.flush().await
+                };
+
+                let receive = async {
+                    let mut received = vec![0; MESSAGE_SIZE];
+                    server_read.read_exact(&mut received).await?;
+                    std::io::Result::Ok(received)
+                };
+
+                let (sent, received) = futures::join!(send, receive);
+                sent.unwrap();
+                (received.unwrap(), message(1))
+            }),
+        ];
+
+        for side in sides {
+            let (received, expected) = side.await.unwrap();
+
+            assert!(received == expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_is_seen_as_eof() {
+        let (client, server) = tls_pair().await;
+
+        let (mut client_write, _client_read) = client.split();
+        let (_server_write, mut server_read) = server.split();
+
+        client_write.write_all(b"bye").await.unwrap();
+        client_write.close().await.unwrap();
+
+        let mut received = Vec::new();
+        server_read.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, b"bye");
+    }
+}
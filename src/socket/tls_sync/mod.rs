@@ -0,0 +1,431 @@
+//! Full duplex synchronous TLS streams.
+//!
+//! A rustls [Connection] keeps the state of both directions of the stream,
+//! so the read and write halves have to share it. To allow a reader thread and a
+//! writer thread to proceed independently, the connection lock is only ever held
+//! while encrypting or decrypting data in memory, and never while blocked on the socket.
+//!
+//! Lock ordering: `connection` is always acquired before `writer`, and the
+//! `connection` lock is released before performing the (possibly blocking)
+//! write to the socket.
+
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rustls::Connection;
+use tracing::error;
+
+use crate::socket::{ReadHalfSync, SyncSocket, WriteHalfSync};
+
+/// The size of the buffer used to read TLS records from the socket
+/// (a TLS record holds at most 16 KiB of plaintext plus some overhead)
+const TLS_READ_BUFFER_SIZE: usize = 32 * 1024;
+
+struct SharedTlsState {
+    connection: Mutex<Connection>,
+    // The write side of the socket. Both halves might have to write TLS records
+    // (the read half has to send alerts and post handshake messages)
+    writer: Mutex<WriteHalfSync>,
+}
+
+/// Whether [SharedTlsState::send_pending] flushes the socket after writing the records
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum FlushPolicy {
+    /// Leave the records buffered, the application flushes them along with its data
+    Never,
+    /// Flush if there were records to write. The read half uses this, as nobody else
+    /// would flush the alerts and post handshake messages it has to send
+    IfWritten,
+    Always,
+}
+
+/// The read half of a synchronous TLS stream
+pub struct TlsReadHalfSync {
+    shared: Arc<SharedTlsState>,
+    socket: ReadHalfSync,
+    // Ciphertext that was read from the socket but not yet fed to the connection
+    tls_buf: Box<[u8]>,
+    tls_start: usize,
+    tls_end: usize,
+    // Scratch buffer for the TLS records this half has to send
+    outgoing: Vec<u8>,
+}
+
+/// The write half of a synchronous TLS stream
+pub struct TlsWriteHalfSync {
+    shared: Arc<SharedTlsState>,
+    // Scratch buffer for the encrypted records, so we do not hold the
+    // connection lock while writing to the socket
+    outgoing: Vec<u8>,
+}
+
+/// Perform the TLS handshake over the given (not yet split) socket,
+/// blocking until it is completed.
+pub(super) fn complete_handshake(
+    connection: &mut Connection,
+    socket: &mut SyncSocket,
+) -> io::Result<()> {
+    while connection.is_handshaking() {
+        connection.complete_io(socket)?;
+    }
+
+    while connection.wants_write() {
+        connection.write_tls(socket)?;
+    }
+
+    socket.flush()
+}
+
+pub(super) fn split(
+    connection: Connection,
+    socket: SyncSocket,
+) -> (TlsWriteHalfSync, TlsReadHalfSync) {
+    let (write, read) = socket.split();
+
+    let shared = Arc::new(SharedTlsState {
+        connection: Mutex::new(connection),
+        writer: Mutex::new(write),
+    });
+
+    (
+        TlsWriteHalfSync {
+            shared: shared.clone(),
+            outgoing: Vec::new(),
+        },
+        TlsReadHalfSync {
+            shared,
+            socket: read,
+            tls_buf: vec![0; TLS_READ_BUFFER_SIZE].into_boxed_slice(),
+            tls_start: 0,
+            tls_end: 0,
+            outgoing: Vec::new(),
+        },
+    )
+}
+
+impl SharedTlsState {
+    /// Send all the TLS records the connection has queued.
+    ///
+    /// Takes ownership of the connection guard, since it is released
+    /// (after acquiring the writer) before the records are written to the socket.
+    /// Records are written to the socket in the order they were produced, since
+    /// the writer lock is acquired before releasing the connection lock.
+    fn send_pending(
+        &self,
+        mut connection: MutexGuard<'_, Connection>,
+        outgoing: &mut Vec<u8>,
+        flush: FlushPolicy,
+    ) -> io::Result<()> {
+        outgoing.clear();
+
+        while connection.wants_write() {
+            connection.write_tls(outgoing)?;
+        }
+
+        if outgoing.is_empty() && flush != FlushPolicy::Always {
+            return Ok(());
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+
+        drop(connection);
+
+        writer.write_all(outgoing)?;
+
+        if flush != FlushPolicy::Never {
+            writer.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl TlsReadHalfSync {
+    fn has_buffered_tls(&self) -> bool {
+        self.tls_start < self.tls_end
+    }
+}
+
+impl Read for TlsReadHalfSync {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            {
+                let mut connection = self.shared.connection.lock().unwrap();
+
+                match connection.reader().read(buf) {
+                    // Ok(0) means the peer cleanly closed the connection
+                    Ok(read) => return Ok(read),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(err),
+                }
+
+                if self.has_buffered_tls() {
+                    let mut pending = &self.tls_buf[self.tls_start..self.tls_end];
+
+                    // Records might be split across socket reads, rustls will keep
+                    // partial records buffered until they are completed
+                    self.tls_start += connection.read_tls(&mut pending)?;
+
+                    let result = connection.process_new_packets();
+
+                    // Reply to the peer, if required (alerts, key updates, etc.)
+                    self.shared.send_pending(
+                        connection,
+                        &mut self.outgoing,
+                        FlushPolicy::IfWritten,
+                    )?;
+
+                    if let Err(err) = result {
+                        error!("Failed to process new tls packets {:?}", err);
+
+                        return Err(io::Error::new(ErrorKind::InvalidData, err));
+                    }
+
+                    continue;
+                }
+            }
+
+            // We have no plaintext nor ciphertext available, so we have to wait for the socket.
+            // The connection lock is not held here, so the write half can keep going
+            let read = self.socket.read(&mut self.tls_buf)?;
+
+            self.tls_start = 0;
+            self.tls_end = read;
+
+            if read == 0 {
+                // Let rustls know the socket was closed, so the next read reports either a
+                // clean close (close_notify received) or an unexpected EOF
+                let mut connection = self.shared.connection.lock().unwrap();
+
+                connection.read_tls(&mut io::empty())?;
+
+                let result = connection.process_new_packets();
+
+                // The peer might still be reading, so let it know of any alert
+                self.shared
+                    .send_pending(connection, &mut self.outgoing, FlushPolicy::IfWritten)?;
+
+                result.map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+            }
+        }
+    }
+}
+
+impl Write for TlsWriteHalfSync {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let mut connection = self.shared.connection.lock().unwrap();
+
+            // The amount of plaintext accepted by the connection, which is what
+            // the caller is interested in (not the size of the TLS records)
+            let written = connection.writer().write(buf)?;
+
+            self.shared
+                .send_pending(connection, &mut self.outgoing, FlushPolicy::Never)?;
+
+            if written > 0 {
+                return Ok(written);
+            }
+
+            // The connection's send buffer was full, now that we have
+            // drained it we can retry
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let connection = self.shared.connection.lock().unwrap();
+
+        self.shared
+            .send_pending(connection, &mut self.outgoing, FlushPolicy::Always)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::thread;
+
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+
+    use crate::socket::{
+        bind_sync_server, connect_sync, SecureSocketSync, SocketConfig, SyncSocket,
+    };
+
+    const MESSAGE_SIZE: usize = 16 * 1024 * 1024 + 7;
+
+    fn tls_configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let cert_der = CertificateDer::from(cert.cert.der().to_vec());
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+
+        let server = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        (Arc::new(server), Arc::new(client))
+    }
+
+    fn socket_config() -> SocketConfig {
+        // Small buffers, so records get split across several socket reads
+        SocketConfig {
+            write_buffer_capacity: 4096,
+            read_buffer_capacity: 1000,
+            ..Default::default()
+        }
+    }
+
+    fn connected_pair() -> (SyncSocket, SyncSocket) {
+        let listener = bind_sync_server(([127, 0, 0, 1], 0), &socket_config()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || connect_sync(addr, &socket_config()).unwrap());
+        let server = listener.accept().unwrap();
+
+        (client.join().unwrap(), server)
+    }
+
+    fn tls_pair() -> (SecureSocketSync, SecureSocketSync) {
+        let (server_config, client_config) = tls_configs();
+        let (client_sock, server_sock) = connected_pair();
+
+        let client = thread::spawn(move || {
+            let connection =
+                ClientConnection::new(client_config, ServerName::try_from("localhost").unwrap())
+                    .unwrap();
+
+            SecureSocketSync::new_tls_client(connection, client_sock).unwrap()
+        });
+
+        let server = SecureSocketSync::new_tls_server(
+            ServerConnection::new(server_config).unwrap(),
+            server_sock,
+        )
+        .unwrap();
+
+        (client.join().unwrap(), server)
+    }
+
+    fn message(seed: u8) -> Vec<u8> {
+        (0..MESSAGE_SIZE)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    #[test]
+    fn test_large_message_round_trip() {
+        let (client, server) = tls_pair();
+
+        let (mut client_write, mut client_read) = client.split();
+        let (mut server_write, mut server_read) = server.split();
+
+        let sent = message(1);
+        let expected = sent.clone();
+
+        let writer = thread::spawn(move || {
+            client_write.write_all(&sent).unwrap();
+            client_write.flush().unwrap();
+            client_write
+        });
+
+        let mut received = vec![0; MESSAGE_SIZE];
+        server_read.read_exact(&mut received).unwrap();
+
+        assert_eq!(received, expected);
+
+        server_write.write_all(b"ack").unwrap();
+        server_write.flush().unwrap();
+
+        let mut ack = [0; 3];
+        client_read.read_exact(&mut ack).unwrap();
+
+        assert_eq!(&ack, b"ack");
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_full_duplex() {
+        let (client, server) = tls_pair();
+
+        let handles = [(client, 1u8), (server, 2u8)].map(|(socket, seed)| {
+            let (mut write, mut read) = socket.split();
+
+            // Both directions are pushed at the same time, by independent threads,
+            // which would deadlock if a blocked reader held the connection
+            let writer = thread::spawn(move || {
+                write.write_all(&message(seed)).unwrap();
+                write.flush().unwrap();
+            });
+
+            let reader = thread::spawn(move || {
+                let mut received = vec![0; MESSAGE_SIZE];
+                read.read_exact(&mut received).unwrap();
+                received
+            });
+
+            (writer, reader, seed)
+        });
+
+        for (writer, reader, seed) in handles {
+            writer.join().unwrap();
+
+            // Each side receives what the other side sent
+            let other_seed = if seed == 1 { 2 } else { 1 };
+
+            assert!(reader.join().unwrap() == message(other_seed));
+        }
+    }
+
+    #[test]
+    fn test_eof_is_reported_only_on_close() {
+        let (client, server) = tls_pair();
+
+        let (mut client_write, client_read) = client.split();
+        let (_server_write, mut server_read) = server.split();
+
+        // A read with no plaintext available yet must block, not report EOF
+        let reader = thread::spawn(move || {
+            let mut buf = [0; 5];
+            server_read.read_exact(&mut buf).unwrap();
+
+            let mut rest = Vec::new();
+            let result = server_read.read_to_end(&mut rest);
+
+            (buf, result.is_err())
+        });
+
+        thread::sleep(std::time::Duration::from_millis(100));
+
+        client_write.write_all(b"hello").unwrap();
+        client_write.flush().unwrap();
+
+        // Closing the connection without a close_notify is an unexpected EOF
+        drop(client_write);
+        drop(client_read);
+
+        let (buf, unexpected_eof) = reader.join().unwrap();
+
+        assert_eq!(&buf, b"hello");
+        assert!(unexpected_eof);
+    }
+}
@@ -1,57 +1,136 @@
 use futures::{AsyncRead, AsyncWrite};
 use std::io;
+use std::io::IoSlice;
 
 use std::net::SocketAddr;
+use std::path::Path;
 
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{tcp, unix};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
+use crate::socket::SocketConfig;
+
 pub struct Socket {
-    inner: Compat<TcpStream>,
+    inner: Stream,
 }
 
 pub struct Listener {
-    inner: TcpListener,
+    inner: TokioListener,
+}
+
+enum Stream {
+    Tcp(Compat<TcpStream>),
+    Unix(Compat<UnixStream>),
+}
+
+enum TokioListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+pub fn from_std_listener(listener: std::net::TcpListener) -> Result<Listener, io::Error> {
+    listener.set_nonblocking(true)?;
+
+    let listener = TcpListener::from_std(listener)?;
+
+    Ok(Listener::new(TokioListener::Tcp(listener)))
+}
+
+pub fn from_std_unix_listener(
+    listener: std::os::unix::net::UnixListener,
+) -> Result<Listener, io::Error> {
+    listener.set_nonblocking(true)?;
+
+    let listener = UnixListener::from_std(listener)?;
+
+    Ok(Listener::new(TokioListener::Unix(listener)))
 }
 
-pub async fn bind<A: Into<SocketAddr>>(addr: A) -> Result<Listener, io::Error> {
-    let listener = TcpListener::bind(addr.into()).await.map(Listener::new)?;
+pub async fn connect<A: Into<SocketAddr>>(
+    addr: A,
+    config: &SocketConfig,
+) -> Result<Socket, io::Error> {
+    let addr = addr.into();
+
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+
+    // The buffer sizes have to be set before connecting, so the window scale is correct
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(u32::try_from(size).unwrap_or(u32::MAX))?;
+    }
+
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(u32::try_from(size).unwrap_or(u32::MAX))?;
+    }
+
+    let stream = match config.connect_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, socket.connect(addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Connection timed out"))??,
+        None => socket.connect(addr).await?,
+    };
 
-    Ok(listener)
+    Ok(Socket::new(Stream::Tcp(stream.compat())))
 }
 
-pub async fn connect<A: Into<SocketAddr>>(addr: A) -> Result<Socket, io::Error> {
-    let socket = TcpStream::connect(addr.into())
-        .await
-        .map(|s| Socket::new(s.compat()))?;
+pub async fn connect_unix(path: &Path, config: &SocketConfig) -> Result<Socket, io::Error> {
+    let stream = match config.connect_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, UnixStream::connect(path))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Connection timed out"))??,
+        None => UnixStream::connect(path).await?,
+    };
 
-    Ok(socket)
+    Ok(Socket::new(Stream::Unix(stream.compat())))
 }
 
 impl Listener {
-    fn new(inner: TcpListener) -> Self {
+    fn new(inner: TokioListener) -> Self {
         Listener { inner }
     }
 
     pub async fn accept(&self) -> Result<Socket, io::Error> {
-        let socket = self
-            .inner
-            .accept()
-            .await
-            .map(|(s, _)| Socket::new(s.compat()))?;
+        let stream = match &self.inner {
+            TokioListener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(s, _)| Stream::Tcp(s.compat()))?,
+            TokioListener::Unix(listener) => listener
+                .accept()
+                .await
+                .map(|(s, _)| Stream::Unix(s.compat()))?,
+        };
 
-        Ok(socket)
+        Ok(Socket::new(stream))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        match &self.inner {
+            TokioListener::Tcp(listener) => listener.local_addr(),
+            TokioListener::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unix domain listeners have no socket address",
+            )),
+        }
     }
 }
 
 impl Socket {
-    fn new(inner: Compat<TcpStream>) -> Self {
+    fn new(inner: Stream) -> Self {
         Socket { inner }
     }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self.inner, Stream::Unix(_))
+    }
 }
 
 impl AsyncRead for Socket {
@@ -60,7 +139,10 @@ impl AsyncRead for Socket {
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_read(cx, buf)
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
     }
 }
 
@@ -70,15 +152,24 @@ impl AsyncWrite for Socket {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_write(cx, buf)
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.inner).poll_flush(cx)
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.inner).poll_close(cx)
+        match &mut self.inner {
+            Stream::Tcp(stream) => Pin::new(stream).poll_close(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_close(cx),
+        }
     }
 }
 
@@ -86,21 +177,27 @@ impl AsyncWrite for Socket {
 /// We utilize the OwnedWriteHalf instead of
 /// [`tokio::io::split`] as https://users.rust-lang.org/t/why-i-have-to-use-tokio-tcpstream-split-for-concurrent-read-writes/47755/3
 /// suggests it is more efficient and does not require a mutex
-pub struct WriteHalf {
-    inner: Compat<OwnedWriteHalf>,
+pub enum WriteHalf {
+    Tcp(Compat<tcp::OwnedWriteHalf>),
+    Unix(Compat<unix::OwnedWriteHalf>),
 }
 
 /// The read half of a socket
-pub struct ReadHalf {
-    inner: Compat<OwnedReadHalf>,
+pub enum ReadHalf {
+    Tcp(Compat<tcp::OwnedReadHalf>),
+    Unix(Compat<unix::OwnedReadHalf>),
 }
+
 impl AsyncRead for ReadHalf {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_read(cx, buf)
+        match &mut *self {
+            ReadHalf::Tcp(read) => Pin::new(read).poll_read(cx, buf),
+            ReadHalf::Unix(read) => Pin::new(read).poll_read(cx, buf),
+        }
     }
 }
 
@@ -110,29 +207,57 @@ impl AsyncWrite for WriteHalf {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_write(cx, buf)
+        match &mut *self {
+            WriteHalf::Tcp(write) => Pin::new(write).poll_write(cx, buf),
+            WriteHalf::Unix(write) => Pin::new(write).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            WriteHalf::Tcp(write) => Pin::new(write).poll_write_vectored(cx, bufs),
+            WriteHalf::Unix(write) => Pin::new(write).poll_write_vectored(cx, bufs),
+        }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.inner).poll_flush(cx)
+        match &mut *self {
+            WriteHalf::Tcp(write) => Pin::new(write).poll_flush(cx),
+            WriteHalf::Unix(write) => Pin::new(write).poll_flush(cx),
+        }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.inner).poll_close(cx)
+        match &mut *self {
+            WriteHalf::Tcp(write) => Pin::new(write).poll_close(cx),
+            WriteHalf::Unix(write) => Pin::new(write).poll_close(cx),
+        }
     }
 }
 
 pub(super) fn split_socket(sock: Socket) -> (WriteHalf, ReadHalf) {
-    let (read, write) = sock.inner.into_inner().into_split();
+    match sock.inner {
+        Stream::Tcp(stream) => {
+            let (read, write) = stream.into_inner().into_split();
 
-    (
-        WriteHalf {
-            inner: write.compat_write(),
-        },
-        ReadHalf {
-            inner: read.compat(),
-        },
-    )
+            (
+                WriteHalf::Tcp(write.compat_write()),
+                ReadHalf::Tcp(read.compat()),
+            )
+        }
+        Stream::Unix(stream) => {
+            let (read, write) = stream.into_inner().into_split();
+
+            (
+                WriteHalf::Unix(write.compat_write()),
+                ReadHalf::Unix(read.compat()),
+            )
+        }
+    }
 }
 
 #[cfg(windows)]
@@ -144,15 +269,23 @@ mod sys {
 mod sys {
     use std::os::unix::io::{AsRawFd, RawFd};
 
+    use super::{Stream, TokioListener};
+
     impl AsRawFd for super::Socket {
         fn as_raw_fd(&self) -> RawFd {
-            self.inner.as_raw_fd()
+            match &self.inner {
+                Stream::Tcp(stream) => stream.get_ref().as_raw_fd(),
+                Stream::Unix(stream) => stream.get_ref().as_raw_fd(),
+            }
         }
     }
 
     impl AsRawFd for super::Listener {
         fn as_raw_fd(&self) -> RawFd {
-            self.inner.as_raw_fd()
+            match &self.inner {
+                TokioListener::Tcp(listener) => listener.as_raw_fd(),
+                TokioListener::Unix(listener) => listener.as_raw_fd(),
+            }
         }
     }
 }
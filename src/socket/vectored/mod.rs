@@ -0,0 +1,236 @@
+//! Vectored sends of shared, reference counted buffers.
+//!
+//! The regular write path copies everything into the write buffer of the socket
+//! half before it reaches the kernel. When the same serialized message is broadcast
+//! to every replica, that means one extra copy per destination. The methods here take
+//! [Bytes] instead, which can be cloned without copying the underlying allocation,
+//! and hand them to the kernel with a single `writev` per attempt.
+//!
+//! Only plain halves can actually skip the copy: TLS and Noise halves have to
+//! encrypt the data anyway (and WebSocket halves have to put it in frames), so they
+//! just write the buffers one after the other.
+//! The `io_uring` backend does not support vectored sends, so its writes fall back to
+//! sending one buffer at a time.
+
+use std::io;
+use std::io::{ErrorKind, IoSlice, Write};
+
+use bytes::Bytes;
+use futures::{AsyncWrite, AsyncWriteExt};
+
+use crate::socket::{SecureWriteHalfAsync, SecureWriteHalfSync, WriteHalfAsync, WriteHalfSync};
+
+impl WriteHalfSync {
+    /// Write all of the given buffers, without copying them into the write buffer.
+    ///
+    /// Anything that was already buffered is flushed first, so the data still reaches
+    /// the peer in the order it was written. Unlike [Write::write], the buffers are
+    /// fully sent by the time this returns.
+    pub fn write_shared(&mut self, bufs: &[Bytes]) -> io::Result<()> {
+        Write::flush(&mut self.inner)?;
+
+        write_all_vectored_sync(self.inner.get_mut(), bufs)
+    }
+}
+
+impl WriteHalfAsync {
+    /// The async counterpart of [WriteHalfSync::write_shared].
+    pub async fn write_shared(&mut self, bufs: &[Bytes]) -> io::Result<()> {
+        AsyncWriteExt::flush(&mut self.inner).await?;
+
+        write_all_vectored_async(self.inner.get_mut(), bufs).await
+    }
+}
+
+impl SecureWriteHalfSync {
+    /// Write all of the given buffers. See [WriteHalfSync::write_shared].
+    pub fn write_shared(&mut self, bufs: &[Bytes]) -> io::Result<()> {
+        match self {
+            SecureWriteHalfSync::Plain(plain) => plain.write_shared(bufs),
+            SecureWriteHalfSync::Tls(_)
+            | SecureWriteHalfSync::Noise(_)
+            | SecureWriteHalfSync::WebSocket(_) => {
+                for buf in bufs {
+                    self.write_all(buf)?;
+                }
+
+                self.flush()
+            }
+        }
+    }
+}
+
+impl SecureWriteHalfAsync {
+    /// Write all of the given buffers. See [WriteHalfSync::write_shared].
+    pub async fn write_shared(&mut self, bufs: &[Bytes]) -> io::Result<()> {
+        match self {
+            SecureWriteHalfAsync::Plain(plain) => plain.write_shared(bufs).await,
+            SecureWriteHalfAsync::Tls(_)
+            | SecureWriteHalfAsync::Noise(_)
+            | SecureWriteHalfAsync::WebSocket(_) => {
+                for buf in bufs {
+                    self.write_all(buf).await?;
+                }
+
+                self.flush().await
+            }
+        }
+    }
+}
+
+fn io_slices(bufs: &[Bytes]) -> Vec<IoSlice<'_>> {
+    bufs.iter()
+        .filter(|buf| !buf.is_empty())
+        .map(|buf| IoSlice::new(buf))
+        .collect()
+}
+
+fn write_all_vectored_sync<W>(writer: &mut W, bufs: &[Bytes]) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut slices = io_slices(bufs);
+    let mut slices = &mut slices[..];
+
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+async fn write_all_vectored_async<W>(writer: &mut W, bufs: &[Bytes]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut slices = io_slices(bufs);
+    let mut slices = &mut slices[..];
+
+    while !slices.is_empty() {
+        match writer.write_vectored(slices).await {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use futures::AsyncReadExt;
+
+    use super::*;
+    use crate::socket::framing::{FramingConfig, SharedFrame};
+    use crate::socket::{
+        bind_async_server, bind_sync_server, connect_async, connect_sync, SecureSocketAsync,
+        SecureSocketSync, SocketConfig,
+    };
+
+    #[test]
+    fn test_write_all_vectored_handles_short_writes() {
+        /// Accepts at most 3 bytes per call, to exercise the slice advancing
+        struct Trickle(Vec<u8>);
+
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let len = buf.len().min(3);
+                self.0.extend_from_slice(&buf[..len]);
+                Ok(len)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let bufs = [
+            Bytes::from_static(b"header"),
+            Bytes::new(),
+            Bytes::from_static(b"payload"),
+        ];
+
+        let mut writer = Trickle(Vec::new());
+        write_all_vectored_sync(&mut writer, &bufs).unwrap();
+
+        assert_eq!(writer.0, b"headerpayload");
+    }
+
+    #[test]
+    fn test_broadcast_shares_the_payload() {
+        let socket_config = SocketConfig::default();
+        let frame =
+            SharedFrame::new(Bytes::from(vec![42; 100_000]), &FramingConfig::default()).unwrap();
+
+        let receivers: Vec<_> = (0..3)
+            .map(|_| {
+                let listener = bind_sync_server(([127, 0, 0, 1], 0), &socket_config).unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let receiver = thread::spawn(move || {
+                    let socket = SecureSocketSync::new_plain(listener.accept().unwrap());
+                    let (_write, mut read) = socket.split_framed(FramingConfig::default());
+
+                    (read.read_frame().unwrap(), read.read_frame().unwrap())
+                });
+
+                (addr, receiver)
+            })
+            .collect();
+
+        for (addr, _) in &receivers {
+            let socket = SecureSocketSync::new_plain(connect_sync(*addr, &socket_config).unwrap());
+            let (mut write, _read) = socket.split_framed(FramingConfig::default());
+
+            // Buffered data written before the shared frame must arrive before it
+            write.write_frame(b"buffered").unwrap();
+            write.write_shared_frame(&frame).unwrap();
+        }
+
+        for (_, receiver) in receivers {
+            let (first, second) = receiver.join().unwrap();
+
+            assert_eq!(first, b"buffered");
+            assert_eq!(&second[..], &frame.payload()[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_write_shared() {
+        let config = SocketConfig::default();
+
+        let listener = bind_async_server(([127, 0, 0, 1], 0), &config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, server) = tokio::join!(connect_async(addr, &config), listener.accept());
+
+        let (mut write, _read) = SecureSocketAsync::new_plain(client.unwrap()).split();
+        let (_write, mut read) = SecureSocketAsync::new_plain(server.unwrap()).split();
+
+        let payload = Bytes::from(vec![7; 50_000]);
+
+        write.write_all(b"start").await.unwrap();
+        write
+            .write_shared(&[Bytes::from_static(b"header"), payload.clone()])
+            .await
+            .unwrap();
+
+        let mut received = vec![0; 5 + 6 + payload.len()];
+        read.read_exact(&mut received).await.unwrap();
+
+        assert_eq!(&received[..5], b"start");
+        assert_eq!(&received[5..11], b"header");
+        assert_eq!(&received[11..], &payload[..]);
+    }
+}
@@ -0,0 +1,1862 @@
+//! WebSocket transport, for clients that can't easily use raw TCP.
+//!
+//! After the HTTP upgrade handshake (RFC 6455), the connection is used as a plain
+//! byte stream: everything written up to a flush is sent in binary messages, and the
+//! payload of the binary messages received is read back in order. Message boundaries
+//! carry no meaning, so the [framing](crate::socket::framing) layer and the
+//! [node authentication](crate::socket::node_auth) handshake work unchanged on top
+//! of it, just like they do over TCP.
+//!
+//! The listener only accepts the TCP connections: the caller runs the upgrade handshake
+//! of each one with [WebSocketConfig::accept_sync] or [WebSocketConfig::accept_async],
+//! so a client that is slow to send its request doesn't hold up the others. Handshakes
+//! are bounded by a timeout. Upgrade requests are accepted for any path, and text
+//! messages are rejected.
+//!
+//! Pings are answered, and the peer's close message echoed, by whichever half gets to
+//! the socket first: the write half sends them in between its own frames, and the read
+//! half sends them as soon as they are received, unless the write half is waiting for
+//! the socket (it is then woken up to send them). A close message from the peer reads
+//! as the end of the stream, and nothing but the echo of the close is written after it.
+
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context as Cntx, Poll, Waker};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+#[cfg(feature = "serialize_serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::error::*;
+use crate::peer_addr::{EndpointKind, PeerAddr};
+use crate::socket::deadline::{with_timeout, DeadlineSocket, DEFAULT_HANDSHAKE_TIMEOUT};
+use crate::socket::happy_eyeballs::{connect_async_peer, connect_sync_peer};
+use crate::socket::{
+    bind_async_server, bind_sync_server, AsyncListener, AsyncSocket, ReadHalfAsync, ReadHalfSync,
+    SocketConfig, SyncListener, SyncSocket, WriteHalfAsync, WriteHalfSync,
+};
+use crate::Err;
+
+/// The GUID that is appended to the client's key to compute the accept key
+const WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The maximum size of the request or response of the handshake
+const MAX_HANDSHAKE_LEN: usize = 8 * 1024;
+
+/// How much of the handshake we try to read at once
+const HANDSHAKE_READ_LEN: usize = 1024;
+
+/// The name of the handshake, for its timeout error
+const HANDSHAKE: &str = "WebSocket";
+
+const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n";
+
+/// The largest possible frame header (2 bytes, 8 bytes of length and the mask)
+const MAX_HEADER_LEN: usize = 14;
+
+/// The maximum payload of control frames
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Control opcodes all have this bit set
+const CONTROL_BIT: u8 = 0x8;
+
+const FIN_BIT: u8 = 0x80;
+const RESERVED_BITS: u8 = 0x70;
+const MASK_BIT: u8 = 0x80;
+
+/// The default maximum size of the payload of a received frame (64 MiB)
+const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// The default maximum size of the frames we send (64 KiB)
+const DEFAULT_WRITE_FRAME_SIZE: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum WebSocketError {
+    #[error("Malformed handshake: {0}")]
+    MalformedHandshake(&'static str),
+    #[error("The handshake is larger than {0} bytes")]
+    HandshakeTooLarge(usize),
+    #[error("Unsupported WebSocket version {0}")]
+    UnsupportedVersion(String),
+    #[error("The server refused the upgrade with {0:?}")]
+    Refused(String),
+    #[error("The server answered with the wrong accept key")]
+    InvalidAcceptKey,
+}
+
+/// Configuration of the WebSocket transport
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize_serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize_serde", serde(default))]
+pub struct WebSocketConfig {
+    /// The maximum size (in bytes) of the payload of a frame we accept
+    pub max_frame_size: usize,
+    /// The maximum size (in bytes) of the payload of the frames we send.
+    /// Data is sent when the write half is flushed, or once this much was written
+    pub write_frame_size: usize,
+    /// The time the upgrade handshake has to complete, or [None] to wait forever
+    pub handshake_timeout: Option<Duration>,
+}
+
+/// Which side of the connection we are, which decides who masks the frames
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Role {
+    /// Masks the frames it sends
+    Client,
+    /// Expects the frames it receives to be masked
+    Server,
+}
+
+/// A socket over which the WebSocket handshake was completed
+pub struct WebSocketStream<S> {
+    read: WebSocketReadState,
+    writer: WebSocketWriter<S>,
+}
+
+/// The read half of a WebSocket stream
+pub struct WebSocketReadHalf<R, W> {
+    socket: R,
+    state: WebSocketReadState,
+    // Shared with the write half, to answer control frames while it is idle
+    writer: Arc<Mutex<WebSocketWriter<W>>>,
+}
+
+/// The write half of a WebSocket stream
+pub struct WebSocketWriteHalf<W> {
+    writer: Arc<Mutex<WebSocketWriter<W>>>,
+}
+
+/// The write side of the socket, along with the state of the frames sent over it
+struct WebSocketWriter<W> {
+    socket: W,
+    state: WebSocketWriteState,
+    // The task of the async write half, while it waits for the socket
+    waiting_writer: Option<Waker>,
+}
+
+/// A listener that accepts WebSocket connections
+pub struct WebSocketListenerSync {
+    listener: SyncListener,
+    config: WebSocketConfig,
+}
+
+/// The async counterpart of [WebSocketListenerSync]
+pub struct WebSocketListenerAsync {
+    listener: AsyncListener,
+    config: WebSocketConfig,
+}
+
+struct WebSocketReadState {
+    role: Role,
+    max_frame_size: usize,
+    // The header of the next frame
+    header: [u8; MAX_HEADER_LEN],
+    received: usize,
+    // The payload left in the current frame
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    mask_offset: usize,
+    // The opcode of the current frame if it is a control frame, whose payload is kept apart
+    control: Option<u8>,
+    scratch: [u8; MAX_CONTROL_PAYLOAD],
+    scratch_len: usize,
+    replies: Arc<Mutex<ControlReplies>>,
+    // Whether answers to control frames were queued, but not yet sent
+    replying: bool,
+    // What was received right after the handshake, read before the socket
+    received_early: Vec<u8>,
+    closed: bool,
+}
+
+/// The answers to the control frames the read half received, which either half can send
+#[derive(Default)]
+struct ControlReplies {
+    // The payload of the last ping, as only the latest one needs to be answered
+    pong: Option<Vec<u8>>,
+    // The status code of the peer's close frame
+    close: Option<Vec<u8>>,
+}
+
+struct WebSocketWriteState {
+    role: Role,
+    frame_size: usize,
+    // Written data that was not yet put in a frame
+    buffer: Vec<u8>,
+    // An encoded frame not yet fully written to the socket
+    pending: Vec<u8>,
+    written: usize,
+    replies: Arc<Mutex<ControlReplies>>,
+    // A reply taken from `replies` that is waiting for `pending` to be written
+    reply: Option<(u8, Vec<u8>)>,
+    // Whether `pending` holds a reply
+    sending_reply: bool,
+    // Whether the close frame was already put in `pending`
+    closing: bool,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            write_frame_size: DEFAULT_WRITE_FRAME_SIZE,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+        }
+    }
+}
+
+impl WebSocketConfig {
+    /// Complete the handshake with a client that connected to us
+    pub fn accept_sync(&self, mut socket: SyncSocket) -> Result<WebSocketStream<SyncSocket>> {
+        let mut deadline = DeadlineSocket::new(&mut socket, self.handshake_timeout, HANDSHAKE);
+
+        let result = accept_handshake_sync(&mut deadline);
+
+        deadline.finish()?;
+
+        Ok(WebSocketStream::new(socket, result?, Role::Server, self))
+    }
+
+    /// Complete the handshake with a server we connected to, which knows itself as `host`
+    pub fn connect_sync(
+        &self,
+        mut socket: SyncSocket,
+        host: &str,
+    ) -> Result<WebSocketStream<SyncSocket>> {
+        let mut deadline = DeadlineSocket::new(&mut socket, self.handshake_timeout, HANDSHAKE);
+
+        let result = connect_handshake_sync(&mut deadline, host);
+
+        deadline.finish()?;
+
+        Ok(WebSocketStream::new(socket, result?, Role::Client, self))
+    }
+
+    /// The async counterpart of [Self::accept_sync]
+    pub async fn accept_async(
+        &self,
+        mut socket: AsyncSocket,
+    ) -> Result<WebSocketStream<AsyncSocket>> {
+        let received = with_timeout(
+            self.handshake_timeout,
+            HANDSHAKE,
+            accept_handshake_async(&mut socket),
+        )
+        .await?;
+
+        Ok(WebSocketStream::new(socket, received, Role::Server, self))
+    }
+
+    /// The async counterpart of [Self::connect_sync]
+    pub async fn connect_async(
+        &self,
+        mut socket: AsyncSocket,
+        host: &str,
+    ) -> Result<WebSocketStream<AsyncSocket>> {
+        let received = with_timeout(
+            self.handshake_timeout,
+            HANDSHAKE,
+            connect_handshake_async(&mut socket, host),
+        )
+        .await?;
+
+        Ok(WebSocketStream::new(socket, received, Role::Client, self))
+    }
+}
+
+impl WebSocketListenerSync {
+    pub fn bind<A: Into<SocketAddr>>(
+        addr: A,
+        socket_config: &SocketConfig,
+        config: WebSocketConfig,
+    ) -> Result<Self> {
+        Ok(Self::new(bind_sync_server(addr, socket_config)?, config))
+    }
+
+    pub fn new(listener: SyncListener, config: WebSocketConfig) -> Self {
+        Self { listener, config }
+    }
+
+    /// Accept the next connection. Its handshake is left to [WebSocketConfig::accept_sync],
+    /// so that it can run on its own thread instead of holding up the next connections
+    pub fn accept(&self) -> io::Result<SyncSocket> {
+        self.listener.accept()
+    }
+
+    /// The configuration to complete the handshake of the accepted connections with
+    pub fn config(&self) -> &WebSocketConfig {
+        &self.config
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl WebSocketListenerAsync {
+    pub async fn bind<A: Into<SocketAddr>>(
+        addr: A,
+        socket_config: &SocketConfig,
+        config: WebSocketConfig,
+    ) -> Result<Self> {
+        Ok(Self::new(
+            bind_async_server(addr, socket_config).await?,
+            config,
+        ))
+    }
+
+    pub fn new(listener: AsyncListener, config: WebSocketConfig) -> Self {
+        Self { listener, config }
+    }
+
+    /// Accept the next connection. Its handshake is left to [WebSocketConfig::accept_async],
+    /// so that it can run on its own task instead of holding up the next connections
+    pub async fn accept(&self) -> io::Result<AsyncSocket> {
+        self.listener.accept().await
+    }
+
+    /// The configuration to complete the handshake of the accepted connections with
+    pub fn config(&self) -> &WebSocketConfig {
+        &self.config
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// Connect to the client facing endpoint of a replica over WebSocket
+pub fn connect_websocket_sync(
+    peer: &PeerAddr,
+    socket_config: &SocketConfig,
+    config: &WebSocketConfig,
+) -> Result<WebSocketStream<SyncSocket>> {
+    let socket = connect_sync_peer(peer, EndpointKind::Client, socket_config)?;
+
+    config.connect_sync(socket, peer.hostname())
+}
+
+/// The async counterpart of [connect_websocket_sync]
+pub async fn connect_websocket_async(
+    peer: &PeerAddr,
+    socket_config: &SocketConfig,
+    config: &WebSocketConfig,
+) -> Result<WebSocketStream<AsyncSocket>> {
+    let socket = connect_async_peer(peer, EndpointKind::Client, socket_config).await?;
+
+    config.connect_async(socket, peer.hostname()).await
+}
+
+impl<S> WebSocketStream<S> {
+    /// `received` is what the peer sent right after its side of the handshake
+    fn new(socket: S, received: Vec<u8>, role: Role, config: &WebSocketConfig) -> Self {
+        let replies = Arc::new(Mutex::new(ControlReplies::default()));
+
+        Self {
+            read: WebSocketReadState::new(role, config, replies.clone(), received),
+            writer: WebSocketWriter {
+                socket,
+                state: WebSocketWriteState::new(role, config, replies),
+                waiting_writer: None,
+            },
+        }
+    }
+
+    /// Split the stream, using `split` to split its socket
+    fn split_with<W, R>(
+        self,
+        split: impl FnOnce(S) -> (W, R),
+    ) -> (WebSocketWriteHalf<W>, WebSocketReadHalf<R, W>) {
+        let (socket, read) = split(self.writer.socket);
+
+        let writer = Arc::new(Mutex::new(WebSocketWriter {
+            socket,
+            state: self.writer.state,
+            waiting_writer: None,
+        }));
+
+        (
+            WebSocketWriteHalf {
+                writer: writer.clone(),
+            },
+            WebSocketReadHalf {
+                socket: read,
+                state: self.read,
+                writer,
+            },
+        )
+    }
+}
+
+impl WebSocketStream<SyncSocket> {
+    pub(super) fn split(
+        self,
+    ) -> (
+        WebSocketWriteHalf<WriteHalfSync>,
+        WebSocketReadHalf<ReadHalfSync, WriteHalfSync>,
+    ) {
+        self.split_with(SyncSocket::split)
+    }
+}
+
+impl WebSocketStream<AsyncSocket> {
+    pub(super) fn split(
+        self,
+    ) -> (
+        WebSocketWriteHalf<WriteHalfAsync>,
+        WebSocketReadHalf<ReadHalfAsync, WriteHalfAsync>,
+    ) {
+        self.split_with(AsyncSocket::split)
+    }
+}
+
+fn protocol_error(reason: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+/// Nothing but the close frame can be sent once it was
+fn closing_error() -> io::Error {
+    io::Error::new(ErrorKind::BrokenPipe, "The WebSocket is closing")
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4], offset: usize) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[(offset + i) % 4];
+    }
+}
+
+impl ControlReplies {
+    fn take(&mut self) -> Option<(u8, Vec<u8>)> {
+        if let Some(payload) = self.pong.take() {
+            return Some((OP_PONG, payload));
+        }
+
+        self.close.take().map(|payload| (OP_CLOSE, payload))
+    }
+}
+
+/// The socket operations [WebSocketReadState::poll_read_with] relies on
+trait ReadIo {
+    fn poll_read(&mut self, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+
+    /// Send the answers to the control frames that were received
+    fn poll_send_replies(&mut self) -> Poll<io::Result<()>>;
+}
+
+/// The IO of a blocking read half
+struct HalfIoSync<'a, R, W> {
+    socket: &'a mut R,
+    writer: &'a Mutex<WebSocketWriter<W>>,
+}
+
+/// The IO of an async read half
+struct HalfIoAsync<'a, 'b, R, W> {
+    socket: &'a mut R,
+    writer: &'a Mutex<WebSocketWriter<W>>,
+    cx: &'a mut Cntx<'b>,
+}
+
+/// The IO of a blocking stream that was not split
+struct StreamIoSync<'a, S> {
+    writer: &'a mut WebSocketWriter<S>,
+}
+
+/// The IO of an async stream that was not split
+struct StreamIoAsync<'a, 'b, S> {
+    writer: &'a mut WebSocketWriter<S>,
+    cx: &'a mut Cntx<'b>,
+}
+
+impl<R: Read, W: Write> ReadIo for HalfIoSync<'_, R, W> {
+    fn poll_read(&mut self, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.socket.read(buf))
+    }
+
+    fn poll_send_replies(&mut self) -> Poll<io::Result<()>> {
+        Poll::Ready(self.writer.lock().unwrap().send_replies())
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> ReadIo for HalfIoAsync<'_, '_, R, W> {
+    fn poll_read(&mut self, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.socket).poll_read(self.cx, buf)
+    }
+
+    fn poll_send_replies(&mut self) -> Poll<io::Result<()>> {
+        self.writer.lock().unwrap().poll_send_replies(self.cx)
+    }
+}
+
+impl<S: Read + Write> ReadIo for StreamIoSync<'_, S> {
+    fn poll_read(&mut self, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.writer.socket.read(buf))
+    }
+
+    fn poll_send_replies(&mut self) -> Poll<io::Result<()>> {
+        Poll::Ready(self.writer.send_replies())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ReadIo for StreamIoAsync<'_, '_, S> {
+    fn poll_read(&mut self, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer.socket).poll_read(self.cx, buf)
+    }
+
+    fn poll_send_replies(&mut self) -> Poll<io::Result<()>> {
+        self.writer.poll_send_replies(self.cx)
+    }
+}
+
+/// Read what was received right after the handshake into `buf`, or from the socket
+/// once it was all read
+fn poll_read_received(
+    received_early: &mut Vec<u8>,
+    io: &mut impl ReadIo,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    if received_early.is_empty() {
+        return io.poll_read(buf);
+    }
+
+    let len = received_early.len().min(buf.len());
+
+    buf[..len].copy_from_slice(&received_early[..len]);
+    received_early.drain(..len);
+
+    Poll::Ready(Ok(len))
+}
+
+impl WebSocketReadState {
+    fn new(
+        role: Role,
+        config: &WebSocketConfig,
+        replies: Arc<Mutex<ControlReplies>>,
+        received_early: Vec<u8>,
+    ) -> Self {
+        Self {
+            role,
+            max_frame_size: config.max_frame_size,
+            header: [0; MAX_HEADER_LEN],
+            received: 0,
+            remaining: 0,
+            mask: None,
+            mask_offset: 0,
+            control: None,
+            scratch: [0; MAX_CONTROL_PAYLOAD],
+            scratch_len: 0,
+            replies,
+            replying: false,
+            received_early,
+            closed: false,
+        }
+    }
+
+    /// The amount of bytes we need to have received to complete the header
+    fn expected_header_len(&self) -> usize {
+        if self.received < 2 {
+            return 2;
+        }
+
+        let extended_len = match self.header[1] & !MASK_BIT {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+
+        let mask_len = if self.header[1] & MASK_BIT != 0 { 4 } else { 0 };
+
+        2 + extended_len + mask_len
+    }
+
+    /// Start reading the frame whose header was just received
+    fn start_frame(&mut self) -> io::Result<()> {
+        let header = &self.header[..self.received];
+
+        if header[0] & RESERVED_BITS != 0 {
+            return Err(protocol_error("Reserved bits set in WebSocket frame"));
+        }
+
+        let fin = header[0] & FIN_BIT != 0;
+        let opcode = header[0] & 0x0F;
+        let masked = header[1] & MASK_BIT != 0;
+
+        // Clients must mask everything they send, and servers must not
+        if masked != (self.role == Role::Server) {
+            return Err(protocol_error("Unexpected masking of WebSocket frame"));
+        }
+
+        let (len, rest) = match header[1] & !MASK_BIT {
+            126 => (
+                u16::from_be_bytes([header[2], header[3]]) as u64,
+                &header[4..],
+            ),
+            127 => (
+                u64::from_be_bytes(header[2..10].try_into().unwrap()),
+                &header[10..],
+            ),
+            len => (len as u64, &header[2..]),
+        };
+
+        self.mask = masked.then(|| [rest[0], rest[1], rest[2], rest[3]]);
+        self.mask_offset = 0;
+        self.remaining = len;
+        self.received = 0;
+
+        // Control frames can't be fragmented, and must fit in the scratch buffer
+        if opcode & CONTROL_BIT != 0 && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(protocol_error("Malformed WebSocket control frame"));
+        }
+
+        match opcode {
+            OP_CONTINUATION | OP_BINARY => {
+                if len > self.max_frame_size as u64 {
+                    return Err(protocol_error("WebSocket frame exceeds the maximum size"));
+                }
+
+                self.control = None;
+            }
+            OP_PING | OP_PONG | OP_CLOSE => {
+                self.control = Some(opcode);
+                self.scratch_len = 0;
+            }
+            OP_TEXT => return Err(protocol_error("WebSocket text messages are not supported")),
+            _ => return Err(protocol_error("Unknown WebSocket opcode")),
+        }
+
+        Ok(())
+    }
+
+    /// Handle the control frame whose payload was just received
+    fn finish_control(&mut self, opcode: u8) -> io::Result<()> {
+        let payload = &self.scratch[..self.scratch_len];
+        let mut replies = self.replies.lock().unwrap();
+
+        match opcode {
+            OP_PING => {
+                replies.pong = Some(payload.to_vec());
+                self.replying = true;
+            }
+            OP_CLOSE => {
+                // The payload is empty, or starts with a status code
+                if payload.len() == 1 {
+                    return Err(protocol_error("Malformed WebSocket close frame"));
+                }
+
+                replies.close = Some(payload[..payload.len().min(2)].to_vec());
+                self.replying = true;
+                self.closed = true;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Read the payload of the binary frames into `buf`, reading from the socket
+    /// through `io` whenever we need more of it
+    fn poll_read_with(&mut self, buf: &mut [u8], io: &mut impl ReadIo) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            // If the socket can't take the replies yet, we try again on the next read
+            if self.replying {
+                if let Poll::Ready(result) = io.poll_send_replies() {
+                    result?;
+
+                    self.replying = false;
+                }
+            }
+
+            if self.closed {
+                return Poll::Ready(Ok(0));
+            }
+
+            if self.remaining > 0 {
+                let target = if self.control.is_some() {
+                    // Control frames are at most as long as the scratch buffer
+                    let end = self.scratch_len + self.remaining as usize;
+
+                    &mut self.scratch[self.scratch_len..end]
+                } else {
+                    let len = (self.remaining as usize).min(buf.len());
+
+                    &mut buf[..len]
+                };
+
+                let read = ready!(poll_read_received(&mut self.received_early, io, target))?;
+
+                if read == 0 {
+                    return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                }
+
+                self.remaining -= read as u64;
+
+                let received = if self.control.is_some() {
+                    self.scratch_len += read;
+
+                    &mut self.scratch[self.scratch_len - read..self.scratch_len]
+                } else {
+                    &mut buf[..read]
+                };
+
+                if let Some(mask) = self.mask {
+                    apply_mask(received, mask, self.mask_offset);
+                    self.mask_offset += read;
+                }
+
+                if self.control.is_some() {
+                    continue;
+                }
+
+                return Poll::Ready(Ok(read));
+            }
+
+            if let Some(opcode) = self.control.take() {
+                self.finish_control(opcode)?;
+
+                continue;
+            }
+
+            let expected = self.expected_header_len();
+
+            if self.received >= 2 && self.received == expected {
+                self.start_frame()?;
+
+                continue;
+            }
+
+            let header = &mut self.header[self.received..expected];
+
+            let read = ready!(poll_read_received(&mut self.received_early, io, header))?;
+
+            if read == 0 {
+                // Only a close in between frames is a clean EOF
+                return if self.received == 0 {
+                    Poll::Ready(Ok(0))
+                } else {
+                    Poll::Ready(Err(ErrorKind::UnexpectedEof.into()))
+                };
+            }
+
+            self.received += read;
+        }
+    }
+}
+
+impl WebSocketWriteState {
+    fn new(role: Role, config: &WebSocketConfig, replies: Arc<Mutex<ControlReplies>>) -> Self {
+        Self {
+            role,
+            frame_size: config.write_frame_size.max(1),
+            buffer: Vec::new(),
+            pending: Vec::new(),
+            written: 0,
+            replies,
+            reply: None,
+            sending_reply: false,
+            closing: false,
+        }
+    }
+
+    /// Encode a frame with the given opcode and payload.
+    /// Must only be called once the previous frame was fully written
+    fn encode_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mask_bit = if self.role == Role::Client {
+            MASK_BIT
+        } else {
+            0
+        };
+
+        self.pending.clear();
+        self.written = 0;
+
+        self.pending.push(FIN_BIT | opcode);
+
+        match payload.len() {
+            len if len < 126 => self.pending.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                self.pending.push(mask_bit | 126);
+                self.pending.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                self.pending.push(mask_bit | 127);
+                self.pending.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        if self.role == Role::Client {
+            let mask: [u8; 4] = rand::thread_rng().gen();
+
+            self.pending.extend_from_slice(&mask);
+
+            let payload_start = self.pending.len();
+            self.pending.extend_from_slice(payload);
+
+            apply_mask(&mut self.pending[payload_start..], mask, 0);
+        } else {
+            self.pending.extend_from_slice(payload);
+        }
+    }
+
+    /// Put the buffered data in a frame
+    fn encode_buffer(&mut self) {
+        let buffer = std::mem::take(&mut self.buffer);
+
+        self.encode_frame(OP_BINARY, &buffer);
+
+        self.buffer = buffer;
+        self.buffer.clear();
+    }
+
+    /// Write the pending frame to the socket with `write_socket`
+    fn poll_drain_with(
+        &mut self,
+        mut write_socket: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let written = ready!(write_socket(&self.pending[self.written..]))?;
+
+            if written == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+
+            self.written += written;
+        }
+
+        self.pending.clear();
+        self.written = 0;
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Send the answers to the control frames the read half received with `write_socket`,
+    /// in between our own frames
+    fn poll_replies_with(
+        &mut self,
+        mut write_socket: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.sending_reply {
+                ready!(self.poll_drain_with(&mut write_socket))?;
+
+                self.sending_reply = false;
+            }
+
+            if self.reply.is_none() {
+                self.reply = self.replies.lock().unwrap().take();
+
+                if self.reply.is_none() {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            ready!(self.poll_drain_with(&mut write_socket))?;
+
+            let (opcode, payload) = self.reply.take().unwrap();
+
+            // Nothing is sent after a close frame, be it ours or the echo of the peer's
+            if self.closing {
+                continue;
+            }
+
+            self.closing = opcode == OP_CLOSE;
+
+            self.encode_frame(opcode, &payload);
+            self.sending_reply = true;
+        }
+    }
+
+    /// Buffer as much of `buf` as fits in the current frame, sending
+    /// the frame with `write_socket` once it is full
+    fn poll_write_with(
+        &mut self,
+        buf: &[u8],
+        mut write_socket: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        ready!(self.poll_replies_with(&mut write_socket))?;
+
+        if self.closing {
+            return Poll::Ready(Err(closing_error()));
+        }
+
+        if self.buffer.len() >= self.frame_size {
+            ready!(self.poll_drain_with(&mut write_socket))?;
+
+            self.encode_buffer();
+        }
+
+        let len = buf.len().min(self.frame_size - self.buffer.len());
+
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        Poll::Ready(Ok(len))
+    }
+
+    /// Send everything that was buffered with `write_socket`
+    fn poll_send_with(
+        &mut self,
+        mut write_socket: impl FnMut(&[u8]) -> Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_replies_with(&mut write_socket))?;
+        ready!(self.poll_drain_with(&mut write_socket))?;
+
+        if self.closing && !self.buffer.is_empty() {
+            return Poll::Ready(Err(closing_error()));
+        }
+
+        if !self.buffer.is_empty() {
+            self.encode_buffer();
+
+            ready!(self.poll_drain_with(&mut write_socket))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush<W: AsyncWrite + Unpin>(
+        &mut self,
+        socket: &mut W,
+        cx: &mut Cntx<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_with(|pending| Pin::new(&mut *socket).poll_write(cx, pending)))?;
+
+        Pin::new(socket).poll_flush(cx)
+    }
+
+    /// Send everything that was buffered, followed by a close frame.
+    /// If the peer closed first, only the echo of its close is sent
+    fn poll_close<W: AsyncWrite + Unpin>(
+        &mut self,
+        socket: &mut W,
+        cx: &mut Cntx<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_replies_with(|pending| Pin::new(&mut *socket).poll_write(cx, pending)))?;
+
+        if !self.closing {
+            ready!(self.poll_send_with(|pending| Pin::new(&mut *socket).poll_write(cx, pending)))?;
+
+            self.encode_frame(OP_CLOSE, &[]);
+            self.closing = true;
+        }
+
+        ready!(self.poll_drain_with(|pending| Pin::new(&mut *socket).poll_write(cx, pending)))?;
+
+        Pin::new(socket).poll_close(cx)
+    }
+}
+
+fn poll_blocking<T>(poll: Poll<io::Result<T>>) -> io::Result<T> {
+    match poll {
+        Poll::Ready(result) => result,
+        Poll::Pending => unreachable!("Blocking IO is always ready"),
+    }
+}
+
+impl<W: Write> WebSocketWriter<W> {
+    /// Send the answers to the control frames the read half received
+    fn send_replies(&mut self) -> io::Result<()> {
+        let socket = &mut self.socket;
+
+        poll_blocking(
+            self.state
+                .poll_replies_with(|frame| Poll::Ready(socket.write(frame))),
+        )?;
+
+        self.socket.flush()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> WebSocketWriter<W> {
+    /// Send the answers to the control frames the read half received. If the write half
+    /// is waiting for the socket, it is woken up to send them instead, as the socket
+    /// would only wake up one of the two halves once it is writable
+    fn poll_send_replies(&mut self, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        if let Some(writer) = &self.waiting_writer {
+            writer.wake_by_ref();
+
+            return Poll::Ready(Ok(()));
+        }
+
+        let socket = &mut self.socket;
+
+        ready!(self
+            .state
+            .poll_replies_with(|frame| Pin::new(&mut *socket).poll_write(cx, frame)))?;
+
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    /// Keep track of whether the write half is waiting for the socket,
+    /// given the outcome of one of its operations
+    fn track_write_half<T>(&mut self, poll: Poll<T>, cx: &Cntx<'_>) -> Poll<T> {
+        self.waiting_writer = poll.is_pending().then(|| cx.waker().clone());
+
+        poll
+    }
+}
+
+impl<W: Write> Write for WebSocketWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let socket = &mut self.socket;
+
+        poll_blocking(
+            self.state
+                .poll_write_with(buf, |frame| Poll::Ready(socket.write(frame))),
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let socket = &mut self.socket;
+
+        poll_blocking(
+            self.state
+                .poll_send_with(|frame| Poll::Ready(socket.write(frame))),
+        )?;
+
+        self.socket.flush()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WebSocketWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let socket = &mut this.socket;
+
+        this.state
+            .poll_write_with(buf, |frame| Pin::new(&mut *socket).poll_write(cx, frame))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        this.state.poll_flush(&mut this.socket, cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        this.state.poll_close(&mut this.socket, cx)
+    }
+}
+
+impl<R: Read, W: Write> Read for WebSocketReadHalf<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut io = HalfIoSync {
+            socket: &mut self.socket,
+            writer: &self.writer,
+        };
+
+        poll_blocking(self.state.poll_read_with(buf, &mut io))
+    }
+}
+
+impl<W: Write> Write for WebSocketWriteHalf<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncRead for WebSocketReadHalf<R, W> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        let mut io = HalfIoAsync {
+            socket: &mut this.socket,
+            writer: &this.writer,
+            cx,
+        };
+
+        this.state.poll_read_with(buf, &mut io)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WebSocketWriteHalf<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Cntx<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut writer = self.writer.lock().unwrap();
+
+        let poll = Pin::new(&mut *writer).poll_write(cx, buf);
+
+        writer.track_write_half(poll, cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let mut writer = self.writer.lock().unwrap();
+
+        let poll = Pin::new(&mut *writer).poll_flush(cx);
+
+        writer.track_write_half(poll, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        let mut writer = self.writer.lock().unwrap();
+
+        let poll = Pin::new(&mut *writer).poll_close(cx);
+
+        writer.track_write_half(poll, cx)
+    }
+}
+
+impl<S: AsRawFd> AsRawFd for WebSocketStream<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.writer.socket.as_raw_fd()
+    }
+}
+
+impl<S: Read + Write> Read for WebSocketStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut io = StreamIoSync {
+            writer: &mut self.writer,
+        };
+
+        poll_blocking(self.read.poll_read_with(buf, &mut io))
+    }
+}
+
+impl<S: Write> Write for WebSocketStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        let mut io = StreamIoAsync {
+            writer: &mut this.writer,
+            cx,
+        };
+
+        this.read.poll_read_with(buf, &mut io)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Cntx<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Cntx<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_close(cx)
+    }
+}
+
+/// A random key for the client's upgrade request
+fn generate_key() -> String {
+    let key: [u8; 16] = rand::thread_rng().gen();
+
+    BASE64.encode(key)
+}
+
+/// The accept key the server must answer with, for the given client key
+fn derive_accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID);
+
+    BASE64.encode(hasher.finalize())
+}
+
+fn upgrade_request(host: &str, key: &str) -> String {
+    format!(
+        "GET / HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        host, key
+    )
+}
+
+fn upgrade_response(accept_key: &str) -> String {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key
+    )
+}
+
+/// The value of the given header (whose name is case insensitive)
+fn header_value<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+
+        header
+            .trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim())
+    })
+}
+
+/// Validate an upgrade request, returning the accept key to answer with
+fn accept_key_for(request: &str) -> Result<String> {
+    let request_line = request.lines().next().unwrap_or_default();
+
+    if !request_line.starts_with("GET ") || !request_line.ends_with(" HTTP/1.1") {
+        return Err!(WebSocketError::MalformedHandshake("not an HTTP/1.1 GET"));
+    }
+
+    let upgrade = header_value(request, "Upgrade").unwrap_or_default();
+
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return Err!(WebSocketError::MalformedHandshake(
+            "not a WebSocket upgrade"
+        ));
+    }
+
+    let connection = header_value(request, "Connection").unwrap_or_default();
+
+    if !connection
+        .split(',')
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    {
+        return Err!(WebSocketError::MalformedHandshake(
+            "missing the Connection: Upgrade header"
+        ));
+    }
+
+    let version = header_value(request, "Sec-WebSocket-Version").unwrap_or_default();
+
+    if version != "13" {
+        return Err!(WebSocketError::UnsupportedVersion(version.to_string()));
+    }
+
+    match header_value(request, "Sec-WebSocket-Key") {
+        Some(key) if !key.is_empty() => Ok(derive_accept_key(key)),
+        _ => Err!(WebSocketError::MalformedHandshake("missing the key")),
+    }
+}
+
+/// Check the server accepted our upgrade request
+fn check_response(response: &str, key: &str) -> Result<()> {
+    let status_line = response.lines().next().unwrap_or_default();
+
+    if !status_line.starts_with("HTTP/1.1 101") {
+        return Err!(WebSocketError::Refused(status_line.to_string()));
+    }
+
+    if header_value(response, "Sec-WebSocket-Accept") != Some(derive_accept_key(key).as_str()) {
+        return Err!(WebSocketError::InvalidAcceptKey);
+    }
+
+    Ok(())
+}
+
+/// Answer the upgrade request of a client, returning what it sent right after it
+fn accept_handshake_sync<S: Read + Write>(socket: &mut S) -> Result<Vec<u8>> {
+    let (request, received) = read_head_sync(socket)?;
+
+    let accept_key = match accept_key_for(&request) {
+        Ok(accept_key) => accept_key,
+        Err(err) => {
+            // Let the client know why, the connection is dropped either way
+            let _ = socket.write_all(BAD_REQUEST);
+
+            return Err(err);
+        }
+    };
+
+    socket.write_all(upgrade_response(&accept_key).as_bytes())?;
+    socket.flush()?;
+
+    Ok(received)
+}
+
+/// Send our upgrade request to the server, returning what it sent right after its answer
+fn connect_handshake_sync<S: Read + Write>(socket: &mut S, host: &str) -> Result<Vec<u8>> {
+    let key = generate_key();
+
+    socket.write_all(upgrade_request(host, &key).as_bytes())?;
+    socket.flush()?;
+
+    let (response, received) = read_head_sync(socket)?;
+
+    check_response(&response, &key)?;
+
+    Ok(received)
+}
+
+/// The async counterpart of [accept_handshake_sync]
+async fn accept_handshake_async<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+) -> Result<Vec<u8>> {
+    let (request, received) = read_head_async(socket).await?;
+
+    let accept_key = match accept_key_for(&request) {
+        Ok(accept_key) => accept_key,
+        Err(err) => {
+            let _ = socket.write_all(BAD_REQUEST).await;
+
+            return Err(err);
+        }
+    };
+
+    socket
+        .write_all(upgrade_response(&accept_key).as_bytes())
+        .await?;
+    socket.flush().await?;
+
+    Ok(received)
+}
+
+/// The async counterpart of [connect_handshake_sync]
+async fn connect_handshake_async<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    host: &str,
+) -> Result<Vec<u8>> {
+    let key = generate_key();
+
+    socket
+        .write_all(upgrade_request(host, &key).as_bytes())
+        .await?;
+    socket.flush().await?;
+
+    let (response, received) = read_head_async(socket).await?;
+
+    check_response(&response, &key)?;
+
+    Ok(received)
+}
+
+/// The length of the head at the start of `received`, once its final empty line was received
+fn head_len(received: &[u8]) -> Result<Option<usize>> {
+    let head_len = received
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4);
+
+    match head_len {
+        Some(len) if len <= MAX_HANDSHAKE_LEN => Ok(Some(len)),
+        None if received.len() < MAX_HANDSHAKE_LEN => Ok(None),
+        _ => Err!(WebSocketError::HandshakeTooLarge(MAX_HANDSHAKE_LEN)),
+    }
+}
+
+/// Split the head off what was received, parsing it without its final empty line
+fn split_head(mut received: Vec<u8>, head_len: usize) -> Result<(String, Vec<u8>)> {
+    let rest = received.split_off(head_len);
+
+    received.truncate(head_len - 4);
+
+    let head = String::from_utf8(received)
+        .map_err(|_| WebSocketError::MalformedHandshake("the handshake is not valid UTF-8"))?;
+
+    Ok((head, rest))
+}
+
+/// Read the head of an HTTP request or response, up to (and including) the empty line.
+/// Returns it along with what was received after it, the start of the first frames
+fn read_head_sync<S: Read>(socket: &mut S) -> Result<(String, Vec<u8>)> {
+    let mut received = Vec::new();
+    let mut chunk = [0; HANDSHAKE_READ_LEN];
+
+    loop {
+        if let Some(head_len) = head_len(&received)? {
+            return split_head(received, head_len);
+        }
+
+        let read = match socket.read(&mut chunk) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        received.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// The async counterpart of [read_head_sync]
+async fn read_head_async<S: AsyncRead + Unpin>(socket: &mut S) -> Result<(String, Vec<u8>)> {
+    let mut received = Vec::new();
+    let mut chunk = [0; HANDSHAKE_READ_LEN];
+
+    loop {
+        if let Some(head_len) = head_len(&received)? {
+            return split_head(received, head_len);
+        }
+
+        let read = match socket.read(&mut chunk).await {
+            Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        received.extend_from_slice(&chunk[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use futures::task::{waker, ArcWake};
+
+    use crate::peer_addr::Endpoint;
+    use crate::socket::framing::FramingConfig;
+    use crate::socket::{connect_async, connect_sync, SecureSocketAsync, SecureSocketSync};
+
+    use super::*;
+
+    fn client_peer(addr: SocketAddr) -> PeerAddr {
+        PeerAddr::with_endpoints(
+            addr,
+            "localhost".to_string(),
+            vec![Endpoint::socket(EndpointKind::Client, addr)],
+        )
+    }
+
+    /// The halves of a stream over in memory sockets, with `received` to read
+    fn memory_halves<R>(
+        received: R,
+        role: Role,
+        config: &WebSocketConfig,
+    ) -> (WebSocketWriteHalf<Vec<u8>>, WebSocketReadHalf<R, Vec<u8>>) {
+        WebSocketStream::new((received, Vec::new()), Vec::new(), role, config)
+            .split_with(|(read, write)| (write, read))
+    }
+
+    /// Everything that was written to the socket of the write half
+    fn sent(write: &WebSocketWriteHalf<Vec<u8>>) -> Vec<u8> {
+        write.writer.lock().unwrap().socket.clone()
+    }
+
+    /// Counts the times it is woken up
+    #[derive(Default)]
+    struct WakeCounter(AtomicUsize);
+
+    impl ArcWake for WakeCounter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455
+        assert_eq!(
+            derive_accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBCTxVZWo3Fp1N1nq2QLZo="
+        );
+    }
+
+    #[test]
+    fn test_control_frames_are_skipped() {
+        let mut stream = Vec::new();
+
+        // A fragmented binary message, with a ping and an empty frame in between
+        stream.extend_from_slice(&[OP_BINARY, 3, b'a', b'b', b'c']);
+        stream.extend_from_slice(&[FIN_BIT | OP_PING, 2, 1, 2]);
+        stream.extend_from_slice(&[OP_CONTINUATION, 0]);
+        stream.extend_from_slice(&[FIN_BIT | OP_CONTINUATION, 2, b'd', b'e']);
+        stream.extend_from_slice(&[FIN_BIT | OP_CLOSE, 0]);
+        stream.extend_from_slice(&[FIN_BIT | OP_BINARY, 1, b'x']);
+
+        let (_write, mut read) = memory_halves(
+            Cursor::new(stream),
+            Role::Client,
+            &WebSocketConfig::default(),
+        );
+
+        let mut received = Vec::new();
+        read.read_to_end(&mut received).unwrap();
+
+        // Nothing after the close frame is read
+        assert_eq!(received, b"abcde");
+    }
+
+    #[test]
+    fn test_pings_and_closes_are_answered() {
+        let mut stream = Vec::new();
+
+        // Masked with an all zero key, so the payload reads as is
+        stream.extend_from_slice(&[FIN_BIT | OP_PING, MASK_BIT | 2, 0, 0, 0, 0, b'h', b'i']);
+        stream.extend_from_slice(&[
+            FIN_BIT | OP_CLOSE,
+            MASK_BIT | 4,
+            0,
+            0,
+            0,
+            0,
+            3,
+            232,
+            b'o',
+            b'k',
+        ]);
+
+        let (mut write, mut read) = memory_halves(
+            Cursor::new(stream),
+            Role::Server,
+            &WebSocketConfig::default(),
+        );
+
+        let mut received = Vec::new();
+        read.read_to_end(&mut received).unwrap();
+        assert!(received.is_empty());
+
+        // The read half answered, without waiting for the write half to be used.
+        // The pong carries the ping's payload, and the close echoes the status code
+        assert_eq!(
+            sent(&write),
+            [
+                FIN_BIT | OP_PONG,
+                2,
+                b'h',
+                b'i',
+                FIN_BIT | OP_CLOSE,
+                2,
+                3,
+                232
+            ]
+        );
+
+        assert_eq!(
+            Write::write(&mut write, b"late").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn test_malformed_control_frames_are_rejected() {
+        let frames: [&[u8]; 3] = [
+            // Fragmented
+            &[OP_PING, 0],
+            // Longer than 125 bytes
+            &[FIN_BIT | OP_CLOSE, 126, 0, 126],
+            // Only half a status code
+            &[FIN_BIT | OP_CLOSE, 1, 3],
+        ];
+
+        for frame in frames {
+            let (_write, mut read) = memory_halves(
+                Cursor::new(frame.to_vec()),
+                Role::Client,
+                &WebSocketConfig::default(),
+            );
+
+            let err = read.read(&mut [0; 1]).unwrap_err();
+
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_masking_is_enforced() {
+        let (_write, mut read) = memory_halves(
+            Cursor::new(vec![FIN_BIT | OP_BINARY, 1, b'x']),
+            Role::Server,
+            &WebSocketConfig::default(),
+        );
+
+        let err = read.read(&mut [0; 1]).unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_client_frames_round_trip() {
+        let config = WebSocketConfig {
+            write_frame_size: 100,
+            ..Default::default()
+        };
+
+        let (mut write, _read) = memory_halves(Cursor::new(Vec::new()), Role::Client, &config);
+
+        let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+
+        Write::write_all(&mut write, &payload).unwrap();
+        Write::flush(&mut write).unwrap();
+
+        let (_write, mut read) = memory_halves(Cursor::new(sent(&write)), Role::Server, &config);
+
+        let mut received = Vec::new();
+        read.read_to_end(&mut received).unwrap();
+
+        assert_eq!(received, payload);
+    }
+
+    #[test]
+    fn test_sync_framed_round_trip() {
+        let socket_config = SocketConfig::default();
+        let config = WebSocketConfig {
+            write_frame_size: 1024,
+            ..Default::default()
+        };
+
+        let listener =
+            WebSocketListenerSync::bind(([127, 0, 0, 1], 0), &socket_config, config).unwrap();
+        let peer = client_peer(listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap();
+            let stream = listener.config().accept_sync(socket).unwrap();
+            let (mut write, mut read) =
+                SecureSocketSync::from(stream).split_framed(FramingConfig::default());
+
+            let request = read.read_frame().unwrap();
+            write.write_frame(&request).unwrap();
+            write.flush().unwrap();
+        });
+
+        let stream = connect_websocket_sync(&peer, &socket_config, &config).unwrap();
+        let (mut write, mut read) =
+            SecureSocketSync::from(stream).split_framed(FramingConfig::default());
+
+        let request = vec![9; 10_000];
+
+        write.write_frame(&request).unwrap();
+        write.flush().unwrap();
+
+        assert_eq!(read.read_frame().unwrap(), request);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_plain_requests_are_refused() {
+        let socket_config = SocketConfig::default();
+        let listener = WebSocketListenerSync::bind(
+            ([127, 0, 0, 1], 0),
+            &socket_config,
+            WebSocketConfig::default(),
+        )
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap();
+
+            listener.config().accept_sync(socket).err().unwrap()
+        });
+
+        let mut socket = connect_sync(addr, &socket_config).unwrap();
+        socket
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        socket.flush().unwrap();
+
+        let (response, _) = read_head_sync(&mut socket).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert!(server
+            .join()
+            .unwrap()
+            .downcast_ref::<WebSocketError>()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_async_framed_round_trip() {
+        let socket_config = SocketConfig::default();
+        let config = WebSocketConfig::default();
+
+        let listener = WebSocketListenerAsync::bind(([127, 0, 0, 1], 0), &socket_config, config)
+            .await
+            .unwrap();
+        let peer = client_peer(listener.local_addr().unwrap());
+
+        let (client, server) = tokio::join!(
+            connect_websocket_async(&peer, &socket_config, &config),
+            async {
+                let socket = listener.accept().await.unwrap();
+
+                listener.config().accept_async(socket).await
+            }
+        );
+
+        let (mut write, _read) =
+            SecureSocketAsync::from(client.unwrap()).split_framed(FramingConfig::default());
+        let (_write, mut read) =
+            SecureSocketAsync::from(server.unwrap()).split_framed(FramingConfig::default());
+
+        write.write_frame(b"request").await.unwrap();
+        write.flush().await.unwrap();
+
+        assert_eq!(read.read_frame().await.unwrap(), b"request");
+    }
+
+    #[tokio::test]
+    async fn test_pings_are_answered_by_the_async_read_half() {
+        let stream = vec![FIN_BIT | OP_PING, MASK_BIT | 2, 0, 0, 0, 0, b'h', b'i'];
+
+        let (mut write, mut read) = memory_halves(
+            futures::io::Cursor::new(stream),
+            Role::Server,
+            &WebSocketConfig::default(),
+        );
+
+        // The write half is waiting for the socket, so it is left to answer
+        let counter = Arc::new(WakeCounter::default());
+        write.writer.lock().unwrap().waiting_writer = Some(waker(counter.clone()));
+
+        let mut received = Vec::new();
+        AsyncReadExt::read_to_end(&mut read, &mut received)
+            .await
+            .unwrap();
+
+        assert!(sent(&write).is_empty());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        AsyncWriteExt::flush(&mut write).await.unwrap();
+
+        assert_eq!(sent(&write), [FIN_BIT | OP_PONG, 2, b'h', b'i']);
+
+        // Once the write half is idle, the read half answers by itself
+        let stream = vec![FIN_BIT | OP_PING, MASK_BIT | 1, 0, 0, 0, 0, b'!'];
+
+        read.socket = futures::io::Cursor::new(stream);
+
+        AsyncReadExt::read_to_end(&mut read, &mut received)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            sent(&write),
+            [FIN_BIT | OP_PONG, 2, b'h', b'i', FIN_BIT | OP_PONG, 1, b'!']
+        );
+    }
+
+    #[test]
+    fn test_frames_received_with_the_handshake_are_read() {
+        let mut stream = b"HTTP/1.1 101 Switching Protocols\r\n\r\n".to_vec();
+
+        stream.extend_from_slice(&[FIN_BIT | OP_BINARY, 2, b'h', b'i']);
+        stream.extend_from_slice(&[FIN_BIT | OP_BINARY, 1, b'!']);
+
+        let (head, mut early) = read_head_sync(&mut Cursor::new(stream)).unwrap();
+
+        assert_eq!(head, "HTTP/1.1 101 Switching Protocols");
+        assert_eq!(early.len(), 7);
+
+        // Only part of the frames came along with the head, the rest is read from the socket
+        let rest = early.split_off(3);
+
+        let (_write, mut read) = WebSocketStream::new(
+            (Cursor::new(rest), Vec::new()),
+            early,
+            Role::Client,
+            &WebSocketConfig::default(),
+        )
+        .split_with(|(read, write)| (write, read));
+
+        let mut received = Vec::new();
+        read.read_to_end(&mut received).unwrap();
+
+        assert_eq!(received, b"hi!");
+    }
+
+    #[test]
+    fn test_silent_client_times_out() {
+        let socket_config = SocketConfig::default();
+        let config = WebSocketConfig {
+            handshake_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        let listener =
+            WebSocketListenerSync::bind(([127, 0, 0, 1], 0), &socket_config, config).unwrap();
+
+        // Connects, but never sends its upgrade request
+        let _silent = connect_sync(listener.local_addr().unwrap(), &socket_config).unwrap();
+
+        let socket = listener.accept().unwrap();
+
+        let err = listener
+            .config()
+            .accept_sync(socket)
+            .map(|_| ())
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(ErrorKind::TimedOut)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_times_out_async() {
+        let socket_config = SocketConfig::default();
+        let config = WebSocketConfig {
+            handshake_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        let listener = WebSocketListenerAsync::bind(([127, 0, 0, 1], 0), &socket_config, config)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (silent, socket) = tokio::join!(connect_async(addr, &socket_config), listener.accept());
+        let _silent = silent.unwrap();
+
+        let err = listener
+            .config()
+            .accept_async(socket.unwrap())
+            .await
+            .map(|_| ())
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(ErrorKind::TimedOut)
+        );
+    }
+}